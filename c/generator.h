@@ -39,6 +39,11 @@ extern int execute_vm(const uint8_t *source,
 #include <stdio.h>
 #endif
 
+/* Tell the host the program has finished, the exit syscall is handled by the
+ * VM itself so the host can't observe the cycles consumed after the last
+ * syscall otherwise. */
+#define _CSAL_FINISH_SYSCALL_NUMBER 3085
+
 int main(int argc, char *argv[]) {
   if (argc != 3) {
     ckb_debug(
@@ -83,7 +88,9 @@ int main(int argc, char *argv[]) {
   csal_change_t existing_values = NULL;
   csal_change_t changes = NULL;
   bool destructed;
-  return execute_vm(source, length, &existing_values, &changes, &destructed);
+  int ret = execute_vm(source, length, &existing_values, &changes, &destructed);
+  syscall(_CSAL_FINISH_SYSCALL_NUMBER, ret, 0, 0, 0, 0, 0);
+  return ret;
 }
 
 #define _CSAL_CHANGE_INSERT_SYSCALL_NUMBER 3073
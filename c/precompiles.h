@@ -0,0 +1,1024 @@
+/*
+ * The precompiled contracts (0x01 - 0x09) run natively by the validator, they
+ * must return the same output as the generator (src/storage/precompiles.rs).
+ *
+ * The output is allocated by malloc and must be freed by the caller. The
+ * pairing check (0x08) is not supported, the generator refuses to build a
+ * transaction calling it.
+ */
+#ifndef POLYJUICE_PRECOMPILES_H_
+#define POLYJUICE_PRECOMPILES_H_
+
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+#define ERROR_PRECOMPILE -115
+
+/* The max length of base/exponent/modulus of modexp */
+#define MODEXP_MAX_LENGTH 1024
+/* Big numbers are little endian 32-bit limbs */
+#define BN_MAX_LIMBS (MODEXP_MAX_LENGTH / 4)
+
+/* The input is treated as infinitely right padded with zeros */
+static void padded_copy(uint8_t *dest,
+                        const uint8_t *input,
+                        size_t input_size,
+                        size_t offset,
+                        size_t length) {
+  memset(dest, 0, length);
+  if (offset < input_size) {
+    size_t copy_size = input_size - offset;
+    if (copy_size > length) {
+      copy_size = length;
+    }
+    memcpy(dest, input + offset, copy_size);
+  }
+}
+
+static int precompile_output(uint8_t **output,
+                             size_t *output_size,
+                             size_t size) {
+  *output_size = size;
+  if (size == 0) {
+    *output = NULL;
+    return 0;
+  }
+  *output = (uint8_t *)malloc(size);
+  if (*output == NULL) {
+    return ERROR_PRECOMPILE;
+  }
+  memset(*output, 0, size);
+  return 0;
+}
+
+/* ==== 0x01: ecrecover ==== */
+
+static secp256k1_context precompile_secp_context;
+static uint8_t *precompile_secp_data = NULL;
+
+static int precompile_secp_initialize() {
+  if (precompile_secp_data != NULL) {
+    return 0;
+  }
+  precompile_secp_data = (uint8_t *)malloc(CKB_SECP256K1_DATA_SIZE);
+  if (precompile_secp_data == NULL) {
+    return ERROR_PRECOMPILE;
+  }
+  int ret = ckb_secp256k1_custom_verify_only_initialize(&precompile_secp_context,
+                                                        precompile_secp_data);
+  if (ret != 0) {
+    free(precompile_secp_data);
+    precompile_secp_data = NULL;
+    return ret;
+  }
+  return 0;
+}
+
+/* Invalid signature returns empty output */
+static int precompile_ecrecover(const uint8_t *input,
+                                size_t input_size,
+                                uint8_t **output,
+                                size_t *output_size) {
+  uint8_t data[128];
+  padded_copy(data, input, input_size, 0, 128);
+  const uint8_t *hash = data;
+  const uint8_t *v = data + 32;
+  const uint8_t *signature = data + 64;
+  for (size_t i = 0; i < 31; i++) {
+    if (v[i] != 0) {
+      return precompile_output(output, output_size, 0);
+    }
+  }
+  if (v[31] != 27 && v[31] != 28) {
+    return precompile_output(output, output_size, 0);
+  }
+
+  int ret = precompile_secp_initialize();
+  if (ret != 0) {
+    return ret;
+  }
+  secp256k1_ecdsa_recoverable_signature recoverable;
+  if (secp256k1_ecdsa_recoverable_signature_parse_compact(
+          &precompile_secp_context, &recoverable, signature, v[31] - 27) == 0) {
+    return precompile_output(output, output_size, 0);
+  }
+  secp256k1_pubkey pubkey;
+  if (secp256k1_ecdsa_recover(&precompile_secp_context, &pubkey, &recoverable,
+                              hash) != 1) {
+    return precompile_output(output, output_size, 0);
+  }
+  uint8_t serialized[65];
+  size_t serialized_size = 65;
+  if (secp256k1_ec_pubkey_serialize(&precompile_secp_context, serialized,
+                                    &serialized_size, &pubkey,
+                                    SECP256K1_EC_UNCOMPRESSED) != 1) {
+    return ERROR_PRECOMPILE;
+  }
+  const union ethash_hash256 pubkey_hash =
+      ethash_keccak256(serialized + 1, serialized_size - 1);
+  ret = precompile_output(output, output_size, 32);
+  if (ret != 0) {
+    return ret;
+  }
+  memcpy(*output + 12, pubkey_hash.bytes + 12, 20);
+  return 0;
+}
+
+/* ==== 0x02: sha256 ==== */
+
+static const uint32_t SHA256_K[64] = {
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+    0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+    0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2};
+
+static uint32_t rotr32(uint32_t x, int n) { return (x >> n) | (x << (32 - n)); }
+
+static void sha256_block(uint32_t state[8], const uint8_t block[64]) {
+  uint32_t w[64];
+  for (int i = 0; i < 16; i++) {
+    w[i] = ((uint32_t)block[i * 4] << 24) | ((uint32_t)block[i * 4 + 1] << 16) |
+           ((uint32_t)block[i * 4 + 2] << 8) | (uint32_t)block[i * 4 + 3];
+  }
+  for (int i = 16; i < 64; i++) {
+    uint32_t s0 = rotr32(w[i - 15], 7) ^ rotr32(w[i - 15], 18) ^ (w[i - 15] >> 3);
+    uint32_t s1 = rotr32(w[i - 2], 17) ^ rotr32(w[i - 2], 19) ^ (w[i - 2] >> 10);
+    w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+  }
+  uint32_t a = state[0], b = state[1], c = state[2], d = state[3];
+  uint32_t e = state[4], f = state[5], g = state[6], h = state[7];
+  for (int i = 0; i < 64; i++) {
+    uint32_t s1 = rotr32(e, 6) ^ rotr32(e, 11) ^ rotr32(e, 25);
+    uint32_t ch = (e & f) ^ (~e & g);
+    uint32_t t1 = h + s1 + ch + SHA256_K[i] + w[i];
+    uint32_t s0 = rotr32(a, 2) ^ rotr32(a, 13) ^ rotr32(a, 22);
+    uint32_t maj = (a & b) ^ (a & c) ^ (b & c);
+    uint32_t t2 = s0 + maj;
+    h = g;
+    g = f;
+    f = e;
+    e = d + t1;
+    d = c;
+    c = b;
+    b = a;
+    a = t1 + t2;
+  }
+  state[0] += a;
+  state[1] += b;
+  state[2] += c;
+  state[3] += d;
+  state[4] += e;
+  state[5] += f;
+  state[6] += g;
+  state[7] += h;
+}
+
+static int precompile_sha256(const uint8_t *input,
+                             size_t input_size,
+                             uint8_t **output,
+                             size_t *output_size) {
+  uint32_t state[8] = {0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+                       0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19};
+  size_t offset = 0;
+  for (; offset + 64 <= input_size; offset += 64) {
+    sha256_block(state, input + offset);
+  }
+  /* Padding: 0x80, zeros, 64-bit big endian bit length */
+  uint8_t block[128];
+  memset(block, 0, 128);
+  size_t rest = input_size - offset;
+  memcpy(block, input + offset, rest);
+  block[rest] = 0x80;
+  size_t block_size = rest + 1 + 8 <= 64 ? 64 : 128;
+  uint64_t bits = (uint64_t)input_size * 8;
+  for (int i = 0; i < 8; i++) {
+    block[block_size - 1 - i] = (uint8_t)(bits >> (8 * i));
+  }
+  sha256_block(state, block);
+  if (block_size == 128) {
+    sha256_block(state, block + 64);
+  }
+
+  int ret = precompile_output(output, output_size, 32);
+  if (ret != 0) {
+    return ret;
+  }
+  for (int i = 0; i < 8; i++) {
+    (*output)[i * 4] = (uint8_t)(state[i] >> 24);
+    (*output)[i * 4 + 1] = (uint8_t)(state[i] >> 16);
+    (*output)[i * 4 + 2] = (uint8_t)(state[i] >> 8);
+    (*output)[i * 4 + 3] = (uint8_t)state[i];
+  }
+  return 0;
+}
+
+/* ==== 0x03: ripemd160 ==== */
+
+static const uint8_t RIPEMD160_R[80] = {
+    0, 1, 2,  3,  4,  5,  6,  7,  8, 9,  10, 11, 12, 13, 14, 15,
+    7, 4, 13, 1,  10, 6,  15, 3,  12, 0, 9,  5,  2,  14, 11, 8,
+    3, 10, 14, 4, 9,  15, 8,  1,  2,  7, 0,  6,  13, 11, 5,  12,
+    1, 9, 11, 10, 0,  8,  12, 4,  13, 3, 7,  15, 14, 5,  6,  2,
+    4, 0, 5,  9,  7,  12, 2,  10, 14, 1, 3,  8,  11, 6,  15, 13};
+static const uint8_t RIPEMD160_RP[80] = {
+    5,  14, 7,  0, 9, 2,  11, 4,  13, 6,  15, 8,  1,  10, 3,  12,
+    6,  11, 3,  7, 0, 13, 5,  10, 14, 15, 8,  12, 4,  9,  1,  2,
+    15, 5,  1,  3, 7, 14, 6,  9,  11, 8,  12, 2,  10, 0,  4,  13,
+    8,  6,  4,  1, 3, 11, 15, 0,  5,  12, 2,  13, 9,  7,  10, 14,
+    12, 15, 10, 4, 1, 5,  8,  7,  6,  2,  13, 14, 0,  3,  9,  11};
+static const uint8_t RIPEMD160_S[80] = {
+    11, 14, 15, 12, 5,  8,  7,  9,  11, 13, 14, 15, 6,  7,  9,  8,
+    7,  6,  8,  13, 11, 9,  7,  15, 7,  12, 15, 9,  11, 7,  13, 12,
+    11, 13, 6,  7,  14, 9,  13, 15, 14, 8,  13, 6,  5,  12, 7,  5,
+    11, 12, 14, 15, 14, 15, 9,  8,  9,  14, 5,  6,  8,  6,  5,  12,
+    9,  15, 5,  11, 6,  8,  13, 12, 5,  12, 13, 14, 11, 8,  5,  6};
+static const uint8_t RIPEMD160_SP[80] = {
+    8,  9,  9,  11, 13, 15, 15, 5,  7,  7,  8,  11, 14, 14, 12, 6,
+    9,  13, 15, 7,  12, 8,  9,  11, 7,  7,  12, 7,  6,  15, 13, 11,
+    9,  7,  15, 11, 8,  6,  6,  14, 12, 13, 5,  14, 13, 13, 7,  5,
+    15, 5,  8,  11, 14, 14, 6,  14, 6,  9,  12, 9,  12, 5,  15, 8,
+    8,  5,  12, 9,  12, 5,  14, 6,  8,  13, 6,  5,  15, 13, 11, 11};
+static const uint32_t RIPEMD160_K[5] = {0x00000000, 0x5a827999, 0x6ed9eba1,
+                                        0x8f1bbcdc, 0xa953fd4e};
+static const uint32_t RIPEMD160_KP[5] = {0x50a28be6, 0x5c4dd124, 0x6d703ef3,
+                                         0x7a6d76e9, 0x00000000};
+
+static uint32_t rotl32(uint32_t x, int n) { return (x << n) | (x >> (32 - n)); }
+
+static uint32_t ripemd160_f(int j, uint32_t x, uint32_t y, uint32_t z) {
+  switch (j / 16) {
+    case 0:
+      return x ^ y ^ z;
+    case 1:
+      return (x & y) | (~x & z);
+    case 2:
+      return (x | ~y) ^ z;
+    case 3:
+      return (x & z) | (y & ~z);
+    default:
+      return x ^ (y | ~z);
+  }
+}
+
+static void ripemd160_block(uint32_t state[5], const uint8_t block[64]) {
+  uint32_t x[16];
+  for (int i = 0; i < 16; i++) {
+    x[i] = (uint32_t)block[i * 4] | ((uint32_t)block[i * 4 + 1] << 8) |
+           ((uint32_t)block[i * 4 + 2] << 16) | ((uint32_t)block[i * 4 + 3] << 24);
+  }
+  uint32_t al = state[0], bl = state[1], cl = state[2], dl = state[3], el = state[4];
+  uint32_t ar = al, br = bl, cr = cl, dr = dl, er = el;
+  for (int j = 0; j < 80; j++) {
+    uint32_t t = rotl32(al + ripemd160_f(j, bl, cl, dl) + x[RIPEMD160_R[j]] +
+                            RIPEMD160_K[j / 16],
+                        RIPEMD160_S[j]) +
+                 el;
+    al = el;
+    el = dl;
+    dl = rotl32(cl, 10);
+    cl = bl;
+    bl = t;
+    t = rotl32(ar + ripemd160_f(79 - j, br, cr, dr) + x[RIPEMD160_RP[j]] +
+                   RIPEMD160_KP[j / 16],
+               RIPEMD160_SP[j]) +
+        er;
+    ar = er;
+    er = dr;
+    dr = rotl32(cr, 10);
+    cr = br;
+    br = t;
+  }
+  uint32_t t = state[1] + cl + dr;
+  state[1] = state[2] + dl + er;
+  state[2] = state[3] + el + ar;
+  state[3] = state[4] + al + br;
+  state[4] = state[0] + bl + cr;
+  state[0] = t;
+}
+
+/* The 20 bytes hash is left padded to 32 bytes */
+static int precompile_ripemd160(const uint8_t *input,
+                                size_t input_size,
+                                uint8_t **output,
+                                size_t *output_size) {
+  uint32_t state[5] = {0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476,
+                       0xc3d2e1f0};
+  size_t offset = 0;
+  for (; offset + 64 <= input_size; offset += 64) {
+    ripemd160_block(state, input + offset);
+  }
+  /* Padding: 0x80, zeros, 64-bit little endian bit length */
+  uint8_t block[128];
+  memset(block, 0, 128);
+  size_t rest = input_size - offset;
+  memcpy(block, input + offset, rest);
+  block[rest] = 0x80;
+  size_t block_size = rest + 1 + 8 <= 64 ? 64 : 128;
+  uint64_t bits = (uint64_t)input_size * 8;
+  for (int i = 0; i < 8; i++) {
+    block[block_size - 8 + i] = (uint8_t)(bits >> (8 * i));
+  }
+  ripemd160_block(state, block);
+  if (block_size == 128) {
+    ripemd160_block(state, block + 64);
+  }
+
+  int ret = precompile_output(output, output_size, 32);
+  if (ret != 0) {
+    return ret;
+  }
+  for (int i = 0; i < 5; i++) {
+    for (int j = 0; j < 4; j++) {
+      (*output)[12 + i * 4 + j] = (uint8_t)(state[i] >> (8 * j));
+    }
+  }
+  return 0;
+}
+
+/* ==== 0x04: identity ==== */
+
+static int precompile_identity(const uint8_t *input,
+                               size_t input_size,
+                               uint8_t **output,
+                               size_t *output_size) {
+  int ret = precompile_output(output, output_size, input_size);
+  if (ret != 0) {
+    return ret;
+  }
+  if (input_size > 0) {
+    memcpy(*output, input, input_size);
+  }
+  return 0;
+}
+
+/* ==== Big number arithmetic for modexp and bn256 ==== */
+
+static void bn_from_be(uint32_t *r, size_t n, const uint8_t *data, size_t size) {
+  memset(r, 0, n * 4);
+  for (size_t i = 0; i < size; i++) {
+    /* Byte position counted from the least significant byte */
+    size_t pos = size - 1 - i;
+    r[pos / 4] |= (uint32_t)data[i] << (8 * (pos % 4));
+  }
+}
+
+static void bn_to_be(uint8_t *data, size_t size, const uint32_t *a, size_t n) {
+  for (size_t i = 0; i < size; i++) {
+    size_t pos = size - 1 - i;
+    data[i] = pos / 4 < n ? (uint8_t)(a[pos / 4] >> (8 * (pos % 4))) : 0;
+  }
+}
+
+/* The number of limbs without the leading zeros */
+static size_t bn_len(const uint32_t *a, size_t n) {
+  while (n > 0 && a[n - 1] == 0) {
+    n--;
+  }
+  return n;
+}
+
+static int bn_cmp(const uint32_t *a, const uint32_t *b, size_t n) {
+  for (size_t i = n; i > 0; i--) {
+    if (a[i - 1] != b[i - 1]) {
+      return a[i - 1] > b[i - 1] ? 1 : -1;
+    }
+  }
+  return 0;
+}
+
+/* r = a + b, return the carry */
+static uint32_t bn_add(uint32_t *r, const uint32_t *a, const uint32_t *b, size_t n) {
+  uint64_t carry = 0;
+  for (size_t i = 0; i < n; i++) {
+    carry += (uint64_t)a[i] + b[i];
+    r[i] = (uint32_t)carry;
+    carry >>= 32;
+  }
+  return (uint32_t)carry;
+}
+
+/* r = a - b, return the borrow */
+static uint32_t bn_sub(uint32_t *r, const uint32_t *a, const uint32_t *b, size_t n) {
+  uint64_t borrow = 0;
+  for (size_t i = 0; i < n; i++) {
+    uint64_t diff = (uint64_t)a[i] - b[i] - borrow;
+    r[i] = (uint32_t)diff;
+    borrow = (diff >> 32) & 1;
+  }
+  return (uint32_t)borrow;
+}
+
+/* r = a * b, r has 2n limbs and must not overlap a or b */
+static void bn_mul(uint32_t *r, const uint32_t *a, const uint32_t *b, size_t n) {
+  memset(r, 0, n * 2 * 4);
+  size_t a_len = bn_len(a, n);
+  size_t b_len = bn_len(b, n);
+  for (size_t i = 0; i < a_len; i++) {
+    uint64_t carry = 0;
+    for (size_t j = 0; j < b_len; j++) {
+      carry += (uint64_t)a[i] * b[j] + r[i + j];
+      r[i + j] = (uint32_t)carry;
+      carry >>= 32;
+    }
+    r[i + b_len] = (uint32_t)carry;
+  }
+}
+
+/*
+ * r = u mod v, u has m limbs (m <= 2 * BN_MAX_LIMBS), v and r have n limbs
+ * (n <= BN_MAX_LIMBS), v must not be zero. Knuth's algorithm D.
+ */
+static void bn_mod(uint32_t *r, const uint32_t *u, size_t m, const uint32_t *v, size_t n) {
+  uint32_t un[BN_MAX_LIMBS * 2 + 1];
+  uint32_t vn[BN_MAX_LIMBS];
+  size_t u_len = bn_len(u, m);
+  size_t v_len = bn_len(v, n);
+  if (u_len < v_len) {
+    memset(r, 0, n * 4);
+    memcpy(r, u, u_len * 4);
+    return;
+  }
+  if (v_len == 1) {
+    uint64_t rem = 0;
+    for (size_t i = u_len; i > 0; i--) {
+      rem = ((rem << 32) | u[i - 1]) % v[0];
+    }
+    memset(r, 0, n * 4);
+    r[0] = (uint32_t)rem;
+    return;
+  }
+
+  /* Normalize so the highest bit of the divisor is set */
+  int shift = __builtin_clz(v[v_len - 1]);
+  for (size_t i = v_len - 1; i > 0; i--) {
+    vn[i] = shift == 0 ? v[i] : (v[i] << shift) | (v[i - 1] >> (32 - shift));
+  }
+  vn[0] = v[0] << shift;
+  un[u_len] = shift == 0 ? 0 : u[u_len - 1] >> (32 - shift);
+  for (size_t i = u_len - 1; i > 0; i--) {
+    un[i] = shift == 0 ? u[i] : (u[i] << shift) | (u[i - 1] >> (32 - shift));
+  }
+  un[0] = u[0] << shift;
+
+  const uint64_t base = (uint64_t)1 << 32;
+  for (size_t j = u_len - v_len + 1; j > 0; j--) {
+    size_t k = j - 1;
+    uint64_t numerator = ((uint64_t)un[k + v_len] << 32) | un[k + v_len - 1];
+    uint64_t qhat = numerator / vn[v_len - 1];
+    uint64_t rhat = numerator % vn[v_len - 1];
+    while (qhat >= base ||
+           qhat * vn[v_len - 2] > ((rhat << 32) | un[k + v_len - 2])) {
+      qhat -= 1;
+      rhat += vn[v_len - 1];
+      if (rhat >= base) {
+        break;
+      }
+    }
+    /* Multiply and subtract */
+    int64_t borrow = 0;
+    int64_t t;
+    for (size_t i = 0; i < v_len; i++) {
+      uint64_t product = qhat * vn[i];
+      t = (int64_t)un[i + k] - borrow - (int64_t)(product & 0xffffffff);
+      un[i + k] = (uint32_t)t;
+      borrow = (int64_t)((product >> 32) - (uint64_t)(t >> 32));
+    }
+    t = (int64_t)un[k + v_len] - borrow;
+    un[k + v_len] = (uint32_t)t;
+    if (t < 0) {
+      /* Add back */
+      uint64_t carry = 0;
+      for (size_t i = 0; i < v_len; i++) {
+        carry += (uint64_t)un[i + k] + vn[i];
+        un[i + k] = (uint32_t)carry;
+        carry >>= 32;
+      }
+      un[k + v_len] += (uint32_t)carry;
+    }
+  }
+
+  /* Unnormalize the remainder */
+  memset(r, 0, n * 4);
+  for (size_t i = 0; i + 1 < v_len; i++) {
+    r[i] = shift == 0 ? un[i] : (un[i] >> shift) | (un[i + 1] << (32 - shift));
+  }
+  r[v_len - 1] = un[v_len - 1] >> shift;
+}
+
+/* r = a * b mod m (n limbs), r may overlap a or b */
+static void bn_mul_mod(uint32_t *r,
+                       const uint32_t *a,
+                       const uint32_t *b,
+                       const uint32_t *m,
+                       size_t n) {
+  uint32_t product[BN_MAX_LIMBS * 2];
+  bn_mul(product, a, b, n);
+  bn_mod(r, product, n * 2, m, n);
+}
+
+/* ==== 0x05: modexp ==== */
+
+static int modexp_read_length(const uint8_t *input,
+                              size_t input_size,
+                              size_t offset,
+                              size_t *length) {
+  uint8_t data[32];
+  padded_copy(data, input, input_size, offset, 32);
+  uint64_t value = 0;
+  for (size_t i = 0; i < 32; i++) {
+    if (i < 24 && data[i] != 0) {
+      debug_print("modexp length too large");
+      return ERROR_PRECOMPILE;
+    }
+    if (i >= 24) {
+      value = (value << 8) | data[i];
+    }
+  }
+  if (value > MODEXP_MAX_LENGTH) {
+    debug_print_int("modexp length too large", value);
+    return ERROR_PRECOMPILE;
+  }
+  *length = (size_t)value;
+  return 0;
+}
+
+static int precompile_modexp(const uint8_t *input,
+                             size_t input_size,
+                             uint8_t **output,
+                             size_t *output_size) {
+  size_t base_length, exponent_length, modulus_length;
+  int ret = modexp_read_length(input, input_size, 0, &base_length);
+  if (ret != 0) {
+    return ret;
+  }
+  ret = modexp_read_length(input, input_size, 32, &exponent_length);
+  if (ret != 0) {
+    return ret;
+  }
+  ret = modexp_read_length(input, input_size, 64, &modulus_length);
+  if (ret != 0) {
+    return ret;
+  }
+  ret = precompile_output(output, output_size, modulus_length);
+  if (ret != 0 || modulus_length == 0) {
+    return ret;
+  }
+
+  uint8_t buffer[MODEXP_MAX_LENGTH];
+  uint32_t modulus[BN_MAX_LIMBS];
+  uint32_t base[BN_MAX_LIMBS];
+  uint32_t result[BN_MAX_LIMBS];
+  size_t n = (modulus_length + 3) / 4;
+  padded_copy(buffer, input, input_size,
+              96 + base_length + exponent_length, modulus_length);
+  bn_from_be(modulus, n, buffer, modulus_length);
+  if (bn_len(modulus, n) == 0) {
+    /* The output is zeros */
+    return 0;
+  }
+  uint32_t full_base[BN_MAX_LIMBS];
+  size_t base_n = (base_length + 3) / 4;
+  padded_copy(buffer, input, input_size, 96, base_length);
+  bn_from_be(full_base, base_n, buffer, base_length);
+  bn_mod(base, full_base, base_n, modulus, n);
+
+  /* result = 1 mod modulus */
+  uint32_t one[BN_MAX_LIMBS];
+  memset(one, 0, n * 4);
+  one[0] = 1;
+  bn_mod(result, one, n, modulus, n);
+
+  uint8_t *exponent = buffer;
+  padded_copy(exponent, input, input_size, 96 + base_length, exponent_length);
+  for (size_t i = 0; i < exponent_length; i++) {
+    for (int bit = 7; bit >= 0; bit--) {
+      bn_mul_mod(result, result, result, modulus, n);
+      if ((exponent[i] >> bit) & 1) {
+        bn_mul_mod(result, result, base, modulus, n);
+      }
+    }
+  }
+  bn_to_be(*output, modulus_length, result, n);
+  return 0;
+}
+
+/* ==== 0x06, 0x07: bn256 (alt_bn128) G1 add and scalar multiplication ==== */
+
+#define FP_LIMBS 8
+
+/* The field modulus p */
+static const uint32_t BN256_P[FP_LIMBS] = {0xd87cfd47, 0x3c208c16, 0x6871ca8d,
+                                           0x97816a91, 0x8181585d, 0xb85045b6,
+                                           0xe131a029, 0x30644e72};
+/* The group order r */
+static const uint32_t BN256_R[FP_LIMBS] = {0xf0000001, 0x43e1f593, 0x79b97091,
+                                           0x2833e848, 0x8181585d, 0xb85045b6,
+                                           0xe131a029, 0x30644e72};
+
+typedef struct {
+  uint32_t x[FP_LIMBS];
+  uint32_t y[FP_LIMBS];
+  /* Jacobian coordinates, z = 0 is the point at infinity */
+  uint32_t z[FP_LIMBS];
+} bn256_point;
+
+static void fp_add(uint32_t *r, const uint32_t *a, const uint32_t *b) {
+  uint32_t carry = bn_add(r, a, b, FP_LIMBS);
+  if (carry || bn_cmp(r, BN256_P, FP_LIMBS) >= 0) {
+    bn_sub(r, r, BN256_P, FP_LIMBS);
+  }
+}
+
+static void fp_sub(uint32_t *r, const uint32_t *a, const uint32_t *b) {
+  if (bn_sub(r, a, b, FP_LIMBS)) {
+    bn_add(r, r, BN256_P, FP_LIMBS);
+  }
+}
+
+static void fp_mul(uint32_t *r, const uint32_t *a, const uint32_t *b) {
+  bn_mul_mod(r, a, b, BN256_P, FP_LIMBS);
+}
+
+static bool fp_is_zero(const uint32_t *a) { return bn_len(a, FP_LIMBS) == 0; }
+
+/* r = a^(p-2) = 1/a */
+static void fp_inverse(uint32_t *r, const uint32_t *a) {
+  uint32_t exponent[FP_LIMBS];
+  uint32_t two[FP_LIMBS] = {2};
+  bn_sub(exponent, BN256_P, two, FP_LIMBS);
+  uint32_t result[FP_LIMBS] = {1};
+  for (size_t i = FP_LIMBS * 32; i > 0; i--) {
+    size_t bit = i - 1;
+    fp_mul(result, result, result);
+    if ((exponent[bit / 32] >> (bit % 32)) & 1) {
+      fp_mul(result, result, a);
+    }
+  }
+  memcpy(r, result, sizeof(result));
+}
+
+static void bn256_set_infinity(bn256_point *point) {
+  memset(point, 0, sizeof(bn256_point));
+  point->x[0] = 1;
+  point->y[0] = 1;
+}
+
+static bool bn256_is_infinity(const bn256_point *point) {
+  return fp_is_zero(point->z);
+}
+
+/* 64 bytes (x, y), (0, 0) is the point at infinity */
+static int bn256_read_point(bn256_point *point, const uint8_t *data) {
+  bn_from_be(point->x, FP_LIMBS, data, 32);
+  bn_from_be(point->y, FP_LIMBS, data + 32, 32);
+  if (bn_cmp(point->x, BN256_P, FP_LIMBS) >= 0 ||
+      bn_cmp(point->y, BN256_P, FP_LIMBS) >= 0) {
+    debug_print("invalid field element");
+    return ERROR_PRECOMPILE;
+  }
+  if (fp_is_zero(point->x) && fp_is_zero(point->y)) {
+    bn256_set_infinity(point);
+    return 0;
+  }
+  /* y^2 = x^3 + 3 */
+  uint32_t lhs[FP_LIMBS], rhs[FP_LIMBS];
+  uint32_t three[FP_LIMBS] = {3};
+  fp_mul(lhs, point->y, point->y);
+  fp_mul(rhs, point->x, point->x);
+  fp_mul(rhs, rhs, point->x);
+  fp_add(rhs, rhs, three);
+  if (bn_cmp(lhs, rhs, FP_LIMBS) != 0) {
+    debug_print("invalid G1 point");
+    return ERROR_PRECOMPILE;
+  }
+  memset(point->z, 0, sizeof(point->z));
+  point->z[0] = 1;
+  return 0;
+}
+
+static void bn256_write_point(uint8_t *data, const bn256_point *point) {
+  memset(data, 0, 64);
+  if (bn256_is_infinity(point)) {
+    return;
+  }
+  uint32_t z_inv[FP_LIMBS], z_inv2[FP_LIMBS], z_inv3[FP_LIMBS];
+  uint32_t x[FP_LIMBS], y[FP_LIMBS];
+  fp_inverse(z_inv, point->z);
+  fp_mul(z_inv2, z_inv, z_inv);
+  fp_mul(z_inv3, z_inv2, z_inv);
+  fp_mul(x, point->x, z_inv2);
+  fp_mul(y, point->y, z_inv3);
+  bn_to_be(data, 32, x, FP_LIMBS);
+  bn_to_be(data + 32, 32, y, FP_LIMBS);
+}
+
+/* dbl-2009-l */
+static void bn256_double(bn256_point *r, const bn256_point *p) {
+  if (bn256_is_infinity(p) || fp_is_zero(p->y)) {
+    bn256_set_infinity(r);
+    return;
+  }
+  uint32_t a[FP_LIMBS], b[FP_LIMBS], c[FP_LIMBS], d[FP_LIMBS];
+  uint32_t e[FP_LIMBS], f[FP_LIMBS], t[FP_LIMBS];
+  fp_mul(a, p->x, p->x);
+  fp_mul(b, p->y, p->y);
+  fp_mul(c, b, b);
+  /* d = 2 * ((x + b)^2 - a - c) */
+  fp_add(t, p->x, b);
+  fp_mul(t, t, t);
+  fp_sub(t, t, a);
+  fp_sub(t, t, c);
+  fp_add(d, t, t);
+  /* e = 3 * a, f = e^2 */
+  fp_add(e, a, a);
+  fp_add(e, e, a);
+  fp_mul(f, e, e);
+  /* z3 = 2 * y * z, computed first since r may be p */
+  fp_mul(t, p->y, p->z);
+  fp_add(r->z, t, t);
+  /* x3 = f - 2 * d */
+  fp_sub(r->x, f, d);
+  fp_sub(r->x, r->x, d);
+  /* y3 = e * (d - x3) - 8 * c */
+  fp_sub(t, d, r->x);
+  fp_mul(t, e, t);
+  fp_add(c, c, c);
+  fp_add(c, c, c);
+  fp_add(c, c, c);
+  fp_sub(r->y, t, c);
+}
+
+/* add-2007-bl */
+static void bn256_add(bn256_point *r, const bn256_point *p, const bn256_point *q) {
+  if (bn256_is_infinity(p)) {
+    *r = *q;
+    return;
+  }
+  if (bn256_is_infinity(q)) {
+    *r = *p;
+    return;
+  }
+  uint32_t z1z1[FP_LIMBS], z2z2[FP_LIMBS], u1[FP_LIMBS], u2[FP_LIMBS];
+  uint32_t s1[FP_LIMBS], s2[FP_LIMBS], h[FP_LIMBS], i[FP_LIMBS];
+  uint32_t j[FP_LIMBS], rr[FP_LIMBS], v[FP_LIMBS], t[FP_LIMBS];
+  fp_mul(z1z1, p->z, p->z);
+  fp_mul(z2z2, q->z, q->z);
+  fp_mul(u1, p->x, z2z2);
+  fp_mul(u2, q->x, z1z1);
+  fp_mul(s1, p->y, q->z);
+  fp_mul(s1, s1, z2z2);
+  fp_mul(s2, q->y, p->z);
+  fp_mul(s2, s2, z1z1);
+  fp_sub(h, u2, u1);
+  fp_sub(rr, s2, s1);
+  if (fp_is_zero(h)) {
+    if (fp_is_zero(rr)) {
+      bn256_double(r, p);
+    } else {
+      bn256_set_infinity(r);
+    }
+    return;
+  }
+  fp_add(rr, rr, rr);
+  /* i = (2 * h)^2, j = h * i, v = u1 * i */
+  fp_add(i, h, h);
+  fp_mul(i, i, i);
+  fp_mul(j, h, i);
+  fp_mul(v, u1, i);
+  /* z3 = ((z1 + z2)^2 - z1z1 - z2z2) * h */
+  fp_add(t, p->z, q->z);
+  fp_mul(t, t, t);
+  fp_sub(t, t, z1z1);
+  fp_sub(t, t, z2z2);
+  fp_mul(r->z, t, h);
+  /* x3 = rr^2 - j - 2 * v */
+  fp_mul(t, rr, rr);
+  fp_sub(t, t, j);
+  fp_sub(t, t, v);
+  fp_sub(r->x, t, v);
+  /* y3 = rr * (v - x3) - 2 * s1 * j */
+  fp_sub(t, v, r->x);
+  fp_mul(t, rr, t);
+  fp_mul(s1, s1, j);
+  fp_add(s1, s1, s1);
+  fp_sub(r->y, t, s1);
+}
+
+static int precompile_bn256_add(const uint8_t *input,
+                                size_t input_size,
+                                uint8_t **output,
+                                size_t *output_size) {
+  uint8_t data[128];
+  padded_copy(data, input, input_size, 0, 128);
+  bn256_point p, q, r;
+  int ret = bn256_read_point(&p, data);
+  if (ret != 0) {
+    return ret;
+  }
+  ret = bn256_read_point(&q, data + 64);
+  if (ret != 0) {
+    return ret;
+  }
+  bn256_add(&r, &p, &q);
+  ret = precompile_output(output, output_size, 64);
+  if (ret != 0) {
+    return ret;
+  }
+  bn256_write_point(*output, &r);
+  return 0;
+}
+
+static int precompile_bn256_scalar_mul(const uint8_t *input,
+                                       size_t input_size,
+                                       uint8_t **output,
+                                       size_t *output_size) {
+  uint8_t data[96];
+  padded_copy(data, input, input_size, 0, 96);
+  bn256_point p, r;
+  int ret = bn256_read_point(&p, data);
+  if (ret != 0) {
+    return ret;
+  }
+  uint32_t scalar[FP_LIMBS];
+  bn_from_be(scalar, FP_LIMBS, data + 64, 32);
+  if (bn_cmp(scalar, BN256_R, FP_LIMBS) >= 0) {
+    debug_print("invalid scalar");
+    return ERROR_PRECOMPILE;
+  }
+  bn256_set_infinity(&r);
+  for (size_t i = FP_LIMBS * 32; i > 0; i--) {
+    size_t bit = i - 1;
+    bn256_double(&r, &r);
+    if ((scalar[bit / 32] >> (bit % 32)) & 1) {
+      bn256_add(&r, &r, &p);
+    }
+  }
+  ret = precompile_output(output, output_size, 64);
+  if (ret != 0) {
+    return ret;
+  }
+  bn256_write_point(*output, &r);
+  return 0;
+}
+
+/* ==== 0x09: blake2f (EIP-152) ==== */
+
+static const uint64_t BLAKE2B_IV[8] = {
+    0x6a09e667f3bcc908ULL, 0xbb67ae8584caa73bULL, 0x3c6ef372fe94f82bULL,
+    0xa54ff53a5f1d36f1ULL, 0x510e527fade682d1ULL, 0x9b05688c2b3e6c1fULL,
+    0x1f83d9abfb41bd6bULL, 0x5be0cd19137e2179ULL};
+
+static const uint8_t BLAKE2B_SIGMA[10][16] = {
+    {0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15},
+    {14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3},
+    {11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4},
+    {7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8},
+    {9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13},
+    {2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9},
+    {12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11},
+    {13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10},
+    {6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5},
+    {10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0}};
+
+static uint64_t rotr64(uint64_t x, int n) { return (x >> n) | (x << (64 - n)); }
+
+static void blake2b_mix(uint64_t v[16], int a, int b, int c, int d, uint64_t x, uint64_t y) {
+  v[a] = v[a] + v[b] + x;
+  v[d] = rotr64(v[d] ^ v[a], 32);
+  v[c] = v[c] + v[d];
+  v[b] = rotr64(v[b] ^ v[c], 24);
+  v[a] = v[a] + v[b] + y;
+  v[d] = rotr64(v[d] ^ v[a], 16);
+  v[c] = v[c] + v[d];
+  v[b] = rotr64(v[b] ^ v[c], 63);
+}
+
+static uint64_t load_u64_le(const uint8_t *data) {
+  uint64_t value = 0;
+  for (int i = 7; i >= 0; i--) {
+    value = (value << 8) | data[i];
+  }
+  return value;
+}
+
+static int precompile_blake2f(const uint8_t *input,
+                              size_t input_size,
+                              uint8_t **output,
+                              size_t *output_size) {
+  if (input_size != 213) {
+    debug_print_int("invalid blake2f input length", input_size);
+    return ERROR_PRECOMPILE;
+  }
+  if (input[212] != 0 && input[212] != 1) {
+    debug_print_int("invalid blake2f final block flag", input[212]);
+    return ERROR_PRECOMPILE;
+  }
+  uint32_t rounds = ((uint32_t)input[0] << 24) | ((uint32_t)input[1] << 16) |
+                    ((uint32_t)input[2] << 8) | (uint32_t)input[3];
+  uint64_t h[8], m[16], v[16];
+  for (int i = 0; i < 8; i++) {
+    h[i] = load_u64_le(input + 4 + i * 8);
+  }
+  for (int i = 0; i < 16; i++) {
+    m[i] = load_u64_le(input + 68 + i * 8);
+  }
+  for (int i = 0; i < 8; i++) {
+    v[i] = h[i];
+    v[i + 8] = BLAKE2B_IV[i];
+  }
+  v[12] ^= load_u64_le(input + 196);
+  v[13] ^= load_u64_le(input + 204);
+  if (input[212] == 1) {
+    v[14] = ~v[14];
+  }
+  for (uint32_t round = 0; round < rounds; round++) {
+    const uint8_t *s = BLAKE2B_SIGMA[round % 10];
+    blake2b_mix(v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+    blake2b_mix(v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+    blake2b_mix(v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+    blake2b_mix(v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+    blake2b_mix(v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+    blake2b_mix(v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+    blake2b_mix(v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+    blake2b_mix(v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+  }
+
+  int ret = precompile_output(output, output_size, 64);
+  if (ret != 0) {
+    return ret;
+  }
+  for (int i = 0; i < 8; i++) {
+    uint64_t value = h[i] ^ v[i] ^ v[i + 8];
+    for (int j = 0; j < 8; j++) {
+      (*output)[i * 8 + j] = (uint8_t)(value >> (8 * j));
+    }
+  }
+  return 0;
+}
+
+/* ==== Dispatch ==== */
+
+typedef int (*precompile_fn)(const uint8_t *input,
+                             size_t input_size,
+                             uint8_t **output,
+                             size_t *output_size);
+
+/* Return NULL if the address is not a supported precompiled contract */
+static precompile_fn find_precompile(const evmc_address *address) {
+  for (size_t i = 0; i < 19; i++) {
+    if (address->bytes[i] != 0) {
+      return NULL;
+    }
+  }
+  switch (address->bytes[19]) {
+    case 1:
+      return precompile_ecrecover;
+    case 2:
+      return precompile_sha256;
+    case 3:
+      return precompile_ripemd160;
+    case 4:
+      return precompile_identity;
+    case 5:
+      return precompile_modexp;
+    case 6:
+      return precompile_bn256_add;
+    case 7:
+      return precompile_bn256_scalar_mul;
+    case 9:
+      return precompile_blake2f;
+    default:
+      return NULL;
+  }
+}
+
+int precompile_execute(const evmc_address *address,
+                       const uint8_t *input,
+                       size_t input_size,
+                       uint8_t **output,
+                       size_t *output_size) {
+  precompile_fn precompile = find_precompile(address);
+  if (precompile == NULL) {
+    debug_print_data("not a supported precompiled contract", address->bytes, 20);
+    return ERROR_PRECOMPILE;
+  }
+  *output = NULL;
+  *output_size = 0;
+  int ret = precompile(input, input_size, output, output_size);
+  if (ret != 0 && *output != NULL) {
+    free(*output);
+    *output = NULL;
+    *output_size = 0;
+  }
+  return ret;
+}
+
+#endif /* POLYJUICE_PRECOMPILES_H_ */
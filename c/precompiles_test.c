@@ -0,0 +1,159 @@
+/*
+ * Run the precompiled contracts of the validator natively against the test
+ * vectors shared with the generator (tests/precompiles/vectors.txt).
+ *
+ *     build/precompiles_test ../tests/precompiles/vectors.txt
+ */
+#include <stdbool.h>
+#include <stdio.h>
+
+#include <evmc/evmc.h>
+#include <ethash/keccak.h>
+
+#define debug_print(s)
+#define debug_print_int(prefix, value)
+#define debug_print_data(prefix, data, data_len)
+
+/*
+ * We are including secp256k1 implementation directly so gcc can strip
+ * unused functions. For some unknown reasons, if we link in libsecp256k1.a
+ * directly, the final binary will include all functions rather than those used.
+ */
+#define HAVE_CONFIG_H 1
+#include <secp256k1.c>
+
+/*
+ * The validator loads the precomputed secp256k1 data from a cell dep, build
+ * the context here instead. The created context is never freed.
+ */
+#define CKB_SECP256K1_DATA_SIZE 1
+int ckb_secp256k1_custom_verify_only_initialize(secp256k1_context *context,
+                                                void *data) {
+  (void)data;
+  secp256k1_context *created = secp256k1_context_create(SECP256K1_CONTEXT_VERIFY);
+  if (created == NULL) {
+    return -1;
+  }
+  *context = *created;
+  return 0;
+}
+
+#include "precompiles.h"
+
+#define ERROR_IO -1
+#define ERROR_INVALID_VECTOR -2
+
+/* The pairing check (0x08) is not supported by the validator */
+#define UNSUPPORTED_PRECOMPILE 8
+
+static int hex_value(char c) {
+  if (c >= '0' && c <= '9') {
+    return c - '0';
+  }
+  if (c >= 'a' && c <= 'f') {
+    return c - 'a' + 10;
+  }
+  return -1;
+}
+
+/* "-" is empty, return the size or -1 if the hex is invalid */
+static long decode_hex(const char *hex, uint8_t *data) {
+  if (strcmp(hex, "-") == 0) {
+    return 0;
+  }
+  size_t length = strlen(hex);
+  if (length % 2 != 0) {
+    return -1;
+  }
+  for (size_t i = 0; i < length / 2; i++) {
+    int high = hex_value(hex[i * 2]);
+    int low = hex_value(hex[i * 2 + 1]);
+    if (high < 0 || low < 0) {
+      return -1;
+    }
+    data[i] = (uint8_t)(high * 16 + low);
+  }
+  return (long)(length / 2);
+}
+
+/* Return 0 if the vector passed, 1 if it failed */
+static int run_vector(int index,
+                      const char *name,
+                      const uint8_t *input,
+                      size_t input_size,
+                      const char *expected,
+                      uint8_t *expected_data) {
+  evmc_address address;
+  memset(address.bytes, 0, 20);
+  address.bytes[19] = (uint8_t)index;
+  uint8_t *output = NULL;
+  size_t output_size = 0;
+  int ret = precompile_execute(&address, input, input_size, &output, &output_size);
+  int failed = 0;
+  if (strcmp(expected, "error") == 0) {
+    failed = ret == 0;
+  } else {
+    long expected_size = decode_hex(expected, expected_data);
+    failed = ret != 0 || expected_size != (long)output_size ||
+             (output_size > 0 && memcmp(output, expected_data, output_size) != 0);
+  }
+  if (failed) {
+    printf("FAILED %s: ret=%d, output=", name, ret);
+    for (size_t i = 0; i < output_size; i++) {
+      printf("%02x", output[i]);
+    }
+    printf("\n");
+  }
+  free(output);
+  return failed;
+}
+
+int main(int argc, char *argv[]) {
+  if (argc != 2) {
+    printf("Usage: %s <vectors.txt>\n", argv[0]);
+    return ERROR_IO;
+  }
+  FILE *fp = fopen(argv[1], "r");
+  if (!fp) {
+    return ERROR_IO;
+  }
+  /* The longest vector is the modexp of 1024 bytes numbers */
+  size_t capacity = 16 * 1024;
+  char *line = (char *)malloc(capacity);
+  uint8_t *input = (uint8_t *)malloc(capacity);
+  uint8_t *expected_data = (uint8_t *)malloc(capacity);
+  char *name = (char *)malloc(capacity);
+  char *input_hex = (char *)malloc(capacity);
+  char *expected = (char *)malloc(capacity);
+  if (!line || !input || !expected_data || !name || !input_hex || !expected) {
+    return ERROR_IO;
+  }
+  int total = 0;
+  int failed = 0;
+  while (fgets(line, (int)capacity, fp) != NULL) {
+    if (line[0] == '#' || line[0] == '\n') {
+      continue;
+    }
+    int index;
+    unsigned long gas;
+    if (sscanf(line, "%d %s %lu %s %s", &index, name, &gas, input_hex,
+               expected) != 5) {
+      printf("Invalid vector: %s", line);
+      return ERROR_INVALID_VECTOR;
+    }
+    long input_size = decode_hex(input_hex, input);
+    if (input_size < 0) {
+      printf("Invalid input: %s\n", name);
+      return ERROR_INVALID_VECTOR;
+    }
+    if (index == UNSUPPORTED_PRECOMPILE) {
+      continue;
+    }
+    total += 1;
+    failed += run_vector(index, name, input, (size_t)input_size, expected,
+                         expected_data);
+  }
+  fclose(fp);
+  printf("%d vectors, %d failed\n", total, failed);
+  return failed == 0 ? 0 : 1;
+}
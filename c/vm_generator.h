@@ -12,6 +12,7 @@
 #define _CSAL_GET_BLOCK_HASH               3081
 #define _CSAL_GET_TX_CONTEXT               3082
 #define _CSAL_GET_BALANCE                  3083
+#define _CSAL_GET_CODE_HASH                3084
 
 static char debug_buffer[64 * 1024];
 static void debug_print_data(const char *prefix,
@@ -57,6 +58,9 @@ int csal_get_tx_context(uint8_t *buffer) {
 int csal_get_balance(uint8_t *address, uint8_t *balance) {
   return syscall(_CSAL_GET_BALANCE, address, balance, 0, 0, 0, 0);
 }
+int csal_get_code_hash(uint8_t *address, evmc_bytes32 *code_hash) {
+  return syscall(_CSAL_GET_CODE_HASH, address, code_hash, 0, 0, 0, 0);
+}
 
 
 void release_result(const struct evmc_result* result) {
@@ -152,6 +156,10 @@ size_t get_code_size(struct evmc_host_context* context,
 evmc_bytes32 get_code_hash(struct evmc_host_context* context,
                            const evmc_address* address) {
   evmc_bytes32 hash{};
+  int ret = csal_get_code_hash((uint8_t *)address->bytes, &hash);
+  if (ret != CKB_SUCCESS) {
+    ckb_debug("get code hash failed");
+  }
   return hash;
 }
 
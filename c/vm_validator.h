@@ -1,10 +1,12 @@
 #include <evmc/evmc.h>
+#include <ethash/keccak.h>
 #include <intx/intx.hpp>
 #include <merkle_tree.h>
 
 #define CSAL_VALIDATOR_TYPE 1
 #include "validator.h"
 #include "secp256k1_helper.h"
+#include "precompiles.h"
 
 #define ONE_CKB ((uint64_t)100000000)
 #define MIN_CONTRACT_CAPACITY ((uint64_t)(ONE_CKB * (uint64_t)158))
@@ -189,6 +191,7 @@ typedef struct {
   uint64_t value;
   bool transfer_only;
   bool is_eoa;
+  bool is_precompile;
 } call_record;
 
 typedef struct {
@@ -201,6 +204,13 @@ typedef struct {
   uint8_t *raw_cellbase_tx;
 } tx_coinbase;
 
+/* The code of a contract not in the transaction (read by EXTCODEHASH) */
+typedef struct {
+  evmc_address address;
+  size_t code_size;
+  uint8_t *code_data;
+} code_dep;
+
 typedef struct contract_program {
   size_t total_size;
   uint8_t *signature;
@@ -227,6 +237,8 @@ typedef struct contract_program {
   size_t call_index;
   call_record *calls;
   tx_coinbase *coinbase;
+  size_t code_deps_count;
+  code_dep *code_deps;
   struct contract_program *next_program;
   struct contract_program *prev_program;
   bool is_processed;
@@ -250,6 +262,9 @@ typedef struct {
   contract_program *current_program;
   size_t special_call_total_count;
   size_t special_call_count;
+  /* The program special calls (maybe nested) are recorded after */
+  contract_program *special_base_program;
+  size_t special_base_index;
   size_t program_count;
   size_t program_index;
 } contract_info;
@@ -286,10 +301,18 @@ static eoa_account global_eoa_list[MAX_EOA_COUNT];
 static size_t global_eoa_count = 0;
 static evmc_address global_current_contract;
 static bool global_current_is_main = false;
+/* > 0 when running in a DELEGATECALL/CALLCODE frame */
+static size_t global_special_call_depth = 0;
 static struct evmc_tx_context global_tx_context;
 static header_info global_header_infos[MAX_HEADER_COUNT];
 static size_t global_header_count = 0;
 static uint64_t global_max_block_number = 0;
+static code_dep *global_code_deps = NULL;
+static size_t global_code_deps_count = 0;
+
+/* The flags byte was the is_eoa byte (0 or 1) before precompiled contracts */
+#define CALL_FLAG_EOA 0x01
+#define CALL_FLAG_PRECOMPILE 0x02
 
 int call_record_load(call_record *record, const uint8_t *buf, const size_t buf_size) {
   if (buf_size < (20 + 4 + 8 + 1 + 1)) {
@@ -312,22 +335,28 @@ int call_record_load(call_record *record, const uint8_t *buf, const size_t buf_s
   }
   record->transfer_only = transfer_only == 1;
   offset += 1;
-  uint8_t is_eoa = buf[offset];
-  if (is_eoa != 1 && is_eoa != 0) {
-    debug_print_int("invalid is_eoa value", is_eoa);
+  uint8_t flags = buf[offset];
+  if ((flags & ~(CALL_FLAG_EOA | CALL_FLAG_PRECOMPILE)) != 0) {
+    debug_print_int("invalid call flags", flags);
     return -99;
   }
-  record->is_eoa = is_eoa == 1;
+  record->is_eoa = (flags & CALL_FLAG_EOA) != 0;
+  record->is_precompile = (flags & CALL_FLAG_PRECOMPILE) != 0;
   offset += 1;
   if (!record->transfer_only && record->is_eoa) {
     debug_print("is_eoa=true only valid when transfer only");
     return -99;
   }
+  if (record->is_precompile && (record->transfer_only || record->is_eoa)) {
+    debug_print("is_precompile=true only valid when not transfer only");
+    return -99;
+  }
   debug_print_data("[call.destination]", record->destination.bytes, 20);
   debug_print_int("[call.program_index]", record->program_index);
   debug_print_int("[call.value]", record->value);
   debug_print_int("[call.transfer_only]", record->transfer_only);
   debug_print_int("[call.is_eoa]", record->is_eoa);
+  debug_print_int("[call.is_precompile]", record->is_precompile);
   return 0;
 }
 
@@ -383,6 +412,46 @@ int tx_coinbase_load(tx_coinbase *coinbase, const uint8_t *buf, const size_t buf
   return 0;
 }
 
+int code_deps_load(code_dep **code_deps,
+                   size_t *code_deps_count,
+                   size_t *loaded_size,
+                   const uint8_t *buf,
+                   const size_t buf_size) {
+  if (buf_size < 4) {
+    debug_print("not enough data to parse code deps");
+    return -99;
+  }
+  const uint32_t count = *(uint32_t *)buf;
+  /* address + code_size */
+  static size_t code_dep_min_size = 20 + 4;
+  if (count > (buf_size - 4) / code_dep_min_size) {
+    debug_print_int("too many code deps", count);
+    return -99;
+  }
+  code_dep *deps = (code_dep *)malloc(count * sizeof(code_dep));
+  size_t offset = 4;
+  for (uint32_t i = 0; i < count; i++) {
+    if (buf_size - offset < code_dep_min_size) {
+      debug_print("not enough data to parse code dep");
+      return -99;
+    }
+    const uint32_t code_size = *(uint32_t *)(buf + offset + 20);
+    if (buf_size - offset - code_dep_min_size < code_size) {
+      debug_print("not enough data to parse code dep");
+      return -99;
+    }
+    deps[i].address = *(evmc_address *)(buf + offset);
+    deps[i].code_size = code_size;
+    deps[i].code_data = (uint8_t *)(buf + offset + code_dep_min_size);
+    debug_print_data("load code dep", deps[i].address.bytes, 20);
+    offset += code_dep_min_size + code_size;
+  }
+  *code_deps = deps;
+  *code_deps_count = count;
+  *loaded_size = offset;
+  return 0;
+}
+
 int contract_program_load(contract_program *program, const uint8_t *buf, const size_t buf_size) {
   const uint32_t source_size = *(uint32_t *)buf;
   if (source_size > buf_size) {
@@ -441,7 +510,26 @@ int contract_program_load(contract_program *program, const uint8_t *buf, const s
     }
   }
 
-  const size_t source_total_size = calls_base - buf + calls_size + 4 + coinbase_size;
+  size_t source_total_size = calls_base - buf + calls_size + 4 + coinbase_size;
+  if (source_total_size > buf_size) {
+    debug_print("not enough data to parse program");
+    return -99;
+  }
+  /* code deps (omitted when empty) */
+  size_t code_deps_count = 0;
+  code_dep *code_deps = NULL;
+  if (source_size + 4 > source_total_size && buf_size >= source_size + 4) {
+    size_t code_deps_size = 0;
+    ret = code_deps_load(&code_deps,
+                         &code_deps_count,
+                         &code_deps_size,
+                         buf + source_total_size,
+                         source_size + 4 - source_total_size);
+    if (ret != CKB_SUCCESS) {
+      return ret;
+    }
+    source_total_size += code_deps_size;
+  }
   if (source_total_size > buf_size) {
     debug_print("not enough data to parse program");
     return -99;
@@ -490,6 +578,8 @@ int contract_program_load(contract_program *program, const uint8_t *buf, const s
   program->call_index = 0;
   program->calls = calls;
   program->coinbase = coinbase;
+  program->code_deps_count = code_deps_count;
+  program->code_deps = code_deps;
   program->next_program = NULL;
   program->prev_program = NULL;
   program->is_processed = false;
@@ -803,6 +893,11 @@ int contract_info_process_calls(contract_info *dest_info,
   int ret;
   while (current_program->call_index < current_program->calls_count) {
     call_record call = current_program->calls[current_program->call_index];
+    if (call.is_precompile) {
+      /* Precompiled contracts are run natively in call(), nothing to process */
+      current_program->call_index += 1;
+      continue;
+    }
     contract_info *info = NULL;
     find_contract_info(&info, info_list, info_count, &call.destination);
     if (call.value > 0) {
@@ -1097,6 +1192,29 @@ size_t get_code_size(struct evmc_host_context* context,
 evmc_bytes32 get_code_hash(struct evmc_host_context* context,
                            const evmc_address* address) {
   evmc_bytes32 hash{};
+  const uint8_t *code_data = NULL;
+  size_t code_size = 0;
+  contract_info *info = NULL;
+  find_contract_info(&info, global_info_list, global_info_count, address);
+  if (info != NULL) {
+    code_data = info->code_data;
+    code_size = info->code_size;
+  } else {
+    /* The contracts not in the transaction, verified in load_code_deps() */
+    for (size_t i = 0; i < global_code_deps_count; i++) {
+      if (memcmp(global_code_deps[i].address.bytes, address->bytes, 20) == 0) {
+        code_data = global_code_deps[i].code_data;
+        code_size = global_code_deps[i].code_size;
+        break;
+      }
+    }
+  }
+  /* Zero for EoA accounts, empty accounts and destructed contracts */
+  if (code_size == 0) {
+    return hash;
+  }
+  const union ethash_hash256 code_hash = ethash_keccak256(code_data, code_size);
+  memcpy(hash.bytes, code_hash.bytes, 32);
   return hash;
 }
 
@@ -1156,6 +1274,41 @@ void selfdestruct(struct evmc_host_context* context,
   context->destructed = true;
 }
 
+void release_precompile_result(const struct evmc_result* result) {
+  free((void *)result->output_data);
+}
+
+struct evmc_result call_precompile(contract_program *program,
+                                   call_record *call,
+                                   const struct evmc_message* msg) {
+  struct evmc_result res{};
+  res.status_code = EVMC_REVERT;
+  if (is_create(msg->kind)
+      || memcmp(call->destination.bytes, msg->destination.bytes, 20) != 0) {
+    debug_print("precompiled contract call not match");
+    return res;
+  }
+  /* The generator refuses to transfer value to a precompiled contract */
+  if (call->value != 0 || verify_call_value(call, msg) != CKB_SUCCESS) {
+    return res;
+  }
+  uint8_t *output = NULL;
+  size_t output_size = 0;
+  int ret = precompile_execute(&msg->destination, msg->input_data, msg->input_size,
+                               &output, &output_size);
+  if (ret != CKB_SUCCESS) {
+    debug_print_int("run precompiled contract failed", ret);
+    return res;
+  }
+  program->call_index += 1;
+  res.status_code = EVMC_SUCCESS;
+  res.gas_left = msg->gas;
+  res.output_data = output;
+  res.output_size = output_size;
+  res.release = release_precompile_result;
+  return res;
+}
+
 struct evmc_result call(struct evmc_host_context* context,
                         const struct evmc_message* msg) {
   debug_print_int("call().kind : ", msg->kind);
@@ -1172,7 +1325,7 @@ struct evmc_result call(struct evmc_host_context* context,
   contract_info *dest_info = NULL;
 
   evmc_address *sender_addr = (evmc_address *)&msg->sender;
-  if (msg->kind == EVMC_DELEGATECALL) {
+  if (msg->kind == EVMC_DELEGATECALL || global_special_call_depth > 0) {
     sender_addr = &global_current_contract;
   }
   if (memcmp(sender_addr->bytes, global_current_contract.bytes, 20) != 0) {
@@ -1188,6 +1341,9 @@ struct evmc_result call(struct evmc_host_context* context,
 
   contract_program *program = sender_info->current_program;
   call_record call = program->calls[program->call_index];
+  if (call.is_precompile) {
+    return call_precompile(program, &call, msg);
+  }
   evmc_address destination{};
   if (is_create(msg->kind)) {
     /* TODO: security check */
@@ -1198,10 +1354,18 @@ struct evmc_result call(struct evmc_host_context* context,
 
   /* Handle value transfer */
   bool transfer_only = !is_create(msg->kind) && msg->input_size == 0;
-  ret = verify_call_value(&call, msg);
-  if (ret != CKB_SUCCESS) {
-    res.status_code = EVMC_REVERT;
-    return res;
+  if (is_special_call(msg->kind)) {
+    /* Special call run in the caller's context, no value is transferred */
+    if (call.value != 0) {
+      res.status_code = EVMC_REVERT;
+      return res;
+    }
+  } else {
+    ret = verify_call_value(&call, msg);
+    if (ret != CKB_SUCCESS) {
+      res.status_code = EVMC_REVERT;
+      return res;
+    }
   }
   if (call.value > 0) {
     ret = contract_info_sub_balance(sender_info, call.value);
@@ -1254,9 +1418,13 @@ struct evmc_result call(struct evmc_host_context* context,
   size_t saved_program_index = sender_info->program_index;
   if (is_special_call(msg->kind)) {
     struct evmc_vm *vm = evmc_create_evmone();
+    if (sender_info->special_call_count == 0) {
+      sender_info->special_base_program = saved_current_program;
+      sender_info->special_base_index = saved_program_index;
+    }
     sender_info->special_call_count += 1;
-    contract_program *current_program = saved_current_program;
-    size_t current_index = saved_program_index;
+    contract_program *current_program = sender_info->special_base_program;
+    size_t current_index = sender_info->special_base_index;
     for (size_t i = 0; i < sender_info->special_call_count; i++) {
       current_program = current_program->next_program;
       current_index += 1;
@@ -1264,7 +1432,9 @@ struct evmc_result call(struct evmc_host_context* context,
     sender_info->current_program = current_program;
     sender_info->program_index = current_index;
     debug_print_int(">> run special program_index", current_index);
+    global_special_call_depth += 1;
     res = vm->execute(vm, context->interface, context, EVMC_MAX_REVISION, msg, dest_info->code_data, dest_info->code_size);
+    global_special_call_depth -= 1;
     /* Verify return data */
     if (sender_info->current_program->return_data_size != res.output_size) {
       res.status_code = EVMC_REVERT;
@@ -1331,13 +1501,18 @@ struct evmc_result call(struct evmc_host_context* context,
 }
 
 evmc_bytes32 get_block_hash(struct evmc_host_context* context, int64_t number) {
+  evmc_bytes32 zero_block_hash{};
+  /* Only the most recent 256 blocks (current block excluded) are available */
+  if (number < 0 || number >= global_tx_context.block_number
+      || global_tx_context.block_number - number > 256) {
+    return zero_block_hash;
+  }
   uint64_t block_number = (uint64_t) number;
   for (size_t i = 0; i < global_header_count; i++) {
     if (global_header_infos[i].number == block_number) {
       return global_header_infos[i].hash;
     }
   }
-  evmc_bytes32 zero_block_hash{};
   return zero_block_hash;
 }
 
@@ -2054,6 +2229,112 @@ int load_tx_context(blake2b_state *blake2b_ctx) {
   return 0;
 }
 
+/* load code deps:
+    - code deps can only be found in entrance contract's first program
+    - verify the code by the code hash in the contract cell of cell_deps
+ */
+int load_code_deps(blake2b_state *blake2b_ctx) {
+  int ret;
+  uint64_t len;
+  for (size_t i = 0; i < global_info_count; i++) {
+    contract_info *info = &global_info_list[i];
+    contract_program *program = info->head_program;
+    for (size_t j = 0; j < info->program_count; j++) {
+      if (info->is_main && j == 0) {
+        global_code_deps = program->code_deps;
+        global_code_deps_count = program->code_deps_count;
+      } else if (program->code_deps_count > 0) {
+        debug_print("found code deps in unexpected place");
+        debug_print_int("info index", i);
+        debug_print_int("program index", j);
+        return -12;
+      }
+      program = program->next_program;
+    }
+  }
+  if (global_code_deps_count == 0) {
+    return 0;
+  }
+
+  uint8_t script[SCRIPT_SIZE];
+  len = SCRIPT_SIZE;
+  ret = ckb_checked_load_script(script, &len, 0);
+  if (ret != CKB_SUCCESS) {
+    debug_print("load current script failed");
+    return ret;
+  }
+  size_t script_size = len;
+  uint8_t type_script[SCRIPT_SIZE];
+  uint8_t cell_data[64];
+  for (size_t i = 0; i < global_code_deps_count; i++) {
+    code_dep *dep = &global_code_deps[i];
+    contract_info *info = NULL;
+    find_contract_info(&info, global_info_list, global_info_count, &dep->address);
+    if (info != NULL) {
+      debug_print_data("code dep contract is in the transaction", dep->address.bytes, 20);
+      return -12;
+    }
+    uint8_t code_hash[32];
+    blake2b_init(blake2b_ctx, 32);
+    blake2b_update(blake2b_ctx, dep->code_data, dep->code_size);
+    blake2b_final(blake2b_ctx, code_hash, 32);
+
+    bool verified = false;
+    for (size_t dep_index = 0; !verified; dep_index++) {
+      len = SCRIPT_SIZE;
+      ret = ckb_load_cell_by_field(type_script, &len, 0, dep_index, CKB_SOURCE_CELL_DEP, CKB_CELL_FIELD_TYPE);
+      if (ret == CKB_INDEX_OUT_OF_BOUND) {
+        break;
+      } else if (ret == CKB_ITEM_MISSING) {
+        continue;
+      } else if (ret != CKB_SUCCESS) {
+        debug_print_int("load type script from cell dep failed", dep_index);
+        return ret;
+      }
+      bool code_matched = false;
+      ret = check_script_code(script, script_size, type_script, len, &code_matched);
+      if (ret != CKB_SUCCESS) {
+        debug_print_int("check type script from cell dep failed", dep_index);
+        return ret;
+      }
+      if (!code_matched) {
+        continue;
+      }
+      mol_seg_t script_seg;
+      script_seg.ptr = type_script;
+      script_seg.size = len;
+      mol_seg_t args_seg = MolReader_Script_get_args(&script_seg);
+      mol_seg_t args_bytes_seg = MolReader_Bytes_raw_bytes(&args_seg);
+      if (args_bytes_seg.size != CSAL_SCRIPT_ARGS_LEN) {
+        return ERROR_INVALID_DATA;
+      }
+      if (memcmp(args_bytes_seg.ptr, dep->address.bytes, 20) != 0) {
+        continue;
+      }
+      len = 64;
+      ret = ckb_load_cell_data(cell_data, &len, 0, dep_index, CKB_SOURCE_CELL_DEP);
+      if (ret != CKB_SUCCESS) {
+        debug_print_int("load cell data from cell dep failed", dep_index);
+        return ret;
+      }
+      if (len != 64) {
+        debug_print_int("contract cell data invlaid len:", len);
+        return -100;
+      }
+      debug_print_data("cell dep code hash: ", cell_data + 32, 32);
+      if (memcmp(code_hash, cell_data + 32, 32) != 0) {
+        return -101;
+      }
+      verified = true;
+    }
+    if (!verified) {
+      debug_print_data("can not find code dep contract cell", dep->address.bytes, 20);
+      return -12;
+    }
+  }
+  return 0;
+}
+
 inline int verify_params(const uint8_t *signature_data,
                          const uint8_t call_kind,
                          const uint32_t flags,
@@ -2110,6 +2391,11 @@ inline int verify_params(const uint8_t *signature_data,
     if (ret != CKB_SUCCESS) {
       return ret;
     }
+
+    ret = load_code_deps(&blake2b_ctx);
+    if (ret != CKB_SUCCESS) {
+      return ret;
+    }
   }
 
   eoa_account *tx_origin_account = NULL;
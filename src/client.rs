@@ -3,140 +3,515 @@ use ckb_jsonrpc_types::{
     OutPoint, TransactionWithStatus,
 };
 use ckb_types::H256;
+use jsonrpc_core::{Id, Output};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-macro_rules! jsonrpc {
-    (
-        $(#[$struct_attr:meta])*
-        pub struct $struct_name:ident {$(
-            $(#[$attr:meta])*
-            pub fn $method:ident(&mut $selff:ident $(, $arg_name:ident: $arg_ty:ty)*)
-                -> $return_ty:ty;
-        )*}
-    ) => (
-        $(#[$struct_attr])*
-        pub struct $struct_name {
-            pub client: reqwest::blocking::Client,
-            pub url: reqwest::Url,
-            pub id: u64,
-        }
+use crate::metrics::METRICS;
 
-        impl $struct_name {
-            pub fn new(uri: &str) -> Self {
-                let url = reqwest::Url::parse(uri).expect("ckb uri, e.g. \"http://127.0.0.1:8114\"");
-                $struct_name { url, id: 0, client: reqwest::blocking::Client::new(), }
-            }
+/// Max requests in one JSON-RPC batch, larger batches are split
+pub const MAX_BATCH_SIZE: usize = 100;
 
-            $(
-                $(#[$attr])*
-                pub fn $method(&mut $selff $(, $arg_name: $arg_ty)*) -> Result<$return_ty, failure::Error> {
-                    let method = String::from(stringify!($method));
-                    let params = serialize_parameters!($($arg_name,)*);
-                    $selff.id += 1;
-
-                    let mut req_json = serde_json::Map::new();
-                    req_json.insert("id".to_owned(), serde_json::json!($selff.id));
-                    req_json.insert("jsonrpc".to_owned(), serde_json::json!("2.0"));
-                    req_json.insert("method".to_owned(), serde_json::json!(method));
-                    req_json.insert("params".to_owned(), params);
-
-                    let resp = $selff.client.post($selff.url.clone()).json(&req_json).send()?;
-                    let output = resp.json::<ckb_jsonrpc_types::response::Output>()?;
-                    match output {
-                        ckb_jsonrpc_types::response::Output::Success(success) => {
-                            serde_json::from_value(success.result).map_err(Into::into)
-                        },
-                        ckb_jsonrpc_types::response::Output::Failure(failure) => {
-                            Err(failure.error.into())
-                        }
-                    }
-                }
-            )*
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ClientError {
+    /// None of the CKB nodes can be reached (connect error, timeout, HTTP error)
+    Unreachable(String),
+    /// The CKB node returned an error response
+    Rpc { code: i64, message: String },
+    /// The response can not be decoded
+    InvalidResponse(String),
+    /// The block/header/transaction is not found on chain
+    NotFound(String),
+}
+
+impl ClientError {
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, ClientError::Unreachable(_))
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Unreachable(err) => write!(f, "CKB node unreachable: {}", err),
+            ClientError::Rpc { code, message } => {
+                write!(f, "CKB RPC error: code={}, message={}", code, message)
+            }
+            ClientError::InvalidResponse(err) => write!(f, "Invalid CKB RPC response: {}", err),
+            ClientError::NotFound(what) => write!(f, "Not found on chain: {}", what),
         }
-    )
+    }
 }
 
-macro_rules! serialize_parameters {
-    () => ( serde_json::Value::Null );
-    ($($arg_name:ident,)+) => ( serde_json::to_value(($($arg_name,)+))?)
+impl From<ClientError> for String {
+    fn from(err: ClientError) -> String {
+        err.to_string()
+    }
 }
 
-jsonrpc!(pub struct RawHttpRpcClient {
-    // Chain
-    pub fn get_block(&mut self, hash: H256) -> Option<BlockView>;
-    pub fn get_epoch_by_number(&mut self, number: EpochNumber) -> Option<EpochView>;
-    pub fn get_header(&mut self, hash: H256) -> Option<HeaderView>;
-    pub fn get_header_by_number(&mut self, number: BlockNumber) -> Option<HeaderView>;
-    pub fn get_live_cell(&mut self, out_point: OutPoint, with_data: bool) -> CellWithStatus;
-    pub fn get_tip_block_number(&mut self) -> BlockNumber;
-    pub fn get_tip_header(&mut self) -> HeaderView;
-    pub fn get_transaction(&mut self, hash: H256) -> Option<TransactionWithStatus>;
-    pub fn get_blockchain_info(&mut self) -> ChainInfo;
-
-    // Pool
-});
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Timeout of a whole request (include reading the response)
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Max retries of a request when the node is unreachable
+    pub max_retries: u32,
+    /// Backoff before the first retry, doubled for every following retry
+    pub retry_backoff: Duration,
+    pub max_retry_backoff: Duration,
+    /// A failed node is not selected for this duration unless all nodes failed
+    pub unhealthy_duration: Duration,
+}
 
-pub struct HttpRpcClient {
-    url: String,
-    client: RawHttpRpcClient,
+impl Default for ClientConfig {
+    fn default() -> ClientConfig {
+        ClientConfig {
+            timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(5),
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            max_retry_backoff: Duration::from_secs(10),
+            unhealthy_duration: Duration::from_secs(30),
+        }
+    }
 }
 
-impl Clone for HttpRpcClient {
-    fn clone(&self) -> HttpRpcClient {
-        HttpRpcClient::new(self.url.clone())
+impl ClientConfig {
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.retry_backoff * 2u32.saturating_pow(retry.min(16));
+        backoff.min(self.max_retry_backoff)
     }
 }
 
+struct Upstream {
+    url: reqwest::Url,
+    unhealthy_until: Option<Instant>,
+}
+
+/// JSON-RPC client of CKB nodes. Requests go to the first healthy node, the
+/// health states are shared by the cloned clients.
+#[derive(Clone)]
+pub struct HttpRpcClient {
+    client: reqwest::blocking::Client,
+    config: ClientConfig,
+    upstreams: Arc<Mutex<Vec<Upstream>>>,
+    id: u64,
+}
+
 impl HttpRpcClient {
+    /// Separate multiple urls by comma, e.g. "http://10.0.0.1:8114,http://10.0.0.2:8114"
     pub fn new(url: String) -> HttpRpcClient {
-        let client = RawHttpRpcClient::new(url.as_str());
-        HttpRpcClient { url, client }
+        HttpRpcClient::with_config(&url, ClientConfig::default())
+            .expect("ckb uri, e.g. \"http://127.0.0.1:8114\"")
+    }
+
+    pub fn with_config(urls: &str, config: ClientConfig) -> Result<HttpRpcClient, String> {
+        let upstreams = urls
+            .split(',')
+            .map(|url| {
+                reqwest::Url::parse(url.trim())
+                    .map(|url| Upstream {
+                        url,
+                        unhealthy_until: None,
+                    })
+                    .map_err(|err| format!("Invalid ckb uri {}: {}", url, err))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let client = reqwest::blocking::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .build()
+            .map_err(|err| err.to_string())?;
+        Ok(HttpRpcClient {
+            client,
+            config,
+            upstreams: Arc::new(Mutex::new(upstreams)),
+            id: 0,
+        })
+    }
+
+    /// The first healthy node, or the one will recover earliest
+    fn select_upstream(&self) -> (usize, reqwest::Url) {
+        let upstreams = self.upstreams.lock().expect("lock upstreams");
+        let now = Instant::now();
+        let index = upstreams
+            .iter()
+            .position(|upstream| {
+                upstream
+                    .unhealthy_until
+                    .map(|until| until <= now)
+                    .unwrap_or(true)
+            })
+            .unwrap_or_else(|| {
+                (0..upstreams.len())
+                    .min_by_key(|index| upstreams[*index].unhealthy_until)
+                    .expect("at least one upstream")
+            });
+        (index, upstreams[index].url.clone())
+    }
+
+    fn set_health(&self, index: usize, healthy: bool) -> bool {
+        let mut upstreams = self.upstreams.lock().expect("lock upstreams");
+        upstreams[index].unhealthy_until = if healthy {
+            None
+        } else {
+            Some(Instant::now() + self.config.unhealthy_duration)
+        };
+        let now = Instant::now();
+        upstreams.iter().any(|upstream| {
+            upstream
+                .unhealthy_until
+                .map(|until| until <= now)
+                .unwrap_or(true)
+        })
+    }
+
+    /// Send the body to a node, failover to other nodes and retry with
+    /// backoff if the node is unreachable. NOTE: only read methods are
+    /// called, they are idempotent thus safe to retry.
+    fn send(&mut self, body: &Value) -> Result<Value, ClientError> {
+        let mut retry = 0;
+        loop {
+            let (index, url) = self.select_upstream();
+            let result = self
+                .client
+                .post(url.clone())
+                .json(body)
+                .send()
+                .and_then(|resp| resp.error_for_status());
+            match result {
+                Ok(resp) => {
+                    self.set_health(index, true);
+                    return resp
+                        .json::<Value>()
+                        .map_err(|err| ClientError::InvalidResponse(err.to_string()));
+                }
+                Err(err) => {
+                    let err = ClientError::Unreachable(format!("{}: {}", url, err));
+                    let any_healthy = self.set_health(index, false);
+                    if retry >= self.config.max_retries {
+                        return Err(err);
+                    }
+                    retry += 1;
+                    log::warn!("{}, retry {}/{}", err, retry, self.config.max_retries);
+                    // Failover immediately if there is another healthy node
+                    if !any_healthy {
+                        sleep(self.config.backoff(retry - 1));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send all the calls in one batch request, results are in the same order
+    fn batch<T: DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params_list: Vec<Value>,
+    ) -> Result<Vec<Result<T, ClientError>>, ClientError> {
+        let mut results = Vec::with_capacity(params_list.len());
+        for chunk in params_list.chunks(MAX_BATCH_SIZE) {
+            let first_id = self.id + 1;
+            let requests = chunk
+                .iter()
+                .map(|params| {
+                    self.id += 1;
+                    json!({"id": self.id, "jsonrpc": "2.0", "method": method, "params": params})
+                })
+                .collect::<Vec<_>>();
+            // Single call is sent as a plain request
+            let body = if requests.len() == 1 {
+                requests[0].clone()
+            } else {
+                Value::Array(requests)
+            };
+            let outputs: Vec<Output> = match self.send(&body)? {
+                Value::Array(outputs) => outputs
+                    .into_iter()
+                    .map(serde_json::from_value)
+                    .collect::<Result<_, _>>(),
+                output => serde_json::from_value(output).map(|output| vec![output]),
+            }
+            .map_err(|err| ClientError::InvalidResponse(err.to_string()))?;
+            let mut outputs = outputs
+                .into_iter()
+                .map(|output| (output.id().clone(), output))
+                .collect::<HashMap<_, _>>();
+            for id in first_id..first_id + chunk.len() as u64 {
+                let output = outputs.remove(&Id::Num(id)).ok_or_else(|| {
+                    ClientError::InvalidResponse(format!("missing response of request {}", id))
+                })?;
+                results.push(parse_output(output));
+            }
+        }
+        Ok(results)
+    }
+
+    fn call<T: DeserializeOwned>(&mut self, method: &str, params: Value) -> Result<T, ClientError> {
+        self.batch(method, vec![params])
+            .and_then(|mut results| results.remove(0))
+            .map_err(|err| rpc_error(method, err))
+    }
+
+    fn call_many<T: DeserializeOwned>(
+        &mut self,
+        method: &str,
+        params_list: Vec<Value>,
+    ) -> Result<Vec<T>, ClientError> {
+        self.batch(method, params_list)
+            .and_then(|results| results.into_iter().collect())
+            .map_err(|err| rpc_error(method, err))
     }
 }
 
-impl HttpRpcClient {
+fn parse_output<T: DeserializeOwned>(output: Output) -> Result<T, ClientError> {
+    match output {
+        Output::Success(success) => serde_json::from_value(success.result)
+            .map_err(|err| ClientError::InvalidResponse(err.to_string())),
+        Output::Failure(failure) => Err(ClientError::Rpc {
+            code: failure.error.code.code(),
+            message: failure.error.message,
+        }),
+    }
+}
+
+/// Where the CKB chain data come from. `HttpRpcClient` talks to CKB nodes,
+/// tests use an in-memory chain (see `mock_chain`).
+pub trait ChainSource: Send + Sync {
+    fn clone_box(&self) -> Box<dyn ChainSource>;
+
+    fn get_block(&mut self, hash: H256) -> Result<Option<BlockView>, ClientError>;
+    fn get_epoch_by_number(&mut self, number: u64) -> Result<Option<EpochView>, ClientError>;
+    fn get_header(&mut self, hash: H256) -> Result<Option<HeaderView>, ClientError>;
+    fn get_header_by_number(&mut self, number: u64) -> Result<Option<HeaderView>, ClientError>;
+    fn get_live_cell(
+        &mut self,
+        out_point: OutPoint,
+        with_data: bool,
+    ) -> Result<CellWithStatus, ClientError>;
+    fn get_tip_block_number(&mut self) -> Result<u64, ClientError>;
+    fn get_tip_header(&mut self) -> Result<HeaderView, ClientError>;
+    fn get_transaction(&mut self, hash: H256)
+        -> Result<Option<TransactionWithStatus>, ClientError>;
+    fn get_blockchain_info(&mut self) -> Result<ChainInfo, ClientError>;
+
+    /// Get many headers at once, in the same order of `hashes`
+    fn get_headers(&mut self, hashes: Vec<H256>) -> Result<Vec<Option<HeaderView>>, ClientError> {
+        hashes
+            .into_iter()
+            .map(|hash| self.get_header(hash))
+            .collect()
+    }
+    /// Get many transactions at once, in the same order of `hashes`
+    fn get_transactions(
+        &mut self,
+        hashes: Vec<H256>,
+    ) -> Result<Vec<Option<TransactionWithStatus>>, ClientError> {
+        hashes
+            .into_iter()
+            .map(|hash| self.get_transaction(hash))
+            .collect()
+    }
+}
+
+impl Clone for Box<dyn ChainSource> {
+    fn clone(&self) -> Box<dyn ChainSource> {
+        self.clone_box()
+    }
+}
+
+impl ChainSource for HttpRpcClient {
+    fn clone_box(&self) -> Box<dyn ChainSource> {
+        Box::new(self.clone())
+    }
+
     // Chain
-    pub fn get_block(&mut self, hash: H256) -> Result<Option<BlockView>, String> {
-        self.client.get_block(hash).map_err(|err| err.to_string())
+    fn get_block(&mut self, hash: H256) -> Result<Option<BlockView>, ClientError> {
+        self.call("get_block", json!([hash]))
     }
-    pub fn get_epoch_by_number(&mut self, number: u64) -> Result<Option<EpochView>, String> {
-        self.client
-            .get_epoch_by_number(EpochNumber::from(number))
-            .map_err(|err| err.to_string())
+    fn get_epoch_by_number(&mut self, number: u64) -> Result<Option<EpochView>, ClientError> {
+        self.call("get_epoch_by_number", json!([EpochNumber::from(number)]))
     }
-    pub fn get_header(&mut self, hash: H256) -> Result<Option<HeaderView>, String> {
-        self.client.get_header(hash).map_err(|err| err.to_string())
+    fn get_header(&mut self, hash: H256) -> Result<Option<HeaderView>, ClientError> {
+        self.call("get_header", json!([hash]))
     }
-    pub fn get_header_by_number(&mut self, number: u64) -> Result<Option<HeaderView>, String> {
-        self.client
-            .get_header_by_number(BlockNumber::from(number))
-            .map_err(|err| err.to_string())
+    fn get_header_by_number(&mut self, number: u64) -> Result<Option<HeaderView>, ClientError> {
+        self.call("get_header_by_number", json!([BlockNumber::from(number)]))
     }
-    pub fn get_live_cell(
+    fn get_live_cell(
         &mut self,
         out_point: OutPoint,
         with_data: bool,
-    ) -> Result<CellWithStatus, String> {
-        self.client
-            .get_live_cell(out_point, with_data)
-            .map_err(|err| err.to_string())
-    }
-    pub fn get_tip_block_number(&mut self) -> Result<u64, String> {
-        self.client
-            .get_tip_block_number()
+    ) -> Result<CellWithStatus, ClientError> {
+        self.call("get_live_cell", json!([out_point, with_data]))
+    }
+    fn get_tip_block_number(&mut self) -> Result<u64, ClientError> {
+        self.call::<BlockNumber>("get_tip_block_number", json!([]))
             .map(|number| number.value())
-            .map_err(|err| err.to_string())
     }
-    pub fn get_tip_header(&mut self) -> Result<HeaderView, String> {
-        self.client.get_tip_header().map_err(|err| err.to_string())
+    fn get_tip_header(&mut self) -> Result<HeaderView, ClientError> {
+        self.call("get_tip_header", json!([]))
     }
-    pub fn get_transaction(&mut self, hash: H256) -> Result<Option<TransactionWithStatus>, String> {
-        self.client
-            .get_transaction(hash)
-            .map_err(|err| err.to_string())
+    fn get_transaction(
+        &mut self,
+        hash: H256,
+    ) -> Result<Option<TransactionWithStatus>, ClientError> {
+        self.call("get_transaction", json!([hash]))
+    }
+    fn get_blockchain_info(&mut self) -> Result<ChainInfo, ClientError> {
+        self.call("get_blockchain_info", json!([]))
     }
-    pub fn get_blockchain_info(&mut self) -> Result<ChainInfo, String> {
-        self.client
-            .get_blockchain_info()
-            .map_err(|err| err.to_string())
+
+    fn get_headers(&mut self, hashes: Vec<H256>) -> Result<Vec<Option<HeaderView>>, ClientError> {
+        let params_list = hashes.into_iter().map(|hash| json!([hash])).collect();
+        self.call_many("get_header", params_list)
+    }
+    fn get_transactions(
+        &mut self,
+        hashes: Vec<H256>,
+    ) -> Result<Vec<Option<TransactionWithStatus>>, ClientError> {
+        let params_list = hashes.into_iter().map(|hash| json!([hash])).collect();
+        self.call_many("get_transaction", params_list)
+    }
+}
+
+fn rpc_error(method: &str, err: ClientError) -> ClientError {
+    METRICS.ckb_rpc_errors.inc(method);
+    err
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A minimal HTTP server reply every request by `handler`
+    fn serve<F>(handler: F) -> String
+    where
+        F: Fn(Value) -> Value + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    let mut header = line.splitn(2, ':');
+                    if let (Some(name), Some(value)) = (header.next(), header.next()) {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0u8; content_length];
+                reader.read_exact(&mut body).unwrap();
+                let response = handler(serde_json::from_slice(&body).unwrap()).to_string();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    // Reply the tip block number `tip`, reply null for other methods
+    fn tip_handler(tip: u64) -> impl Fn(Value) -> Value {
+        move |request| {
+            let reply = |request: &Value| {
+                let result = if request["method"] == "get_tip_block_number" {
+                    json!(format!("{:#x}", tip))
+                } else {
+                    Value::Null
+                };
+                json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+            };
+            match request {
+                Value::Array(requests) => {
+                    // Reply in reversed order
+                    Value::Array(requests.iter().rev().map(reply).collect())
+                }
+                request => reply(&request),
+            }
+        }
+    }
+
+    fn test_config() -> ClientConfig {
+        ClientConfig {
+            connect_timeout: Duration::from_millis(200),
+            timeout: Duration::from_secs(2),
+            retry_backoff: Duration::from_millis(10),
+            ..Default::default()
+        }
+    }
+
+    // Nothing listens on the port
+    fn dead_url() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    }
+
+    #[test]
+    fn test_failover() {
+        let urls = format!("{},{}", dead_url(), serve(tip_handler(42)));
+        let mut client = HttpRpcClient::with_config(&urls, test_config()).unwrap();
+        assert_eq!(client.get_tip_block_number().unwrap(), 42);
+        // The dead node is skipped
+        let (index, _) = client.select_upstream();
+        assert_eq!(index, 1);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let mut client = HttpRpcClient::with_config(&dead_url(), test_config()).unwrap();
+        let err = client.get_tip_block_number().unwrap_err();
+        assert!(err.is_unreachable(), "{}", err);
+    }
+
+    #[test]
+    fn test_batch() {
+        let url = serve(tip_handler(42));
+        let mut client = HttpRpcClient::with_config(&url, test_config()).unwrap();
+        let hashes = (0..(MAX_BATCH_SIZE as u8 + 2))
+            .map(|n| H256::from([n; 32]))
+            .collect::<Vec<_>>();
+        let transactions = client.get_transactions(hashes).unwrap();
+        assert_eq!(transactions.len(), MAX_BATCH_SIZE + 2);
+        assert!(transactions.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn test_rpc_error() {
+        let url = serve(|request| {
+            json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "error": {"code": -32601, "message": "Method not found"},
+            })
+        });
+        let mut client = HttpRpcClient::with_config(&url, test_config()).unwrap();
+        assert_eq!(
+            client.get_tip_block_number(),
+            Err(ClientError::Rpc {
+                code: -32601,
+                message: String::from("Method not found"),
+            })
+        );
     }
 }
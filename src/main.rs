@@ -1,11 +1,17 @@
 mod client;
+mod metrics;
+#[cfg(test)]
+mod mock_chain;
+mod pubsub;
 mod server;
+mod sinks;
 mod storage;
 mod types;
 
-use client::HttpRpcClient;
-use jsonrpc_core::IoHandler;
+use client::{ChainSource, ClientConfig, HttpRpcClient};
+use jsonrpc_core::{MetaIoHandler, Output};
 use jsonrpc_http_server::ServerBuilder;
+use jsonrpc_pubsub::{PubSubHandler, Session};
 use jsonrpc_server_utils::cors::AccessControlAllowOrigin;
 use jsonrpc_server_utils::hosts::DomainsValidation;
 
@@ -18,19 +24,25 @@ use ckb_types::{
     prelude::*,
     H160, H256,
 };
-use clap::{App, Arg, SubCommand};
-use rocksdb::DB;
-use serde::{Deserialize, Serialize};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use metrics::RpcMetricsMiddleware;
+use pubsub::{SubscriptionRpc, SubscriptionRpcImpl, Subscriptions};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use server::{Rpc, RpcImpl, TransactionReceipt};
 use std::collections::HashMap;
 use std::fs;
 use std::process::Command;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use storage::{Indexer, Loader};
+use std::time::Duration;
+use storage::{DbOptions, Indexer, IndexerStatus, IndexerSupervisor, Verifier};
 use tempfile::NamedTempFile;
-use types::{CallKind, EoaAddress, Program, RunConfig, WitnessData, ONE_CKB, SECP256K1};
+use types::{
+    CallKind, EoaAddress, ExecutionLimits, Program, RunConfig, WitnessData, ONE_CKB, SECP256K1,
+    SIGHASH_TYPE_HASH,
+};
 
 const ANYONE_CAN_PAY_CODE_HASH: H256 =
     h256!("0x8b10144daa110152e78dd002b44f429238cbbee5e62052205fdc6a1fc2c57a2a");
@@ -43,7 +55,7 @@ fn main() -> Result<(), String> {
         .takes_value(true)
         .required(true)
         .default_value("http://127.0.0.1:8114")
-        .help("The ckb rpc url");
+        .help("The ckb rpc url, separate multiple urls by comma to failover between nodes");
     let arg_privkey = Arg::with_name("privkey")
         .long("privkey")
         .short("k")
@@ -55,6 +67,32 @@ fn main() -> Result<(), String> {
                 .map_err(|err| err.to_string())
         })
         .help("The private key file (hex)");
+    let arg_polyjuice_url = Arg::with_name("polyjuice-url")
+        .long("polyjuice-url")
+        .takes_value(true)
+        .required(true)
+        .default_value("http://127.0.0.1:8214")
+        .help("The polyjuice rpc url");
+    let arg_receipt_output = Arg::with_name("output")
+        .long("output")
+        .short("o")
+        .takes_value(true)
+        .help("The transaction receipt file path");
+    let arg_db = Arg::with_name("db")
+        .long("db")
+        .takes_value(true)
+        .required(true)
+        .default_value("./data")
+        .help("Database directory");
+    let arg_db_config = Arg::with_name("config")
+        .long("config")
+        .takes_value(true)
+        .validator(|input| {
+            fs::File::open(input)
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        })
+        .help("The config (json) of the server, to open the database with its RocksDB options");
     let matches = App::new("polyjuice")
         .subcommand(
             SubCommand::with_name("run")
@@ -75,14 +113,7 @@ fn main() -> Result<(), String> {
                         .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
                         .help("The config (json)")
                 )
-                .arg(
-                    Arg::with_name("db")
-                        .long("db")
-                        .takes_value(true)
-                        .required(true)
-                        .default_value("./data")
-                        .help("Database directory")
-                )
+                .arg(arg_db.clone())
                 .arg(
                     Arg::with_name("listen")
                         .long("listen")
@@ -91,6 +122,112 @@ fn main() -> Result<(), String> {
                         .default_value("127.0.0.1:8214")
                         .help("Polyjuice rpc server listen address")
                 )
+                .arg(
+                    Arg::with_name("ws-listen")
+                        .long("ws-listen")
+                        .takes_value(true)
+                        .help("WebSocket rpc server (with subscriptions) listen address, disabled by default")
+                )
+                .arg(
+                    Arg::with_name("event-sink")
+                        .long("event-sink")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Deliver indexer events to a JSON-lines file (jsonl:<path>) or a Unix socket (unix:<path>)")
+                )
+                .arg(
+                    Arg::with_name("max-lag")
+                        .long("max-lag")
+                        .takes_value(true)
+                        .default_value("20")
+                        .validator(|input| input.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The node is unhealthy (GET /health) if the indexer lag behind CKB tip more than this number of blocks")
+                )
+                .arg(
+                    Arg::with_name("max-reorg-depth")
+                        .long("max-reorg-depth")
+                        .takes_value(true)
+                        .default_value("100")
+                        .validator(|input| input.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The indexer halts (instead of rolling back) if the chain reorganized deeper than this number of blocks")
+                )
+                .arg(
+                    Arg::with_name("rpc-timeout")
+                        .long("rpc-timeout")
+                        .takes_value(true)
+                        .default_value("30")
+                        .validator(|input| input.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+                        .help("Timeout (in seconds) of requests to CKB node")
+                )
+                .arg(
+                    Arg::with_name("rpc-retries")
+                        .long("rpc-retries")
+                        .takes_value(true)
+                        .default_value("3")
+                        .validator(|input| input.parse::<u32>().map(|_| ()).map_err(|err| err.to_string()))
+                        .help("Max retries (with exponential backoff) when CKB node is unreachable")
+                )
+                .arg(
+                    Arg::with_name("metrics-listen")
+                        .long("metrics-listen")
+                        .takes_value(true)
+                        .help("Prometheus metrics listen address (GET /metrics), disabled by default")
+                )
+                .arg(
+                    Arg::with_name("verify-interval")
+                        .long("verify-interval")
+                        .takes_value(true)
+                        .validator(|input| input.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+                        .help("Verify the index against CKB live cells every N seconds in background, disabled by default")
+                )
+                .arg(
+                    Arg::with_name("exit-on-indexer-failure")
+                        .long("exit-on-indexer-failure")
+                        .help("Shutdown the server when the indexer stopped by a fatal error")
+                )
+                .arg(arg_ckb_url.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("verify-db")
+                .about("Verify indexed contract cells and EoA cells against CKB live cells")
+                .arg(arg_db.clone())
+                .arg(arg_db_config.clone())
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Rollback the index to the block before the earliest discrepancy (the server must be stopped)")
+                )
+                .arg(arg_ckb_url.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("export-snapshot")
+                .about("Export the indexed database to a snapshot file (the server must be stopped)")
+                .arg(arg_db.clone())
+                .arg(arg_db_config.clone())
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The snapshot file path")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("import-snapshot")
+                .about("Import a snapshot file into an empty database")
+                .arg(arg_db.clone())
+                .arg(arg_db_config.clone())
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| fs::File::open(input).map(|_| ()).map_err(|err| err.to_string()))
+                        .help("The snapshot file path")
+                )
                 .arg(arg_ckb_url.clone())
         )
         .subcommand(
@@ -129,6 +266,95 @@ fn main() -> Result<(), String> {
                 )
                 .arg(arg_ckb_url.clone())
         )
+        .subcommand(
+            SubCommand::with_name("transfer")
+                .about("Build a transaction transferring value between EoA accounts (sign it by sign-tx)")
+                .arg(arg_polyjuice_url.clone())
+                .arg(
+                    Arg::with_name("sender")
+                        .long("sender")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| parse_h160(input.as_str()).map(|_| ()))
+                        .help("The sender EoA address")
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .long("destination")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| parse_h160(input.as_str()).map(|_| ()))
+                        .help("The destination EoA address")
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .long("value")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| HumanCapacity::from_str(input.as_str()).map(|_| ()))
+                        .help("The value to transfer (unit: CKB, format: 123.335)")
+                )
+                .arg(arg_receipt_output.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("deposit")
+                .about("Build a transaction depositing value from secp256k1 cells to an EoA account (sign it by sign-tx)")
+                .arg(arg_polyjuice_url.clone())
+                .arg(
+                    Arg::with_name("lock-arg")
+                        .long("lock-arg")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| parse_h160(input.as_str()).map(|_| ()))
+                        .help("The lock arg of the secp256k1 cells (the change goes back to it)")
+                )
+                .arg(
+                    Arg::with_name("destination")
+                        .long("destination")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| parse_h160(input.as_str()).map(|_| ()))
+                        .help("The destination EoA address")
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .long("value")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| HumanCapacity::from_str(input.as_str()).map(|_| ()))
+                        .help("The value to deposit (unit: CKB, format: 123.335)")
+                )
+                .arg(arg_receipt_output.clone())
+        )
+        .subcommand(
+            SubCommand::with_name("withdraw")
+                .about("Build a transaction withdrawing value from an EoA account to a secp256k1 cell (sign it by sign-tx)")
+                .arg(arg_polyjuice_url.clone())
+                .arg(
+                    Arg::with_name("sender")
+                        .long("sender")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| parse_h160(input.as_str()).map(|_| ()))
+                        .help("The sender EoA address")
+                )
+                .arg(
+                    Arg::with_name("lock-arg")
+                        .long("lock-arg")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|input| parse_h160(input.as_str()).map(|_| ()))
+                        .help("The lock arg of the secp256k1 cell to receive the value")
+                )
+                .arg(
+                    Arg::with_name("value")
+                        .long("value")
+                        .takes_value(true)
+                        .validator(|input| HumanCapacity::from_str(input.as_str()).map(|_| ()))
+                        .help("The value to withdraw (unit: CKB, format: 123.335), withdraw all and close the EoA account if absent")
+                )
+                .arg(arg_receipt_output.clone())
+        )
         .subcommand(
             SubCommand::with_name("build-tx")
                 .about("Build and serialize a eth transaction which will put into witness data")
@@ -194,11 +420,7 @@ fn main() -> Result<(), String> {
             let generator = fs::read(m.value_of("generator").unwrap())
                 .map(Bytes::from)
                 .map_err(|err| err.to_string())?;
-            let config_json: RunConfigJson = fs::read_to_string(m.value_of("config").unwrap())
-                .map_err(|err| err.to_string())
-                .and_then(|json_string| {
-                    serde_json::from_str(json_string.as_str()).map_err(|err| err.to_string())
-                })?;
+            let config_json = read_config(m.value_of("config").unwrap())?;
             let run_config = RunConfig {
                 generator,
                 type_dep: config_json.type_dep.into(),
@@ -207,50 +429,194 @@ fn main() -> Result<(), String> {
                 lock_script: config_json.lock_script.into(),
                 eoa_lock_dep: config_json.eoa_lock_dep.into(),
                 eoa_lock_script: config_json.eoa_lock_script.into(),
+                limits: config_json.limits,
             };
             let ckb_uri = m.value_of("url").unwrap();
             let db_dir = m.value_of("db").unwrap();
             let listen_addr = m.value_of("listen").unwrap();
+            let ws_listen_addr = m.value_of("ws-listen");
+            let metrics_listen_addr = m.value_of("metrics-listen");
+            let max_lag: u64 = m.value_of("max-lag").unwrap().parse().unwrap();
+            let max_reorg_depth: u64 = m.value_of("max-reorg-depth").unwrap().parse().unwrap();
+            let verify_interval: Option<u64> = m
+                .value_of("verify-interval")
+                .map(|input| input.parse().unwrap());
+            let exit_on_indexer_failure = m.is_present("exit-on-indexer-failure");
+            let client_config = ClientConfig {
+                timeout: Duration::from_secs(m.value_of("rpc-timeout").unwrap().parse().unwrap()),
+                max_retries: m.value_of("rpc-retries").unwrap().parse().unwrap(),
+                ..Default::default()
+            };
+            // The clients share the health states of the CKB nodes
+            let client = HttpRpcClient::with_config(ckb_uri, client_config)?;
+
+            // Notified by Ctrl-C or indexer failure
+            let exit = Arc::new((Mutex::new(false), Condvar::new()));
+            let indexer_stop = Arc::new(AtomicBool::new(false));
+            // Installed before any thread or server is started, so that a
+            // Ctrl-C during startup still stops the indexer gracefully
+            let e = Arc::clone(&exit);
+            let stop = Arc::clone(&indexer_stop);
+            ctrlc::set_handler(move || {
+                stop.store(true, Ordering::SeqCst);
+                notify_exit(&e);
+            })
+            .expect("error setting Ctrl-C handler");
 
             log::info!("Open database: {:?}", db_dir);
-            let db = Arc::new(DB::open_default(db_dir).expect("rocksdb"));
-            let loader = Arc::new(Loader::new(Arc::clone(&db), ckb_uri).expect("loader failure"));
-            let mut indexer = Indexer::new(Arc::clone(&db), ckb_uri, run_config.clone());
-            let _ = thread::spawn(move || indexer.index().expect("indexer faliure"));
-
-            let mut io_handler = IoHandler::new();
-            io_handler.extend_with(
-                RpcImpl {
-                    loader: Arc::clone(&loader),
-                    run_config,
-                }
-                .to_delegate(),
+            let db = Arc::new(storage::open_db(db_dir, &config_json.db_options)?);
+            storage::migrate(&db)?;
+            let indexer_status = IndexerStatus::new_shared();
+            let subscriptions = Arc::new(Subscriptions::default());
+            let mut indexer = Indexer::with_chain(
+                Arc::clone(&db),
+                Box::new(client.clone()),
+                run_config.clone(),
+                Arc::clone(&indexer_status),
+            );
+            indexer.max_reorg_depth = max_reorg_depth;
+            // Share the chain cache fed by the indexer with the RPC handlers
+            let loader = Arc::new(indexer.loader.clone());
+            for spec in m.values_of("event-sink").into_iter().flatten() {
+                indexer.sinks.push(sinks::open_sink(spec)?);
+            }
+            if ws_listen_addr.is_some() {
+                indexer.sinks.push(Box::new(Arc::clone(&subscriptions)));
+            }
+            let supervisor = IndexerSupervisor::new(
+                indexer,
+                Arc::clone(&indexer_stop),
+                Arc::clone(&indexer_status),
             );
+            let e = Arc::clone(&exit);
+            let indexer_handle = thread::spawn(move || {
+                let result = supervisor.run();
+                if result.is_err() && exit_on_indexer_failure {
+                    notify_exit(&e);
+                }
+                result
+            });
+
+            if let Some(verify_interval) = verify_interval {
+                let verifier = Verifier::with_chain(Arc::clone(&db), Box::new(client.clone()));
+                let stop = Arc::clone(&indexer_stop);
+                thread::spawn(move || {
+                    verifier.run_periodically(Duration::from_secs(verify_interval), stop)
+                });
+            }
+
+            if let Some(metrics_listen_addr) = metrics_listen_addr {
+                metrics::start_server(metrics_listen_addr, Arc::clone(&loader))?;
+                log::info!("Metrics server listen on: {}", metrics_listen_addr);
+            }
+
+            let new_rpc_impl = || RpcImpl {
+                loader: Arc::clone(&loader),
+                run_config: run_config.clone(),
+                indexer_status: Arc::clone(&indexer_status),
+                max_lag,
+            };
+            let mut io_handler = MetaIoHandler::with_middleware(RpcMetricsMiddleware);
+            io_handler.extend_with(new_rpc_impl().to_delegate());
 
             let rpc_server = ServerBuilder::new(io_handler)
                 .cors(DomainsValidation::AllowOnly(vec![
                     AccessControlAllowOrigin::Null,
                     AccessControlAllowOrigin::Any,
                 ]))
+                .health_api(("/health", "health"))
                 .threads(4)
                 .max_request_body_size(10_485_760)
                 .start_http(&listen_addr.parse().expect("parse listen address"))
                 .expect("jsonrpc initialize");
             log::info!("RPC server listen on: {}", listen_addr);
 
+            let ws_server = if let Some(ws_listen_addr) = ws_listen_addr {
+                let mut ws_io_handler =
+                    PubSubHandler::new(MetaIoHandler::with_middleware(RpcMetricsMiddleware));
+                ws_io_handler.extend_with(new_rpc_impl().to_delegate());
+                ws_io_handler.extend_with(SubscriptionRpcImpl { subscriptions }.to_delegate());
+                let ws_server = jsonrpc_ws_server::ServerBuilder::with_meta_extractor(
+                    ws_io_handler,
+                    |context: &jsonrpc_ws_server::RequestContext| {
+                        Some(Arc::new(Session::new(context.sender())))
+                    },
+                )
+                .max_payload(10_485_760)
+                .start(&ws_listen_addr.parse().expect("parse ws listen address"))
+                .expect("websocket jsonrpc initialize");
+                log::info!("WebSocket RPC server listen on: {}", ws_listen_addr);
+                Some(ws_server)
+            } else {
+                None
+            };
+
             // Wait for exit
-            let exit = Arc::new((Mutex::new(()), Condvar::new()));
-            let e = Arc::clone(&exit);
-            ctrlc::set_handler(move || {
-                e.1.notify_all();
-            })
-            .expect("error setting Ctrl-C handler");
-            let _guard = exit
-                .1
-                .wait(exit.0.lock().expect("locking"))
-                .expect("waiting");
+            let mut exited = exit.0.lock().expect("locking");
+            while !*exited {
+                exited = exit.1.wait(exited).expect("waiting");
+            }
+            drop(exited);
             rpc_server.close();
+            if let Some(ws_server) = ws_server {
+                ws_server.close();
+            }
+            // Let the indexer finish current block
+            log::info!("Waiting for indexer to stop...");
+            indexer_stop.store(true, Ordering::SeqCst);
+            let indexer_result = indexer_handle
+                .join()
+                .map_err(|_| String::from("indexer thread panicked"))?;
             log::info!("exiting...");
+            if exit_on_indexer_failure {
+                indexer_result?;
+            }
+        }
+        ("verify-db", Some(m)) => {
+            let db = storage::open_db(m.value_of("db").unwrap(), &db_options(m)?)?;
+            storage::migrate(&db)?;
+            let mut verifier = Verifier::new(Arc::new(db), m.value_of("url").unwrap())?;
+            let report = verifier.verify()?;
+            for discrepancy in &report.discrepancies {
+                println!("{}", discrepancy);
+            }
+            println!(
+                "Verified index at block {}: contracts={}, eoa_accounts={}, skipped={}, discrepancies={}",
+                report.number,
+                report.contracts,
+                report.eoa_accounts,
+                report.skipped,
+                report.discrepancies.len()
+            );
+            if m.is_present("repair") && !report.discrepancies.is_empty() {
+                match verifier.repair(&report)? {
+                    Some(number) => println!("Rolled back index to block {}", number),
+                    None => println!("Rolled back index to genesis"),
+                }
+            } else if !report.discrepancies.is_empty() {
+                return Err(String::from("Index discrepancies found"));
+            }
+        }
+        ("export-snapshot", Some(m)) => {
+            let db = storage::open_db(m.value_of("db").unwrap(), &db_options(m)?)?;
+            storage::migrate(&db)?;
+            let info = storage::export_snapshot(&db, m.value_of("output").unwrap())?;
+            println!(
+                "Exported {} records at block {} ({:#x}), schema version: {}",
+                info.records, info.number, info.hash, info.schema_version
+            );
+        }
+        ("import-snapshot", Some(m)) => {
+            let db = storage::open_db(m.value_of("db").unwrap(), &db_options(m)?)?;
+            let info = storage::import_snapshot(
+                &db,
+                m.value_of("input").unwrap(),
+                m.value_of("url").unwrap(),
+            )?;
+            println!(
+                "Imported {} records at block {} ({:#x}), schema version: {}",
+                info.records, info.number, info.hash, info.schema_version
+            );
         }
         ("sign-tx", Some(m)) => {
             let mut tx_receipt: TransactionReceipt =
@@ -277,7 +643,7 @@ fn main() -> Result<(), String> {
             println!("Building signature");
             let tx = packed::Transaction::from(tx_receipt.tx.clone());
             let tx_hash: H256 = tx.calc_tx_hash().unpack();
-            let (message, entrance_witness_args, entrance_raw_witness) = {
+            let entrance = {
                 let mut entrance_witness = None;
                 let mut unsigned_data = BytesMut::default();
                 unsigned_data.put(tx_hash.as_bytes());
@@ -319,31 +685,33 @@ fn main() -> Result<(), String> {
                     }
                 }
 
-                let (entrance_witness_args, entrance_raw_witness) =
-                    entrance_witness.ok_or_else(|| String::from("No entrance witness found"))?;
-                let message = secp256k1::Message::from_slice(&blake2b_256(&unsigned_data)[..])
-                    .map_err(|err| err.to_string())?;
-                (message, entrance_witness_args, entrance_raw_witness)
-            };
-            let signature = SECP256K1.sign_recoverable(&message, &privkey);
-            let (recov_id, data) = signature.serialize_compact();
-            let mut signature_bytes = [0u8; 65];
-            signature_bytes[0..64].copy_from_slice(&data[0..64]);
-            signature_bytes[64] = recov_id.to_i32() as u8;
-
-            println!("Rebuilding witness");
-            let mut raw_witness = entrance_raw_witness.as_ref().to_vec();
-            raw_witness[4..4 + 65].copy_from_slice(&signature_bytes[..]);
-            let data = packed::BytesOpt::new_builder()
-                .set(Some(Bytes::from(raw_witness).pack()))
-                .build();
-            let witness = if entrance_witness_args.output_type().to_opt().is_some() {
-                entrance_witness_args.as_builder().output_type(data).build()
-            } else {
-                entrance_witness_args.as_builder().input_type(data).build()
+                // No entrance witness in EoA transfer, only the anyone-can-pay
+                // signature is required
+                entrance_witness
+                    .map(|(entrance_witness_args, entrance_raw_witness)| {
+                        secp256k1::Message::from_slice(&blake2b_256(&unsigned_data)[..])
+                            .map(|message| (message, entrance_witness_args, entrance_raw_witness))
+                            .map_err(|err| err.to_string())
+                    })
+                    .transpose()?
             };
+            if let Some((message, entrance_witness_args, entrance_raw_witness)) = entrance {
+                let signature = SECP256K1.sign_recoverable(&message, &privkey);
+                let signature_bytes = serialize_signature(&signature);
 
-            tx_receipt.tx.witnesses[0] = json_types::JsonBytes::from_bytes(witness.as_bytes());
+                println!("Rebuilding witness");
+                let mut raw_witness = entrance_raw_witness.as_ref().to_vec();
+                raw_witness[4..4 + 65].copy_from_slice(&signature_bytes[..]);
+                let data = packed::BytesOpt::new_builder()
+                    .set(Some(Bytes::from(raw_witness).pack()))
+                    .build();
+                let witness = if entrance_witness_args.output_type().to_opt().is_some() {
+                    entrance_witness_args.as_builder().output_type(data).build()
+                } else {
+                    entrance_witness_args.as_builder().input_type(data).build()
+                };
+                tx_receipt.tx.witnesses[0] = json_types::JsonBytes::from_bytes(witness.as_bytes());
+            }
             while tx_receipt.tx.witnesses.len() < tx_receipt.tx.inputs.len() {
                 tx_receipt.tx.witnesses.push(Default::default());
             }
@@ -353,12 +721,24 @@ fn main() -> Result<(), String> {
             let mut client = HttpRpcClient::new(ckb_uri.to_string());
             // {lock_arg => {type_hash => (input_index, input_capacity, output_capacity)}}
             let mut eoa_cells: HashMap<H160, HashMap<H256, (usize, u64, u64)>> = Default::default();
+            // The secp256k1 (sighash) inputs spent by deposit
+            let mut sighash_idxs: Vec<usize> = Vec::new();
             for (idx, input) in tx_view.inputs().into_iter().enumerate() {
                 let output: packed::CellOutput =
                     get_live_cell(&mut client, input.previous_output().into(), false)?.into();
                 let lock_script = output.lock();
                 let code_hash: H256 = lock_script.code_hash().unpack();
-                if code_hash == ANYONE_CAN_PAY_CODE_HASH {
+                if code_hash == SIGHASH_TYPE_HASH {
+                    let lock_arg =
+                        H160::from_slice(lock_script.args().raw_data().as_ref()).unwrap();
+                    if lock_arg != tx_origin_lock_arg {
+                        return Err(format!(
+                            "The secp256k1 input is not owned by the private key, lock arg: {:x}",
+                            lock_arg
+                        ));
+                    }
+                    sighash_idxs.push(idx);
+                } else if code_hash == ANYONE_CAN_PAY_CODE_HASH {
                     let type_hash: H256 = output
                         .type_()
                         .to_opt()
@@ -454,6 +834,30 @@ fn main() -> Result<(), String> {
                         .as_bytes(),
                 );
             }
+            if !sighash_idxs.is_empty() {
+                println!("Sign secp256k1 inputs");
+                let input_size = tx_view.inputs().len();
+                let witnesses: Vec<packed::Bytes> = tx_view.witnesses().into_iter().collect();
+                let signature = build_signature(
+                    &tx_view,
+                    input_size,
+                    &sighash_idxs,
+                    &witnesses,
+                    None,
+                    |message: &H256, _tx| {
+                        let message = secp256k1::Message::from_slice(message.as_bytes()).unwrap();
+                        Ok(serialize_signature(
+                            &SECP256K1.sign_recoverable(&message, &privkey),
+                        ))
+                    },
+                )?;
+                tx_receipt.tx.witnesses[sighash_idxs[0]] = json_types::JsonBytes::from_bytes(
+                    packed::WitnessArgs::new_builder()
+                        .lock(Some(signature).pack())
+                        .build()
+                        .as_bytes(),
+                );
+            }
 
             let tx_file = NamedTempFile::new().map_err(|err| err.to_string())?;
             let tx_path_str = tx_file.path().to_str().unwrap();
@@ -557,6 +961,57 @@ fn main() -> Result<(), String> {
             blake2b.finalize(&mut ret);
             println!("0x{}", hex::encode(&ret[0..20]));
         }
+        ("transfer", Some(m)) => {
+            let sender = parse_h160(m.value_of("sender").unwrap())?;
+            let destination = parse_h160(m.value_of("destination").unwrap())?;
+            let value = HumanCapacity::from_str(m.value_of("value").unwrap())?.0;
+            let tx_receipt: TransactionReceipt = polyjuice_rpc(
+                m.value_of("polyjuice-url").unwrap(),
+                "transfer",
+                serde_json::json!([sender, destination, value]),
+            )?;
+            let content = serde_json::to_string_pretty(&tx_receipt).unwrap();
+            if let Some(output) = m.value_of("output") {
+                fs::write(output, content.as_bytes()).map_err(|err| err.to_string())?;
+            } else {
+                println!("{}", content);
+            }
+        }
+        ("deposit", Some(m)) => {
+            let lock_arg = parse_h160(m.value_of("lock-arg").unwrap())?;
+            let destination = parse_h160(m.value_of("destination").unwrap())?;
+            let value = HumanCapacity::from_str(m.value_of("value").unwrap())?.0;
+            let tx_receipt: TransactionReceipt = polyjuice_rpc(
+                m.value_of("polyjuice-url").unwrap(),
+                "deposit",
+                serde_json::json!([lock_arg, destination, value]),
+            )?;
+            let content = serde_json::to_string_pretty(&tx_receipt).unwrap();
+            if let Some(output) = m.value_of("output") {
+                fs::write(output, content.as_bytes()).map_err(|err| err.to_string())?;
+            } else {
+                println!("{}", content);
+            }
+        }
+        ("withdraw", Some(m)) => {
+            let sender = parse_h160(m.value_of("sender").unwrap())?;
+            let lock_arg = parse_h160(m.value_of("lock-arg").unwrap())?;
+            let value = m
+                .value_of("value")
+                .map(|value| HumanCapacity::from_str(value).map(|value| value.0))
+                .transpose()?;
+            let tx_receipt: TransactionReceipt = polyjuice_rpc(
+                m.value_of("polyjuice-url").unwrap(),
+                "withdraw",
+                serde_json::json!([sender, lock_arg, value]),
+            )?;
+            let content = serde_json::to_string_pretty(&tx_receipt).unwrap();
+            if let Some(output) = m.value_of("output") {
+                fs::write(output, content.as_bytes()).map_err(|err| err.to_string())?;
+            } else {
+                println!("{}", content);
+            }
+        }
         ("build-tx", Some(m)) => {
             let signature = m
                 .value_of("signature")
@@ -611,7 +1066,7 @@ fn main() -> Result<(), String> {
 }
 
 pub fn get_live_cell(
-    client: &mut HttpRpcClient,
+    client: &mut dyn ChainSource,
     out_point: json_types::OutPoint,
     with_data: bool,
 ) -> Result<json_types::CellOutput, String> {
@@ -639,6 +1094,54 @@ pub fn serialize_signature(signature: &secp256k1::recovery::RecoverableSignature
     signature_bytes
 }
 
+// Send a request to the polyjuice rpc server
+fn polyjuice_rpc<T: DeserializeOwned>(
+    url: &str,
+    method: &str,
+    params: serde_json::Value,
+) -> Result<T, String> {
+    let request = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 0,
+        "method": method,
+        "params": params,
+    });
+    let output: Output = reqwest::blocking::Client::new()
+        .post(url)
+        .json(&request)
+        .send()
+        .and_then(|resp| resp.json())
+        .map_err(|err| err.to_string())?;
+    match output {
+        Output::Success(success) => {
+            serde_json::from_value(success.result).map_err(|err| err.to_string())
+        }
+        Output::Failure(failure) => Err(format!("polyjuice rpc error: {}", failure.error.message)),
+    }
+}
+
+fn read_config(path: &str) -> Result<RunConfigJson, String> {
+    fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|json_string| {
+            serde_json::from_str(json_string.as_str()).map_err(|err| err.to_string())
+        })
+}
+
+// The RocksDB options of the server config (--config), default if not given
+fn db_options(m: &ArgMatches) -> Result<DbOptions, String> {
+    match m.value_of("config") {
+        Some(path) => read_config(path).map(|config_json| config_json.db_options),
+        None => Ok(DbOptions::default()),
+    }
+}
+
+fn notify_exit(exit: &(Mutex<bool>, Condvar)) {
+    let mut exited = exit.0.lock().expect("locking");
+    *exited = true;
+    exit.1.notify_all();
+}
+
 fn parse_h160(input: &str) -> Result<H160, String> {
     serde_json::from_str(format!("\"{}\"", input).as_str()).map_err(|err| err.to_string())
 }
@@ -665,4 +1168,10 @@ pub struct RunConfigJson {
     // Lock script for EoA account
     pub eoa_lock_dep: json_types::CellDep,
     pub eoa_lock_script: json_types::Script,
+    // Execution limits of a transaction (default derived from CKB limits)
+    #[serde(default)]
+    pub limits: ExecutionLimits,
+    // RocksDB options
+    #[serde(default)]
+    pub db_options: DbOptions,
 }
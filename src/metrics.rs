@@ -0,0 +1,385 @@
+//! Prometheus metrics, exposed in text format by a tiny HTTP server
+
+use crate::storage::Loader;
+use jsonrpc_core::futures::{future::Either, Future};
+use jsonrpc_core::middleware::{Middleware, NoopFuture};
+use jsonrpc_core::{Call, ErrorCode, Metadata, Output};
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static::lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Upper bounds (seconds) of RPC latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+    pub fn inc_by(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, value: u64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters with one label
+#[derive(Default)]
+pub struct CounterVec(Mutex<BTreeMap<String, u64>>);
+
+impl CounterVec {
+    pub fn inc(&self, label: &str) {
+        self.inc_by(label, 1);
+    }
+    pub fn inc_by(&self, label: &str, value: u64) {
+        let mut counters = self.0.lock().expect("lock counters");
+        *counters.entry(label.to_string()).or_default() += value;
+    }
+    fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.0.lock().expect("lock counters").clone()
+    }
+}
+
+#[derive(Default, Clone)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Histograms with one label
+#[derive(Default)]
+pub struct HistogramVec(Mutex<BTreeMap<String, Histogram>>);
+
+impl HistogramVec {
+    pub fn observe(&self, label: &str, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut histograms = self.0.lock().expect("lock histograms");
+        let histogram = histograms.entry(label.to_string()).or_default();
+        for (bucket, upper_bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS.iter()) {
+            if seconds <= *upper_bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+    fn snapshot(&self) -> BTreeMap<String, Histogram> {
+        self.0.lock().expect("lock histograms").clone()
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// label: method
+    pub rpc_requests: CounterVec,
+    /// label: method
+    pub rpc_errors: CounterVec,
+    /// label: method
+    pub rpc_duration: HistogramVec,
+    /// label: kind (create/call/static_call/simulate)
+    pub runner_executions: CounterVec,
+    /// label: kind
+    pub runner_failures: CounterVec,
+    /// label: kind
+    pub runner_cycles: CounterVec,
+    pub indexer_blocks: Counter,
+    pub indexer_transactions: Counter,
+    pub indexer_rollbacks: Counter,
+    pub indexer_tip_number: Gauge,
+    /// label: method
+    pub ckb_rpc_errors: CounterVec,
+    pub verify_discrepancies: Gauge,
+}
+
+impl Metrics {
+    pub fn observe_execution(&self, kind: &str, cycles: u64, success: bool) {
+        self.runner_executions.inc(kind);
+        self.runner_cycles.inc_by(kind, cycles);
+        if !success {
+            self.runner_failures.inc(kind);
+        }
+    }
+
+    /// Render all metrics in Prometheus text exposition format
+    pub fn render(&self, loader: Option<&Loader>) -> String {
+        let mut out = String::new();
+        render_counter_vec(
+            &mut out,
+            "polyjuice_rpc_requests_total",
+            "Total RPC requests",
+            "method",
+            &self.rpc_requests,
+        );
+        render_counter_vec(
+            &mut out,
+            "polyjuice_rpc_errors_total",
+            "Total RPC requests returned error",
+            "method",
+            &self.rpc_errors,
+        );
+        render_histogram_vec(
+            &mut out,
+            "polyjuice_rpc_duration_seconds",
+            "RPC request latency",
+            "method",
+            &self.rpc_duration,
+        );
+        render_counter_vec(
+            &mut out,
+            "polyjuice_runner_executions_total",
+            "Total contract executions",
+            "kind",
+            &self.runner_executions,
+        );
+        render_counter_vec(
+            &mut out,
+            "polyjuice_runner_failures_total",
+            "Total failed contract executions",
+            "kind",
+            &self.runner_failures,
+        );
+        render_counter_vec(
+            &mut out,
+            "polyjuice_runner_cycles_total",
+            "Total cycles consumed by contract executions",
+            "kind",
+            &self.runner_cycles,
+        );
+        render_single(
+            &mut out,
+            "polyjuice_indexer_blocks_total",
+            "Total blocks indexed",
+            "counter",
+            self.indexer_blocks.get(),
+        );
+        render_single(
+            &mut out,
+            "polyjuice_indexer_transactions_total",
+            "Total transactions indexed",
+            "counter",
+            self.indexer_transactions.get(),
+        );
+        render_single(
+            &mut out,
+            "polyjuice_indexer_rollbacks_total",
+            "Total blocks rolled back",
+            "counter",
+            self.indexer_rollbacks.get(),
+        );
+        render_single(
+            &mut out,
+            "polyjuice_indexer_tip_number",
+            "The last indexed block number",
+            "gauge",
+            self.indexer_tip_number.get(),
+        );
+        render_counter_vec(
+            &mut out,
+            "polyjuice_ckb_rpc_errors_total",
+            "Total errors of requests to CKB node",
+            "method",
+            &self.ckb_rpc_errors,
+        );
+        render_single(
+            &mut out,
+            "polyjuice_verify_discrepancies",
+            "Discrepancies found by the last index verification",
+            "gauge",
+            self.verify_discrepancies.get(),
+        );
+        if let Some(loader) = loader {
+            match loader.load_db_stats() {
+                Ok(stats) => {
+                    let properties = [
+                        ("estimate_num_keys", stats.estimate_num_keys),
+                        ("estimate_live_data_size", stats.estimate_live_data_size),
+                        ("total_sst_files_size", stats.total_sst_files_size),
+                        ("cur_size_all_mem_tables", stats.cur_size_all_mem_tables),
+                    ];
+                    for (name, value_opt) in properties.iter() {
+                        if let Some(value) = value_opt {
+                            render_single(
+                                &mut out,
+                                &format!("polyjuice_rocksdb_{}", name),
+                                &format!("RocksDB property rocksdb.{}", name.replace('_', "-")),
+                                "gauge",
+                                *value,
+                            );
+                        }
+                    }
+                }
+                Err(err) => log::warn!("Load RocksDB stats error: {}", err),
+            }
+        }
+        out
+    }
+}
+
+fn render_single(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn render_counter_vec(out: &mut String, name: &str, help: &str, label: &str, vec: &CounterVec) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (label_value, value) in vec.snapshot() {
+        let _ = writeln!(
+            out,
+            "{}{{{}=\"{}\"}} {}",
+            name,
+            label,
+            escape_label(&label_value),
+            value
+        );
+    }
+}
+
+fn render_histogram_vec(out: &mut String, name: &str, help: &str, label: &str, vec: &HistogramVec) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (label_value, histogram) in vec.snapshot() {
+        let label_value = escape_label(&label_value);
+        for (count, upper_bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}=\"{}\",le=\"{}\"}} {}",
+                name, label, label_value, upper_bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}=\"{}\",le=\"+Inf\"}} {}",
+            name, label, label_value, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}=\"{}\"}} {}",
+            name, label, label_value, histogram.sum
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{{{}=\"{}\"}} {}",
+            name, label, label_value, histogram.count
+        );
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Record request count, errors and latency of every RPC method
+#[derive(Debug, Default, Clone)]
+pub struct RpcMetricsMiddleware;
+
+impl<M: Metadata> Middleware<M> for RpcMetricsMiddleware {
+    type Future = NoopFuture;
+    type CallFuture = Box<dyn Future<Item = Option<Output>, Error = ()> + Send>;
+
+    fn on_call<F, X>(&self, call: Call, meta: M, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, M) -> X + Send + Sync,
+        X: Future<Item = Option<Output>, Error = ()> + Send + 'static,
+    {
+        let method = match call {
+            Call::MethodCall(ref method_call) => method_call.method.clone(),
+            _ => return Either::B(next(call, meta)),
+        };
+        let start = Instant::now();
+        Either::A(Box::new(next(call, meta).map(move |output| {
+            let (method, is_error) = match output {
+                // Avoid unbounded label values from unknown methods
+                Some(Output::Failure(ref failure))
+                    if failure.error.code == ErrorCode::MethodNotFound =>
+                {
+                    (String::from("unknown"), true)
+                }
+                Some(Output::Failure(_)) => (method, true),
+                _ => (method, false),
+            };
+            METRICS.rpc_requests.inc(&method);
+            if is_error {
+                METRICS.rpc_errors.inc(&method);
+            }
+            METRICS.rpc_duration.observe(&method, start.elapsed());
+            output
+        })))
+    }
+}
+
+/// Serve `GET /metrics` on `listen_addr` in a background thread
+pub fn start_server(listen_addr: &str, loader: Arc<Loader>) -> Result<(), String> {
+    let listener = TcpListener::bind(listen_addr).map_err(|err| err.to_string())?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = handle_connection(stream, &loader) {
+                        log::debug!("Metrics connection error: {}", err);
+                    }
+                }
+                Err(err) => log::warn!("Metrics server accept error: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_connection(mut stream: TcpStream, loader: &Loader) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip the headers
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", METRICS.render(Some(loader))),
+        _ => ("404 Not Found", String::from("Not Found\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,438 @@
+//! An in-memory CKB chain for tests. Blocks are produced from the given
+//! transactions directly, scripts are NOT verified.
+
+use ckb_chain_spec::consensus::TYPE_ID_CODE_HASH;
+use ckb_hash::new_blake2b;
+use ckb_jsonrpc_types::{
+    BlockView, CellData, CellInfo, CellWithStatus, ChainInfo, EpochView, HeaderView, JsonBytes,
+    OutPoint, Status, TransactionWithStatus, TxStatus,
+};
+use ckb_types::{
+    bytes::Bytes,
+    core::{self, DepType, ScriptHashType},
+    h256, packed,
+    prelude::*,
+    H160, H256, U256,
+};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::client::{ChainSource, ClientError};
+use crate::storage::{EventSink, Indexer, IndexerEvent};
+use crate::types::RunConfig;
+
+pub const EPOCH_LENGTH: u64 = 1000;
+
+#[derive(Default)]
+struct ChainState {
+    /// The main chain, indexed by block number
+    blocks: Vec<core::BlockView>,
+    /// All produced blocks, include the orphaned ones
+    all_blocks: HashMap<H256, core::BlockView>,
+    /// Committed transactions => block hash
+    transactions: HashMap<H256, (core::TransactionView, H256)>,
+    live_cells: HashMap<packed::OutPoint, (packed::CellOutput, Bytes)>,
+    /// Make the blocks of a new fork differ from the orphaned ones
+    forks: u64,
+    /// Simulate a CKB node outage (get_header_by_number and get_block fail)
+    unreachable: bool,
+}
+
+impl ChainState {
+    fn attach(&mut self, block: core::BlockView) {
+        let block_hash: H256 = block.hash().unpack();
+        for tx in block.transactions() {
+            for out_point in tx.input_pts_iter() {
+                if !out_point.is_null() {
+                    self.live_cells.remove(&out_point);
+                }
+            }
+            for (index, (output, data)) in tx.outputs_with_data_iter().enumerate() {
+                let out_point = packed::OutPoint::new(tx.hash(), index as u32);
+                self.live_cells.insert(out_point, (output, data));
+            }
+            self.transactions
+                .insert(tx.hash().unpack(), (tx, block_hash.clone()));
+        }
+        self.all_blocks.insert(block_hash, block.clone());
+        self.blocks.push(block);
+    }
+
+    fn detach(&mut self) -> Option<core::BlockView> {
+        let block = self.blocks.pop()?;
+        for tx in block.transactions().into_iter().rev() {
+            for out_point in tx.output_pts() {
+                self.live_cells.remove(&out_point);
+            }
+            for out_point in tx.input_pts_iter() {
+                let prev_tx_hash: H256 = out_point.tx_hash().unpack();
+                let index: u32 = out_point.index().unpack();
+                if let Some(cell) = self
+                    .transactions
+                    .get(&prev_tx_hash)
+                    .and_then(|(prev_tx, _)| prev_tx.output_with_data(index as usize))
+                {
+                    self.live_cells.insert(out_point, cell);
+                }
+            }
+            self.transactions.remove(&tx.hash().unpack());
+        }
+        Some(block)
+    }
+}
+
+/// Cloned chains share the same state
+#[derive(Clone)]
+pub struct MockChain {
+    state: Arc<Mutex<ChainState>>,
+}
+
+impl MockChain {
+    /// Create the chain with a genesis block contains the transactions
+    pub fn new(genesis_txs: Vec<core::TransactionView>) -> MockChain {
+        let chain = MockChain {
+            state: Default::default(),
+        };
+        chain.produce_block(genesis_txs);
+        chain
+    }
+
+    /// Commit the transactions in a new block on top of the tip
+    pub fn produce_block(&self, txs: Vec<core::TransactionView>) -> core::BlockView {
+        let mut state = self.state.lock().expect("lock chain");
+        let number = state.blocks.len() as u64;
+        let parent_hash = state
+            .blocks
+            .last()
+            .map(|block| block.hash())
+            .unwrap_or_default();
+        let epoch = core::EpochNumberWithFraction::new(
+            number / EPOCH_LENGTH,
+            number % EPOCH_LENGTH,
+            EPOCH_LENGTH,
+        );
+        let block = core::BlockBuilder::default()
+            .parent_hash(parent_hash)
+            .number(number.pack())
+            .epoch(epoch.full_value().pack())
+            .timestamp((1_600_000_000_000 + number * 1000).pack())
+            .nonce(u128::from(state.forks).pack())
+            .transactions(txs)
+            .build();
+        state.attach(block.clone());
+        block
+    }
+
+    /// Detach the blocks after `number`, following blocks are produced on a new fork
+    pub fn rollback(&self, number: u64) {
+        let mut state = self.state.lock().expect("lock chain");
+        while state.blocks.len() as u64 > number + 1 {
+            state.detach();
+        }
+        state.forks += 1;
+    }
+
+    pub fn tip(&self) -> core::BlockView {
+        let state = self.state.lock().expect("lock chain");
+        state.blocks.last().cloned().expect("genesis block")
+    }
+
+    pub fn set_unreachable(&self, unreachable: bool) {
+        self.state.lock().expect("lock chain").unreachable = unreachable;
+    }
+
+    fn check_reachable(&self) -> Result<(), ClientError> {
+        if self.with_state(|state| state.unreachable) {
+            Err(ClientError::Unreachable(String::from("mock chain is down")))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn with_state<T, F: FnOnce(&ChainState) -> T>(&self, f: F) -> T {
+        f(&self.state.lock().expect("lock chain"))
+    }
+}
+
+impl ChainSource for MockChain {
+    fn clone_box(&self) -> Box<dyn ChainSource> {
+        Box::new(self.clone())
+    }
+
+    fn get_block(&mut self, hash: H256) -> Result<Option<BlockView>, ClientError> {
+        self.check_reachable()?;
+        Ok(self.with_state(|state| state.all_blocks.get(&hash).cloned().map(Into::into)))
+    }
+    fn get_epoch_by_number(&mut self, number: u64) -> Result<Option<EpochView>, ClientError> {
+        let tip_number = self.tip().number();
+        if number > tip_number / EPOCH_LENGTH {
+            return Ok(None);
+        }
+        Ok(Some(EpochView {
+            number: number.into(),
+            start_number: (number * EPOCH_LENGTH).into(),
+            length: EPOCH_LENGTH.into(),
+            compact_target: 0u32.into(),
+        }))
+    }
+    fn get_header(&mut self, hash: H256) -> Result<Option<HeaderView>, ClientError> {
+        Ok(self.with_state(|state| {
+            state
+                .all_blocks
+                .get(&hash)
+                .map(|block| block.header().into())
+        }))
+    }
+    fn get_header_by_number(&mut self, number: u64) -> Result<Option<HeaderView>, ClientError> {
+        self.check_reachable()?;
+        Ok(self.with_state(|state| {
+            state
+                .blocks
+                .get(number as usize)
+                .map(|block| block.header().into())
+        }))
+    }
+    fn get_live_cell(
+        &mut self,
+        out_point: OutPoint,
+        with_data: bool,
+    ) -> Result<CellWithStatus, ClientError> {
+        let out_point = packed::OutPoint::from(out_point);
+        Ok(self.with_state(|state| {
+            if let Some((output, data)) = state.live_cells.get(&out_point) {
+                let data = if with_data {
+                    Some(CellData {
+                        hash: packed::CellOutput::calc_data_hash(data).unpack(),
+                        content: JsonBytes::from_bytes(data.clone()),
+                    })
+                } else {
+                    None
+                };
+                CellWithStatus {
+                    cell: Some(CellInfo {
+                        output: output.clone().into(),
+                        data,
+                    }),
+                    status: String::from("live"),
+                }
+            } else {
+                let tx_hash: H256 = out_point.tx_hash().unpack();
+                let status = if state.transactions.contains_key(&tx_hash) {
+                    "dead"
+                } else {
+                    "unknown"
+                };
+                CellWithStatus {
+                    cell: None,
+                    status: status.to_string(),
+                }
+            }
+        }))
+    }
+    fn get_tip_block_number(&mut self) -> Result<u64, ClientError> {
+        Ok(self.tip().number())
+    }
+    fn get_tip_header(&mut self) -> Result<HeaderView, ClientError> {
+        Ok(self.tip().header().into())
+    }
+    fn get_transaction(
+        &mut self,
+        hash: H256,
+    ) -> Result<Option<TransactionWithStatus>, ClientError> {
+        Ok(self.with_state(|state| {
+            state
+                .transactions
+                .get(&hash)
+                .map(|(tx, block_hash)| TransactionWithStatus {
+                    transaction: tx.clone().into(),
+                    tx_status: TxStatus {
+                        status: Status::Committed,
+                        block_hash: Some(block_hash.clone()),
+                    },
+                })
+        }))
+    }
+    fn get_blockchain_info(&mut self) -> Result<ChainInfo, ClientError> {
+        let tip = self.tip();
+        Ok(ChainInfo {
+            chain: String::from("ckb_dev"),
+            median_time: tip.timestamp().into(),
+            epoch: tip.epoch().full_value().into(),
+            difficulty: U256::zero(),
+            is_initial_block_download: false,
+            alerts: Vec::new(),
+        })
+    }
+}
+
+/// Scripts and deps used by the tests, the deps are not on the mock chain
+pub fn test_run_config(generator: Bytes) -> RunConfig {
+    let dep = |index: u32| {
+        let out_point = packed::OutPoint::new(
+            h256!("0x2222222222222222222222222222222222222222222222222222222222222222").pack(),
+            index,
+        );
+        packed::CellDep::new_builder()
+            .out_point(out_point)
+            .dep_type(DepType::Code.into())
+            .build()
+    };
+    let script = |code_hash: H256, hash_type: ScriptHashType| {
+        packed::Script::new_builder()
+            .code_hash(code_hash.pack())
+            .hash_type(hash_type.into())
+            .build()
+    };
+    RunConfig {
+        generator,
+        type_dep: dep(0),
+        type_script: script(
+            h256!("0x3333333333333333333333333333333333333333333333333333333333333333"),
+            ScriptHashType::Data,
+        ),
+        lock_dep: dep(1),
+        lock_script: script(
+            h256!("0x4444444444444444444444444444444444444444444444444444444444444444"),
+            ScriptHashType::Data,
+        ),
+        eoa_lock_dep: dep(2),
+        eoa_lock_script: script(
+            h256!("0x5555555555555555555555555555555555555555555555555555555555555555"),
+            ScriptHashType::Type,
+        ),
+        limits: Default::default(),
+    }
+}
+
+/// An EoA cell owned by `lock_arg`, return the cell output and the EoA address
+pub fn eoa_output(
+    run_config: &RunConfig,
+    lock_arg: &H160,
+    type_id: &H256,
+    capacity: u64,
+) -> (packed::CellOutput, H160) {
+    let type_script = packed::Script::new_builder()
+        .code_hash(TYPE_ID_CODE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::from(type_id.as_bytes().to_vec()).pack())
+        .build();
+    let lock_script = run_config
+        .eoa_lock_script
+        .clone()
+        .as_builder()
+        .args(Bytes::from(lock_arg.as_bytes().to_vec()).pack())
+        .build();
+    let mut blake2b = new_blake2b();
+    blake2b.update(type_id.as_bytes());
+    blake2b.update(lock_arg.as_bytes());
+    let mut hash = [0u8; 32];
+    blake2b.finalize(&mut hash);
+    let address = H160::from_slice(&hash[0..20]).expect("convert to h160");
+    let output = packed::CellOutput::new_builder()
+        .capacity(capacity.pack())
+        .lock(lock_script)
+        .type_(Some(type_script).pack())
+        .build();
+    (output, address)
+}
+
+/// Stop the indexer once the block is indexed
+struct StopAt {
+    number: u64,
+    stop: Arc<AtomicBool>,
+}
+
+impl EventSink for StopAt {
+    fn id(&self) -> Option<String> {
+        None
+    }
+
+    fn send(&mut self, event: &IndexerEvent) -> Result<(), String> {
+        if let IndexerEvent::Block(block) = event {
+            if block.header.inner.number.value() >= self.number {
+                self.stop.store(true, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the indexer until block `number` is indexed (the block must exist)
+pub fn index_until(indexer: &mut Indexer, number: u64) {
+    let stop = Arc::new(AtomicBool::new(false));
+    indexer.sinks.push(Box::new(StopAt {
+        number,
+        stop: Arc::clone(&stop),
+    }));
+    let result = indexer.index(&stop);
+    indexer.sinks.pop();
+    result.expect("index blocks");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn transfer(inputs: Vec<packed::OutPoint>, capacities: &[u64]) -> core::TransactionView {
+        let run_config = test_run_config(Bytes::default());
+        let outputs = capacities
+            .iter()
+            .map(|capacity| {
+                eoa_output(&run_config, &H160::default(), &H256::default(), *capacity).0
+            })
+            .collect::<Vec<_>>();
+        core::TransactionBuilder::default()
+            .inputs(
+                inputs
+                    .into_iter()
+                    .map(|out_point| packed::CellInput::new(out_point, 0)),
+            )
+            .outputs_data(outputs.iter().map(|_| Bytes::default().pack()))
+            .outputs(outputs)
+            .build()
+    }
+
+    #[test]
+    fn test_mock_chain_live_cells() {
+        let genesis_tx = transfer(Vec::new(), &[1000]);
+        let mut chain = MockChain::new(vec![genesis_tx.clone()]);
+        let genesis_cell = packed::OutPoint::new(genesis_tx.hash(), 0);
+        let tx = transfer(vec![genesis_cell.clone()], &[600, 300]);
+        let block = chain.produce_block(vec![tx.clone()]);
+        assert_eq!(chain.get_tip_block_number().unwrap(), 1);
+        assert_eq!(
+            chain
+                .get_live_cell(genesis_cell.clone().into(), false)
+                .unwrap()
+                .status,
+            "dead"
+        );
+        let cell = chain
+            .get_live_cell(packed::OutPoint::new(tx.hash(), 1).into(), true)
+            .unwrap();
+        assert_eq!(cell.status, "live");
+        assert_eq!(cell.cell.unwrap().output.capacity.value(), 300);
+        let tx_status = chain
+            .get_transaction(tx.hash().unpack())
+            .unwrap()
+            .unwrap()
+            .tx_status;
+        assert_eq!(tx_status.block_hash, Some(block.hash().unpack()));
+
+        chain.rollback(0);
+        assert_eq!(chain.get_tip_block_number().unwrap(), 0);
+        assert_eq!(
+            chain
+                .get_live_cell(genesis_cell.into(), false)
+                .unwrap()
+                .status,
+            "live"
+        );
+        assert!(chain.get_transaction(tx.hash().unpack()).unwrap().is_none());
+        // The orphaned block is still available by hash
+        assert!(chain.get_block(block.hash().unpack()).unwrap().is_some());
+        let fork_block = chain.produce_block(vec![tx]);
+        assert_ne!(fork_block.hash(), block.hash());
+    }
+}
@@ -0,0 +1,396 @@
+use ckb_jsonrpc_types::JsonBytes;
+use ckb_types::H256;
+use jsonrpc_core::futures::Future;
+use jsonrpc_core::Result as RpcResult;
+use jsonrpc_derive::rpc;
+use jsonrpc_pubsub::typed::{Sink, Subscriber};
+use jsonrpc_pubsub::{Session, SubscriptionId};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use crate::server::ContractChangeJson;
+use crate::storage::{BlockEvent, EventSink, IndexerEvent, RollbackEvent};
+use crate::types::{ContractAddress, ContractChange};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    /// Header of every indexed block
+    NewHeads,
+    /// Logs match the filter
+    Logs,
+    /// Contract changes of the watched contracts
+    ContractChanges,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionFilter {
+    /// Only notify logs/changes of these contracts (all contracts if null)
+    pub addresses: Option<Vec<ContractAddress>>,
+    /// Only notify logs contain any of these topics (all logs if null)
+    pub topics: Option<Vec<H256>>,
+}
+
+#[rpc(server)]
+pub trait SubscriptionRpc {
+    type Metadata;
+
+    #[pubsub(subscription = "subscription", subscribe, name = "subscribe")]
+    fn subscribe(
+        &self,
+        meta: Self::Metadata,
+        subscriber: Subscriber<Value>,
+        topic: Topic,
+        filter: Option<SubscriptionFilter>,
+    );
+
+    #[pubsub(subscription = "subscription", unsubscribe, name = "unsubscribe")]
+    fn unsubscribe(&self, meta: Option<Self::Metadata>, id: SubscriptionId) -> RpcResult<bool>;
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct LogNotification {
+    pub block_number: u64,
+    pub block_hash: H256,
+    pub tx_index: u32,
+    pub tx_hash: H256,
+    pub address: ContractAddress,
+    pub topics: Vec<H256>,
+    pub data: JsonBytes,
+    /// The log is in an orphaned block
+    pub removed: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ContractChangeNotification {
+    pub block_hash: H256,
+    pub change: ContractChangeJson,
+    /// The change is in an orphaned block
+    pub removed: bool,
+}
+
+/// Notifications queued for a subscriber at most, a subscriber lags behind
+/// more than this is dropped
+pub const MAX_QUEUED_NOTIFICATIONS: usize = 1024;
+
+struct Subscription {
+    topic: Topic,
+    addresses: Option<HashSet<ContractAddress>>,
+    topics: Option<HashSet<H256>>,
+    /// Drained to the WebSocket client by the subscription's own thread, so a
+    /// slow client never blocks the indexer
+    sender: SyncSender<Value>,
+}
+
+impl Subscription {
+    fn match_address(&self, address: &ContractAddress) -> bool {
+        self.addresses
+            .as_ref()
+            .map(|addresses| addresses.contains(address))
+            .unwrap_or(true)
+    }
+
+    fn match_topics(&self, topics: &[H256]) -> bool {
+        self.topics
+            .as_ref()
+            .map(|filter_topics| topics.iter().any(|topic| filter_topics.contains(topic)))
+            .unwrap_or(true)
+    }
+}
+
+/// All active subscriptions, notified by the indexer
+pub struct Subscriptions {
+    next_id: AtomicUsize,
+    max_queued: usize,
+    subscriptions: RwLock<HashMap<SubscriptionId, Subscription>>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Subscriptions {
+        Subscriptions::new(MAX_QUEUED_NOTIFICATIONS)
+    }
+}
+
+impl Subscriptions {
+    pub fn new(max_queued: usize) -> Subscriptions {
+        Subscriptions {
+            next_id: AtomicUsize::new(0),
+            max_queued,
+            subscriptions: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn notify_block(&self, event: &BlockEvent) {
+        let block_hash = &event.header.hash;
+        self.notify(|subscription| match subscription.topic {
+            Topic::NewHeads => vec![serde_json::to_value(&event.header)],
+            Topic::Logs => log_notifications(subscription, block_hash, &event.changes, false),
+            Topic::ContractChanges => {
+                change_notifications(subscription, block_hash, &event.changes, false)
+            }
+        });
+    }
+
+    pub fn notify_rollback(&self, event: &RollbackEvent) {
+        self.notify(|subscription| match subscription.topic {
+            Topic::NewHeads => Vec::new(),
+            Topic::Logs => log_notifications(subscription, &event.hash, &event.changes, true),
+            Topic::ContractChanges => {
+                change_notifications(subscription, &event.hash, &event.changes, true)
+            }
+        });
+    }
+
+    fn notify<F>(&self, messages: F)
+    where
+        F: Fn(&Subscription) -> Vec<Result<Value, serde_json::Error>>,
+    {
+        let mut closed = Vec::new();
+        {
+            let subscriptions = self.subscriptions.read().expect("read subscriptions");
+            for (id, subscription) in subscriptions.iter() {
+                for message in messages(subscription) {
+                    let message = match message {
+                        Ok(message) => message,
+                        Err(err) => {
+                            log::error!("Serialize notification for {:?} failed: {}", id, err);
+                            continue;
+                        }
+                    };
+                    match subscription.sender.try_send(message) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            log::warn!("Subscription {:?} lags behind, dropped", id);
+                            closed.push(id.clone());
+                            break;
+                        }
+                        Err(TrySendError::Disconnected(_)) => {
+                            log::debug!("Subscription {:?} closed", id);
+                            closed.push(id.clone());
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        if !closed.is_empty() {
+            let mut subscriptions = self.subscriptions.write().expect("write subscriptions");
+            for id in closed {
+                subscriptions.remove(&id);
+            }
+        }
+    }
+}
+
+// Subscriptions are best-effort, the closed and lagging ones are removed
+impl EventSink for Arc<Subscriptions> {
+    fn id(&self) -> Option<String> {
+        None
+    }
+
+    fn send(&mut self, event: &IndexerEvent) -> Result<(), String> {
+        match event {
+            IndexerEvent::Block(block) => self.notify_block(block),
+            IndexerEvent::Rollback(rollback) => self.notify_rollback(rollback),
+        }
+        Ok(())
+    }
+}
+
+fn log_notifications(
+    subscription: &Subscription,
+    block_hash: &H256,
+    changes: &[ContractChange],
+    removed: bool,
+) -> Vec<Result<Value, serde_json::Error>> {
+    changes
+        .iter()
+        .filter(|change| subscription.match_address(&change.address))
+        .flat_map(|change| {
+            change
+                .logs
+                .iter()
+                .filter(|(topics, _)| subscription.match_topics(topics))
+                .map(move |(topics, data)| LogNotification {
+                    block_number: change.number,
+                    block_hash: block_hash.clone(),
+                    tx_index: change.tx_index,
+                    tx_hash: change.tx_hash.clone(),
+                    address: change.address.clone(),
+                    topics: topics.clone(),
+                    data: JsonBytes::from_bytes(data.clone()),
+                    removed,
+                })
+        })
+        .map(|notification| serde_json::to_value(&notification))
+        .collect()
+}
+
+fn change_notifications(
+    subscription: &Subscription,
+    block_hash: &H256,
+    changes: &[ContractChange],
+    removed: bool,
+) -> Vec<Result<Value, serde_json::Error>> {
+    changes
+        .iter()
+        .filter(|change| subscription.match_address(&change.address))
+        .map(|change| {
+            serde_json::to_value(&ContractChangeNotification {
+                block_hash: block_hash.clone(),
+                change: ContractChangeJson::from(change.clone()),
+                removed,
+            })
+        })
+        .collect()
+}
+
+// Stops when the client is gone or the subscription is removed
+fn spawn_sender(id: SubscriptionId, sink: Sink<Value>, receiver: Receiver<Value>) {
+    thread::spawn(move || {
+        for message in receiver {
+            if let Err(err) = sink.notify(Ok(message)).wait() {
+                log::debug!("Notify subscription {:?} failed: {}", id, err);
+                break;
+            }
+        }
+    });
+}
+
+pub struct SubscriptionRpcImpl {
+    pub subscriptions: Arc<Subscriptions>,
+}
+
+impl SubscriptionRpc for SubscriptionRpcImpl {
+    type Metadata = Option<Arc<Session>>;
+
+    fn subscribe(
+        &self,
+        _meta: Self::Metadata,
+        subscriber: Subscriber<Value>,
+        topic: Topic,
+        filter: Option<SubscriptionFilter>,
+    ) {
+        let filter = filter.unwrap_or_default();
+        let id = self.subscriptions.next_id.fetch_add(1, Ordering::SeqCst);
+        let sub_id = SubscriptionId::String(format!("{:#x}", id));
+        if let Ok(sink) = subscriber.assign_id(sub_id.clone()) {
+            let (sender, receiver) = sync_channel(self.subscriptions.max_queued);
+            spawn_sender(sub_id.clone(), sink, receiver);
+            let subscription = Subscription {
+                topic,
+                addresses: filter
+                    .addresses
+                    .map(|addresses| addresses.into_iter().collect()),
+                topics: filter.topics.map(|topics| topics.into_iter().collect()),
+                sender,
+            };
+            self.subscriptions
+                .subscriptions
+                .write()
+                .expect("write subscriptions")
+                .insert(sub_id, subscription);
+        }
+    }
+
+    fn unsubscribe(&self, _meta: Option<Self::Metadata>, id: SubscriptionId) -> RpcResult<bool> {
+        Ok(self
+            .subscriptions
+            .subscriptions
+            .write()
+            .expect("write subscriptions")
+            .remove(&id)
+            .is_some())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ckb_types::{bytes::Bytes, h160, h256};
+    use jsonrpc_core::futures::{sync::mpsc, Stream};
+    use jsonrpc_pubsub::oneshot;
+
+    type IdReceiver = oneshot::Receiver<Result<SubscriptionId, jsonrpc_core::Error>>;
+
+    fn subscribe(
+        rpc: &SubscriptionRpcImpl,
+        topic: Topic,
+        filter: Option<SubscriptionFilter>,
+    ) -> (IdReceiver, mpsc::Receiver<String>) {
+        let (subscriber, id, receiver) = Subscriber::new_test("subscription");
+        rpc.subscribe(None, subscriber, topic, filter);
+        (id, receiver)
+    }
+
+    fn rollback_with_log(address: ContractAddress, data: u8) -> RollbackEvent {
+        let change = ContractChange {
+            address,
+            logs: vec![(vec![h256!("0x1")], Bytes::from(vec![data]))],
+            ..Default::default()
+        };
+        RollbackEvent {
+            number: 1,
+            hash: h256!("0x2"),
+            changes: vec![change],
+        }
+    }
+
+    fn next_result(receiver: mpsc::Receiver<String>) -> (Value, mpsc::Receiver<String>) {
+        let (message, receiver) = receiver.into_future().wait().ok().unwrap();
+        let value: Value = serde_json::from_str(&message.unwrap()).unwrap();
+        (value["params"]["result"].clone(), receiver)
+    }
+
+    #[test]
+    fn test_notify_filtered_logs() {
+        let rpc = SubscriptionRpcImpl {
+            subscriptions: Arc::new(Subscriptions::default()),
+        };
+        let watched = ContractAddress(h160!("0x1"));
+        let filter = SubscriptionFilter {
+            addresses: Some(vec![watched.clone()]),
+            topics: None,
+        };
+        let (_id, receiver) = subscribe(&rpc, Topic::Logs, Some(filter));
+
+        rpc.subscriptions
+            .notify_rollback(&rollback_with_log(ContractAddress(h160!("0x2")), 1));
+        rpc.subscriptions
+            .notify_rollback(&rollback_with_log(watched, 2));
+        let (result, _receiver) = next_result(receiver);
+        assert_eq!(result["data"], "0x02");
+        assert_eq!(result["removed"], true);
+
+        let id = SubscriptionId::String("0x0".to_string());
+        assert_eq!(rpc.unsubscribe(None, id.clone()), Ok(true));
+        assert_eq!(rpc.unsubscribe(None, id), Ok(false));
+    }
+
+    #[test]
+    fn test_drop_lagging_subscriber() {
+        let rpc = SubscriptionRpcImpl {
+            subscriptions: Arc::new(Subscriptions::new(2)),
+        };
+        // Never reads its notifications
+        let (_lagging_id, _lagging_receiver) = subscribe(&rpc, Topic::Logs, None);
+        let (_id, mut receiver) = subscribe(&rpc, Topic::Logs, None);
+
+        for data in 0..10 {
+            let address = ContractAddress(h160!("0x1"));
+            rpc.subscriptions
+                .notify_rollback(&rollback_with_log(address, data));
+            let (result, next) = next_result(receiver);
+            assert_eq!(result["data"], format!("0x{:02x}", data));
+            receiver = next;
+        }
+        let subscriptions = rpc.subscriptions.subscriptions.read().unwrap();
+        assert_eq!(subscriptions.len(), 1);
+        assert!(subscriptions.contains_key(&SubscriptionId::String("0x1".to_string())));
+    }
+}
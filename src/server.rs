@@ -1,11 +1,17 @@
-use crate::storage::{CsalRunContext, Loader, Runner};
-use crate::types::{ContractAddress, ContractChange, ContractMeta, EoaAddress, RunConfig};
+use crate::storage::{
+    replay_transaction, CallFrame, CsalRunContext, DbStats, IndexerState, IndexerStatus, KeyType,
+    Loader, Runner, SharedIndexerStatus, StateOverride,
+};
+use crate::types::{
+    ContractAddress, ContractChange, ContractMeta, EoaAddress, LimitError, RunConfig,
+};
 use ckb_hash::blake2b_256;
 use ckb_jsonrpc_types::{JsonBytes, Transaction};
-use ckb_types::{bytes::Bytes, prelude::*, H160, H256};
+use ckb_types::{bytes::Bytes, packed, prelude::*, H160, H256};
 use jsonrpc_core::{Error, ErrorCode, Result as RpcResult};
 use jsonrpc_derive::rpc;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::error::Error as StdError;
 use std::sync::Arc;
@@ -24,14 +30,73 @@ pub trait Rpc {
         value: u64,
     ) -> RpcResult<TransactionReceipt>;
 
+    /// Transfer value from an EoA account to another EoA account, the receipt
+    /// is signed by `sign-tx` like create/call
+    #[rpc(name = "transfer")]
+    fn transfer(
+        &self,
+        sender: H160,
+        destination: H160,
+        value: u64,
+    ) -> RpcResult<TransactionReceipt>;
+
+    /// Deposit value from the secp256k1 (sighash) cells of `lock_arg` to an
+    /// EoA account, the change goes back to `lock_arg`
+    #[rpc(name = "deposit")]
+    fn deposit(
+        &self,
+        lock_arg: H160,
+        destination: H160,
+        value: u64,
+    ) -> RpcResult<TransactionReceipt>;
+
+    /// Withdraw value from an EoA account to a secp256k1 (sighash) cell of
+    /// `lock_arg`, withdraw all and close the account if `value` is null
+    #[rpc(name = "withdraw")]
+    fn withdraw(
+        &self,
+        sender: H160,
+        lock_arg: H160,
+        value: Option<u64>,
+    ) -> RpcResult<TransactionReceipt>;
+
+    /// The sender may not exist, `state_overrides` replaces the code, balance
+    /// or storage slots of the accounts before execution
     #[rpc(name = "static_call")]
     fn static_call(
         &self,
         sender: H160,
         contract_address: ContractAddress,
         input: JsonBytes,
+        state_overrides: Option<HashMap<H160, StateOverrideJson>>,
     ) -> RpcResult<StaticCallResponse>;
 
+    /// Run a create (contract_address is null, input is the code) or a call
+    /// and return all the effects, no transaction is built
+    #[rpc(name = "simulate")]
+    fn simulate(
+        &self,
+        sender: H160,
+        contract_address: Option<ContractAddress>,
+        input: JsonBytes,
+        value: u64,
+    ) -> RpcResult<SimulateResponse>;
+
+    /// Run a create (contract_address is null, input is the code) or a call
+    /// and return the call tree, the state is not changed
+    #[rpc(name = "trace_call")]
+    fn trace_call(
+        &self,
+        sender: H160,
+        contract_address: Option<ContractAddress>,
+        input: JsonBytes,
+        value: u64,
+    ) -> RpcResult<CallFrame>;
+
+    /// Replay a committed transaction from its witnesses and return the call tree
+    #[rpc(name = "debug_trace_transaction")]
+    fn debug_trace_transaction(&self, tx_hash: H256) -> RpcResult<CallFrame>;
+
     #[rpc(name = "get_code")]
     fn get_code(&self, contract_address: ContractAddress) -> RpcResult<ContractCodeJson>;
 
@@ -49,6 +114,17 @@ pub trait Rpc {
         block_number: Option<u64>,
     ) -> RpcResult<ContractChangeJson>;
 
+    /// Every state transition of a contract, with storage diff to previous one
+    #[rpc(name = "get_contract_history")]
+    fn get_contract_history(
+        &self,
+        contract_address: ContractAddress,
+        from_block: u64,
+        to_block: Option<u64>,
+        cursor: Option<ContractChangeCursor>,
+        limit: Option<u32>,
+    ) -> RpcResult<ContractHistoryJson>;
+
     #[rpc(name = "get_logs")]
     fn get_logs(
         &self,
@@ -61,11 +137,60 @@ pub trait Rpc {
 
     #[rpc(name = "get_balance")]
     fn get_balance(&self, address: H160) -> RpcResult<u64>;
+
+    /// Contract calls sent by the account and balance changes of its EoA cell
+    #[rpc(name = "get_account_transactions")]
+    fn get_account_transactions(
+        &self,
+        address: H160,
+        from_block: u64,
+        to_block: Option<u64>,
+        cursor: Option<AccountTxCursor>,
+        limit: Option<u32>,
+    ) -> RpcResult<AccountTransactionsJson>;
+
+    #[rpc(name = "get_indexer_status")]
+    fn get_indexer_status(&self) -> RpcResult<IndexerStatus>;
+
+    #[rpc(name = "get_status")]
+    fn get_status(&self) -> RpcResult<StatusJson>;
+
+    /// Also exposed as `GET /health`, returns error when the node is not in sync
+    #[rpc(name = "health")]
+    fn health(&self) -> RpcResult<HealthJson>;
 }
 
+/// Default/max page size of paginated RPCs
+const DEFAULT_PAGE_LIMIT: u32 = 100;
+const MAX_PAGE_LIMIT: u32 = 1000;
+
 pub struct RpcImpl {
     pub loader: Arc<Loader>,
     pub run_config: RunConfig,
+    pub indexer_status: SharedIndexerStatus,
+    /// The max lag (in blocks) between indexed tip and CKB tip of a healthy node
+    pub max_lag: u64,
+}
+
+impl RpcImpl {
+    fn load_health(&self) -> Result<HealthJson, String> {
+        let mut loader = Loader::clone(&self.loader);
+        let indexed_number = loader.load_last_block()?.map(|last| last.number);
+        let tip_number = loader.load_header(None)?.number();
+        let lag = tip_number.saturating_sub(indexed_number.unwrap_or(0));
+        let indexer_state = self
+            .indexer_status
+            .read()
+            .map_err(|err| err.to_string())?
+            .state;
+        Ok(HealthJson {
+            healthy: lag <= self.max_lag && indexer_state != IndexerState::Failed,
+            indexed_number,
+            tip_number,
+            lag,
+            max_lag: self.max_lag,
+        })
+    }
 }
 
 impl Rpc for RpcImpl {
@@ -104,11 +229,72 @@ impl Rpc for RpcImpl {
         resp
     }
 
+    fn transfer(
+        &self,
+        sender: H160,
+        destination: H160,
+        value: u64,
+    ) -> RpcResult<TransactionReceipt> {
+        log::debug!(
+            "transfer(sender: {:x}, destination: {:x}, value: {})",
+            sender,
+            destination,
+            value
+        );
+        let loader = Loader::clone(&self.loader);
+        let run_config = self.run_config.clone();
+        let tx = Runner::new(loader, run_config)
+            .transfer(sender, destination, value)
+            .map_err(convert_err_box)?;
+        Ok(TransactionReceipt::without_contract(tx))
+    }
+
+    fn deposit(
+        &self,
+        lock_arg: H160,
+        destination: H160,
+        value: u64,
+    ) -> RpcResult<TransactionReceipt> {
+        log::debug!(
+            "deposit(lock_arg: {:x}, destination: {:x}, value: {})",
+            lock_arg,
+            destination,
+            value
+        );
+        let loader = Loader::clone(&self.loader);
+        let run_config = self.run_config.clone();
+        let tx = Runner::new(loader, run_config)
+            .deposit(lock_arg, destination, value)
+            .map_err(convert_err_box)?;
+        Ok(TransactionReceipt::without_contract(tx))
+    }
+
+    fn withdraw(
+        &self,
+        sender: H160,
+        lock_arg: H160,
+        value: Option<u64>,
+    ) -> RpcResult<TransactionReceipt> {
+        log::debug!(
+            "withdraw(sender: {:x}, lock_arg: {:x}, value: {:?})",
+            sender,
+            lock_arg,
+            value
+        );
+        let loader = Loader::clone(&self.loader);
+        let run_config = self.run_config.clone();
+        let tx = Runner::new(loader, run_config)
+            .withdraw(sender, lock_arg, value)
+            .map_err(convert_err_box)?;
+        Ok(TransactionReceipt::without_contract(tx))
+    }
+
     fn static_call(
         &self,
         sender: H160,
         contract_address: ContractAddress,
         input: JsonBytes,
+        state_overrides: Option<HashMap<H160, StateOverrideJson>>,
     ) -> RpcResult<StaticCallResponse> {
         log::debug!(
             "static_call(sender: {:x}, contract_address: {:x}, input: {})",
@@ -118,13 +304,66 @@ impl Rpc for RpcImpl {
         );
         let loader = Loader::clone(&self.loader);
         let run_config = self.run_config.clone();
+        let overrides = state_overrides
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(address, state_override)| (address, state_override.into()))
+            .collect::<HashMap<_, _>>();
         let context = Runner::new(loader, run_config)
-            .static_call(sender, contract_address, input.into_bytes())
+            .static_call(sender, contract_address, input.into_bytes(), overrides)
             .map_err(convert_err_box)?;
         log::debug!("static_call finished");
         StaticCallResponse::try_from(context).map_err(convert_err)
     }
 
+    fn simulate(
+        &self,
+        sender: H160,
+        contract_address: Option<ContractAddress>,
+        input: JsonBytes,
+        value: u64,
+    ) -> RpcResult<SimulateResponse> {
+        log::debug!(
+            "simulate(sender: {:x}, contract_address: {:?}, input: {})",
+            sender,
+            contract_address,
+            hex::encode(input.as_bytes())
+        );
+        let loader = Loader::clone(&self.loader);
+        let run_config = self.run_config.clone();
+        let context = Runner::new(loader, run_config)
+            .simulate(sender, contract_address, input.into_bytes(), value)
+            .map_err(convert_err_box)?;
+        log::debug!("simulate finished");
+        SimulateResponse::try_from(context).map_err(convert_err)
+    }
+
+    fn trace_call(
+        &self,
+        sender: H160,
+        contract_address: Option<ContractAddress>,
+        input: JsonBytes,
+        value: u64,
+    ) -> RpcResult<CallFrame> {
+        log::debug!(
+            "trace_call(sender: {:x}, contract_address: {:?}, input: {})",
+            sender,
+            contract_address,
+            hex::encode(input.as_bytes())
+        );
+        let loader = Loader::clone(&self.loader);
+        let run_config = self.run_config.clone();
+        Runner::new(loader, run_config)
+            .trace_call(sender, contract_address, input.into_bytes(), value)
+            .map_err(convert_err_box)
+    }
+
+    fn debug_trace_transaction(&self, tx_hash: H256) -> RpcResult<CallFrame> {
+        log::debug!("debug_trace_transaction(tx_hash: {:#x})", tx_hash);
+        let mut loader = Loader::clone(&self.loader);
+        replay_transaction(&mut loader, &self.run_config, &tx_hash).map_err(convert_err)
+    }
+
     fn get_code(&self, contract_address: ContractAddress) -> RpcResult<ContractCodeJson> {
         log::debug!("get_code(contract_address: {:x})", contract_address.0);
         self.loader
@@ -166,6 +405,64 @@ impl Rpc for RpcImpl {
             .map_err(convert_err)
     }
 
+    fn get_contract_history(
+        &self,
+        contract_address: ContractAddress,
+        from_block: u64,
+        to_block: Option<u64>,
+        cursor: Option<ContractChangeCursor>,
+        limit: Option<u32>,
+    ) -> RpcResult<ContractHistoryJson> {
+        let limit = check_page_limit(limit)?;
+        let start = cursor
+            .map(|cursor| (cursor.block_number, cursor.tx_index, cursor.output_index))
+            .unwrap_or((from_block, 0, 0));
+        let page = self
+            .loader
+            .load_contract_changes(contract_address, start, to_block, limit as usize)
+            .map_err(convert_err)?;
+        let mut previous = page.previous;
+        let mut changes = Vec::with_capacity(page.changes.len());
+        for change in page.changes {
+            let storage_diff = change
+                .storage_diff(previous.as_ref())
+                .into_iter()
+                .map(|(key, old_value, new_value)| StorageDiffJson {
+                    key,
+                    old_value,
+                    new_value,
+                })
+                .collect();
+            changes.push(ContractTransitionJson {
+                number: change.number,
+                tx_index: change.tx_index,
+                output_index: change.output_index,
+                tx_hash: change.tx_hash.clone(),
+                tx_origin: change.tx_origin.clone(),
+                capacity: change.capacity,
+                balance: change.balance,
+                is_create: change.is_create,
+                storage_diff,
+                logs: change
+                    .logs
+                    .iter()
+                    .map(|(topics, data)| (topics.clone(), JsonBytes::from_bytes(data.clone())))
+                    .collect(),
+            });
+            previous = Some(change);
+        }
+        Ok(ContractHistoryJson {
+            changes,
+            next_cursor: page.next.map(|(block_number, tx_index, output_index)| {
+                ContractChangeCursor {
+                    block_number,
+                    tx_index,
+                    output_index,
+                }
+            }),
+        })
+    }
+
     fn get_logs(
         &self,
         from_block: u64,
@@ -204,6 +501,106 @@ impl Rpc for RpcImpl {
             })
             .map_err(convert_err)
     }
+
+    fn get_account_transactions(
+        &self,
+        address: H160,
+        from_block: u64,
+        to_block: Option<u64>,
+        cursor: Option<AccountTxCursor>,
+        limit: Option<u32>,
+    ) -> RpcResult<AccountTransactionsJson> {
+        let limit = check_page_limit(limit)?;
+        let start = cursor
+            .map(|cursor| (cursor.block_number, cursor.tx_index))
+            .unwrap_or((from_block, 0));
+        let (account_txs, next) = self
+            .loader
+            .load_account_txs(address, start, to_block, limit as usize)
+            .map_err(convert_err)?;
+        let transactions = account_txs
+            .into_iter()
+            .map(
+                |(block_number, tx_index, account_tx)| AccountTransactionJson {
+                    block_number,
+                    tx_index,
+                    tx_hash: account_tx.tx_hash,
+                    balance_before: account_tx.balance_before,
+                    balance_after: account_tx.balance_after,
+                    contract_changes: account_tx
+                        .contract_changes
+                        .into_iter()
+                        .map(|(address, output_index)| AccountContractChangeJson {
+                            address,
+                            output_index,
+                        })
+                        .collect(),
+                },
+            )
+            .collect();
+        Ok(AccountTransactionsJson {
+            transactions,
+            next_cursor: next.map(|(block_number, tx_index)| AccountTxCursor {
+                block_number,
+                tx_index,
+            }),
+        })
+    }
+
+    fn get_indexer_status(&self) -> RpcResult<IndexerStatus> {
+        self.indexer_status
+            .read()
+            .map(|status| status.clone())
+            .map_err(|err| convert_err(err.to_string()))
+    }
+
+    fn get_status(&self) -> RpcResult<StatusJson> {
+        let health = self.load_health().map_err(convert_err)?;
+        let indexed_hash = self
+            .loader
+            .load_last_block()
+            .map_err(convert_err)?
+            .map(|last| last.hash);
+        let indexer = self.get_indexer_status()?;
+        Ok(StatusJson {
+            health,
+            indexed_hash,
+            contract_count: self
+                .loader
+                .estimate_num_keys(KeyType::ContractMeta)
+                .map_err(convert_err)?,
+            eoa_account_count: self
+                .loader
+                .estimate_num_keys(KeyType::EoaLiveCell)
+                .map_err(convert_err)?,
+            db_stats: self.loader.load_db_stats().map_err(convert_err)?,
+            indexer,
+        })
+    }
+
+    fn health(&self) -> RpcResult<HealthJson> {
+        let health = self.load_health().map_err(convert_err)?;
+        if health.healthy {
+            Ok(health)
+        } else {
+            Err(Error {
+                code: ErrorCode::ServerError(-1),
+                message: String::from("unhealthy"),
+                data: serde_json::to_value(&health).ok(),
+            })
+        }
+    }
+}
+
+fn check_page_limit(limit: Option<u32>) -> RpcResult<u32> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if limit == 0 || limit > MAX_PAGE_LIMIT {
+        return Err(convert_err(format!(
+            "limit must be in range [1, {}]",
+            MAX_PAGE_LIMIT
+        )));
+    }
+    Ok(limit)
 }
 
 fn convert_err(err: String) -> Error {
@@ -215,8 +612,14 @@ fn convert_err(err: String) -> Error {
 }
 
 fn convert_err_box(err: Box<dyn StdError>) -> Error {
+    // The transaction exceeds the execution limits
+    let code = if err.downcast_ref::<LimitError>().is_some() {
+        ErrorCode::ServerError(-2)
+    } else {
+        ErrorCode::InvalidRequest
+    };
     Error {
-        code: ErrorCode::InvalidRequest,
+        code,
         message: err.to_string(),
         data: None,
     }
@@ -259,6 +662,83 @@ impl From<ContractChange> for ContractChangeJson {
     }
 }
 
+/// The position to continue loading contract changes
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ContractChangeCursor {
+    pub block_number: u64,
+    pub tx_index: u32,
+    pub output_index: u32,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ContractHistoryJson {
+    pub changes: Vec<ContractTransitionJson>,
+    /// None if there is no more changes
+    pub next_cursor: Option<ContractChangeCursor>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ContractTransitionJson {
+    /// Block number
+    pub number: u64,
+    /// Transaction index in current block
+    pub tx_index: u32,
+    /// Output index in current transaction
+    pub output_index: u32,
+    pub tx_hash: H256,
+    pub tx_origin: EoaAddress,
+    pub capacity: u64,
+    pub balance: u64,
+    /// The change is create the contract
+    pub is_create: bool,
+    /// Storage keys changed relative to the previous change
+    pub storage_diff: Vec<StorageDiffJson>,
+    pub logs: Vec<(Vec<H256>, JsonBytes)>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct StorageDiffJson {
+    pub key: H256,
+    /// None if the key is newly added
+    pub old_value: Option<H256>,
+    /// None if the key is removed
+    pub new_value: Option<H256>,
+}
+
+/// The position to continue loading account transactions
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AccountTxCursor {
+    pub block_number: u64,
+    pub tx_index: u32,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct AccountTransactionsJson {
+    pub transactions: Vec<AccountTransactionJson>,
+    /// None if there is no more transactions
+    pub next_cursor: Option<AccountTxCursor>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct AccountTransactionJson {
+    pub block_number: u64,
+    pub tx_index: u32,
+    pub tx_hash: H256,
+    /// The balance of the account's EoA cell in inputs (null: not in inputs)
+    pub balance_before: Option<u64>,
+    /// The balance of the account's EoA cell in outputs (null: not in outputs)
+    pub balance_after: Option<u64>,
+    /// Contract changes sent by the account (as tx_origin)
+    pub contract_changes: Vec<AccountContractChangeJson>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct AccountContractChangeJson {
+    pub address: ContractAddress,
+    /// Output index in the transaction
+    pub output_index: u32,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct ContractCodeJson {
     pub code: JsonBytes,
@@ -306,6 +786,7 @@ impl LogEntry {
 pub struct TransactionReceipt {
     pub tx: Transaction,
     pub tx_hash: H256,
+    /// Zero address for EoA transfers, deposits and withdrawals
     pub entrance_contract: ContractAddress,
     /// The newly created contract's address
     pub created_addresses: Vec<ContractAddress>,
@@ -315,6 +796,21 @@ pub struct TransactionReceipt {
     pub return_data: Option<JsonBytes>,
 }
 
+impl TransactionReceipt {
+    /// The receipt of a transaction which doesn't involve any contract
+    pub fn without_contract(tx: packed::Transaction) -> TransactionReceipt {
+        TransactionReceipt {
+            tx_hash: tx.calc_tx_hash().unpack(),
+            tx: Transaction::from(tx),
+            entrance_contract: ContractAddress::default(),
+            created_addresses: Vec::new(),
+            destructed_addresses: Vec::new(),
+            logs: Vec::new(),
+            return_data: None,
+        }
+    }
+}
+
 impl TryFrom<CsalRunContext> for TransactionReceipt {
     type Error = String;
     fn try_from(mut context: CsalRunContext) -> Result<TransactionReceipt, String> {
@@ -366,6 +862,109 @@ impl TryFrom<CsalRunContext> for StaticCallResponse {
     }
 }
 
+/// All the effects of a simulated create/call
+#[derive(Debug, Clone, Serialize)]
+pub struct SimulateResponse {
+    pub entrance_contract: ContractAddress,
+    pub return_data: JsonBytes,
+    pub logs: Vec<LogEntry>,
+    pub created_addresses: Vec<ContractAddress>,
+    pub destructed_addresses: Vec<ContractAddress>,
+    /// Storage diffs of the contracts with storage written
+    pub storage_changes: Vec<ContractStorageChangeJson>,
+    pub balance_changes: Vec<BalanceChangeJson>,
+    pub cycles: u64,
+    /// A transaction can only be built when the state changed
+    pub state_changed: bool,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ContractStorageChangeJson {
+    pub address: ContractAddress,
+    pub storage_diff: Vec<StorageDiffJson>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct BalanceChangeJson {
+    /// Contract or EoA address
+    pub address: H160,
+    pub old_balance: u64,
+    pub new_balance: u64,
+}
+
+impl TryFrom<CsalRunContext> for SimulateResponse {
+    type Error = String;
+    fn try_from(context: CsalRunContext) -> Result<SimulateResponse, String> {
+        let logs = context
+            .get_logs()?
+            .into_iter()
+            .map(|(addr, topics, data)| LogEntry::new(addr, topics, data))
+            .collect::<Vec<_>>();
+        let non_zero = |value: &H256| Some(value.clone()).filter(|value| value != &H256::default());
+        let storage_changes = context
+            .contracts()
+            .filter_map(|(address, info)| {
+                let storage_diff = info
+                    .storage_changes
+                    .iter()
+                    .filter(|(_, (old_value, new_value))| old_value != new_value)
+                    .map(|(key, (old_value, new_value))| StorageDiffJson {
+                        key: key.clone(),
+                        old_value: non_zero(old_value),
+                        new_value: non_zero(new_value),
+                    })
+                    .collect::<Vec<_>>();
+                if storage_diff.is_empty() {
+                    None
+                } else {
+                    Some(ContractStorageChangeJson {
+                        address: address.clone(),
+                        storage_diff,
+                    })
+                }
+            })
+            .collect();
+        let balance_changes = context
+            .balance_changes()
+            .into_iter()
+            .map(|(address, old_balance, new_balance)| BalanceChangeJson {
+                address,
+                old_balance,
+                new_balance,
+            })
+            .collect();
+        Ok(SimulateResponse {
+            entrance_contract: context.entrance_contract(),
+            return_data: JsonBytes::from_bytes(context.entrance_info().return_data()),
+            logs,
+            created_addresses: context.created_contracts(),
+            destructed_addresses: context.destructed_contracts(),
+            storage_changes,
+            balance_changes,
+            cycles: context.cycles,
+            state_changed: context.state_changed(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StateOverrideJson {
+    pub code: Option<JsonBytes>,
+    pub balance: Option<u64>,
+    /// storage key => storage value
+    pub storage: Option<HashMap<H256, H256>>,
+}
+
+impl From<StateOverrideJson> for StateOverride {
+    fn from(json: StateOverrideJson) -> StateOverride {
+        StateOverride {
+            code: json.code.map(JsonBytes::into_bytes),
+            balance: json.balance,
+            storage: json.storage.unwrap_or_default(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContractMetaJson {
     /// The block where the contract created
@@ -399,3 +998,29 @@ impl ContractMetaJson {
         }
     }
 }
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthJson {
+    /// lag <= max_lag and the indexer is not failed
+    pub healthy: bool,
+    /// The last indexed block number (`None` if nothing indexed yet)
+    pub indexed_number: Option<u64>,
+    /// The tip block number of CKB
+    pub tip_number: u64,
+    pub lag: u64,
+    pub max_lag: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusJson {
+    #[serde(flatten)]
+    pub health: HealthJson,
+    pub indexed_hash: Option<H256>,
+    /// Estimated by RocksDB
+    pub contract_count: u64,
+    /// Estimated by RocksDB
+    pub eoa_account_count: u64,
+    pub db_stats: DbStats,
+    /// Include last rollback information
+    pub indexer: IndexerStatus,
+}
@@ -0,0 +1,239 @@
+use ckb_types::H256;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::server::{ContractChangeJson, ContractMetaJson};
+use crate::storage::{EoaBalanceChange, EventSink, IndexerEvent};
+use crate::types::ContractAddress;
+
+/// How long to wait for the consumer to acknowledge an event
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One line in the JSON-lines file/socket stream
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventJson {
+    Block {
+        number: u64,
+        hash: H256,
+        parent_hash: H256,
+        changes: Vec<ContractChangeJson>,
+        created_contracts: Vec<ContractMetaJson>,
+        destructed_contracts: Vec<ContractAddress>,
+        eoa_balance_changes: Vec<EoaBalanceChange>,
+    },
+    /// The block and all the blocks after it are reverted
+    Rollback {
+        number: u64,
+        hash: H256,
+        changes: Vec<ContractChangeJson>,
+    },
+}
+
+impl From<&IndexerEvent> for EventJson {
+    fn from(event: &IndexerEvent) -> EventJson {
+        match event {
+            IndexerEvent::Block(block) => {
+                let number = block.header.inner.number.value();
+                EventJson::Block {
+                    number,
+                    hash: block.header.hash.clone(),
+                    parent_hash: block.header.inner.parent_hash.clone(),
+                    changes: block
+                        .changes
+                        .iter()
+                        .cloned()
+                        .map(ContractChangeJson::from)
+                        .collect(),
+                    created_contracts: block
+                        .created_contracts
+                        .iter()
+                        .cloned()
+                        .map(|meta| ContractMetaJson::new(number, meta))
+                        .collect(),
+                    destructed_contracts: block.destructed_contracts.clone(),
+                    eoa_balance_changes: block.eoa_balance_changes.clone(),
+                }
+            }
+            IndexerEvent::Rollback(rollback) => EventJson::Rollback {
+                number: rollback.number,
+                hash: rollback.hash.clone(),
+                changes: rollback
+                    .changes
+                    .iter()
+                    .cloned()
+                    .map(ContractChangeJson::from)
+                    .collect(),
+            },
+        }
+    }
+}
+
+fn event_line(event: &IndexerEvent) -> Result<Vec<u8>, String> {
+    let mut line = serde_json::to_vec(&EventJson::from(event)).map_err(|err| err.to_string())?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Open a sink from command line argument: "jsonl:<path>" or "unix:<path>"
+pub fn open_sink(spec: &str) -> Result<Box<dyn EventSink>, String> {
+    let mut parts = spec.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("jsonl"), Some(path)) => Ok(Box::new(JsonLinesSink::open(path)?)),
+        (Some("unix"), Some(path)) => Ok(Box::new(UnixSocketSink::new(path))),
+        _ => Err(format!(
+            "Invalid event sink: {}, expected jsonl:<path> or unix:<path>",
+            spec
+        )),
+    }
+}
+
+/// Append events to a file, one JSON per line. A line may be written more
+/// than once (or partially) if the node crashed before the cursor is saved.
+pub struct JsonLinesSink {
+    path: PathBuf,
+    file: File,
+}
+
+impl JsonLinesSink {
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<JsonLinesSink, String> {
+        let path = path.into();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|err| format!("Open {}: {}", path.display(), err))?;
+        Ok(JsonLinesSink { path, file })
+    }
+}
+
+impl EventSink for JsonLinesSink {
+    fn id(&self) -> Option<String> {
+        Some(format!("jsonl:{}", self.path.display()))
+    }
+
+    fn send(&mut self, event: &IndexerEvent) -> Result<(), String> {
+        let line = event_line(event)?;
+        self.file
+            .write_all(&line)
+            .and_then(|_| self.file.sync_data())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Stream events to a consumer listening on a Unix socket, one JSON per line.
+/// The consumer must reply a line (any content) after the event is processed,
+/// otherwise the event will be sent again after reconnected.
+pub struct UnixSocketSink {
+    path: PathBuf,
+    stream: Option<BufReader<UnixStream>>,
+}
+
+impl UnixSocketSink {
+    pub fn new<P: Into<PathBuf>>(path: P) -> UnixSocketSink {
+        UnixSocketSink {
+            path: path.into(),
+            stream: None,
+        }
+    }
+
+    fn send_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.stream.is_none() {
+            let stream = UnixStream::connect(&self.path)?;
+            stream.set_read_timeout(Some(ACK_TIMEOUT))?;
+            self.stream = Some(BufReader::new(stream));
+        }
+        let reader = self.stream.as_mut().expect("connected");
+        reader.get_mut().write_all(line)?;
+        let mut ack = String::new();
+        if reader.read_line(&mut ack)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed by consumer",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl EventSink for UnixSocketSink {
+    fn id(&self) -> Option<String> {
+        Some(format!("unix:{}", self.path.display()))
+    }
+
+    fn send(&mut self, event: &IndexerEvent) -> Result<(), String> {
+        let line = event_line(event)?;
+        self.send_line(&line).map_err(|err| {
+            // Reconnect on next send
+            self.stream = None;
+            format!("{}: {}", self.path.display(), err)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::RollbackEvent;
+    use ckb_types::h256;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    fn rollback_event(number: u64) -> IndexerEvent {
+        IndexerEvent::Rollback(RollbackEvent {
+            number,
+            hash: h256!("0x1"),
+            changes: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_json_lines_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let mut sink = open_sink(&format!("jsonl:{}", path.display())).unwrap();
+        sink.send(&rollback_event(3)).unwrap();
+        sink.send(&rollback_event(2)).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines = content.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let value: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(value["type"], "rollback");
+        assert_eq!(value["number"], 2);
+    }
+
+    #[test]
+    fn test_invalid_sink() {
+        assert!(open_sink("events.jsonl").is_err());
+        assert!(open_sink("tcp:127.0.0.1:8000").is_err());
+    }
+
+    #[test]
+    fn test_unix_socket_sink() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.sock");
+        let mut sink = UnixSocketSink::new(&path);
+        // No consumer yet
+        assert!(sink.send(&rollback_event(1)).is_err());
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            reader.get_mut().write_all(b"ok\n").unwrap();
+            line
+        });
+        sink.send(&rollback_event(1)).unwrap();
+        let line = handle.join().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["type"], "rollback");
+        assert_eq!(value["number"], 1);
+    }
+}
@@ -0,0 +1,241 @@
+use ckb_types::{
+    core::{BlockView, HeaderView},
+    prelude::*,
+    H256,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+/// Headers of recent blocks to cache, covers the BLOCKHASH window
+pub const DEFAULT_MAX_HEADERS: usize = 256;
+/// Full blocks of recent blocks to cache
+pub const DEFAULT_MAX_BLOCKS: usize = 16;
+
+struct CachedBlock {
+    header: HeaderView,
+    /// Only the latest `max_blocks` blocks keep the full block
+    block: Option<BlockView>,
+    tx_hashes: Vec<H256>,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    /// block number => block, a continuous range of the indexed chain
+    blocks: BTreeMap<u64, CachedBlock>,
+    /// block hash => block number
+    numbers: HashMap<H256, u64>,
+    /// transaction hash => block hash
+    tx_blocks: HashMap<H256, H256>,
+}
+
+impl CacheInner {
+    fn remove(&mut self, number: u64) {
+        if let Some(cached) = self.blocks.remove(&number) {
+            self.numbers.remove(&cached.header.hash().unpack());
+            for tx_hash in cached.tx_hashes {
+                self.tx_blocks.remove(&tx_hash);
+            }
+        }
+    }
+
+    fn remove_from(&mut self, number: u64) {
+        let numbers = self
+            .blocks
+            .range(number..)
+            .map(|(number, _)| *number)
+            .collect::<Vec<_>>();
+        for number in numbers {
+            self.remove(number);
+        }
+    }
+}
+
+/// Recent blocks of the indexed chain, fed by the indexer and shared by all
+/// the loaders (RPC handlers). Blocks are removed when rolled back, so the
+/// cache never contains orphaned blocks the indexer known of.
+pub struct ChainCache {
+    max_headers: usize,
+    max_blocks: usize,
+    inner: RwLock<CacheInner>,
+}
+
+impl Default for ChainCache {
+    fn default() -> ChainCache {
+        ChainCache::new(DEFAULT_MAX_HEADERS, DEFAULT_MAX_BLOCKS)
+    }
+}
+
+impl ChainCache {
+    pub fn new(max_headers: usize, max_blocks: usize) -> ChainCache {
+        ChainCache {
+            max_headers,
+            max_blocks: max_blocks.min(max_headers),
+            inner: Default::default(),
+        }
+    }
+
+    /// Add a newly indexed block
+    pub fn insert_block(&self, block: &BlockView) {
+        let mut inner = self.inner.write().expect("write chain cache");
+        let number = block.number();
+        inner.remove_from(number);
+        let parent_matched = number
+            .checked_sub(1)
+            .and_then(|parent_number| inner.blocks.get(&parent_number))
+            .map(|parent| parent.header.hash() == block.parent_hash())
+            .unwrap_or(false);
+        if !parent_matched {
+            // Not continuous with the cached blocks
+            *inner = CacheInner::default();
+        }
+
+        let block_hash: H256 = block.hash().unpack();
+        let tx_hashes = block
+            .tx_hashes()
+            .iter()
+            .map(|tx_hash| tx_hash.unpack())
+            .collect::<Vec<H256>>();
+        for tx_hash in &tx_hashes {
+            inner.tx_blocks.insert(tx_hash.clone(), block_hash.clone());
+        }
+        inner.numbers.insert(block_hash, number);
+        inner.blocks.insert(
+            number,
+            CachedBlock {
+                header: block.header(),
+                block: Some(block.clone()),
+                tx_hashes,
+            },
+        );
+
+        if let Some(cached) = number
+            .checked_sub(self.max_blocks as u64)
+            .and_then(|number| inner.blocks.get_mut(&number))
+        {
+            cached.block = None;
+        }
+        while inner.blocks.len() > self.max_headers {
+            let first_number = *inner.blocks.keys().next().expect("not empty");
+            inner.remove(first_number);
+        }
+    }
+
+    /// Remove the rolled back block (and all blocks after it)
+    pub fn rollback(&self, number: u64) {
+        self.inner
+            .write()
+            .expect("write chain cache")
+            .remove_from(number);
+    }
+
+    pub fn tip_block(&self) -> Option<BlockView> {
+        let inner = self.inner.read().expect("read chain cache");
+        inner
+            .blocks
+            .values()
+            .next_back()
+            .and_then(|cached| cached.block.clone())
+    }
+
+    pub fn header_by_number(&self, number: u64) -> Option<HeaderView> {
+        let inner = self.inner.read().expect("read chain cache");
+        inner
+            .blocks
+            .get(&number)
+            .map(|cached| cached.header.clone())
+    }
+
+    pub fn block(&self, hash: &H256) -> Option<BlockView> {
+        let inner = self.inner.read().expect("read chain cache");
+        inner
+            .numbers
+            .get(hash)
+            .and_then(|number| inner.blocks.get(number))
+            .and_then(|cached| cached.block.clone())
+    }
+
+    /// The block hash of the transaction committed in
+    pub fn tx_block_hash(&self, tx_hash: &H256) -> Option<H256> {
+        let inner = self.inner.read().expect("read chain cache");
+        inner.tx_blocks.get(tx_hash).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ckb_types::{
+        bytes::Bytes,
+        core::{BlockBuilder, TransactionBuilder},
+    };
+
+    fn build_block(parent: Option<&BlockView>, nonce: u128) -> BlockView {
+        let number = parent.map(|parent| parent.number() + 1).unwrap_or(0);
+        let tx = TransactionBuilder::default()
+            .witness(Bytes::from(vec![nonce as u8]).pack())
+            .build();
+        BlockBuilder::default()
+            .parent_hash(parent.map(|parent| parent.hash()).unwrap_or_default())
+            .number(number.pack())
+            .nonce(nonce.pack())
+            .transaction(tx)
+            .build()
+    }
+
+    fn build_chain(length: usize, nonce: u128) -> Vec<BlockView> {
+        let mut blocks: Vec<BlockView> = Vec::new();
+        for _ in 0..length {
+            let block = build_block(blocks.last(), nonce);
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let cache = ChainCache::new(4, 2);
+        let blocks = build_chain(6, 0);
+        for block in &blocks {
+            cache.insert_block(block);
+        }
+        assert_eq!(cache.tip_block().unwrap().hash(), blocks[5].hash());
+        assert!(cache.header_by_number(1).is_none());
+        assert_eq!(cache.header_by_number(2).unwrap().hash(), blocks[2].hash());
+        // Only the header is cached
+        assert!(cache.block(&blocks[3].hash().unpack()).is_none());
+        assert!(cache.block(&blocks[4].hash().unpack()).is_some());
+        let tx_hash = blocks[5].tx_hashes()[0].unpack();
+        assert_eq!(
+            cache.tx_block_hash(&tx_hash),
+            Some(blocks[5].hash().unpack())
+        );
+        let tx_hash = blocks[1].tx_hashes()[0].unpack();
+        assert_eq!(cache.tx_block_hash(&tx_hash), None);
+    }
+
+    #[test]
+    fn test_cache_rollback() {
+        let cache = ChainCache::default();
+        let blocks = build_chain(4, 0);
+        for block in &blocks {
+            cache.insert_block(block);
+        }
+        cache.rollback(2);
+        assert_eq!(cache.tip_block().unwrap().hash(), blocks[1].hash());
+        assert!(cache.header_by_number(2).is_none());
+        assert!(cache.block(&blocks[3].hash().unpack()).is_none());
+        let tx_hash = blocks[2].tx_hashes()[0].unpack();
+        assert_eq!(cache.tx_block_hash(&tx_hash), None);
+
+        // The fork block replaces the orphaned one
+        let fork_block = build_block(Some(&blocks[1]), 1);
+        cache.insert_block(&fork_block);
+        assert_eq!(cache.header_by_number(2).unwrap().hash(), fork_block.hash());
+
+        // A block not continuous with the cached blocks clears the cache
+        let other_block = build_block(Some(&build_chain(4, 2)[3]), 2);
+        cache.insert_block(&other_block);
+        assert!(cache.header_by_number(0).is_none());
+        assert_eq!(cache.tip_block().unwrap().hash(), other_block.hash());
+    }
+}
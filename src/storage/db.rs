@@ -0,0 +1,174 @@
+use ckb_types::bytes::Bytes;
+use rocksdb::{
+    BlockBasedOptions, ColumnFamily, ColumnFamilyDescriptor, DBCompressionType, Options,
+    SliceTransform, WriteBatch, DB,
+};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use super::{Key, KeyType};
+
+/// Key types stored in dedicated column families (`KeyType::Version` is in
+/// the default column family)
+pub const COLUMN_KEY_TYPES: [KeyType; 10] = [
+    KeyType::Last,
+    KeyType::BlockMap,
+    KeyType::ContractChange,
+    KeyType::ContractLogs,
+    KeyType::ContractMeta,
+    KeyType::LockLiveCell,
+    KeyType::EoaLiveCell,
+    KeyType::LiveCellMap,
+    KeyType::AccountTx,
+    KeyType::BlockDelta,
+];
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DbCompression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl From<DbCompression> for DBCompressionType {
+    fn from(compression: DbCompression) -> DBCompressionType {
+        match compression {
+            DbCompression::None => DBCompressionType::None,
+            DbCompression::Snappy => DBCompressionType::Snappy,
+            DbCompression::Lz4 => DBCompressionType::Lz4,
+            DbCompression::Zstd => DBCompressionType::Zstd,
+        }
+    }
+}
+
+/// RocksDB options (the "db_options" field of run config)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DbOptions {
+    /// Total block cache size (MB), split evenly between column families
+    pub block_cache_size_mb: usize,
+    pub compression: DbCompression,
+    /// Bits per key of the bloom filters
+    pub bloom_filter_bits: i32,
+}
+
+impl Default for DbOptions {
+    fn default() -> DbOptions {
+        DbOptions {
+            block_cache_size_mb: 256,
+            compression: DbCompression::Lz4,
+            bloom_filter_bits: 10,
+        }
+    }
+}
+
+/// Open the database with all column families (created if missing)
+pub fn open_db<P: AsRef<Path>>(path: P, options: &DbOptions) -> Result<DB, String> {
+    let mut db_opts = Options::default();
+    db_opts.create_if_missing(true);
+    db_opts.create_missing_column_families(true);
+    let mut cf_descriptors = vec![ColumnFamilyDescriptor::new(
+        KeyType::Version.cf_name(),
+        cf_options(KeyType::Version, options),
+    )];
+    cf_descriptors.extend(COLUMN_KEY_TYPES.iter().map(|key_type| {
+        ColumnFamilyDescriptor::new(key_type.cf_name(), cf_options(*key_type, options))
+    }));
+    DB::open_cf_descriptors(&db_opts, path, cf_descriptors).map_err(|err| err.to_string())
+}
+
+fn cf_options(key_type: KeyType, options: &DbOptions) -> Options {
+    let cache_size = options.block_cache_size_mb * 1024 * 1024 / (COLUMN_KEY_TYPES.len() + 1);
+    let mut block_opts = BlockBasedOptions::default();
+    block_opts.set_lru_cache(cache_size);
+    block_opts.set_bloom_filter(options.bloom_filter_bits, false);
+
+    let mut opts = Options::default();
+    opts.set_block_based_table_factory(&block_opts);
+    opts.set_compression_type(options.compression.into());
+    if let Some(prefix_len) = prefix_len(key_type) {
+        opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(prefix_len));
+    }
+    opts
+}
+
+// The prefix used by range queries: KeyType + ContractAddress/LockHash
+fn prefix_len(key_type: KeyType) -> Option<usize> {
+    match key_type {
+        KeyType::ContractChange | KeyType::ContractLogs | KeyType::AccountTx => Some(1 + 20),
+        KeyType::LockLiveCell => Some(1 + 32),
+        _ => None,
+    }
+}
+
+pub fn cf_handle(db: &DB, key_type: KeyType) -> &ColumnFamily {
+    db.cf_handle(key_type.cf_name())
+        .unwrap_or_else(|| panic!("column family {} not found", key_type.cf_name()))
+}
+
+/// No key in any column family
+pub fn is_empty(db: &DB) -> bool {
+    [KeyType::Version]
+        .iter()
+        .chain(COLUMN_KEY_TYPES.iter())
+        .all(|key_type| {
+            let mut iter = db.raw_iterator_cf(cf_handle(db, *key_type));
+            iter.seek_to_first();
+            !iter.valid()
+        })
+}
+
+/// Sum an integer property of all column families
+pub fn property_int_value_sum(db: &DB, name: &str) -> Result<Option<u64>, String> {
+    let mut sum = None;
+    for key_type in [KeyType::Version].iter().chain(COLUMN_KEY_TYPES.iter()) {
+        if let Some(value) = db
+            .property_int_value_cf(cf_handle(db, *key_type), name)
+            .map_err(|err| err.to_string())?
+        {
+            sum = Some(sum.unwrap_or(0) + value);
+        }
+    }
+    Ok(sum)
+}
+
+/// A WriteBatch put the keys into their column families
+pub struct Batch<'a> {
+    db: &'a DB,
+    inner: WriteBatch,
+}
+
+impl<'a> Batch<'a> {
+    pub fn new(db: &'a DB) -> Batch<'a> {
+        Batch {
+            db,
+            inner: WriteBatch::default(),
+        }
+    }
+
+    pub fn put(&mut self, key: &Key, value: &[u8]) {
+        self.inner
+            .put_cf(cf_handle(self.db, key.key_type()), &Bytes::from(key), value);
+    }
+
+    pub fn delete(&mut self, key: &Key) {
+        self.inner
+            .delete_cf(cf_handle(self.db, key.key_type()), &Bytes::from(key));
+    }
+
+    /// Delete keys in range [from, to), the keys must have same key type
+    pub fn delete_range(&mut self, from: &Key, to: &Key) {
+        assert_eq!(from.key_type(), to.key_type());
+        self.inner.delete_range_cf(
+            cf_handle(self.db, from.key_type()),
+            &Bytes::from(from),
+            &Bytes::from(to),
+        );
+    }
+
+    pub fn write(self) -> Result<(), String> {
+        self.db.write(self.inner).map_err(|err| err.to_string())
+    }
+}
@@ -0,0 +1,76 @@
+use bincode::serialize;
+use ckb_jsonrpc_types::HeaderView;
+use ckb_types::{bytes::Bytes, H160, H256};
+use rocksdb::DB;
+use serde::Serialize;
+
+use super::db::cf_handle;
+use super::{db_get, value, Key, KeyType};
+use crate::types::{ContractAddress, ContractChange, ContractMeta};
+
+/// A block just committed by the indexer
+pub struct BlockEvent {
+    pub header: HeaderView,
+    pub changes: Vec<ContractChange>,
+    pub created_contracts: Vec<ContractMeta>,
+    pub destructed_contracts: Vec<ContractAddress>,
+    pub eoa_balance_changes: Vec<EoaBalanceChange>,
+}
+
+/// A block just reverted by the indexer (the chain reorganized)
+pub struct RollbackEvent {
+    pub number: u64,
+    pub hash: H256,
+    /// The contract changes (with logs) of the orphaned block
+    pub changes: Vec<ContractChange>,
+}
+
+pub enum IndexerEvent {
+    Block(BlockEvent),
+    Rollback(RollbackEvent),
+}
+
+/// The balance change of an EoA cell in a transaction
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct EoaBalanceChange {
+    pub address: H160,
+    pub tx_index: u32,
+    pub tx_hash: H256,
+    /// None if the EoA cell is not in inputs
+    pub balance_before: Option<u64>,
+    /// None if the EoA cell is not in outputs
+    pub balance_after: Option<u64>,
+}
+
+/// Receive the events of the indexer, called in the indexer thread
+pub trait EventSink: Send {
+    /// Sinks with an id get at-least-once delivery: a failed `send` is retried
+    /// (the indexer waits), and the last delivered block is persisted under the
+    /// id so that the missed events are replayed after restart. Sinks without
+    /// an id are best-effort.
+    fn id(&self) -> Option<String>;
+
+    fn send(&mut self, event: &IndexerEvent) -> Result<(), String>;
+}
+
+pub fn load_cursor(db: &DB, sink_id: &str) -> Result<Option<value::EventCursor>, String> {
+    db_get(db, &Key::EventCursor(sink_id.to_string()))
+}
+
+/// Save the cursor, or delete it when nothing is delivered (rollback genesis)
+pub fn save_cursor(
+    db: &DB,
+    sink_id: &str,
+    cursor: Option<&value::EventCursor>,
+) -> Result<(), String> {
+    let key_bytes = Bytes::from(&Key::EventCursor(sink_id.to_string()));
+    let cf = cf_handle(db, KeyType::EventCursor);
+    match cursor {
+        Some(cursor) => {
+            let value_bytes = serialize(cursor).map_err(|err| err.to_string())?;
+            db.put_cf(cf, &key_bytes, &value_bytes)
+        }
+        None => db.delete_cf(cf, &key_bytes),
+    }
+    .map_err(|err| err.to_string())
+}
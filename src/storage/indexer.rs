@@ -13,200 +13,148 @@ use ckb_vm::{
     registers::{A0, A1, A2, A3, A4, A7},
     Error as VMError, Memory, Register, SupportMachine,
 };
-use rocksdb::{WriteBatch, DB};
+use rocksdb::DB;
 use sparse_merkle_tree::{default_store::DefaultStore, SparseMerkleTree, H256 as SmtH256};
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::error::Error as StdError;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use super::db::Batch;
+use super::events::{
+    load_cursor, save_cursor, BlockEvent, EoaBalanceChange, EventSink, IndexerEvent, RollbackEvent,
+};
+use super::precompiles::Precompiles;
+use super::supervisor::{unix_timestamp, RollbackInfo, SharedIndexerStatus};
+use super::tracer::{CallFrame, Tracer};
 use super::{db_get, value, Key, Loader};
-use crate::client::HttpRpcClient;
+use crate::client::{ChainSource, ClientError};
+use crate::metrics::METRICS;
 use crate::types::{
-    cell_balance, contract_account_balance, h256_to_smth256, parse_log, smth256_to_h256,
-    vm_load_data, vm_load_h160, vm_load_h256, vm_load_i32, vm_load_i64, vm_load_u256, vm_load_u32,
-    vm_load_u8, CallKind, ContractAddress, ContractChange, ContractMeta, EoaAddress, RunConfig,
-    WitnessData, ONE_CKB,
+    block_hash_available, cell_balance, code_hash, contract_account_balance, h256_to_smth256,
+    parse_log, smth256_to_h256, vm_load_data, vm_load_h160, vm_load_h256, vm_load_i32, vm_load_i64,
+    vm_load_u256, vm_load_u32, vm_load_u8, CallKind, ContractAddress, ContractChange, ContractMeta,
+    EoaAddress, Program, RunConfig, WitnessData, ONE_CKB,
 };
 
 pub const TYPE_ARGS_LEN: usize = 20;
 // 32 bytes storage root + 32 bytes code_hash
 pub const OUTPUT_DATA_LEN: usize = 32 + 32;
+/// Default max blocks can be rolled back in one chain reorganization
+pub const DEFAULT_MAX_REORG_DEPTH: u64 = 100;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum IndexerError {
+    /// The CKB node is unreachable or returned an error, retry may help
+    Rpc(String),
+    /// The database or the block data is in unexpected state, can not continue
+    Fatal(String),
+}
+
+impl fmt::Display for IndexerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexerError::Rpc(err) => write!(f, "RPC error: {}", err),
+            IndexerError::Fatal(err) => write!(f, "Fatal error: {}", err),
+        }
+    }
+}
+
+impl From<String> for IndexerError {
+    fn from(err: String) -> IndexerError {
+        IndexerError::Fatal(err)
+    }
+}
+
+// The node may be recovered or synced later, or failover to another node
+impl From<ClientError> for IndexerError {
+    fn from(err: ClientError) -> IndexerError {
+        IndexerError::Rpc(err.to_string())
+    }
+}
 
 pub struct Indexer {
     pub db: Arc<DB>,
     pub loader: Loader,
-    pub client: HttpRpcClient,
+    pub client: Box<dyn ChainSource>,
     pub run_config: RunConfig,
+    pub status: SharedIndexerStatus,
+    /// Notified after every committed/reverted block
+    pub sinks: Vec<Box<dyn EventSink>>,
+    /// The indexer halts if the chain reorganized deeper than this
+    pub max_reorg_depth: u64,
 }
 
 impl Indexer {
-    pub fn new(db: Arc<DB>, ckb_uri: &str, run_config: RunConfig) -> Self {
-        let loader = Loader::new(Arc::clone(&db), ckb_uri).unwrap();
+    pub fn with_chain(
+        db: Arc<DB>,
+        client: Box<dyn ChainSource>,
+        run_config: RunConfig,
+        status: SharedIndexerStatus,
+    ) -> Self {
+        let loader = Loader::with_chain(Arc::clone(&db), client.clone());
         Indexer {
             db,
             loader,
-            client: HttpRpcClient::new(ckb_uri.to_string()),
+            client,
             run_config,
+            status,
+            sinks: Vec::new(),
+            max_reorg_depth: DEFAULT_MAX_REORG_DEPTH,
         }
     }
 
-    // Ideally this should never return. The caller is responsible for wrapping
-    // it into a separate thread.
-    pub fn index(&mut self) -> Result<(), String> {
-        let type_code_hash: H256 = self.run_config.type_script.code_hash().unpack();
-        let type_hash_type = {
-            let ty =
-                core::ScriptHashType::try_from(self.run_config.type_script.hash_type()).unwrap();
-            ScriptHashType::from(ty)
-        };
-        let eoa_lock_code_hash: H256 = self.run_config.eoa_lock_script.code_hash().unpack();
-        let eoa_lock_hash_type = {
-            let ty = core::ScriptHashType::try_from(self.run_config.eoa_lock_script.hash_type())
-                .unwrap();
-            ScriptHashType::from(ty)
-        };
+    // Index blocks until `stop` is set, the stop signal is only checked between
+    // blocks so that a block is always fully processed. The caller is
+    // responsible for wrapping it into a separate thread (see IndexerSupervisor).
+    pub fn index(&mut self, stop: &AtomicBool) -> Result<(), IndexerError> {
+        let (type_code_hash, type_hash_type) = script_code(&self.run_config.type_script);
+        let (eoa_lock_code_hash, eoa_lock_hash_type) =
+            script_code(&self.run_config.eoa_lock_script);
         log::info!("type code hash: {:x}", type_code_hash);
         log::info!("type hash type: {:?}", type_hash_type);
         log::info!("eoa lock code hash: {:x}", eoa_lock_code_hash);
         log::info!("eoa lock hash type: {:?}", eoa_lock_hash_type);
-        let last_block_key_bytes = Bytes::from(&Key::Last);
+        self.replay_events(stop)?;
         loop {
-            let next_header = if let Some(value::Last { number, hash }) =
-                db_get(&self.db, &last_block_key_bytes)?
-            {
-                match self.client.get_header_by_number(number + 1) {
-                    Ok(Some(header)) if header.inner.parent_hash == hash => header,
-                    // Rollback
-                    Ok(Some(_header)) => {
-                        log::info!("Rollback block, nubmer={}, hash={}", number, hash);
-                        let block_delta_key = Bytes::from(&Key::BlockDelta(number));
-                        let block_delta: value::BlockDelta = db_get(&self.db, &block_delta_key)?
-                            .unwrap_or_else(|| panic!("Can not load BlockDelta({})", number));
-                        let last_block_info_opt = if number >= 1 {
-                            let last_block_map_key = Bytes::from(&Key::BlockMap(number - 1));
-                            let block_hash: value::BlockMap =
-                                db_get(&self.db, &last_block_map_key)?.unwrap_or_else(|| {
-                                    panic!("Can not load BlockMap({})", number - 1)
-                                });
-                            Some(value::Last {
-                                number: number - 1,
-                                hash: block_hash.0,
-                            })
-                        } else {
-                            None
-                        };
-
-                        let mut batch = WriteBatch::default();
-                        for (address, is_create) in block_delta.contracts {
-                            let change_start_key = Key::ContractChange {
-                                address: address.clone(),
-                                number: Some(number),
-                                tx_index: None,
-                                output_index: None,
-                            };
-                            let change_end_key = Key::ContractChange {
-                                address: address.clone(),
-                                number: Some(number + 1),
-                                tx_index: None,
-                                output_index: None,
-                            };
-                            let logs_start_key = Key::ContractLogs {
-                                address: address.clone(),
-                                number: Some(number),
-                                tx_index: None,
-                                output_index: None,
-                            };
-                            let logs_end_key = Key::ContractLogs {
-                                address: address.clone(),
-                                number: Some(number + 1),
-                                tx_index: None,
-                                output_index: None,
-                            };
-                            batch.delete_range(
-                                &Bytes::from(&change_start_key),
-                                &Bytes::from(&change_end_key),
-                            );
-                            batch.delete_range(
-                                &Bytes::from(&logs_start_key),
-                                &Bytes::from(&logs_end_key),
-                            );
-                            if is_create {
-                                batch.delete(&Bytes::from(&Key::ContractMeta(address)));
-                            }
-                        }
-                        for (lock_hash, tx_index, output_index, value) in block_delta.added_cells {
-                            batch.delete(&Bytes::from(&Key::LockLiveCell {
-                                lock_hash,
-                                number: Some(number),
-                                tx_index: Some(tx_index),
-                                output_index: Some(output_index),
-                            }));
-                            batch.delete(&Bytes::from(&Key::LiveCellMap(value.out_point())));
-                        }
-                        for (lock_hash, old_number, tx_index, output_index, value) in
-                            block_delta.removed_cells
-                        {
-                            let key = Key::LockLiveCell {
-                                lock_hash,
-                                number: Some(old_number),
-                                tx_index: Some(tx_index),
-                                output_index: Some(output_index),
-                            };
-                            batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
-                            let map_key = Key::LiveCellMap(value.out_point());
-                            let map_value = value::LiveCellMap {
-                                number: old_number,
-                                tx_index,
-                            };
-                            batch.put(&Bytes::from(&map_key), &serialize(&map_value).unwrap());
-                        }
-                        for eoa_address in block_delta.eoa_added_cells {
-                            batch.delete(&Bytes::from(&Key::EoaLiveCell(eoa_address)));
-                        }
-                        for (eoa_address, value) in block_delta.eoa_removed_cells {
-                            let key = Key::EoaLiveCell(eoa_address);
-                            batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
-                        }
-                        for contract_address in block_delta.destructed_contracts {
-                            let key_bytes =
-                                Bytes::from(&Key::ContractMeta(contract_address.clone()));
-                            let mut meta: value::ContractMeta = db_get(&self.db, &key_bytes)?
-                                .ok_or_else(|| {
-                                    format!("no such contract: {:x}", contract_address.0)
-                                })?;
-                            assert_eq!(meta.destructed, true);
-                            meta.destructed = false;
-                            batch.put(&key_bytes, &serialize(&meta).unwrap());
+            if stop.load(Ordering::SeqCst) {
+                log::info!("Indexer stopped");
+                return Ok(());
+            }
+            let next_header =
+                if let Some(value::Last { number, hash }) = db_get(&self.db, &Key::Last)? {
+                    match self.client.get_header_by_number(number + 1) {
+                        Ok(Some(header)) if header.inner.parent_hash == hash => header,
+                        // Rollback
+                        Ok(Some(_header)) => {
+                            self.reorg(value::Last { number, hash }, stop)?;
+                            continue;
                         }
-                        batch.delete(&Bytes::from(&Key::BlockMap(number)));
-                        batch.delete(&block_delta_key);
-                        // Update last block info
-                        if let Some(block_info) = last_block_info_opt {
-                            let value_bytes =
-                                serialize(&block_info).map_err(|err| err.to_string())?;
-                            batch.put(&last_block_key_bytes, &value_bytes);
+                        Ok(None) => {
+                            // Reach the tip, the tip itself may be replaced by
+                            // another block at the same height (or detached)
+                            let tip = self.client.get_header_by_number(number)?;
+                            if tip.map(|header| header.hash).as_ref() != Some(&hash) {
+                                self.reorg(value::Last { number, hash }, stop)?;
+                                continue;
+                            }
+                            // Wait 50ms for next block
+                            sleep(Duration::from_millis(50));
+                            // TODO: clean up OLD block delta here (before tip-200)
+                            continue;
                         }
-                        self.db.write(batch).map_err(|err| err.to_string())?;
-                        continue;
-                    }
-                    Ok(None) => {
-                        // Reach the tip, wait 50ms for next block
-                        sleep(Duration::from_millis(50));
-                        // TODO: clean up OLD block delta here (before tip-200)
-                        continue;
-                    }
-                    Err(err) => {
-                        log::warn!("RPC error: {}", err);
-                        sleep(Duration::from_millis(5000));
-                        continue;
+                        // Let the supervisor restart the indexer with backoff
+                        Err(err) => return Err(err.into()),
                     }
-                }
-            } else {
-                self.client.get_header_by_number(0)?.unwrap()
-            };
+                } else {
+                    self.client
+                        .get_header_by_number(0)?
+                        .ok_or_else(|| ClientError::NotFound(String::from("genesis block")))?
+                };
 
             log::debug!(
                 "get block {} => {:x}",
@@ -220,15 +168,11 @@ impl Indexer {
                     sleep(Duration::from_millis(200));
                     continue;
                 }
-                Err(err) => {
-                    log::error!("RPC error: {}", err);
-                    sleep(Duration::from_millis(1000));
-                    continue;
-                }
+                Err(err) => return Err(err.into()),
             };
 
             let next_number = next_header.inner.number.value();
-            let next_hash = next_header.hash;
+            let next_hash = next_header.hash.clone();
 
             log::info!(
                 "Process block: hash={:#x}, number={}",
@@ -242,12 +186,36 @@ impl Indexer {
 
             let mut eoa_added_cells: HashMap<H160, value::EoaLiveCell> = HashMap::new();
             let mut eoa_removed_cells: HashMap<H160, value::EoaLiveCell> = HashMap::new();
+            let mut account_txs: HashMap<(H160, u32), value::AccountTx> = HashMap::new();
             let mut added_cells: HashSet<(H256, u32, u32, value::LockLiveCell)> = HashSet::new();
             let mut removed_cells: HashSet<(H256, u64, u32, u32, value::LockLiveCell)> =
                 HashSet::new();
             let mut block_added_cells: HashMap<value::LockLiveCell, value::LiveCellMap> =
                 HashMap::default();
             let mut block_removed_cells: HashSet<value::LockLiveCell> = HashSet::default();
+            let block_tx_count = next_block.transactions.len() as u64;
+            let block_view = core::BlockView::from(next_block.clone());
+            // Fetch the transactions of all the inputs in batch
+            let prev_tx_hashes = next_block
+                .transactions
+                .iter()
+                .flat_map(|tx| tx.inner.inputs.iter())
+                .map(|input| input.previous_output.tx_hash.clone())
+                .filter(|tx_hash| tx_hash != &H256::default())
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .collect::<Vec<_>>();
+            let mut prev_txs = HashMap::with_capacity(prev_tx_hashes.len());
+            for (prev_tx_hash, tx_with_status) in prev_tx_hashes
+                .clone()
+                .into_iter()
+                .zip(self.client.get_transactions(prev_tx_hashes)?)
+            {
+                let prev_tx = tx_with_status.ok_or_else(|| {
+                    ClientError::NotFound(format!("transaction {:#x}", prev_tx_hash))
+                })?;
+                prev_txs.insert(prev_tx_hash, prev_tx.transaction.inner);
+            }
             for (tx_index, (tx, tx_hash)) in next_block
                 .transactions
                 .into_iter()
@@ -271,12 +239,7 @@ impl Indexer {
                         continue;
                     }
 
-                    let prev_tx = self
-                        .client
-                        .get_transaction(input.previous_output.tx_hash.clone())?
-                        .unwrap()
-                        .transaction
-                        .inner;
+                    let prev_tx = &prev_txs[&input.previous_output.tx_hash];
                     let prev_index = input.previous_output.index.value() as usize;
                     let output = prev_tx.outputs[prev_index].clone();
                     let output_data_size = prev_tx.outputs_data[prev_index].len() as u32;
@@ -296,14 +259,13 @@ impl Indexer {
                     }
                     let type_script = output.type_.clone().unwrap_or_default();
                     let lock_script = output.lock.clone();
-                    if data.len() == OUTPUT_DATA_LEN
-                        && type_script.code_hash == type_code_hash
-                        && type_script.hash_type == type_hash_type
-                        && type_script.args.len() == TYPE_ARGS_LEN
-                    {
+                    if let Some(address) = match_contract_cell(
+                        &type_script,
+                        data.len(),
+                        &type_code_hash,
+                        &type_hash_type,
+                    ) {
                         log::debug!("match type script: input_index={}", input_index);
-                        let address = ContractAddress::try_from(type_script.args.as_bytes())
-                            .expect("checked length");
                         let change = self.loader.load_latest_contract_change(
                             address.clone(),
                             None,
@@ -340,22 +302,27 @@ impl Indexer {
                             .map(packed::Script::from)
                             .map(|data| data.calc_script_hash().unpack()),
                     };
-                    let info: value::LiveCellMap =
-                        block_added_cells.get(&value).cloned().unwrap_or_else(|| {
-                            db_get(&self.db, &Bytes::from(&Key::LiveCellMap(out_point.clone())))
-                                .unwrap()
-                                .unwrap()
-                        });
+                    let info: value::LiveCellMap = match block_added_cells.get(&value) {
+                        Some(info) => info.clone(),
+                        None => db_get(&self.db, &Key::LiveCellMap(out_point.clone()))?
+                            .ok_or_else(|| format!("LiveCellMap not found: {}", out_point))?,
+                    };
                     if is_eoa {
+                        // The cell restored by rollback is the previous output
                         let (eoa_address, eoa_value) = eoa_record(
                             &type_script,
                             &lock_script,
-                            &tx_hash,
+                            &value.tx_hash,
                             prev_output_index as u32,
                             &output,
                             output_data_size,
                         );
                         eoa_accounts.insert(eoa_address.clone(), (eoa_value.balance(), 0));
+                        let account_tx = account_txs
+                            .entry((eoa_address.clone(), tx_index as u32))
+                            .or_default();
+                        account_tx.tx_hash = tx_hash.clone();
+                        account_tx.balance_before = Some(eoa_value.balance());
                         if eoa_added_cells.contains_key(&eoa_address) {
                             let _ = eoa_added_cells.remove(&eoa_address);
                         } else {
@@ -392,17 +359,19 @@ impl Indexer {
                     }
                     let type_script = output.type_.clone().unwrap_or_default();
                     let lock_script = output.lock.clone();
-                    if data.len() == OUTPUT_DATA_LEN
-                        && type_script.code_hash == type_code_hash
-                        && type_script.hash_type == type_hash_type
-                        && type_script.args.len() == TYPE_ARGS_LEN
-                    {
+                    if let Some(address) = match_contract_cell(
+                        &type_script,
+                        data.len(),
+                        &type_code_hash,
+                        &type_hash_type,
+                    ) {
                         log::debug!("match type script: output_index={}", output_index);
-                        let address = ContractAddress::try_from(type_script.args.as_bytes())
-                            .expect("checked length");
-                        let info = script_groups.entry(address).or_default();
+                        let info = script_groups.entry(address.clone()).or_default();
                         if info.output.is_some() {
-                            panic!("multiple output contract address");
+                            return Err(IndexerError::Fatal(format!(
+                                "multiple output contract address: {:x}, tx_hash={:#x}",
+                                address.0, tx_hash
+                            )));
                         }
                         let packed_output = packed::CellOutput::from(output.clone());
                         info.output_balance =
@@ -446,12 +415,15 @@ impl Indexer {
                             &output,
                             data_size,
                         );
-                        if eoa_removed_cells.contains_key(&eoa_address) {
-                            let _ = eoa_removed_cells.remove(&eoa_address);
-                            eoa_accounts
-                                .get_mut(&eoa_address)
-                                .expect("eoa not exists")
-                                .1 = eoa_value.balance();
+                        let account_tx = account_txs
+                            .entry((eoa_address.clone(), tx_index as u32))
+                            .or_default();
+                        account_tx.tx_hash = tx_hash.clone();
+                        account_tx.balance_after = Some(eoa_value.balance());
+                        // Keep the cell in eoa_removed_cells (if any) so that rollback
+                        // can restore the cell before this block
+                        if let Some(balances) = eoa_accounts.get_mut(&eoa_address) {
+                            balances.1 = eoa_value.balance();
                         }
                         eoa_added_cells.insert(eoa_address, eoa_value);
                     }
@@ -459,18 +431,22 @@ impl Indexer {
                 }
 
                 if let Some(tip_block_hash) = tx.header_deps.get(0) {
-                    let tip_block = self
-                        .client
-                        .get_block(tip_block_hash.clone())?
-                        .map(core::BlockView::from)
-                        .expect("block not exists");
-                    let mut header_deps = HashMap::default();
-                    for block_hash in tx.header_deps {
-                        let header_view = self
+                    let tip_block = match self.loader.cache.block(tip_block_hash) {
+                        Some(block) => block,
+                        None => self
                             .client
-                            .get_header(block_hash.clone())?
-                            .map(core::HeaderView::from)
-                            .expect("header deps block not exists");
+                            .get_block(tip_block_hash.clone())?
+                            .map(core::BlockView::from)
+                            .ok_or_else(|| {
+                                ClientError::NotFound(format!("block {:#x}", tip_block_hash))
+                            })?,
+                    };
+                    let mut header_deps = HashMap::default();
+                    let headers = self.client.get_headers(tx.header_deps.clone())?;
+                    for (block_hash, header) in tx.header_deps.into_iter().zip(headers) {
+                        let header_view = header.map(core::HeaderView::from).ok_or_else(|| {
+                            ClientError::NotFound(format!("header deps block {:#x}", block_hash))
+                        })?;
                         header_deps.insert(header_view.number(), header_view);
                     }
                     if let Some(mut extractor) = ContractExtractor::init(
@@ -490,13 +466,20 @@ impl Indexer {
                 }
             }
 
-            let mut batch = WriteBatch::default();
+            for change in &block_changes {
+                let account_tx = account_txs
+                    .entry((change.tx_origin.0.clone(), change.tx_index))
+                    .or_default();
+                account_tx.tx_hash = change.tx_hash.clone();
+                account_tx
+                    .contract_changes
+                    .push((change.address.clone(), change.output_index));
+            }
+
+            let mut batch = Batch::new(&self.db);
             // Key::BlockMap
             let block_map_value_bytes = serialize(&value::BlockMap(next_hash.clone())).unwrap();
-            batch.put(
-                &Bytes::from(&Key::BlockMap(next_number)),
-                &block_map_value_bytes,
-            );
+            batch.put(&Key::BlockMap(next_number), &block_map_value_bytes);
 
             // Key::Last
             let last_block_info = value::Last {
@@ -504,35 +487,35 @@ impl Indexer {
                 hash: next_hash.clone(),
             };
             let last_block_info_bytes = serialize(&last_block_info).unwrap();
-            batch.put(&last_block_key_bytes, &last_block_info_bytes);
+            batch.put(&Key::Last, &last_block_info_bytes);
 
             let mut block_contracts: HashMap<ContractAddress, bool> = HashMap::default();
-            for change in block_changes {
+            for change in &block_changes {
                 block_contracts.insert(change.address.clone(), change.is_create);
                 // Key::ContractChange
                 let db_value_bytes = serialize(&change.db_value()).unwrap();
-                batch.put(&Bytes::from(&change.db_key()), &db_value_bytes);
+                batch.put(&change.db_key(), &db_value_bytes);
                 if !change.is_create {
-                    let meta_key_bytes = Bytes::from(&Key::ContractMeta(change.address.clone()));
-                    let mut meta: value::ContractMeta = db_get(&self.db, &meta_key_bytes)?
+                    let meta_key = Key::ContractMeta(change.address.clone());
+                    let mut meta: value::ContractMeta = db_get(&self.db, &meta_key)?
                         .ok_or_else(|| format!("no such contract: {:x}", change.address.0))?;
                     if meta.balance != change.balance {
                         meta.balance = change.balance;
-                        batch.put(&meta_key_bytes, &serialize(&meta).unwrap());
+                        batch.put(&meta_key, &serialize(&meta).unwrap());
                     }
                 }
                 // Key::ContractLogs
                 if let Some(key_logs) = change.db_key_logs() {
                     let db_value_logs_bytes = serialize(&change.db_value_logs()).unwrap();
-                    batch.put(&Bytes::from(&key_logs), &db_value_logs_bytes);
+                    batch.put(&key_logs, &db_value_logs_bytes);
                 }
             }
-            for code in block_codes {
+            for code in &block_codes {
                 // NOTE: May have another transaction after the contract created
                 block_contracts.insert(code.address.clone(), true);
                 // Key::ContractMeta
                 let db_value_bytes = serialize(&code.db_value()).unwrap();
-                batch.put(&Bytes::from(&code.db_key()), &db_value_bytes);
+                batch.put(&code.db_key(), &db_value_bytes);
             }
             let common_cells = block_added_cells
                 .keys()
@@ -556,13 +539,13 @@ impl Indexer {
                     tx_index: Some(tx_index),
                     output_index: Some(output_index),
                 };
-                batch.put(&Bytes::from(&key), &serialize(&value).unwrap());
+                batch.put(&key, &serialize(&value).unwrap());
                 let map_key = Key::LiveCellMap(value.out_point());
                 let map_value = value::LiveCellMap {
                     number: next_number,
                     tx_index,
                 };
-                batch.put(&Bytes::from(&map_key), &serialize(&map_value).unwrap());
+                batch.put(&map_key, &serialize(&map_value).unwrap());
             }
             for (lock_hash, number, tx_index, output_index, value) in removed_cells.clone() {
                 if common_cells.contains(&value) {
@@ -579,30 +562,58 @@ impl Indexer {
                     tx_index: Some(tx_index),
                     output_index: Some(output_index),
                 };
-                batch.delete(&Bytes::from(&key));
-                batch.delete(&Bytes::from(&Key::LiveCellMap(value.out_point())));
+                batch.delete(&key);
+                batch.delete(&Key::LiveCellMap(value.out_point()));
             }
             for (eoa_address, eoa_value) in eoa_added_cells.clone() {
                 log::debug!("add eoa account: {:x}", eoa_address);
                 let key = Key::EoaLiveCell(eoa_address);
-                batch.put(&Bytes::from(&key), &serialize(&eoa_value).unwrap());
+                batch.put(&key, &serialize(&eoa_value).unwrap());
             }
-            for (eoa_address, _) in eoa_removed_cells.iter() {
+            for eoa_address in eoa_removed_cells
+                .keys()
+                .filter(|eoa_address| !eoa_added_cells.contains_key(eoa_address))
+            {
                 log::debug!("remove eoa account: {:x}", eoa_address);
                 let key = Key::EoaLiveCell(eoa_address.clone());
-                batch.delete(&Bytes::from(&key));
+                batch.delete(&key);
             }
 
             // selfdestruct
             for contract_address in &destructed_contracts {
                 // For clean up logs when rollback
                 block_contracts.insert(contract_address.clone(), false);
-                let key_bytes = Bytes::from(&Key::ContractMeta(contract_address.clone()));
-                let mut meta: value::ContractMeta = db_get(&self.db, &key_bytes)?
+                let meta_key = Key::ContractMeta(contract_address.clone());
+                let mut meta: value::ContractMeta = db_get(&self.db, &meta_key)?
                     .ok_or_else(|| format!("no such contract: {:x}", contract_address.0))?;
                 assert_eq!(meta.destructed, false);
                 meta.destructed = true;
-                batch.put(&key_bytes, &serialize(&meta).unwrap());
+                batch.put(&meta_key, &serialize(&meta).unwrap());
+            }
+            let mut eoa_balance_changes = account_txs
+                .iter()
+                .filter(|(_, account_tx)| {
+                    account_tx.balance_before.is_some() || account_tx.balance_after.is_some()
+                })
+                .map(|((eoa_address, tx_index), account_tx)| EoaBalanceChange {
+                    address: eoa_address.clone(),
+                    tx_index: *tx_index,
+                    tx_hash: account_tx.tx_hash.clone(),
+                    balance_before: account_tx.balance_before,
+                    balance_after: account_tx.balance_after,
+                })
+                .collect::<Vec<_>>();
+            eoa_balance_changes.sort_by_key(|change| (change.tx_index, change.address.clone()));
+            // Key::AccountTx
+            let mut account_tx_keys = Vec::new();
+            for ((eoa_address, tx_index), account_tx) in account_txs {
+                let key = Key::AccountTx {
+                    address: eoa_address.clone(),
+                    number: Some(next_number),
+                    tx_index: Some(tx_index),
+                };
+                batch.put(&key, &serialize(&account_tx).unwrap());
+                account_tx_keys.push((eoa_address, tx_index));
             }
             // Key::BlockDelta
             let block_delta = value::BlockDelta {
@@ -614,16 +625,615 @@ impl Indexer {
                     .map(|(eoa_address, _)| eoa_address)
                     .collect(),
                 eoa_removed_cells: eoa_removed_cells.into_iter().collect(),
-                destructed_contracts,
+                destructed_contracts: destructed_contracts.clone(),
+                account_txs: account_tx_keys,
             };
             let block_contracts_bytes = serialize(&block_delta).unwrap();
-            batch.put(
-                &Bytes::from(&Key::BlockDelta(next_number)),
-                &block_contracts_bytes,
+            batch.put(&Key::BlockDelta(next_number), &block_contracts_bytes);
+
+            batch.write()?;
+            self.loader.cache.insert_block(&block_view);
+            METRICS.indexer_blocks.inc();
+            METRICS.indexer_transactions.inc_by(block_tx_count);
+            METRICS.indexer_tip_number.set(next_number);
+            let event = IndexerEvent::Block(BlockEvent {
+                header: next_header,
+                changes: block_changes,
+                created_contracts: block_codes,
+                destructed_contracts,
+                eoa_balance_changes,
+            });
+            self.dispatch(&event, stop)?;
+        }
+    }
+
+    // Rollback to the common ancestor of the index and the chain
+    fn reorg(&mut self, last: value::Last, stop: &AtomicBool) -> Result<(), IndexerError> {
+        let client = &mut self.client;
+        let ancestor = find_common_ancestor(&self.db, &last, self.max_reorg_depth, |number| {
+            client
+                .get_header_by_number(number)
+                .map(|header| header.map(|header| header.hash))
+        })?;
+        let target = ancestor.map(|number| number + 1).unwrap_or(0);
+        log::info!(
+            "Chain reorganized, rollback {} blocks from {}",
+            last.number + 1 - target,
+            last.number
+        );
+        for number in (target..=last.number).rev() {
+            if stop.load(Ordering::SeqCst) {
+                // The remaining blocks will be rolled back after restart
+                return Ok(());
+            }
+            let block_hash: value::BlockMap = db_get(&self.db, &Key::BlockMap(number))?
+                .ok_or_else(|| format!("Can not load BlockMap({})", number))?;
+            log::info!(
+                "Rollback block, number={}, hash={:#x}",
+                number,
+                block_hash.0
             );
+            let orphaned_changes = if self.sinks.is_empty() {
+                Vec::new()
+            } else {
+                self.load_block_changes(number)?
+            };
+            rollback_block(&self.db, number)?;
+            self.loader.cache.rollback(number);
+            METRICS.indexer_rollbacks.inc();
+            METRICS.indexer_tip_number.set(number.saturating_sub(1));
+            self.status
+                .write()
+                .expect("write indexer status")
+                .last_rollback = Some(RollbackInfo {
+                number: number.saturating_sub(1),
+                depth: last.number + 1 - number,
+                time: unix_timestamp(),
+            });
+            let event = IndexerEvent::Rollback(RollbackEvent {
+                number,
+                hash: block_hash.0,
+                changes: orphaned_changes,
+            });
+            self.dispatch(&event, stop)?;
+        }
+        Ok(())
+    }
 
-            self.db.write(batch).map_err(|err| err.to_string())?;
+    // Deliver the event to all the sinks, returns early if `stop` is set while
+    // retrying (the event will be replayed on next start).
+    fn dispatch(&mut self, event: &IndexerEvent, stop: &AtomicBool) -> Result<(), String> {
+        // The indexed block after the event
+        let cursor = match event {
+            IndexerEvent::Block(block) => Some(value::EventCursor {
+                number: block.header.inner.number.value(),
+                hash: block.header.hash.clone(),
+            }),
+            IndexerEvent::Rollback(_) => {
+                db_get::<value::Last>(&self.db, &Key::Last)?.map(|last| value::EventCursor {
+                    number: last.number,
+                    hash: last.hash,
+                })
+            }
+        };
+        for sink in self.sinks.iter_mut() {
+            match sink.id() {
+                Some(sink_id) => {
+                    if !send_until_success(sink.as_mut(), event, stop) {
+                        return Ok(());
+                    }
+                    save_cursor(&self.db, &sink_id, cursor.as_ref())?;
+                }
+                None => {
+                    if let Err(err) = sink.send(event) {
+                        log::debug!("Send event failed: {}", err);
+                    }
+                }
+            }
         }
+        Ok(())
+    }
+
+    // Send the events missed by the sinks (stopped before delivered) from the
+    // database. A new sink starts from the last indexed block.
+    fn replay_events(&mut self, stop: &AtomicBool) -> Result<(), IndexerError> {
+        let last = db_get::<value::Last>(&self.db, &Key::Last)?.map(|last| value::EventCursor {
+            number: last.number,
+            hash: last.hash,
+        });
+        for index in 0..self.sinks.len() {
+            let sink_id = match self.sinks[index].id() {
+                Some(sink_id) => sink_id,
+                None => continue,
+            };
+            let mut cursor = load_cursor(&self.db, &sink_id)?;
+            if cursor.is_none() {
+                save_cursor(&self.db, &sink_id, last.as_ref())?;
+                continue;
+            }
+            while let Some(current) = cursor.clone() {
+                if stop.load(Ordering::SeqCst) {
+                    return Ok(());
+                }
+                let block_hash =
+                    db_get::<value::BlockMap>(&self.db, &Key::BlockMap(current.number))?
+                        .map(|block_hash| block_hash.0);
+                let (event, next_cursor) = if block_hash.as_ref() != Some(&current.hash) {
+                    // Rolled back before delivered (maybe by `verify-db --repair`), the
+                    // changes are already deleted. Continue from the highest indexed
+                    // block below it.
+                    let parent_number = current
+                        .number
+                        .checked_sub(1)
+                        .and_then(|number| last.as_ref().map(|last| last.number.min(number)));
+                    let parent = match parent_number {
+                        Some(number) => {
+                            db_get::<value::BlockMap>(&self.db, &Key::BlockMap(number))?.map(
+                                |block_hash| value::EventCursor {
+                                    number,
+                                    hash: block_hash.0,
+                                },
+                            )
+                        }
+                        None => None,
+                    };
+                    let event = IndexerEvent::Rollback(RollbackEvent {
+                        number: current.number,
+                        hash: current.hash,
+                        changes: Vec::new(),
+                    });
+                    (event, parent)
+                } else if last.as_ref().map(|last| last.number) > Some(current.number) {
+                    let block = self.load_block_event(current.number + 1)?;
+                    let next_cursor = value::EventCursor {
+                        number: current.number + 1,
+                        hash: block.header.hash.clone(),
+                    };
+                    (IndexerEvent::Block(block), Some(next_cursor))
+                } else {
+                    break;
+                };
+                log::info!("Replay event to sink {}", sink_id);
+                if !send_until_success(self.sinks[index].as_mut(), &event, stop) {
+                    return Ok(());
+                }
+                save_cursor(&self.db, &sink_id, next_cursor.as_ref())?;
+                cursor = next_cursor;
+            }
+        }
+        Ok(())
+    }
+
+    // Rebuild the event of an indexed block from the database
+    fn load_block_event(&mut self, number: u64) -> Result<BlockEvent, IndexerError> {
+        let block_hash: value::BlockMap = db_get(&self.db, &Key::BlockMap(number))?
+            .ok_or_else(|| format!("Can not load BlockMap({})", number))?;
+        let header = self
+            .client
+            .get_header(block_hash.0.clone())?
+            .ok_or_else(|| ClientError::NotFound(format!("header {:#x}", block_hash.0)))?;
+        let block_delta: value::BlockDelta = db_get(&self.db, &Key::BlockDelta(number))?
+            .ok_or_else(|| format!("Can not load BlockDelta({})", number))?;
+        let changes = self.load_block_changes(number)?;
+        let mut created_contracts = Vec::new();
+        for (address, is_create) in &block_delta.contracts {
+            if *is_create {
+                created_contracts.push(self.loader.load_contract_meta(address.clone())?);
+            }
+        }
+        let mut eoa_balance_changes = Vec::new();
+        for (address, tx_index) in block_delta.account_txs {
+            let key = Key::AccountTx {
+                address: address.clone(),
+                number: Some(number),
+                tx_index: Some(tx_index),
+            };
+            let account_tx: value::AccountTx =
+                db_get(&self.db, &key)?.ok_or_else(|| format!("Can not load {:?}", key))?;
+            if account_tx.balance_before.is_some() || account_tx.balance_after.is_some() {
+                eoa_balance_changes.push(EoaBalanceChange {
+                    address,
+                    tx_index,
+                    tx_hash: account_tx.tx_hash,
+                    balance_before: account_tx.balance_before,
+                    balance_after: account_tx.balance_after,
+                });
+            }
+        }
+        eoa_balance_changes.sort_by_key(|change| (change.tx_index, change.address.clone()));
+        Ok(BlockEvent {
+            header,
+            changes,
+            created_contracts,
+            destructed_contracts: block_delta.destructed_contracts,
+            eoa_balance_changes,
+        })
+    }
+
+    // Contract changes (with logs) of an indexed block
+    fn load_block_changes(&self, number: u64) -> Result<Vec<ContractChange>, String> {
+        let block_delta: value::BlockDelta = db_get(&self.db, &Key::BlockDelta(number))?
+            .ok_or_else(|| format!("Can not load BlockDelta({})", number))?;
+        let mut changes = Vec::new();
+        for (address, _) in block_delta.contracts {
+            let page = self.loader.load_contract_changes(
+                address,
+                (number, 0, 0),
+                Some(number),
+                std::usize::MAX,
+            )?;
+            changes.extend(page.changes);
+        }
+        changes.sort_by_key(|change| (change.tx_index, change.output_index));
+        Ok(changes)
+    }
+}
+/// Walk back from the last indexed block to find the highest block which is
+/// also on the chain (`chain_block_hash` returns the hash of the block on the
+/// chain by number). Returns None if all the indexed blocks must be rolled
+/// back. Fails if more than `max_depth` blocks need to be rolled back, or the
+/// `BlockDelta` of a block to roll back is missing.
+pub fn find_common_ancestor<F>(
+    db: &DB,
+    last: &value::Last,
+    max_depth: u64,
+    mut chain_block_hash: F,
+) -> Result<Option<u64>, IndexerError>
+where
+    F: FnMut(u64) -> Result<Option<H256>, ClientError>,
+{
+    let mut number = last.number;
+    let mut indexed_hash = last.hash.clone();
+    loop {
+        if chain_block_hash(number)?.as_ref() == Some(&indexed_hash) {
+            return Ok(Some(number));
+        }
+        let depth = last.number - number + 1;
+        if depth > max_depth {
+            return Err(IndexerError::Fatal(format!(
+                "Chain reorganization deeper than max reorg depth {} (last indexed block: {}, hash: {:#x}), please check the CKB node or re-index",
+                max_depth, last.number, last.hash
+            )));
+        }
+        if db_get::<value::BlockDelta>(db, &Key::BlockDelta(number))?.is_none() {
+            return Err(IndexerError::Fatal(format!(
+                "Can not rollback block {}: BlockDelta is missing",
+                number
+            )));
+        }
+        if number == 0 {
+            return Ok(None);
+        }
+        number -= 1;
+        indexed_hash = db_get::<value::BlockMap>(db, &Key::BlockMap(number))?
+            .ok_or_else(|| format!("Can not load BlockMap({})", number))?
+            .0;
+    }
+}
+
+/// Revert all the changes of the last indexed block (`number`)
+pub fn rollback_block(db: &DB, number: u64) -> Result<(), String> {
+    let block_delta_key = Key::BlockDelta(number);
+    let block_delta: value::BlockDelta = db_get(db, &block_delta_key)?
+        .ok_or_else(|| format!("Can not load BlockDelta({})", number))?;
+    let last_block_info_opt = if number >= 1 {
+        let last_block_map_key = Key::BlockMap(number - 1);
+        let block_hash: value::BlockMap = db_get(db, &last_block_map_key)?
+            .ok_or_else(|| format!("Can not load BlockMap({})", number - 1))?;
+        Some(value::Last {
+            number: number - 1,
+            hash: block_hash.0,
+        })
+    } else {
+        None
+    };
+
+    let mut batch = Batch::new(db);
+    for (address, is_create) in block_delta.contracts {
+        let change_start_key = Key::ContractChange {
+            address: address.clone(),
+            number: Some(number),
+            tx_index: None,
+            output_index: None,
+        };
+        let change_end_key = Key::ContractChange {
+            address: address.clone(),
+            number: Some(number + 1),
+            tx_index: None,
+            output_index: None,
+        };
+        let logs_start_key = Key::ContractLogs {
+            address: address.clone(),
+            number: Some(number),
+            tx_index: None,
+            output_index: None,
+        };
+        let logs_end_key = Key::ContractLogs {
+            address: address.clone(),
+            number: Some(number + 1),
+            tx_index: None,
+            output_index: None,
+        };
+        batch.delete_range(&change_start_key, &change_end_key);
+        batch.delete_range(&logs_start_key, &logs_end_key);
+        if is_create {
+            batch.delete(&Key::ContractMeta(address));
+        }
+    }
+    for (lock_hash, tx_index, output_index, value) in block_delta.added_cells {
+        batch.delete(&Key::LockLiveCell {
+            lock_hash,
+            number: Some(number),
+            tx_index: Some(tx_index),
+            output_index: Some(output_index),
+        });
+        batch.delete(&Key::LiveCellMap(value.out_point()));
+    }
+    for (lock_hash, old_number, tx_index, output_index, value) in block_delta.removed_cells {
+        let key = Key::LockLiveCell {
+            lock_hash,
+            number: Some(old_number),
+            tx_index: Some(tx_index),
+            output_index: Some(output_index),
+        };
+        batch.put(&key, &serialize(&value).unwrap());
+        let map_key = Key::LiveCellMap(value.out_point());
+        let map_value = value::LiveCellMap {
+            number: old_number,
+            tx_index,
+        };
+        batch.put(&map_key, &serialize(&map_value).unwrap());
+    }
+    for eoa_address in block_delta.eoa_added_cells {
+        batch.delete(&Key::EoaLiveCell(eoa_address));
+    }
+    for (eoa_address, value) in block_delta.eoa_removed_cells {
+        let key = Key::EoaLiveCell(eoa_address);
+        batch.put(&key, &serialize(&value).unwrap());
+    }
+    for contract_address in block_delta.destructed_contracts {
+        let meta_key = Key::ContractMeta(contract_address.clone());
+        let mut meta: value::ContractMeta = db_get(db, &meta_key)?
+            .ok_or_else(|| format!("no such contract: {:x}", contract_address.0))?;
+        assert_eq!(meta.destructed, true);
+        meta.destructed = false;
+        batch.put(&meta_key, &serialize(&meta).unwrap());
+    }
+    for (address, tx_index) in block_delta.account_txs {
+        batch.delete(&Key::AccountTx {
+            address,
+            number: Some(number),
+            tx_index: Some(tx_index),
+        });
+    }
+    batch.delete(&Key::BlockMap(number));
+    batch.delete(&block_delta_key);
+    // Update last block info
+    if let Some(block_info) = last_block_info_opt {
+        let value_bytes = serialize(&block_info).map_err(|err| err.to_string())?;
+        batch.put(&Key::Last, &value_bytes);
+    } else {
+        // Index from genesis again
+        batch.delete(&Key::Last);
+    }
+    batch.write()
+}
+
+// Retry until the sink accepted the event, return false if `stop` is set
+fn send_until_success(sink: &mut dyn EventSink, event: &IndexerEvent, stop: &AtomicBool) -> bool {
+    loop {
+        match sink.send(event) {
+            Ok(()) => return true,
+            Err(err) => {
+                log::warn!(
+                    "Send event to sink {} failed: {}",
+                    sink.id().unwrap_or_default(),
+                    err
+                );
+                sleep_unless_stopped(stop, Duration::from_millis(1000));
+                if stop.load(Ordering::SeqCst) {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+// Sleep for `duration`, but wake up early when `stop` is set
+pub fn sleep_unless_stopped(stop: &AtomicBool, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while !stop.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        sleep(std::cmp::min(deadline - now, Duration::from_millis(100)));
+    }
+}
+
+/// Re-execute a committed (and indexed) transaction from its witnesses on the
+/// contract states right before the transaction, return the call tree of the
+/// entrance program.
+pub fn replay_transaction(
+    loader: &mut Loader,
+    run_config: &RunConfig,
+    tx_hash: &H256,
+) -> Result<CallFrame, String> {
+    let (type_code_hash, type_hash_type) = script_code(&run_config.type_script);
+    let (eoa_lock_code_hash, eoa_lock_hash_type) = script_code(&run_config.eoa_lock_script);
+
+    let tx_with_status = loader
+        .load_transactions(vec![tx_hash.clone()])?
+        .pop()
+        .flatten()
+        .ok_or_else(|| format!("Transaction not found: {:#x}", tx_hash))?;
+    let block_hash = tx_with_status
+        .tx_status
+        .block_hash
+        .ok_or_else(|| format!("Transaction not committed: {:#x}", tx_hash))?;
+    let block = loader.load_block(Some(block_hash.clone()))?;
+    let number = block.number();
+    let indexed_hash =
+        db_get::<value::BlockMap>(&loader.db, &Key::BlockMap(number))?.map(|block_map| block_map.0);
+    if indexed_hash.as_ref() != Some(&block_hash) {
+        return Err(format!("Block not indexed: {:#x}", block_hash));
+    }
+    let tx_index = block
+        .tx_hashes()
+        .iter()
+        .position(|hash| &Unpack::<H256>::unpack(hash) == tx_hash)
+        .ok_or_else(|| format!("Transaction not in block: {:#x}", tx_hash))?
+        as u32;
+
+    let tx = tx_with_status.transaction.inner;
+    let prev_tx_hashes = tx
+        .inputs
+        .iter()
+        .map(|input| input.previous_output.tx_hash.clone())
+        .filter(|tx_hash| tx_hash != &H256::default())
+        .collect::<Vec<_>>();
+    let mut prev_txs = HashMap::with_capacity(prev_tx_hashes.len());
+    for (prev_tx_hash, tx_with_status) in prev_tx_hashes
+        .clone()
+        .into_iter()
+        .zip(loader.load_transactions(prev_tx_hashes)?)
+    {
+        let prev_tx =
+            tx_with_status.ok_or_else(|| format!("Transaction not found: {:#x}", prev_tx_hash))?;
+        prev_txs.insert(prev_tx_hash, prev_tx.transaction.inner);
+    }
+
+    let mut script_groups: HashMap<ContractAddress, ContractInfo> = HashMap::default();
+    let mut eoa_accounts: HashMap<H160, (u64, u64)> = HashMap::default();
+    for (input_index, input) in tx.inputs.iter().enumerate() {
+        if input.previous_output.tx_hash == H256::default() {
+            continue;
+        }
+        let prev_tx = &prev_txs[&input.previous_output.tx_hash];
+        let prev_index = input.previous_output.index.value() as usize;
+        let output = &prev_tx.outputs[prev_index];
+        let data_size = prev_tx.outputs_data[prev_index].len();
+        let type_script = output.type_.clone().unwrap_or_default();
+        if let Some(address) =
+            match_contract_cell(&type_script, data_size, &type_code_hash, &type_hash_type)
+        {
+            // The last change before the transaction
+            let change = loader
+                .load_contract_changes(address.clone(), (number, tx_index, 0), Some(number), 1)?
+                .previous
+                .ok_or_else(|| format!("Contract change not found: {:x}", address.0))?;
+            let mut info = ContractInfo::default();
+            info.tree = change.merkle_tree();
+            info.input = Some((input_index, change));
+            info.input_balance = info.init_balance();
+            info.input_capacity = output.capacity.value();
+            script_groups.insert(address, info);
+        }
+        if is_eoa(
+            &type_script,
+            &output.lock,
+            &eoa_lock_code_hash,
+            &eoa_lock_hash_type,
+        ) {
+            let (eoa_address, eoa_value) = eoa_record(
+                &type_script,
+                &output.lock,
+                tx_hash,
+                prev_index as u32,
+                output,
+                data_size as u32,
+            );
+            eoa_accounts.insert(eoa_address, (eoa_value.balance(), 0));
+        }
+    }
+    for (output_index, output) in tx.outputs.iter().enumerate() {
+        let data_size = tx.outputs_data[output_index].len();
+        let type_script = output.type_.clone().unwrap_or_default();
+        if let Some(address) =
+            match_contract_cell(&type_script, data_size, &type_code_hash, &type_hash_type)
+        {
+            let info = script_groups.entry(address).or_default();
+            let packed_output = packed::CellOutput::from(output.clone());
+            info.output_balance = cell_balance(&packed_output, (data_size as u64) * ONE_CKB);
+            info.output_capacity = output.capacity.value();
+            info.output = Some((output_index, packed_output));
+        }
+        if is_eoa(
+            &type_script,
+            &output.lock,
+            &eoa_lock_code_hash,
+            &eoa_lock_hash_type,
+        ) {
+            let (eoa_address, eoa_value) = eoa_record(
+                &type_script,
+                &output.lock,
+                tx_hash,
+                output_index as u32,
+                output,
+                data_size as u32,
+            );
+            if let Some(balances) = eoa_accounts.get_mut(&eoa_address) {
+                balances.1 = eoa_value.balance();
+            }
+        }
+    }
+
+    let tip_block_hash = tx
+        .header_deps
+        .get(0)
+        .cloned()
+        .ok_or_else(|| format!("Transaction has no header deps: {:#x}", tx_hash))?;
+    let tip_block = loader.load_block(Some(tip_block_hash))?;
+    let mut header_deps = HashMap::default();
+    let headers = loader.load_headers(tx.header_deps.clone())?;
+    for (block_hash, header) in tx.header_deps.iter().zip(headers) {
+        let header_view = header
+            .map(core::HeaderView::from)
+            .ok_or_else(|| format!("Header not found: {:#x}", block_hash))?;
+        header_deps.insert(header_view.number(), header_view);
+    }
+    let mut extractor = ContractExtractor::init(
+        run_config.clone(),
+        tip_block,
+        header_deps,
+        (tx_hash.clone(), tx_index),
+        tx.witnesses,
+        script_groups,
+        eoa_accounts,
+    )?
+    .ok_or_else(|| format!("Not a contract transaction: {:#x}", tx_hash))?;
+    extractor.tracer = Some(Tracer::default());
+    if let Err(err) = extractor.run() {
+        log::debug!("replay transaction {:#x} failed: {}", tx_hash, err);
+    }
+    extractor
+        .tracer
+        .as_mut()
+        .and_then(|tracer| tracer.take_root())
+        .ok_or_else(|| String::from("no call frame recorded"))
+}
+
+// The code hash and hash type (in JSON type) of the script
+fn script_code(script: &packed::Script) -> (H256, ScriptHashType) {
+    let ty = core::ScriptHashType::try_from(script.hash_type()).unwrap();
+    (script.code_hash().unpack(), ScriptHashType::from(ty))
+}
+
+// The contract address if the cell is a contract cell
+fn match_contract_cell(
+    type_script: &Script,
+    data_len: usize,
+    type_code_hash: &H256,
+    type_hash_type: &ScriptHashType,
+) -> Option<ContractAddress> {
+    if data_len == OUTPUT_DATA_LEN
+        && &type_script.code_hash == type_code_hash
+        && &type_script.hash_type == type_hash_type
+        && type_script.args.len() == TYPE_ARGS_LEN
+    {
+        Some(ContractAddress::try_from(type_script.args.as_bytes()).expect("checked length"))
+    } else {
+        None
     }
 }
 
@@ -682,6 +1292,15 @@ pub struct ContractExtractor {
     script_groups: HashMap<ContractAddress, ContractInfo>,
     // EoA address => (input_balance, output_balance)
     eoa_accounts: HashMap<H160, (u64, u64)>,
+
+    // Record the call tree when replaying a transaction
+    tracer: Option<Tracer>,
+    // Total cycles consumed by all the programs
+    cycles: u64,
+    // The cycles of current machine when last syscall (or finish) happened
+    machine_cycles: u64,
+    // Precompiled contracts run natively
+    precompiles: Precompiles,
 }
 
 #[derive(Default)]
@@ -693,6 +1312,8 @@ pub struct ContractInfo {
     programs: Vec<WitnessData>,
     call_indices: Vec<usize>,
     special_call_count: usize,
+    // The record of the running program (special call included)
+    record_index: usize,
     // Updated by ckb-vm
     logs: Vec<(Vec<H256>, Bytes)>,
     pub run_result: RunResult,
@@ -739,10 +1360,13 @@ impl ContractInfo {
     }
 
     pub fn current_call_index(&self) -> usize {
-        self.call_indices[self.program_index]
+        self.call_indices[self.record_index]
     }
     pub fn current_call_index_mut(&mut self) -> &mut usize {
-        &mut self.call_indices[self.program_index]
+        &mut self.call_indices[self.record_index]
+    }
+    pub fn running_witness(&self) -> &WitnessData {
+        &self.programs[self.record_index]
     }
     pub fn current_witness(&self) -> &WitnessData {
         &self.programs[self.program_index]
@@ -886,10 +1510,33 @@ impl ContractExtractor {
                 current_contract,
                 script_groups,
                 eoa_accounts,
+                tracer: None,
+                cycles: 0,
+                machine_cycles: 0,
+                precompiles: Precompiles::default(),
             }
         }))
     }
 
+    /// Same as the runner and the validator, the code of the contracts not in
+    /// the transaction is in the witness of the entrance program.
+    pub fn get_code_hash(&self, address: &ContractAddress) -> H256 {
+        if let Some(info) = self.script_groups.get(address) {
+            return code_hash(&info.code());
+        }
+        self.script_groups
+            .get(&self.entrance_contract)
+            .and_then(|info| info.programs.first())
+            .and_then(|witness_data| {
+                witness_data
+                    .code_deps
+                    .iter()
+                    .find(|(dep_address, _)| dep_address == &address.0)
+            })
+            .map(|(_, code)| code_hash(code))
+            .unwrap_or_default()
+    }
+
     pub fn run(&mut self) -> Result<(), Box<dyn StdError>> {
         let entrance_contract = self.entrance_contract.clone();
         self.run_with(&entrance_contract, false).map(|_| ())
@@ -901,20 +1548,21 @@ impl ContractExtractor {
         is_special_call: bool,
     ) -> Result<Bytes, Box<dyn StdError>> {
         self.current_contract = contract.clone();
-        let (tree_clone, saved_program_index, program, program_data) = {
+        let (tree_clone, record_index, saved_record_index, program, program_data) = {
             let info = self
                 .script_groups
                 .get_mut(contract)
                 .ok_or_else(|| format!("No such contract to run: {:x}", contract.0))?;
             let tree_clone = SparseMerkleTree::new(*info.tree.root(), info.tree.store().clone());
-            let saved_program_index = info.program_index;
-            let program_index = if is_special_call {
+            // Special calls (maybe nested) are recorded after current program
+            let record_index = if is_special_call {
                 info.special_call_count += 1;
                 info.program_index + info.special_call_count
             } else {
                 info.program_index
             };
-            let program = info.programs[program_index].program.clone();
+            let saved_record_index = std::mem::replace(&mut info.record_index, record_index);
+            let program = info.programs[record_index].program.clone();
             let program_data = if program.kind.is_special_call() {
                 let dest_info = self
                     .script_groups
@@ -926,16 +1574,35 @@ impl ContractExtractor {
             } else {
                 info.current_program_data()
             };
-            (tree_clone, saved_program_index, program, program_data)
+            (
+                tree_clone,
+                record_index,
+                saved_record_index,
+                program,
+                program_data,
+            )
         };
 
-        self.handle_transfer(&program.sender, &contract.0, program.value);
+        // Special call run in the caller's context, the value is not transferred
+        if !program.kind.is_special_call() {
+            self.handle_transfer(&program.sender, &contract.0, program.value)?;
+        }
 
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.enter(CallFrame::new(&program, program.destination.clone()));
+        }
         let config = Config::from(&self.run_config);
-        let _result = match run_with_context(&config, &tree_clone, &program_data, self) {
+        let saved_cycles = self.cycles;
+        let saved_machine_cycles = std::mem::replace(&mut self.machine_cycles, 0);
+        let result = run_with_context(&config, &tree_clone, &program_data, self);
+        self.cycles += self.machine_cycles;
+        self.machine_cycles = saved_machine_cycles;
+        let cycles = self.cycles - saved_cycles;
+        let _result = match result {
             Ok(result) => result,
             Err(err) => {
                 log::warn!("Error: {:?}", err);
+                self.trace_exit(cycles, None, Some(err.to_string()));
                 return Err(err);
             }
         };
@@ -944,22 +1611,40 @@ impl ContractExtractor {
             .get_mut(contract)
             .ok_or_else(|| format!("No such contract to run: {:x}", contract.0))?;
 
-        let program_index = if program.kind.is_special_call() {
-            info.program_index + info.special_call_count
-        } else {
-            saved_program_index
-        };
-        let return_data = info.programs[program_index].return_data.clone();
+        let return_data = info.programs[record_index].return_data.clone();
+        info.record_index = saved_record_index;
         if !program.kind.is_special_call() {
             let run_result = std::mem::take(&mut info.run_result);
+            if let Some(frame) = self.tracer.as_mut().and_then(|tracer| tracer.current_mut()) {
+                frame.set_storage(&run_result);
+            }
             run_result.commit(&mut info.tree).unwrap();
             info.program_index += info.special_call_count + 1;
             info.special_call_count = 0;
         }
+        self.trace_exit(cycles, Some(&return_data), None);
         Ok(return_data)
     }
 
-    fn handle_transfer(&mut self, sender: &H160, destination: &H160, value: u64) {
+    fn trace_exit(&mut self, cycles: u64, return_data: Option<&Bytes>, error: Option<String>) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Some(frame) = tracer.current_mut() {
+                frame.cycles = cycles;
+                frame.error = error;
+                if let Some(return_data) = return_data {
+                    frame.return_data = JsonBytes::from_bytes(return_data.clone());
+                }
+            }
+            tracer.exit();
+        }
+    }
+
+    fn handle_transfer(
+        &mut self,
+        sender: &H160,
+        destination: &H160,
+        value: u64,
+    ) -> Result<(), String> {
         // handle trasnfer
         log::debug!(
             ">> transfer {} wei from {:x} to {:x}",
@@ -968,14 +1653,14 @@ impl ContractExtractor {
             destination
         );
         if &self.tx_origin.0 == sender {
-            self.eoa_sub_balance(sender, value);
+            self.eoa_sub_balance(sender, value)?;
         } else {
-            self.contract_sub_balance(&ContractAddress(sender.clone()), value);
+            self.contract_sub_balance(&ContractAddress(sender.clone()), value)?;
         }
         if self.eoa_accounts.contains_key(destination) {
-            self.eoa_add_balance(destination, value);
+            self.eoa_add_balance(destination, value)
         } else {
-            self.contract_add_balance(&ContractAddress(destination.clone()), value);
+            self.contract_add_balance(&ContractAddress(destination.clone()), value)
         }
     }
 
@@ -998,53 +1683,69 @@ impl ContractExtractor {
             .collect()
     }
 
-    pub fn eoa_sub_balance(&mut self, address: &H160, value: u64) {
+    pub fn eoa_sub_balance(&mut self, address: &H160, value: u64) -> Result<(), String> {
         log::debug!("subbing {} value from eoa {:x}", value, address);
         let input_balance = &mut self
             .eoa_accounts
             .get_mut(address)
-            .expect("eoa account not exists")
+            .ok_or_else(|| format!("eoa account {:x} not exists", address))?
             .0;
         if *input_balance < value {
-            panic!("eoa sub balance faield: {} < {}", input_balance, value);
+            return Err(format!(
+                "eoa sub balance failed: {} < {}",
+                input_balance, value
+            ));
         }
         *input_balance -= value;
+        Ok(())
     }
-    pub fn eoa_add_balance(&mut self, address: &H160, value: u64) {
+    pub fn eoa_add_balance(&mut self, address: &H160, value: u64) -> Result<(), String> {
         log::debug!("adding {} value to eoa {:x}", value, address);
         let input_balance = &mut self
             .eoa_accounts
             .get_mut(address)
-            .expect("eoa account not exists")
+            .ok_or_else(|| format!("eoa account {:x} not exists", address))?
             .0;
         *input_balance += value;
+        Ok(())
     }
-    pub fn contract_sub_balance(&mut self, address: &ContractAddress, value: u64) {
+    pub fn contract_sub_balance(
+        &mut self,
+        address: &ContractAddress,
+        value: u64,
+    ) -> Result<(), String> {
         log::debug!("subbing {} value from contract {:x}", value, address.0);
         let info = self
             .script_groups
             .get_mut(address)
-            .expect("contract account not exists");
+            .ok_or_else(|| format!("contract account {:x} not exists", address.0))?;
         if info.input_balance < value {
-            panic!(
-                "contract sub balance faield: {} < {}",
+            return Err(format!(
+                "contract sub balance failed: {} < {}",
                 info.input_balance, value
-            );
+            ));
         }
         info.input_balance -= value;
+        Ok(())
     }
-    pub fn contract_add_balance(&mut self, address: &ContractAddress, value: u64) {
+    pub fn contract_add_balance(
+        &mut self,
+        address: &ContractAddress,
+        value: u64,
+    ) -> Result<(), String> {
         log::debug!("adding {} value to contract {:x}", value, address.0);
         let info = self
             .script_groups
             .get_mut(address)
-            .expect("contract account not exists");
+            .ok_or_else(|| format!("contract account {:x} not exists", address.0))?;
         info.input_balance += value;
+        Ok(())
     }
 }
 
 impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
     fn ecall(&mut self, machine: &mut Mac) -> Result<bool, VMError> {
+        self.machine_cycles = machine.cycles();
         let code = machine.registers()[A7].to_u64();
         match code {
             // ckb_debug
@@ -1066,6 +1767,9 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
 
                 let s = String::from_utf8(buffer).map_err(|_| VMError::ParseError)?;
                 log::debug!("ckb_debug: {}", s);
+                if let Some(frame) = self.tracer.as_mut().and_then(|tracer| tracer.current_mut()) {
+                    frame.debug.push(s);
+                }
                 Ok(true)
             }
             // insert
@@ -1116,11 +1820,15 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 let data_address = machine.registers()[A0].to_u64();
                 let data_length = machine.registers()[A1].to_u32();
                 let data = vm_load_data(machine, data_address, data_length)?;
+                let (topics, data) = parse_log(&data[..]).unwrap();
+                if let Some(frame) = self.tracer.as_mut().and_then(|tracer| tracer.current_mut()) {
+                    frame.add_log(&self.current_contract, topics.clone(), data.clone());
+                }
                 self.script_groups
                     .get_mut(&self.current_contract)
                     .unwrap()
                     .logs
-                    .push(parse_log(&data[..]).unwrap());
+                    .push((topics, data));
                 Ok(true)
             }
             // SELFDESTRUCT
@@ -1134,11 +1842,15 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                     .get(&self.current_contract)
                     .expect("selfdestruct account info")
                     .input_capacity;
-                if self.eoa_accounts.contains_key(&address) {
-                    self.eoa_add_balance(&address, input_capacity);
+                let result = if self.eoa_accounts.contains_key(&address) {
+                    self.eoa_add_balance(&address, input_capacity)
                 } else {
-                    self.contract_add_balance(&ContractAddress(address), input_capacity);
-                }
+                    self.contract_add_balance(&ContractAddress(address), input_capacity)
+                };
+                result.map_err(|err| {
+                    log::warn!("selfdestruct failed: {}", err);
+                    VMError::Unexpected
+                })?;
                 let info = self
                     .script_groups
                     .get_mut(&self.current_contract)
@@ -1165,7 +1877,7 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 msg_data_address += 20;
                 let input_size: u32 = vm_load_u32(machine, msg_data_address)?;
                 msg_data_address += 4;
-                let _input_data: Vec<u8> = vm_load_data(machine, msg_data_address, input_size)?;
+                let input_data: Vec<u8> = vm_load_data(machine, msg_data_address, input_size)?;
                 msg_data_address += input_size as u64;
                 let _value: U256 = vm_load_u256(machine, msg_data_address)?;
 
@@ -1181,7 +1893,7 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                     info_mut.program_index
                 );
                 let call_record =
-                    info_mut.current_witness().calls[info_mut.current_call_index()].clone();
+                    info_mut.running_witness().calls[info_mut.current_call_index()].clone();
                 *info_mut.current_call_index_mut() += 1;
                 if kind.is_call() {
                     assert_eq!(
@@ -1190,8 +1902,74 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                     );
                 };
 
-                let (return_data, create_address) = if call_record.transfer_only {
-                    self.handle_transfer(&sender, &call_record.destination, call_record.value);
+                let (return_data, create_address) = if call_record.is_precompile {
+                    let result = self
+                        .precompiles
+                        .execute(&call_record.destination, &input_data)
+                        .unwrap_or_else(|| {
+                            Err(format!(
+                                "not a precompiled contract: {:x}",
+                                call_record.destination
+                            ))
+                        });
+                    // Charged the same as the runner, the limits are already
+                    // checked by the runner (and the chain)
+                    let cycles = self
+                        .precompiles
+                        .cycles(&call_record.destination, &input_data)
+                        .unwrap_or(0);
+                    self.cycles = self.cycles.saturating_add(cycles);
+                    if let Some(tracer) = self.tracer.as_mut() {
+                        let program = Program {
+                            kind,
+                            sender: sender.clone(),
+                            destination: call_record.destination.clone(),
+                            input: Bytes::from(input_data),
+                            ..Default::default()
+                        };
+                        let mut frame = CallFrame::new(&program, program.destination.clone());
+                        frame.cycles = cycles;
+                        match result {
+                            Ok(ref return_data) => {
+                                frame.return_data = JsonBytes::from_bytes(return_data.clone())
+                            }
+                            Err(ref err) => frame.error = Some(err.clone()),
+                        }
+                        tracer.enter(frame);
+                        tracer.exit();
+                    }
+                    let return_data = result.map_err(|err| {
+                        log::warn!(
+                            "precompiled contract {:x} failed: {}",
+                            call_record.destination,
+                            err
+                        );
+                        VMError::Unexpected
+                    })?;
+                    (return_data, Default::default())
+                } else if call_record.transfer_only {
+                    // The sender is the contract whose storage is in use
+                    let sender = if kind == CallKind::DELEGATECALL {
+                        sender
+                    } else {
+                        self.current_contract.0.clone()
+                    };
+                    self.handle_transfer(&sender, &call_record.destination, call_record.value)
+                        .map_err(|err| {
+                            log::warn!("transfer failed: {}", err);
+                            VMError::Unexpected
+                        })?;
+                    if let Some(tracer) = self.tracer.as_mut() {
+                        let program = Program {
+                            kind,
+                            sender: sender.clone(),
+                            destination: call_record.destination.clone(),
+                            value: call_record.value,
+                            ..Default::default()
+                        };
+                        tracer.enter(CallFrame::new(&program, program.destination.clone()));
+                        tracer.exit();
+                    }
                     (Default::default(), Default::default())
                 } else {
                     let info_address = if kind.is_special_call() {
@@ -1273,11 +2051,15 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
             3081 => {
                 let block_hash_ptr = machine.registers()[A0].to_u64();
                 let number = machine.registers()[A1].to_u64();
-                let header_view = self.header_deps.get(&number).ok_or_else(|| {
-                    log::warn!("get_block_hash({}), load header failed", number);
-                    VMError::IO(std::io::ErrorKind::InvalidInput)
-                })?;
-                let block_hash: H256 = header_view.hash().unpack();
+                let block_hash: H256 = if block_hash_available(self.tip_block.number(), number) {
+                    let header_view = self.header_deps.get(&number).ok_or_else(|| {
+                        log::warn!("get_block_hash({}), load header failed", number);
+                        VMError::IO(std::io::ErrorKind::InvalidInput)
+                    })?;
+                    header_view.hash().unpack()
+                } else {
+                    Default::default()
+                };
                 machine
                     .memory_mut()
                     .store_bytes(block_hash_ptr, block_hash.as_bytes())?;
@@ -1351,7 +2133,269 @@ impl<Mac: SupportMachine> RunContext<Mac> for ContractExtractor {
                 machine.set_register(A0, Mac::REG::from_u8(0));
                 Ok(true)
             }
+            // evmc_bytes32 get_code_hash
+            3084 => {
+                let address_ptr = machine.registers()[A0].to_u64();
+                let address: H160 = vm_load_h160(machine, address_ptr)?;
+                let code_hash_ptr = machine.registers()[A1].to_u64();
+                let hash = self.get_code_hash(&ContractAddress(address.clone()));
+                log::debug!("[get_code_hash]: address={:x}, hash={:x}", address, hash);
+                machine
+                    .memory_mut()
+                    .store_bytes(code_hash_ptr, hash.as_bytes())?;
+                machine.set_register(A0, Mac::REG::from_u8(0));
+                Ok(true)
+            }
+            // finish (the cycles are already sampled above)
+            3085 => Ok(true),
             _ => Ok(false),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::super::db::{open_db, DbOptions};
+    use super::super::supervisor::IndexerStatus;
+    use super::*;
+    use crate::mock_chain::{eoa_output, index_until, test_run_config, MockChain};
+    use ckb_types::h160;
+
+    fn block_hash(fork: u8, number: u64) -> H256 {
+        let mut bytes = [0u8; 32];
+        bytes[0] = fork;
+        bytes[24..].copy_from_slice(&number.to_be_bytes());
+        H256::from(bytes)
+    }
+
+    // Index empty blocks 0..=tip of fork 0
+    fn index_chain(db: &DB, tip: u64) -> value::Last {
+        let mut batch = Batch::new(db);
+        for number in 0..=tip {
+            let block_map = value::BlockMap(block_hash(0, number));
+            batch.put(&Key::BlockMap(number), &serialize(&block_map).unwrap());
+            let block_delta = value::BlockDelta::default();
+            batch.put(&Key::BlockDelta(number), &serialize(&block_delta).unwrap());
+        }
+        let last = value::Last {
+            number: tip,
+            hash: block_hash(0, tip),
+        };
+        batch.put(&Key::Last, &serialize(&last).unwrap());
+        batch.write().unwrap();
+        last
+    }
+
+    // Mocked CKB chain: blocks since `fork_number` are on fork 1
+    fn mock_chain(
+        fork_number: u64,
+        tip: u64,
+    ) -> impl FnMut(u64) -> Result<Option<H256>, ClientError> {
+        move |number| {
+            Ok(if number > tip {
+                None
+            } else if number < fork_number {
+                Some(block_hash(0, number))
+            } else {
+                Some(block_hash(1, number))
+            })
+        }
+    }
+
+    #[test]
+    fn test_no_reorg() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        let last = index_chain(&db, 5);
+        let ancestor = find_common_ancestor(&db, &last, 10, mock_chain(10, 8)).unwrap();
+        assert_eq!(ancestor, Some(5));
+    }
+
+    #[test]
+    fn test_reorg_multiple_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        let last = index_chain(&db, 5);
+        let ancestor = find_common_ancestor(&db, &last, 10, mock_chain(3, 6)).unwrap();
+        assert_eq!(ancestor, Some(2));
+
+        for number in (3..=5).rev() {
+            rollback_block(&db, number).unwrap();
+        }
+        let new_last: value::Last = db_get(&db, &Key::Last).unwrap().unwrap();
+        assert_eq!(new_last.number, 2);
+        assert_eq!(new_last.hash, block_hash(0, 2));
+        assert!(db_get::<value::BlockMap>(&db, &Key::BlockMap(3))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_reorg_shorter_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        let last = index_chain(&db, 5);
+        let ancestor = find_common_ancestor(&db, &last, 10, mock_chain(10, 3)).unwrap();
+        assert_eq!(ancestor, Some(3));
+    }
+
+    #[test]
+    fn test_reorg_from_genesis() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        let last = index_chain(&db, 2);
+        let ancestor = find_common_ancestor(&db, &last, 10, mock_chain(0, 4)).unwrap();
+        assert_eq!(ancestor, None);
+
+        for number in (0..=2).rev() {
+            rollback_block(&db, number).unwrap();
+        }
+        assert!(db_get::<value::Last>(&db, &Key::Last).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reorg_too_deep() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        let last = index_chain(&db, 5);
+        match find_common_ancestor(&db, &last, 3, mock_chain(2, 6)) {
+            Err(IndexerError::Fatal(err)) => assert!(err.contains("max reorg depth")),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        // Exactly max depth
+        let ancestor = find_common_ancestor(&db, &last, 3, mock_chain(3, 6)).unwrap();
+        assert_eq!(ancestor, Some(2));
+    }
+
+    #[test]
+    fn test_reorg_missing_block_delta() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        let last = index_chain(&db, 5);
+        let mut batch = Batch::new(&db);
+        batch.delete(&Key::BlockDelta(4));
+        batch.write().unwrap();
+        match find_common_ancestor(&db, &last, 10, mock_chain(3, 6)) {
+            Err(IndexerError::Fatal(err)) => assert!(err.contains("BlockDelta")),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    fn eoa_transfer(
+        inputs: Vec<packed::OutPoint>,
+        outputs: Vec<packed::CellOutput>,
+    ) -> core::TransactionView {
+        core::TransactionBuilder::default()
+            .inputs(
+                inputs
+                    .into_iter()
+                    .map(|out_point| packed::CellInput::new(out_point, 0)),
+            )
+            .outputs_data(outputs.iter().map(|_| Bytes::default().pack()))
+            .outputs(outputs)
+            .build()
+    }
+
+    fn eoa_cell(db: &DB, address: &H160) -> Option<value::EoaLiveCell> {
+        db_get(db, &Key::EoaLiveCell(address.clone())).unwrap()
+    }
+
+    #[test]
+    fn test_index_eoa_transfer_with_reorg() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open_db(dir.path(), &DbOptions::default()).unwrap());
+        let run_config = test_run_config(Bytes::default());
+        let eoa = |lock_arg: &H160, type_id: &H256, capacity: u64| {
+            eoa_output(&run_config, lock_arg, type_id, capacity * ONE_CKB)
+        };
+        let (alice_output, alice) = eoa(&h160!("0x1"), &h256!("0x1"), 1000);
+        let genesis_tx = eoa_transfer(Vec::new(), vec![alice_output]);
+        let chain = MockChain::new(vec![genesis_tx.clone()]);
+        let mut indexer = Indexer::with_chain(
+            Arc::clone(&db),
+            Box::new(chain.clone()),
+            run_config.clone(),
+            IndexerStatus::new_shared(),
+        );
+        index_until(&mut indexer, 0);
+        let genesis_cell = eoa_cell(&db, &alice).unwrap();
+        assert_eq!(genesis_cell.capacity(), 1000 * ONE_CKB);
+
+        // Alice transfers to Bob, the change goes back to Alice
+        let alice_input = packed::OutPoint::new(genesis_tx.hash(), 0);
+        let (bob_output, bob) = eoa(&h160!("0x2"), &h256!("0x2"), 499);
+        let tx = eoa_transfer(
+            vec![alice_input.clone()],
+            vec![eoa(&h160!("0x1"), &h256!("0x1"), 500).0, bob_output],
+        );
+        chain.produce_block(vec![tx.clone()]);
+        index_until(&mut indexer, 1);
+        let alice_cell = eoa_cell(&db, &alice).unwrap();
+        assert_eq!(alice_cell.tx_hash, tx.hash().unpack());
+        assert_eq!(alice_cell.capacity(), 500 * ONE_CKB);
+        assert_eq!(eoa_cell(&db, &bob).unwrap().capacity(), 499 * ONE_CKB);
+
+        // Block 1 is replaced, Alice transfers to Carol instead
+        chain.rollback(0);
+        let (carol_output, carol) = eoa(&h160!("0x3"), &h256!("0x3"), 299);
+        let fork_tx = eoa_transfer(
+            vec![alice_input],
+            vec![eoa(&h160!("0x1"), &h256!("0x1"), 700).0, carol_output],
+        );
+        let fork_block = chain.produce_block(vec![fork_tx.clone()]);
+        // The reorg is noticed when the next block's parent hash mismatches
+        chain.produce_block(Vec::new());
+        index_until(&mut indexer, 2);
+        let block_map: value::BlockMap = db_get(&db, &Key::BlockMap(1)).unwrap().unwrap();
+        assert_eq!(block_map.0, fork_block.hash().unpack());
+        let alice_cell = eoa_cell(&db, &alice).unwrap();
+        assert_eq!(alice_cell.tx_hash, fork_tx.hash().unpack());
+        assert_eq!(alice_cell.capacity(), 700 * ONE_CKB);
+        assert!(eoa_cell(&db, &bob).is_none());
+        assert_eq!(eoa_cell(&db, &carol).unwrap().capacity(), 299 * ONE_CKB);
+
+        // Rollback all the blocks after genesis restores Alice's genesis cell
+        rollback_block(&db, 2).unwrap();
+        rollback_block(&db, 1).unwrap();
+        assert_eq!(eoa_cell(&db, &alice), Some(genesis_cell));
+        assert!(eoa_cell(&db, &carol).is_none());
+    }
+
+    #[test]
+    fn test_reorg_at_tip() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open_db(dir.path(), &DbOptions::default()).unwrap());
+        let run_config = test_run_config(Bytes::default());
+        let eoa = |lock_arg: &H160, type_id: &H256, capacity: u64| {
+            eoa_output(&run_config, lock_arg, type_id, capacity * ONE_CKB)
+        };
+        let (alice_output, _alice) = eoa(&h160!("0x1"), &h256!("0x1"), 1000);
+        let genesis_tx = eoa_transfer(Vec::new(), vec![alice_output]);
+        let chain = MockChain::new(vec![genesis_tx.clone()]);
+        let mut indexer = Indexer::with_chain(
+            Arc::clone(&db),
+            Box::new(chain.clone()),
+            run_config.clone(),
+            IndexerStatus::new_shared(),
+        );
+        let alice_input = packed::OutPoint::new(genesis_tx.hash(), 0);
+        let (bob_output, bob) = eoa(&h160!("0x2"), &h256!("0x2"), 999);
+        chain.produce_block(vec![eoa_transfer(
+            vec![alice_input.clone()],
+            vec![bob_output],
+        )]);
+        index_until(&mut indexer, 1);
+        assert!(eoa_cell(&db, &bob).is_some());
+
+        // Block 1 is replaced by another block 1, no block follows it yet
+        chain.rollback(0);
+        let (carol_output, carol) = eoa(&h160!("0x3"), &h256!("0x3"), 999);
+        let fork_block =
+            chain.produce_block(vec![eoa_transfer(vec![alice_input], vec![carol_output])]);
+        index_until(&mut indexer, 1);
+        let block_map: value::BlockMap = db_get(&db, &Key::BlockMap(1)).unwrap().unwrap();
+        assert_eq!(block_map.0, fork_block.hash().unpack());
+        assert!(eoa_cell(&db, &bob).is_none());
+        assert_eq!(eoa_cell(&db, &carol).unwrap().capacity(), 999 * ONE_CKB);
+    }
+}
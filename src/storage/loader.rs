@@ -2,35 +2,59 @@ use bincode::deserialize;
 use ckb_jsonrpc_types as json_types;
 use ckb_types::{
     bytes::Bytes,
-    core::{BlockView, EpochNumberWithFraction, HeaderView, ScriptHashType},
+    core::{BlockView, EpochNumberWithFraction, HeaderView},
     packed,
     prelude::*,
     H160, H256, U256,
 };
 use rocksdb::DB;
+use serde::Serialize;
 use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::sync::Arc;
 
-use super::{db_get, value, Key};
-use crate::client::HttpRpcClient;
+use super::cache::ChainCache;
+use super::db::{cf_handle, property_int_value_sum};
+use super::{db_get, value, Key, KeyType};
+use crate::client::ChainSource;
 use crate::types::{
-    ContractAddress, ContractChange, ContractMeta, EoaAddress, LogInfo, CELLBASE_MATURITY,
-    SIGHASH_TYPE_HASH,
+    sighash_lock, ContractAddress, ContractChange, ContractMeta, EoaAddress, LogInfo,
+    CELLBASE_MATURITY,
 };
 
+/// RocksDB size estimates
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DbStats {
+    pub estimate_num_keys: Option<u64>,
+    pub estimate_live_data_size: Option<u64>,
+    pub total_sst_files_size: Option<u64>,
+    pub cur_size_all_mem_tables: Option<u64>,
+}
+
+/// A page of contract changes
+pub struct ContractChanges {
+    /// The change before the first change
+    pub previous: Option<ContractChange>,
+    pub changes: Vec<ContractChange>,
+    /// The position (block_number, tx_index, output_index) of next change
+    pub next: Option<(u64, u32, u32)>,
+}
+
 #[derive(Clone)]
 pub struct Loader {
     pub db: Arc<DB>,
-    client: HttpRpcClient,
+    /// Recent indexed blocks, shared by all the clones
+    pub cache: Arc<ChainCache>,
+    client: Box<dyn ChainSource>,
 }
 
 impl Loader {
-    pub fn new(db: Arc<DB>, ckb_uri: &str) -> Result<Loader, String> {
-        Ok(Loader {
+    pub fn with_chain(db: Arc<DB>, client: Box<dyn ChainSource>) -> Loader {
+        Loader {
             db,
-            client: HttpRpcClient::new(ckb_uri.to_string()),
-        })
+            cache: Default::default(),
+            client,
+        }
     }
 
     pub fn load_contract_live_cell(
@@ -56,8 +80,7 @@ impl Loader {
         &mut self,
         eoa_address: H160,
     ) -> Result<(value::EoaLiveCell, packed::CellOutput, Bytes), String> {
-        let key_bytes = Bytes::from(&Key::EoaLiveCell(eoa_address.clone()));
-        let value = db_get::<_, value::EoaLiveCell>(&self.db, &key_bytes)?
+        let value = db_get::<value::EoaLiveCell>(&self.db, &Key::EoaLiveCell(eoa_address.clone()))?
             .ok_or_else(|| format!("eoa live cell not found: {:x}", eoa_address))?;
         let cell_with_status = self.client.get_live_cell(value.out_point().into(), true)?;
         let cell = cell_with_status.cell.ok_or_else(|| {
@@ -73,20 +96,14 @@ impl Loader {
         ))
     }
 
-    // TODO: remove #[allow(unused)] later
-    #[allow(unused)]
+    /// Collect the plain secp256k1 (sighash) cells owned by `sender` (the lock
+    /// arg) until the total capacity reaches `min_capacity`
     pub fn collect_cells(
         &mut self,
         sender: EoaAddress,
         min_capacity: u64,
     ) -> Result<(Vec<packed::OutPoint>, u64), String> {
-        let lock_hash: H256 = packed::Script::new_builder()
-            .code_hash(SIGHASH_TYPE_HASH.pack())
-            .hash_type(ScriptHashType::Type.into())
-            .args(Bytes::from(sender.0.as_bytes().to_vec()).pack())
-            .build()
-            .calc_script_hash()
-            .unpack();
+        let lock_hash: H256 = sighash_lock(&sender.0).calc_script_hash().unpack();
         let key_prefix_bytes = Bytes::from(&Key::LockLiveCell {
             lock_hash,
             number: None,
@@ -94,11 +111,13 @@ impl Loader {
             output_index: None,
         });
 
-        let max_mature_number = get_max_mature_number(&mut self.client)?;
+        let max_mature_number = get_max_mature_number(self.client.as_mut())?;
         let mut total_capacity: u64 = 0;
         let mut live_cells = Vec::new();
 
-        let mut iter = self.db.raw_iterator();
+        let mut iter = self
+            .db
+            .raw_iterator_cf(cf_handle(&self.db, KeyType::LockLiveCell));
         iter.seek(&key_prefix_bytes);
         while iter.valid() {
             if let Some((key_bytes, value_bytes)) = iter
@@ -170,6 +189,20 @@ impl Loader {
                 return Err(format!("Contract already destructed: {:x}", address.0));
             }
         }
+        let next_block_number = block_number
+            .map(|number| number + 1)
+            .unwrap_or(std::u64::MAX);
+        self.load_contract_change_before(address.clone(), next_block_number, load_logs)?
+            .ok_or_else(|| format!("Latest contract change not found: {:x}", address.0))
+    }
+
+    /// Load the last contract change before block `next_block_number`
+    pub fn load_contract_change_before(
+        &self,
+        address: ContractAddress,
+        next_block_number: u64,
+        load_logs: bool,
+    ) -> Result<Option<ContractChange>, String> {
         let prefix_key = Key::ContractChange {
             address: address.clone(),
             number: None,
@@ -177,18 +210,16 @@ impl Loader {
             output_index: None,
         };
         let prefix_key_bytes = Bytes::from(&prefix_key);
-
-        let next_block_number = block_number
-            .map(|number| number + 1)
-            .unwrap_or(std::u64::MAX);
         let last_key = Key::ContractChange {
-            address: address.clone(),
+            address,
             number: Some(next_block_number),
             tx_index: None,
             output_index: None,
         };
 
-        let mut iter = self.db.raw_iterator();
+        let mut iter = self
+            .db
+            .raw_iterator_cf(cf_handle(&self.db, KeyType::ContractChange));
         iter.seek_for_prev(&Bytes::from(&last_key));
         let is_valid = iter.valid();
         if let Some((key_bytes, value_bytes)) = iter
@@ -196,52 +227,136 @@ impl Loader {
             .filter(|key| is_valid && key.starts_with(&prefix_key_bytes))
             .and_then(|key| iter.value().map(|value| (key, value)))
         {
-            let value: value::ContractChange =
-                deserialize(value_bytes).map_err(|err| err.to_string())?;
-            let (address, number, tx_index, output_index) = match Key::try_from(key_bytes)? {
-                Key::ContractChange {
-                    address,
-                    number,
-                    tx_index,
-                    output_index,
-                } => (
-                    address,
-                    number.expect("illegal key"),
-                    tx_index.expect("illegal key"),
-                    output_index.expect("illegal key"),
-                ),
-                _ => {
-                    panic!("DB corrupted deserialize Key::ContractChange");
+            self.decode_contract_change(key_bytes, value_bytes, load_logs)
+                .map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Load contract changes in ascending order, start from
+    /// `(block_number, tx_index, output_index)`. At most `limit` changes are
+    /// loaded, the change before start is also loaded for computing diffs.
+    pub fn load_contract_changes(
+        &self,
+        address: ContractAddress,
+        start: (u64, u32, u32),
+        to_block: Option<u64>,
+        limit: usize,
+    ) -> Result<ContractChanges, String> {
+        let prefix_key_bytes = Bytes::from(&Key::ContractChange {
+            address: address.clone(),
+            number: None,
+            tx_index: None,
+            output_index: None,
+        });
+        let start_key_bytes = Bytes::from(&Key::ContractChange {
+            address,
+            number: Some(start.0),
+            tx_index: Some(start.1),
+            output_index: Some(start.2),
+        });
+        let to_block = to_block.unwrap_or(std::u64::MAX);
+
+        let mut iter = self
+            .db
+            .raw_iterator_cf(cf_handle(&self.db, KeyType::ContractChange));
+        iter.seek_for_prev(&start_key_bytes);
+        if iter.valid() && iter.key() == Some(start_key_bytes.as_ref()) {
+            iter.prev();
+        }
+        let is_valid = iter.valid();
+        let previous = match iter
+            .key()
+            .filter(|key| is_valid && key.starts_with(&prefix_key_bytes))
+            .and_then(|key| iter.value().map(|value| (key, value)))
+        {
+            Some((key_bytes, value_bytes)) => {
+                Some(self.decode_contract_change(key_bytes, value_bytes, false)?)
+            }
+            None => None,
+        };
+
+        let mut changes = Vec::new();
+        let mut next = None;
+        iter.seek(&start_key_bytes);
+        while iter.valid() {
+            if let Some((key_bytes, value_bytes)) = iter
+                .key()
+                .filter(|key| key.starts_with(&prefix_key_bytes))
+                .and_then(|key| iter.value().map(|value| (key, value)))
+            {
+                let change = self.decode_contract_change(key_bytes, value_bytes, true)?;
+                if change.number > to_block {
+                    break;
                 }
-            };
-            let logs = if load_logs {
-                let logs_key_bytes = Bytes::from(&Key::ContractLogs {
-                    address: address.clone(),
-                    number: Some(number),
-                    tx_index: Some(tx_index),
-                    output_index: Some(output_index),
-                });
-                db_get(&self.db, &logs_key_bytes)?
-                    .map(|logs: value::ContractLogs| logs.0)
-                    .unwrap_or_default()
+                if changes.len() >= limit {
+                    next = Some((change.number, change.tx_index, change.output_index));
+                    break;
+                }
+                changes.push(change);
             } else {
-                Vec::new()
-            };
-            return Ok(ContractChange {
-                tx_origin: value.tx_origin,
+                break;
+            }
+            iter.next();
+        }
+        Ok(ContractChanges {
+            previous,
+            changes,
+            next,
+        })
+    }
+
+    fn decode_contract_change(
+        &self,
+        key_bytes: &[u8],
+        value_bytes: &[u8],
+        load_logs: bool,
+    ) -> Result<ContractChange, String> {
+        let value: value::ContractChange =
+            deserialize(value_bytes).map_err(|err| err.to_string())?;
+        let (address, number, tx_index, output_index) = match Key::try_from(key_bytes)? {
+            Key::ContractChange {
                 address,
-                tx_hash: value.tx_hash,
-                new_storage: value.new_storage.into_iter().collect(),
-                capacity: value.capacity,
-                balance: value.balance,
-                is_create: value.is_create,
                 number,
                 tx_index,
                 output_index,
-                logs,
-            });
-        }
-        Err(format!("Latest contract change not found: {:x}", address.0))
+            } => (
+                address,
+                number.expect("illegal key"),
+                tx_index.expect("illegal key"),
+                output_index.expect("illegal key"),
+            ),
+            _ => {
+                panic!("DB corrupted deserialize Key::ContractChange");
+            }
+        };
+        let logs = if load_logs {
+            let logs_key = Key::ContractLogs {
+                address: address.clone(),
+                number: Some(number),
+                tx_index: Some(tx_index),
+                output_index: Some(output_index),
+            };
+            db_get(&self.db, &logs_key)?
+                .map(|logs: value::ContractLogs| logs.0)
+                .unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        Ok(ContractChange {
+            tx_origin: value.tx_origin,
+            address,
+            tx_hash: value.tx_hash,
+            new_storage: value.new_storage.into_iter().collect(),
+            capacity: value.capacity,
+            balance: value.balance,
+            is_create: value.is_create,
+            number,
+            tx_index,
+            output_index,
+            logs,
+        })
     }
 
     pub fn load_contract_meta_list(
@@ -255,8 +370,8 @@ impl Loader {
 
         let mut all_metas = Vec::new();
         for number in from_block..=to_block {
-            let key_bytes = Bytes::from(&Key::BlockDelta(number));
-            let block_delta = match db_get::<_, value::BlockDelta>(&self.db, &key_bytes)? {
+            let block_delta = match db_get::<value::BlockDelta>(&self.db, &Key::BlockDelta(number))?
+            {
                 Some(block_delta) => block_delta,
                 None => {
                     return Ok(all_metas);
@@ -268,8 +383,8 @@ impl Loader {
                 .filter(|(_, is_create)| *is_create)
                 .map(|(addr, _)| addr)
             {
-                let key_bytes = Bytes::from(&Key::ContractMeta(addr.clone()));
-                let meta = db_get::<_, value::ContractMeta>(&self.db, &key_bytes)?.unwrap();
+                let meta_key = Key::ContractMeta(addr.clone());
+                let meta = db_get::<value::ContractMeta>(&self.db, &meta_key)?.unwrap();
                 all_metas.push((
                     number,
                     ContractMeta {
@@ -287,8 +402,8 @@ impl Loader {
     }
 
     pub fn load_contract_meta(&self, address: ContractAddress) -> Result<ContractMeta, String> {
-        let key_bytes = Bytes::from(&Key::ContractMeta(address.clone()));
-        if let Some(value) = db_get::<_, value::ContractMeta>(&self.db, &key_bytes)? {
+        let meta_key = Key::ContractMeta(address.clone());
+        if let Some(value) = db_get::<value::ContractMeta>(&self.db, &meta_key)? {
             Ok(ContractMeta {
                 address,
                 code: value.code,
@@ -317,8 +432,8 @@ impl Loader {
 
         let mut all_logs = Vec::new();
         for number in from_block..=to_block {
-            let key_bytes = Bytes::from(&Key::BlockDelta(number));
-            let block_delta = match db_get::<_, value::BlockDelta>(&self.db, &key_bytes)? {
+            let block_delta = match db_get::<value::BlockDelta>(&self.db, &Key::BlockDelta(number))?
+            {
                 Some(block_delta) => block_delta,
                 None => {
                     return Ok(all_logs);
@@ -336,7 +451,9 @@ impl Loader {
                     tx_index: None,
                     output_index: None,
                 });
-                let mut iter = self.db.raw_iterator();
+                let mut iter = self
+                    .db
+                    .raw_iterator_cf(cf_handle(&self.db, KeyType::ContractLogs));
                 iter.seek(&key_prefix_bytes);
                 while iter.valid() {
                     if let Some((key_bytes, value_bytes)) = iter
@@ -381,23 +498,146 @@ impl Loader {
         Ok(all_logs)
     }
 
+    /// Load the transactions of an EoA account in ascending order, start from
+    /// `(block_number, tx_index)`. Return at most `limit` records and the
+    /// position of next record if there are more.
+    pub fn load_account_txs(
+        &self,
+        address: H160,
+        start: (u64, u32),
+        to_block: Option<u64>,
+        limit: usize,
+    ) -> Result<(Vec<(u64, u32, value::AccountTx)>, Option<(u64, u32)>), String> {
+        let key_prefix_bytes = Bytes::from(&Key::AccountTx {
+            address: address.clone(),
+            number: None,
+            tx_index: None,
+        });
+        let start_key = Key::AccountTx {
+            address,
+            number: Some(start.0),
+            tx_index: Some(start.1),
+        };
+        let to_block = to_block.unwrap_or(std::u64::MAX);
+
+        let mut account_txs = Vec::new();
+        let mut iter = self
+            .db
+            .raw_iterator_cf(cf_handle(&self.db, KeyType::AccountTx));
+        iter.seek(&Bytes::from(&start_key));
+        while iter.valid() {
+            if let Some((key_bytes, value_bytes)) = iter
+                .key()
+                .filter(|key| key.starts_with(&key_prefix_bytes))
+                .and_then(|key| iter.value().map(|value| (key, value)))
+            {
+                let (number, tx_index) = match Key::try_from(key_bytes)? {
+                    Key::AccountTx {
+                        number, tx_index, ..
+                    } => (number.expect("illegal key"), tx_index.expect("illegal key")),
+                    _ => {
+                        panic!("DB corrupted deserialize Key::AccountTx");
+                    }
+                };
+                if number > to_block {
+                    break;
+                }
+                if account_txs.len() >= limit {
+                    return Ok((account_txs, Some((number, tx_index))));
+                }
+                let value: value::AccountTx =
+                    deserialize(value_bytes).map_err(|err| err.to_string())?;
+                account_txs.push((number, tx_index, value));
+            } else {
+                break;
+            }
+            iter.next();
+        }
+        Ok((account_txs, None))
+    }
+
     pub fn load_header_deps(&mut self, inputs: &[packed::CellInput]) -> Result<Vec<H256>, String> {
-        inputs
+        let tx_hashes = inputs
             .iter()
-            .map(|input| {
-                let tx_hash = Unpack::<H256>::unpack(&input.previous_output().tx_hash());
-                self.client
-                    .get_transaction(tx_hash.clone())?
-                    .ok_or_else(|| format!("Transaction not found for input: {:?}", input))?
-                    .tx_status
-                    .block_hash
-                    .ok_or_else(|| format!("Transaction not committed: {:x}", tx_hash))
-            })
-            .collect()
+            .map(|input| input.previous_output().tx_hash().unpack())
+            .collect::<Vec<H256>>();
+        let mut block_hashes = tx_hashes
+            .iter()
+            .map(|tx_hash| self.cache.tx_block_hash(tx_hash))
+            .collect::<Vec<Option<H256>>>();
+        let missing_tx_hashes = tx_hashes
+            .iter()
+            .zip(block_hashes.iter())
+            .filter(|(_, block_hash)| block_hash.is_none())
+            .map(|(tx_hash, _)| tx_hash.clone())
+            .collect::<Vec<H256>>();
+        if !missing_tx_hashes.is_empty() {
+            let mut fetched = self
+                .client
+                .get_transactions(missing_tx_hashes.clone())?
+                .into_iter()
+                .zip(missing_tx_hashes);
+            for block_hash in block_hashes.iter_mut().filter(|hash| hash.is_none()) {
+                let (tx_with_status, tx_hash) = fetched.next().expect("fetched transaction");
+                *block_hash = Some(
+                    tx_with_status
+                        .ok_or_else(|| format!("Transaction not found for input: {:x}", tx_hash))?
+                        .tx_status
+                        .block_hash
+                        .ok_or_else(|| format!("Transaction not committed: {:x}", tx_hash))?,
+                );
+            }
+        }
+        Ok(block_hashes
+            .into_iter()
+            .map(|block_hash| block_hash.expect("loaded block hash"))
+            .collect())
     }
 
+    pub fn load_transactions(
+        &mut self,
+        tx_hashes: Vec<H256>,
+    ) -> Result<Vec<Option<json_types::TransactionWithStatus>>, String> {
+        Ok(self.client.get_transactions(tx_hashes)?)
+    }
+
+    pub fn load_headers(
+        &mut self,
+        block_hashes: Vec<H256>,
+    ) -> Result<Vec<Option<json_types::HeaderView>>, String> {
+        Ok(self.client.get_headers(block_hashes)?)
+    }
+
+    pub fn load_last_block(&self) -> Result<Option<value::Last>, String> {
+        db_get(&self.db, &Key::Last)
+    }
+
+    /// Estimate the number of keys of the key type without scanning the column
+    /// family, it's inaccurate after deletions until compaction.
+    pub fn estimate_num_keys(&self, key_type: KeyType) -> Result<u64, String> {
+        self.db
+            .property_int_value_cf(cf_handle(&self.db, key_type), "rocksdb.estimate-num-keys")
+            .map(|value| value.unwrap_or(0))
+            .map_err(|err| err.to_string())
+    }
+
+    pub fn load_db_stats(&self) -> Result<DbStats, String> {
+        let property = |name: &str| property_int_value_sum(&self.db, name);
+        Ok(DbStats {
+            estimate_num_keys: property("rocksdb.estimate-num-keys")?,
+            estimate_live_data_size: property("rocksdb.estimate-live-data-size")?,
+            total_sst_files_size: property("rocksdb.total-sst-files-size")?,
+            cur_size_all_mem_tables: property("rocksdb.cur-size-all-mem-tables")?,
+        })
+    }
+
+    /// Load the header by number, or the tip header of the chain (not the
+    /// indexed tip) if number is None
     pub fn load_header(&mut self, number_opt: Option<u64>) -> Result<HeaderView, String> {
         let header = if let Some(number) = number_opt {
+            if let Some(header) = self.cache.header_by_number(number) {
+                return Ok(header);
+            }
             self.client
                 .get_header_by_number(number)?
                 .ok_or_else(|| format!("Block #{} not exists", number))?
@@ -407,10 +647,18 @@ impl Loader {
         Ok(HeaderView::from(header))
     }
 
+    /// Load the block by hash, or the indexed tip block if hash is None (the
+    /// tip of the chain if nothing indexed yet)
     pub fn load_block(&mut self, hash_opt: Option<H256>) -> Result<BlockView, String> {
         let hash = if let Some(hash) = hash_opt {
+            if let Some(block) = self.cache.block(&hash) {
+                return Ok(block);
+            }
             hash
         } else {
+            if let Some(block) = self.cache.tip_block() {
+                return Ok(block);
+            }
             self.client.get_tip_header()?.hash
         };
         self.client
@@ -421,7 +669,7 @@ impl Loader {
 }
 
 // Get max mature block number
-pub fn get_max_mature_number(client: &mut HttpRpcClient) -> Result<u64, String> {
+pub fn get_max_mature_number(client: &mut dyn ChainSource) -> Result<u64, String> {
     let tip_epoch = client
         .get_tip_header()
         .map(|header| EpochNumberWithFraction::from_full_value(header.inner.epoch.value()))?;
@@ -0,0 +1,364 @@
+use bincode::{deserialize, serialize};
+use ckb_types::{bytes::Bytes, H160, H256};
+use rocksdb::{WriteBatch, DB};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+
+use super::db::{cf_handle, is_empty, Batch};
+use super::{db_get, value, Key, KeyType};
+use crate::types::ContractAddress;
+
+/// The schema version this polyjuice works with. When the layout of `Key` or
+/// `value::*` changes, bump it and register a migration in `MIGRATIONS`.
+pub const SCHEMA_VERSION: u32 = 3;
+
+struct Migration {
+    /// The schema version after this migration
+    version: u32,
+    description: &'static str,
+    run: fn(&DB) -> Result<(), String>,
+}
+
+/// All the migrations, ordered by version
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "add schema version",
+        run: migrate_v1,
+    },
+    Migration {
+        version: 2,
+        description: "move keys into column families",
+        run: migrate_v2,
+    },
+    Migration {
+        version: 3,
+        description: "add account transactions index",
+        run: migrate_v3,
+    },
+];
+
+/// How many keys are moved in one write batch
+const MIGRATE_BATCH_SIZE: usize = 10000;
+
+/// Upgrade the database to `SCHEMA_VERSION` in place. A database without
+/// version key but with data is created before versioning (version 0), and a
+/// database with newer version is refused.
+pub fn migrate(db: &DB) -> Result<(), String> {
+    let current_version = match load_version(db)? {
+        Some(version) => version,
+        None if is_empty(db) => {
+            log::info!("Initialize database schema version: {}", SCHEMA_VERSION);
+            return save_version(db, SCHEMA_VERSION);
+        }
+        None => 0,
+    };
+    if current_version > SCHEMA_VERSION {
+        return Err(format!(
+            "Database schema version {} is newer than supported version {}, please upgrade polyjuice",
+            current_version, SCHEMA_VERSION
+        ));
+    }
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > current_version)
+    {
+        log::info!(
+            "Migrate database to version {}: {}",
+            migration.version,
+            migration.description
+        );
+        (migration.run)(db)?;
+        save_version(db, migration.version)?;
+    }
+    Ok(())
+}
+
+pub fn load_version(db: &DB) -> Result<Option<u32>, String> {
+    db_get::<value::Version>(db, &Key::Version)
+        .map(|version_opt| version_opt.map(|version| version.0))
+}
+
+fn save_version(db: &DB, version: u32) -> Result<(), String> {
+    let value_bytes = serialize(&value::Version(version)).map_err(|err| err.to_string())?;
+    db.put_cf(
+        cf_handle(db, KeyType::Version),
+        &Bytes::from(&Key::Version),
+        &value_bytes,
+    )
+    .map_err(|err| err.to_string())
+}
+
+// Version 0 => 1: only the version key is added
+fn migrate_v1(_db: &DB) -> Result<(), String> {
+    Ok(())
+}
+
+// Version 1 => 2: all keys except version key are moved from the default
+// column family into the column family of their key type. Interrupted
+// migration can be run again.
+fn migrate_v2(db: &DB) -> Result<(), String> {
+    let default_cf = cf_handle(db, KeyType::Version);
+    let mut moved: u64 = 0;
+    loop {
+        let mut batch = WriteBatch::default();
+        let mut batch_size = 0;
+        let mut iter = db.raw_iterator_cf(default_cf);
+        iter.seek_to_first();
+        while iter.valid() && batch_size < MIGRATE_BATCH_SIZE {
+            let key = iter.key().unwrap();
+            let key_type = KeyType::try_from(key[0])?;
+            if key_type != KeyType::Version {
+                batch.put_cf(cf_handle(db, key_type), key, iter.value().unwrap());
+                batch.delete_cf(default_cf, key);
+                batch_size += 1;
+            }
+            iter.next();
+        }
+        drop(iter);
+        if batch_size == 0 {
+            break;
+        }
+        db.write(batch).map_err(|err| err.to_string())?;
+        moved += batch_size as u64;
+        log::info!("Moved {} keys into column families", moved);
+    }
+    Ok(())
+}
+
+/// The layout of `value::BlockDelta` before version 3
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct BlockDeltaV2 {
+    contracts: Vec<(ContractAddress, bool)>,
+    added_cells: Vec<(H256, u32, u32, value::LockLiveCell)>,
+    removed_cells: Vec<(H256, u64, u32, u32, value::LockLiveCell)>,
+    eoa_added_cells: Vec<H160>,
+    eoa_removed_cells: Vec<(H160, value::EoaLiveCell)>,
+    destructed_contracts: Vec<ContractAddress>,
+}
+
+// Version 2 => 3: add Key::AccountTx and `BlockDelta.account_txs`. Only the
+// contract calls are backfilled (from Key::ContractChange), the EoA cell
+// balance changes of indexed blocks are not recoverable.
+fn migrate_v3(db: &DB) -> Result<(), String> {
+    // (tx_origin, number, tx_index) => AccountTx
+    let mut account_txs: BTreeMap<(H160, u64, u32), value::AccountTx> = BTreeMap::new();
+    let mut iter = db.raw_iterator_cf(cf_handle(db, KeyType::ContractChange));
+    iter.seek_to_first();
+    while iter.valid() {
+        if let Key::ContractChange {
+            address,
+            number: Some(number),
+            tx_index: Some(tx_index),
+            output_index: Some(output_index),
+        } = Key::try_from(iter.key().unwrap())?
+        {
+            let change: value::ContractChange =
+                deserialize(iter.value().unwrap()).map_err(|err| err.to_string())?;
+            let account_tx = account_txs
+                .entry((change.tx_origin.0, number, tx_index))
+                .or_default();
+            account_tx.tx_hash = change.tx_hash;
+            account_tx.contract_changes.push((address, output_index));
+        }
+        iter.next();
+    }
+    drop(iter);
+
+    let mut batch = Batch::new(db);
+    let mut batch_size = 0;
+    let mut block_account_txs: HashMap<u64, Vec<(H160, u32)>> = HashMap::new();
+    for ((address, number, tx_index), account_tx) in account_txs {
+        let key = Key::AccountTx {
+            address: address.clone(),
+            number: Some(number),
+            tx_index: Some(tx_index),
+        };
+        batch.put(
+            &key,
+            &serialize(&account_tx).map_err(|err| err.to_string())?,
+        );
+        block_account_txs
+            .entry(number)
+            .or_default()
+            .push((address, tx_index));
+        batch_size += 1;
+        if batch_size >= MIGRATE_BATCH_SIZE {
+            std::mem::replace(&mut batch, Batch::new(db)).write()?;
+            batch_size = 0;
+        }
+    }
+
+    let mut iter = db.raw_iterator_cf(cf_handle(db, KeyType::BlockDelta));
+    iter.seek_to_first();
+    while iter.valid() {
+        let number = match Key::try_from(iter.key().unwrap())? {
+            Key::BlockDelta(number) => number,
+            key => return Err(format!("Invalid BlockDelta key: {:?}", key)),
+        };
+        let old: BlockDeltaV2 =
+            deserialize(iter.value().unwrap()).map_err(|err| err.to_string())?;
+        let block_delta = value::BlockDelta {
+            contracts: old.contracts,
+            added_cells: old.added_cells,
+            removed_cells: old.removed_cells,
+            eoa_added_cells: old.eoa_added_cells,
+            eoa_removed_cells: old.eoa_removed_cells,
+            destructed_contracts: old.destructed_contracts,
+            account_txs: block_account_txs.remove(&number).unwrap_or_default(),
+        };
+        batch.put(
+            &Key::BlockDelta(number),
+            &serialize(&block_delta).map_err(|err| err.to_string())?,
+        );
+        batch_size += 1;
+        if batch_size >= MIGRATE_BATCH_SIZE {
+            std::mem::replace(&mut batch, Batch::new(db)).write()?;
+            batch_size = 0;
+        }
+        iter.next();
+    }
+    drop(iter);
+    batch.write()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::db::{open_db, DbOptions};
+    use super::*;
+    use crate::types::EoaAddress;
+    use ckb_types::{h160, h256};
+
+    #[test]
+    fn test_migrations_order() {
+        let mut last_version = 0;
+        for migration in MIGRATIONS {
+            assert_eq!(migration.version, last_version + 1);
+            last_version = migration.version;
+        }
+        assert_eq!(last_version, SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_migrate_empty_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        migrate(&db).unwrap();
+        assert_eq!(load_version(&db).unwrap(), Some(SCHEMA_VERSION));
+        // Migrate again is ok
+        migrate(&db).unwrap();
+        assert_eq!(load_version(&db).unwrap(), Some(SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn test_migrate_unversioned_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let last = value::Last {
+            number: 3,
+            hash: h256!("0x3"),
+        };
+        {
+            // The layout before column families
+            let db = DB::open_default(dir.path()).unwrap();
+            db.put(&Bytes::from(&Key::Last), &serialize(&last).unwrap())
+                .unwrap();
+        }
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        assert_eq!(load_version(&db).unwrap(), None);
+        migrate(&db).unwrap();
+        assert_eq!(load_version(&db).unwrap(), Some(SCHEMA_VERSION));
+        let value = db_get::<value::Last>(&db, &Key::Last).unwrap().unwrap();
+        assert_eq!(value.number, 3);
+    }
+
+    #[test]
+    fn test_migrate_v2() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = vec![
+            Key::Last,
+            Key::BlockMap(1),
+            Key::BlockMap(2),
+            Key::BlockDelta(2),
+        ];
+        {
+            let db = DB::open_default(dir.path()).unwrap();
+            for key in &keys {
+                db.put(&Bytes::from(key), &[1, 2, 3]).unwrap();
+            }
+            db.put(
+                &Bytes::from(&Key::Version),
+                &serialize(&value::Version(1)).unwrap(),
+            )
+            .unwrap();
+        }
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        migrate_v2(&db).unwrap();
+        let default_cf = cf_handle(&db, KeyType::Version);
+        for key in &keys {
+            let key_bytes = Bytes::from(key);
+            assert_eq!(db.get_cf(default_cf, &key_bytes).unwrap(), None);
+            let value = db
+                .get_cf(cf_handle(&db, key.key_type()), &key_bytes)
+                .unwrap();
+            assert_eq!(value.as_deref(), Some(&[1u8, 2, 3][..]));
+        }
+    }
+
+    #[test]
+    fn test_migrate_v3() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        let contract_address = ContractAddress(h160!("0xab"));
+        let tx_origin = h160!("0xcd");
+        let change_key = Key::ContractChange {
+            address: contract_address.clone(),
+            number: Some(5),
+            tx_index: Some(1),
+            output_index: Some(2),
+        };
+        let change = value::ContractChange {
+            tx_hash: h256!("0x55"),
+            tx_origin: EoaAddress(tx_origin.clone()),
+            new_storage: Vec::new(),
+            capacity: 100,
+            balance: 0,
+            is_create: true,
+        };
+        let old_block_delta = BlockDeltaV2 {
+            contracts: vec![(contract_address.clone(), true)],
+            added_cells: Vec::new(),
+            removed_cells: Vec::new(),
+            eoa_added_cells: Vec::new(),
+            eoa_removed_cells: Vec::new(),
+            destructed_contracts: Vec::new(),
+        };
+        let mut batch = Batch::new(&db);
+        batch.put(&change_key, &serialize(&change).unwrap());
+        batch.put(&Key::BlockDelta(5), &serialize(&old_block_delta).unwrap());
+        batch.write().unwrap();
+        save_version(&db, 2).unwrap();
+
+        migrate(&db).unwrap();
+        assert_eq!(load_version(&db).unwrap(), Some(3));
+        let account_tx_key = Key::AccountTx {
+            address: tx_origin.clone(),
+            number: Some(5),
+            tx_index: Some(1),
+        };
+        let account_tx: value::AccountTx = db_get(&db, &account_tx_key).unwrap().unwrap();
+        assert_eq!(account_tx.tx_hash, h256!("0x55"));
+        assert_eq!(account_tx.contract_changes, vec![(contract_address, 2)]);
+        let block_delta: value::BlockDelta = db_get(&db, &Key::BlockDelta(5)).unwrap().unwrap();
+        assert_eq!(block_delta.account_txs, vec![(tx_origin, 1)]);
+    }
+
+    #[test]
+    fn test_refuse_newer_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path(), &DbOptions::default()).unwrap();
+        save_version(&db, SCHEMA_VERSION + 1).unwrap();
+        assert!(migrate(&db).is_err());
+        assert_eq!(load_version(&db).unwrap(), Some(SCHEMA_VERSION + 1));
+    }
+}
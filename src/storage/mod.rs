@@ -1,14 +1,34 @@
+mod cache;
+mod db;
+mod events;
 mod indexer;
 mod loader;
+mod migration;
+mod precompiles;
 mod runner;
-
-pub use indexer::Indexer;
-pub use loader::Loader;
-pub use runner::{CsalRunContext, Runner};
+mod snapshot;
+mod supervisor;
+mod tracer;
+mod verifier;
+
+pub use cache::ChainCache;
+pub use db::{open_db, DbCompression, DbOptions};
+pub use events::{BlockEvent, EoaBalanceChange, EventSink, IndexerEvent, RollbackEvent};
+pub use indexer::{replay_transaction, Indexer, IndexerError};
+pub use loader::{DbStats, Loader};
+pub use migration::migrate;
+pub use runner::{CsalRunContext, Runner, StateOverride};
+pub use snapshot::{export_snapshot, import_snapshot};
+pub use supervisor::{
+    IndexerState, IndexerStatus, IndexerSupervisor, RollbackInfo, SharedIndexerStatus,
+};
+pub use tracer::CallFrame;
+pub use verifier::Verifier;
 
 use crate::types::ContractAddress;
 use bincode::deserialize;
 use ckb_types::{bytes::Bytes, packed, prelude::*, H160, H256};
+use db::cf_handle;
 use rocksdb::DB;
 use serde::de::DeserializeOwned;
 use std::convert::TryFrom;
@@ -16,8 +36,8 @@ use std::mem;
 
 type BlockNumber = u64;
 
-/// The indexer key type
-#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+/// The indexer key type, every key type is stored in its own column family
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 #[repr(u8)]
 pub enum KeyType {
     /// The key is just last
@@ -55,9 +75,39 @@ pub enum KeyType {
     ///   OutPoint => (BlockNumber, TransactionIndex)
     LiveCellMap = 0x07,
 
+    /// Transactions of an EoA account (as tx_origin or EoA cell owner)
+    ///   (EthAddress, BlockNumber, TransactionIndex) => value::AccountTx
+    AccountTx = 0x08,
+
     /// Delta in the block (for rollback)
     ///   BlockNumber => value::BlockDelta
     BlockDelta = 0xF0,
+
+    /// The last block delivered to an event sink (always in default column family)
+    ///   SinkId => value::EventCursor
+    EventCursor = 0xFD,
+
+    /// The schema version of the database (always in default column family)
+    ///   "version" => u32
+    Version = 0xFE,
+}
+
+impl KeyType {
+    pub fn cf_name(self) -> &'static str {
+        match self {
+            KeyType::Last => "last",
+            KeyType::BlockMap => "block_map",
+            KeyType::ContractChange => "contract_change",
+            KeyType::ContractLogs => "contract_logs",
+            KeyType::ContractMeta => "contract_meta",
+            KeyType::LockLiveCell => "lock_live_cell",
+            KeyType::EoaLiveCell => "eoa_live_cell",
+            KeyType::LiveCellMap => "live_cell_map",
+            KeyType::AccountTx => "account_tx",
+            KeyType::BlockDelta => "block_delta",
+            KeyType::EventCursor | KeyType::Version => "default",
+        }
+    }
 }
 
 impl TryFrom<u8> for KeyType {
@@ -72,7 +122,10 @@ impl TryFrom<u8> for KeyType {
             0x05 => Ok(KeyType::LockLiveCell),
             0x06 => Ok(KeyType::EoaLiveCell),
             0x07 => Ok(KeyType::LiveCellMap),
+            0x08 => Ok(KeyType::AccountTx),
             0xF0 => Ok(KeyType::BlockDelta),
+            0xFD => Ok(KeyType::EventCursor),
+            0xFE => Ok(KeyType::Version),
             _ => Err(format!("Invalid KeyType {}", value)),
         }
     }
@@ -109,7 +162,34 @@ pub enum Key {
     },
     EoaLiveCell(H160),
     LiveCellMap(packed::OutPoint),
+    AccountTx {
+        address: H160,
+        number: Option<BlockNumber>,
+        /// Transaction index in current block
+        tx_index: Option<u32>,
+    },
     BlockDelta(BlockNumber),
+    EventCursor(String),
+    Version,
+}
+
+impl Key {
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            Key::Last => KeyType::Last,
+            Key::BlockMap(_) => KeyType::BlockMap,
+            Key::ContractChange { .. } => KeyType::ContractChange,
+            Key::ContractLogs { .. } => KeyType::ContractLogs,
+            Key::ContractMeta(_) => KeyType::ContractMeta,
+            Key::LockLiveCell { .. } => KeyType::LockLiveCell,
+            Key::EoaLiveCell(_) => KeyType::EoaLiveCell,
+            Key::LiveCellMap(_) => KeyType::LiveCellMap,
+            Key::AccountTx { .. } => KeyType::AccountTx,
+            Key::BlockDelta(_) => KeyType::BlockDelta,
+            Key::EventCursor(_) => KeyType::EventCursor,
+            Key::Version => KeyType::Version,
+        }
+    }
 }
 
 impl From<&Key> for Bytes {
@@ -199,11 +279,27 @@ impl From<&Key> for Bytes {
                 bytes.extend(out_point.as_slice());
                 bytes.into()
             }
+            Key::AccountTx {
+                address,
+                number,
+                tx_index,
+            } => {
+                let mut bytes = vec![KeyType::AccountTx as u8];
+                bytes.extend(address.as_bytes());
+                serialize_output_pos(&mut bytes, *number, *tx_index, None);
+                bytes.into()
+            }
             Key::BlockDelta(number) => {
                 let mut bytes = vec![KeyType::BlockDelta as u8];
                 bytes.extend(&number.to_be_bytes());
                 bytes.into()
             }
+            Key::EventCursor(sink_id) => {
+                let mut bytes = vec![KeyType::EventCursor as u8];
+                bytes.extend(sink_id.as_bytes());
+                bytes.into()
+            }
+            Key::Version => vec![KeyType::Version as u8].into(),
         }
     }
 }
@@ -312,11 +408,28 @@ impl TryFrom<&[u8]> for Key {
                 let out_point = packed::OutPoint::from_slice(content).unwrap();
                 Ok(Key::LiveCellMap(out_point))
             }
+            KeyType::AccountTx => {
+                ensure_content_len("AccountTx", content, 20 + 8 + 4)?;
+                let address = H160::from_slice(&content[0..20]).expect("deserialize eth address");
+                let number = deserialize_u64(&content[20..28]);
+                let tx_index = deserialize_u32(&content[28..32]);
+                Ok(Key::AccountTx {
+                    address,
+                    number: Some(number),
+                    tx_index: Some(tx_index),
+                })
+            }
             KeyType::BlockDelta => {
                 ensure_content_len("BlockDelta", content, mem::size_of::<BlockNumber>())?;
                 let number = deserialize_u64(&content[0..8]);
                 Ok(Key::BlockDelta(number))
             }
+            KeyType::EventCursor => {
+                let sink_id = String::from_utf8(content.to_vec())
+                    .map_err(|err| format!("Invalid Key::EventCursor content: {}", err))?;
+                Ok(Key::EventCursor(sink_id))
+            }
+            KeyType::Version => Ok(Key::Version),
         }
     }
 }
@@ -337,6 +450,9 @@ pub mod value {
     #[derive(Debug, Clone, Deserialize, Serialize)]
     pub struct BlockMap(pub H256);
 
+    #[derive(Debug, Clone, Copy, Deserialize, Serialize, Eq, PartialEq)]
+    pub struct Version(pub u32);
+
     #[derive(Debug, Clone, Deserialize, Serialize)]
     pub struct ContractChange {
         pub tx_hash: H256,
@@ -424,8 +540,21 @@ pub mod value {
         pub tx_index: u32,
     }
 
+    /// An EoA account related transaction
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct AccountTx {
+        pub tx_hash: H256,
+        /// The balance of the EoA cell in inputs (None: not in inputs)
+        pub balance_before: Option<u64>,
+        /// The balance of the EoA cell in outputs (None: not in outputs)
+        pub balance_after: Option<u64>,
+        /// Contract changes sent by the account (as tx_origin)
+        ///   (ContractAddress, OutputIndex)
+        pub contract_changes: Vec<(ContractAddress, u32)>,
+    }
+
     /// For rollback
-    #[derive(Debug, Clone, Deserialize, Serialize)]
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
     pub struct BlockDelta {
         /// If the bool field is true, the contract is created in this block
         pub contracts: Vec<(ContractAddress, bool)>,
@@ -438,11 +567,20 @@ pub mod value {
         pub eoa_removed_cells: Vec<(H160, EoaLiveCell)>,
         /// The selfdestruct contracts in current block
         pub destructed_contracts: Vec<ContractAddress>,
+        /// (eoa_address, tx_index) of Key::AccountTx
+        pub account_txs: Vec<(H160, u32)>,
+    }
+
+    /// The last block delivered to an event sink
+    #[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq)]
+    pub struct EventCursor {
+        pub number: BlockNumber,
+        pub hash: H256,
     }
 }
 
-fn db_get<K: AsRef<[u8]>, T: DeserializeOwned>(db: &DB, key: K) -> Result<Option<T>, String> {
-    db.get(key)
+fn db_get<T: DeserializeOwned>(db: &DB, key: &Key) -> Result<Option<T>, String> {
+    db.get_cf(cf_handle(db, key.key_type()), &Bytes::from(key))
         .map_err(|err| err.to_string())?
         .map(|value_bytes| deserialize(&value_bytes).map_err(|err| err.to_string()))
         .transpose()
@@ -477,12 +615,16 @@ mod test {
                 tx_index: Some(4),
                 output_index: Some(55),
             },
-            Key::EoaLiveCell {
-                type_id_args: h256!("0x5342"),
-                anyone_can_pay_args: h160!("0xabcd"),
-            }
+            Key::EoaLiveCell(h160!("0xabcd")),
             Key::LiveCellMap(packed::OutPoint::default()),
+            Key::AccountTx {
+                address: h160!("0xabcd"),
+                number: Some(777),
+                tx_index: Some(5),
+            },
             Key::BlockDelta(8),
+            Key::EventCursor(String::from("jsonl:/tmp/events.jsonl")),
+            Key::Version,
         ] {
             let binary = Bytes::from(&key1);
             let key2 = Key::try_from(binary.as_ref()).unwrap();
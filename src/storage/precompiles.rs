@@ -0,0 +1,594 @@
+use bn::{pairing_batch, AffineG1, AffineG2, Fq, Fq2, Fr, Group, Gt, G1, G2};
+use ckb_types::{bytes::Bytes, H160};
+use num_bigint::BigUint;
+use ripemd160::Ripemd160;
+use secp256k1::{
+    recovery::{RecoverableSignature, RecoveryId},
+    Message,
+};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use tiny_keccak::{Hasher, Keccak};
+
+use crate::types::SECP256K1;
+
+/// Run a precompiled contract with the call input, return the output
+pub type PrecompileFn = fn(&[u8]) -> Result<Bytes, String>;
+/// The gas of a precompiled contract call in Ethereum's gas schedule
+pub type GasFn = fn(&[u8]) -> u64;
+
+/// Cycles charged for one unit of gas, MAX_TX_CYCLES is then close to the gas
+/// limit of an Ethereum block
+pub const CYCLES_PER_GAS: u64 = 100;
+
+/// The max length of base/exponent/modulus of modexp
+const MODEXP_MAX_LENGTH: usize = 1024;
+
+/// The precompiled contracts (0x01 - 0x09) run natively instead of in ckb-vm
+#[derive(Clone)]
+pub struct Precompiles {
+    contracts: HashMap<H160, (PrecompileFn, GasFn)>,
+    // Not supported by the validator, can only be called in static call/simulation
+    unverified: HashSet<H160>,
+}
+
+impl Default for Precompiles {
+    fn default() -> Precompiles {
+        let mut precompiles = Precompiles {
+            contracts: HashMap::default(),
+            unverified: HashSet::default(),
+        };
+        precompiles.register(precompile_address(1), ecrecover, |_| 3000);
+        precompiles.register(precompile_address(2), sha256, |input| {
+            linear_gas(input, 60, 12)
+        });
+        precompiles.register(precompile_address(3), ripemd160, |input| {
+            linear_gas(input, 600, 120)
+        });
+        precompiles.register(precompile_address(4), identity, |input| {
+            linear_gas(input, 15, 3)
+        });
+        precompiles.register(precompile_address(5), modexp, modexp_gas);
+        precompiles.register(precompile_address(6), bn256_add, |_| 150);
+        precompiles.register(precompile_address(7), bn256_scalar_mul, |_| 6000);
+        precompiles.register(precompile_address(8), bn256_pairing, |input| {
+            45000 + 34000 * (input.len() / 192) as u64
+        });
+        precompiles.register(precompile_address(9), blake2f, blake2f_gas);
+        precompiles.unverified.insert(precompile_address(8));
+        precompiles
+    }
+}
+
+impl Precompiles {
+    pub fn register(&mut self, address: H160, precompile: PrecompileFn, gas: GasFn) {
+        self.contracts.insert(address, (precompile, gas));
+    }
+
+    pub fn contains(&self, address: &H160) -> bool {
+        self.contracts.contains_key(address)
+    }
+
+    /// Whether the validator can run the precompiled contract to verify the call
+    pub fn is_verified(&self, address: &H160) -> bool {
+        !self.unverified.contains(address)
+    }
+
+    /// The cycles to charge before executing the call, the work of modexp and
+    /// blake2f is only bounded by them. Return None if the address is not a
+    /// precompiled contract.
+    pub fn cycles(&self, address: &H160, input: &[u8]) -> Option<u64> {
+        self.contracts
+            .get(address)
+            .map(|(_, gas)| gas(input).saturating_mul(CYCLES_PER_GAS))
+    }
+
+    /// Return None if the address is not a precompiled contract
+    pub fn execute(&self, address: &H160, input: &[u8]) -> Option<Result<Bytes, String>> {
+        self.contracts
+            .get(address)
+            .map(|(precompile, _)| precompile(input))
+    }
+}
+
+pub fn precompile_address(index: u8) -> H160 {
+    let mut address = [0u8; 20];
+    address[19] = index;
+    H160(address)
+}
+
+// The input is treated as infinitely right padded with zeros
+fn padded_slice(input: &[u8], offset: usize, length: usize) -> Vec<u8> {
+    let mut data = vec![0u8; length];
+    if offset < input.len() {
+        let end = std::cmp::min(offset + length, input.len());
+        data[..end - offset].copy_from_slice(&input[offset..end]);
+    }
+    data
+}
+
+fn left_padded(data: &[u8], length: usize) -> Vec<u8> {
+    let mut output = vec![0u8; length];
+    output[length - data.len()..].copy_from_slice(data);
+    output
+}
+
+// Base gas plus the gas of every 32 bytes word
+fn linear_gas(input: &[u8], base: u64, word: u64) -> u64 {
+    base + word * ((input.len() as u64 + 31) / 32)
+}
+
+fn ecrecover(input: &[u8]) -> Result<Bytes, String> {
+    let input = padded_slice(input, 0, 128);
+    let (hash, v, signature) = (&input[0..32], &input[32..64], &input[64..128]);
+    // Invalid signature returns empty output
+    if v[..31].iter().any(|byte| *byte != 0) || (v[31] != 27 && v[31] != 28) {
+        return Ok(Bytes::default());
+    }
+    let recovered = RecoveryId::from_i32(i32::from(v[31] - 27))
+        .and_then(|recovery_id| RecoverableSignature::from_compact(signature, recovery_id))
+        .and_then(|signature| {
+            let message = Message::from_slice(hash)?;
+            SECP256K1.recover(&message, &signature)
+        });
+    let pubkey = match recovered {
+        Ok(pubkey) => pubkey,
+        Err(_) => return Ok(Bytes::default()),
+    };
+    let mut hasher = Keccak::v256();
+    hasher.update(&pubkey.serialize_uncompressed()[1..]);
+    let mut pubkey_hash = [0u8; 32];
+    hasher.finalize(&mut pubkey_hash);
+    Ok(Bytes::from(left_padded(&pubkey_hash[12..], 32)))
+}
+
+fn sha256(input: &[u8]) -> Result<Bytes, String> {
+    Ok(Bytes::from(Sha256::digest(input).to_vec()))
+}
+
+fn ripemd160(input: &[u8]) -> Result<Bytes, String> {
+    Ok(Bytes::from(left_padded(&Ripemd160::digest(input), 32)))
+}
+
+fn identity(input: &[u8]) -> Result<Bytes, String> {
+    Ok(Bytes::from(input.to_vec()))
+}
+
+fn modexp(input: &[u8]) -> Result<Bytes, String> {
+    let read_length = |offset: usize| -> Result<usize, String> {
+        let data = padded_slice(input, offset, 32);
+        if data[..24].iter().any(|byte| *byte != 0) {
+            return Err(String::from("modexp length too large"));
+        }
+        let mut length_bytes = [0u8; 8];
+        length_bytes.copy_from_slice(&data[24..]);
+        let length = u64::from_be_bytes(length_bytes) as usize;
+        if length > MODEXP_MAX_LENGTH {
+            return Err(format!("modexp length too large: {}", length));
+        }
+        Ok(length)
+    };
+    let base_length = read_length(0)?;
+    let exponent_length = read_length(32)?;
+    let modulus_length = read_length(64)?;
+    if modulus_length == 0 {
+        return Ok(Bytes::default());
+    }
+    let base = BigUint::from_bytes_be(&padded_slice(input, 96, base_length));
+    let exponent = BigUint::from_bytes_be(&padded_slice(input, 96 + base_length, exponent_length));
+    let modulus = BigUint::from_bytes_be(&padded_slice(
+        input,
+        96 + base_length + exponent_length,
+        modulus_length,
+    ));
+    if modulus == BigUint::from(0u8) {
+        return Ok(Bytes::from(vec![0u8; modulus_length]));
+    }
+    let result = base.modpow(&exponent, &modulus).to_bytes_be();
+    Ok(Bytes::from(left_padded(&result, modulus_length)))
+}
+
+// Saturated to u64::MAX (the call is rejected anyway)
+fn read_u64_saturated(data: &[u8]) -> u64 {
+    if data[..24].iter().any(|byte| *byte != 0) {
+        return std::u64::MAX;
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[24..32]);
+    u64::from_be_bytes(bytes)
+}
+
+// EIP-198
+fn modexp_gas(input: &[u8]) -> u64 {
+    let base_length = read_u64_saturated(&padded_slice(input, 0, 32));
+    let exponent_length = read_u64_saturated(&padded_slice(input, 32, 32));
+    let modulus_length = read_u64_saturated(&padded_slice(input, 64, 32));
+    if [base_length, exponent_length, modulus_length]
+        .iter()
+        .any(|length| *length > MODEXP_MAX_LENGTH as u64)
+    {
+        return std::u64::MAX;
+    }
+    let length = std::cmp::max(base_length, modulus_length);
+    let complexity = if length <= 64 {
+        length * length
+    } else {
+        length * length / 4 + 96 * length - 3072
+    };
+    // The index of the highest bit of the first 32 bytes of the exponent
+    let head_length = std::cmp::min(exponent_length, 32) as usize;
+    let head = BigUint::from_bytes_be(&padded_slice(input, 96 + base_length as usize, head_length));
+    let head_bits = head.bits().saturating_sub(1);
+    let adjusted_exponent_length = if exponent_length <= 32 {
+        head_bits
+    } else {
+        8 * (exponent_length - 32) + head_bits
+    };
+    complexity * std::cmp::max(adjusted_exponent_length, 1) / 20
+}
+
+fn read_fq(data: &[u8]) -> Result<Fq, String> {
+    Fq::from_slice(data).map_err(|err| format!("invalid field element: {:?}", err))
+}
+
+// 64 bytes (x, y), (0, 0) is the point at infinity
+fn read_g1(data: &[u8]) -> Result<G1, String> {
+    let x = read_fq(&data[0..32])?;
+    let y = read_fq(&data[32..64])?;
+    if x.is_zero() && y.is_zero() {
+        Ok(G1::zero())
+    } else {
+        AffineG1::new(x, y)
+            .map(G1::from)
+            .map_err(|err| format!("invalid G1 point: {:?}", err))
+    }
+}
+
+// 128 bytes (x_imaginary, x_real, y_imaginary, y_real)
+fn read_g2(data: &[u8]) -> Result<G2, String> {
+    let x = Fq2::new(read_fq(&data[32..64])?, read_fq(&data[0..32])?);
+    let y = Fq2::new(read_fq(&data[96..128])?, read_fq(&data[64..96])?);
+    if x.is_zero() && y.is_zero() {
+        Ok(G2::zero())
+    } else {
+        AffineG2::new(x, y)
+            .map(G2::from)
+            .map_err(|err| format!("invalid G2 point: {:?}", err))
+    }
+}
+
+fn write_g1(point: G1) -> Result<Bytes, String> {
+    let mut output = vec![0u8; 64];
+    if let Some(point) = AffineG1::from_jacobian(point) {
+        point
+            .x()
+            .to_big_endian(&mut output[0..32])
+            .map_err(|err| format!("write G1 point: {:?}", err))?;
+        point
+            .y()
+            .to_big_endian(&mut output[32..64])
+            .map_err(|err| format!("write G1 point: {:?}", err))?;
+    }
+    Ok(Bytes::from(output))
+}
+
+fn bn256_add(input: &[u8]) -> Result<Bytes, String> {
+    let input = padded_slice(input, 0, 128);
+    let p1 = read_g1(&input[0..64])?;
+    let p2 = read_g1(&input[64..128])?;
+    write_g1(p1 + p2)
+}
+
+fn bn256_scalar_mul(input: &[u8]) -> Result<Bytes, String> {
+    let input = padded_slice(input, 0, 96);
+    let point = read_g1(&input[0..64])?;
+    let scalar =
+        Fr::from_slice(&input[64..96]).map_err(|err| format!("invalid scalar: {:?}", err))?;
+    write_g1(point * scalar)
+}
+
+fn bn256_pairing(input: &[u8]) -> Result<Bytes, String> {
+    if input.len() % 192 != 0 {
+        return Err(format!("invalid pairing input length: {}", input.len()));
+    }
+    let pairs = input
+        .chunks(192)
+        .map(|chunk| Ok((read_g1(&chunk[0..64])?, read_g2(&chunk[64..192])?)))
+        .collect::<Result<Vec<_>, String>>()?;
+    let mut output = vec![0u8; 32];
+    if pairing_batch(&pairs) == Gt::one() {
+        output[31] = 1;
+    }
+    Ok(Bytes::from(output))
+}
+
+const BLAKE2B_IV: [u64; 8] = [
+    0x6a09_e667_f3bc_c908,
+    0xbb67_ae85_84ca_a73b,
+    0x3c6e_f372_fe94_f82b,
+    0xa54f_f53a_5f1d_36f1,
+    0x510e_527f_ade6_82d1,
+    0x9b05_688c_2b3e_6c1f,
+    0x1f83_d9ab_fb41_bd6b,
+    0x5be0_cd19_137e_2179,
+];
+
+const BLAKE2B_SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+#[allow(clippy::many_single_char_names)]
+fn blake2b_mix(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+// The BLAKE2b compression function F (EIP-152)
+fn blake2b_compress(rounds: u32, h: &mut [u64; 8], m: &[u64; 16], t: [u64; 2], last: bool) {
+    let mut v = [0u64; 16];
+    v[..8].copy_from_slice(&h[..]);
+    v[8..].copy_from_slice(&BLAKE2B_IV[..]);
+    v[12] ^= t[0];
+    v[13] ^= t[1];
+    if last {
+        v[14] = !v[14];
+    }
+    for round in 0..rounds as usize {
+        let s = &BLAKE2B_SIGMA[round % 10];
+        blake2b_mix(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+        blake2b_mix(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+        blake2b_mix(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+        blake2b_mix(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+        blake2b_mix(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+        blake2b_mix(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+        blake2b_mix(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+        blake2b_mix(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+    }
+    for (i, value) in h.iter_mut().enumerate() {
+        *value ^= v[i] ^ v[i + 8];
+    }
+}
+
+// One gas per round (EIP-152)
+fn blake2f_gas(input: &[u8]) -> u64 {
+    if input.len() != 213 {
+        return 0;
+    }
+    let mut rounds_bytes = [0u8; 4];
+    rounds_bytes.copy_from_slice(&input[0..4]);
+    u64::from(u32::from_be_bytes(rounds_bytes))
+}
+
+fn blake2f(input: &[u8]) -> Result<Bytes, String> {
+    if input.len() != 213 {
+        return Err(format!("invalid blake2f input length: {}", input.len()));
+    }
+    let read_u64 = |offset: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&input[offset..offset + 8]);
+        u64::from_le_bytes(bytes)
+    };
+    let mut rounds_bytes = [0u8; 4];
+    rounds_bytes.copy_from_slice(&input[0..4]);
+    let rounds = u32::from_be_bytes(rounds_bytes);
+    let mut h = [0u64; 8];
+    for (i, value) in h.iter_mut().enumerate() {
+        *value = read_u64(4 + i * 8);
+    }
+    let mut m = [0u64; 16];
+    for (i, value) in m.iter_mut().enumerate() {
+        *value = read_u64(68 + i * 8);
+    }
+    let t = [read_u64(196), read_u64(204)];
+    let last = match input[212] {
+        0 => false,
+        1 => true,
+        flag => return Err(format!("invalid blake2f final block flag: {}", flag)),
+    };
+    blake2b_compress(rounds, &mut h, &m, t, last);
+    let mut output = Vec::with_capacity(64);
+    for value in &h {
+        output.extend_from_slice(&value.to_le_bytes());
+    }
+    Ok(Bytes::from(output))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn execute(index: u8, input: &[u8]) -> Result<Bytes, String> {
+        Precompiles::default()
+            .execute(&precompile_address(index), input)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_unverified_precompiles() {
+        let precompiles = Precompiles::default();
+        for index in 1..=9 {
+            let address = precompile_address(index);
+            assert!(precompiles.contains(&address));
+            assert_eq!(precompiles.is_verified(&address), index != 8);
+        }
+    }
+
+    #[test]
+    fn test_hash_precompiles() {
+        assert_eq!(
+            hex::encode(execute(2, &[]).unwrap()),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex::encode(execute(3, &[]).unwrap()),
+            "0000000000000000000000009c1185a5c5e9fc54612808977ee8f548b2258d31"
+        );
+        assert_eq!(execute(4, &[1, 2, 3]).unwrap().as_ref(), &[1, 2, 3][..]);
+        assert!(Precompiles::default()
+            .execute(&precompile_address(10), &[])
+            .is_none());
+    }
+
+    #[test]
+    fn test_ecrecover() {
+        let privkey = secp256k1::SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let pubkey = secp256k1::PublicKey::from_secret_key(&SECP256K1, &privkey);
+        let hash = [2u8; 32];
+        let message = Message::from_slice(&hash).unwrap();
+        let (recovery_id, signature) = SECP256K1
+            .sign_recoverable(&message, &privkey)
+            .serialize_compact();
+        let mut input = hash.to_vec();
+        input.extend(left_padded(&[27 + recovery_id.to_i32() as u8], 32));
+        input.extend_from_slice(&signature[..]);
+
+        let mut hasher = Keccak::v256();
+        hasher.update(&pubkey.serialize_uncompressed()[1..]);
+        let mut pubkey_hash = [0u8; 32];
+        hasher.finalize(&mut pubkey_hash);
+        let output = execute(1, &input).unwrap();
+        assert_eq!(output.as_ref(), &left_padded(&pubkey_hash[12..], 32)[..]);
+
+        // Invalid v
+        input[63] = 29;
+        assert!(execute(1, &input).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_modexp() {
+        // 3 ** 5 % 7
+        let mut input = Vec::new();
+        for _ in 0..3 {
+            input.extend(left_padded(&[1], 32));
+        }
+        input.extend_from_slice(&[3, 5, 7]);
+        assert_eq!(execute(5, &input).unwrap().as_ref(), &[5][..]);
+    }
+
+    #[test]
+    fn test_bn256() {
+        let generator = [left_padded(&[1], 32), left_padded(&[2], 32)].concat();
+        let double = "030644e72e131a029b85045b68181585d97816a916871ca8d3c208c16d87cfd3\
+                      15ed738c0e0a7c92e7845f96b2ae9c0a68a6a449e3538fc7ff3ebf7a5a18a2c4";
+        let input = [generator.clone(), generator.clone()].concat();
+        assert_eq!(hex::encode(execute(6, &input).unwrap()), double);
+        let input = [generator.clone(), left_padded(&[2], 32)].concat();
+        assert_eq!(hex::encode(execute(7, &input).unwrap()), double);
+        // Not on the curve
+        let input = [left_padded(&[1], 32), left_padded(&[3], 32)].concat();
+        assert!(execute(6, &input).is_err());
+        // Empty input pairing
+        assert_eq!(
+            execute(8, &[]).unwrap().as_ref(),
+            &left_padded(&[1], 32)[..]
+        );
+    }
+
+    #[test]
+    fn test_precompile_cycles() {
+        let precompiles = Precompiles::default();
+        let cycles = |index: u8, input: &[u8]| {
+            precompiles
+                .cycles(&precompile_address(index), input)
+                .map(|cycles| cycles / CYCLES_PER_GAS)
+        };
+        assert_eq!(cycles(2, &[0u8; 33]), Some(60 + 12 * 2));
+        assert_eq!(cycles(8, &[0u8; 384]), Some(45000 + 34000 * 2));
+        assert_eq!(cycles(10, &[]), None);
+
+        // The example of EIP-198
+        let input = hex::decode(
+            "0000000000000000000000000000000000000000000000000000000000000001\
+             0000000000000000000000000000000000000000000000000000000000000020\
+             0000000000000000000000000000000000000000000000000000000000000020\
+             03\
+             fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2e\
+             fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f",
+        )
+        .unwrap();
+        assert_eq!(cycles(5, &input), Some(13056));
+        let mut input = left_padded(&[8, 0], 32);
+        input.extend(left_padded(&[1], 32));
+        input.extend(left_padded(&[1], 32));
+        assert_eq!(cycles(5, &input), Some(std::u64::MAX / CYCLES_PER_GAS));
+
+        let mut input = std::u32::MAX.to_be_bytes().to_vec();
+        input.extend_from_slice(&[0u8; 209]);
+        assert_eq!(cycles(9, &input), Some(u64::from(std::u32::MAX)));
+    }
+
+    // The vectors shared with the validator (c/precompiles_test.c)
+    #[test]
+    fn test_shared_vectors() {
+        let precompiles = Precompiles::default();
+        let decode = |data: &str| {
+            if data == "-" {
+                Vec::new()
+            } else {
+                hex::decode(data).unwrap()
+            }
+        };
+        let vectors = include_str!("../../tests/precompiles/vectors.txt");
+        for line in vectors
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        {
+            let parts = line.split(' ').collect::<Vec<_>>();
+            let (address, name, gas) = (
+                precompile_address(parts[0].parse().unwrap()),
+                parts[1],
+                parts[2].parse::<u64>().unwrap(),
+            );
+            let input = decode(parts[3]);
+            assert_eq!(
+                precompiles.cycles(&address, &input),
+                Some(gas * CYCLES_PER_GAS),
+                "{}",
+                name
+            );
+            let result = precompiles.execute(&address, &input).unwrap();
+            if parts[4] == "error" {
+                assert!(result.is_err(), "{}", name);
+            } else {
+                assert_eq!(result.unwrap().as_ref(), &decode(parts[4])[..], "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_blake2f() {
+        // The compression of blake2b-512("abc")
+        let mut h = BLAKE2B_IV;
+        h[0] ^= 0x0101_0040;
+        let mut input = 12u32.to_be_bytes().to_vec();
+        for value in &h {
+            input.extend_from_slice(&value.to_le_bytes());
+        }
+        input.extend(padded_slice(b"abc", 0, 128));
+        input.extend_from_slice(&3u64.to_le_bytes());
+        input.extend_from_slice(&0u64.to_le_bytes());
+        input.push(1);
+        assert_eq!(
+            hex::encode(execute(9, &input).unwrap()),
+            "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1\
+             7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+        );
+        input.push(0);
+        assert!(execute(9, &input).is_err());
+    }
+}
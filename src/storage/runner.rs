@@ -1,14 +1,15 @@
 use ckb_hash::{blake2b_256, new_blake2b};
+use ckb_jsonrpc_types::JsonBytes;
 use ckb_sdk::HumanCapacity;
 use ckb_simple_account_layer::{
     run_with_context, CkbBlake2bHasher, Config, RunContext, RunProofResult, RunResult,
 };
 use ckb_types::{
     bytes::{BufMut, Bytes, BytesMut},
-    core::{BlockView, Capacity, ScriptHashType, TransactionBuilder},
+    core::{BlockView, Capacity, DepType, ScriptHashType, TransactionBuilder},
     h256,
     packed::{
-        Byte32, BytesOpt, CellInput, CellOutput, OutPoint, Script, ScriptOpt, Transaction,
+        Byte32, BytesOpt, CellDep, CellInput, CellOutput, OutPoint, Script, ScriptOpt, Transaction,
         WitnessArgs,
     },
     prelude::*,
@@ -20,18 +21,25 @@ use ckb_vm::{
 };
 use numext_fixed_uint::prelude::UintConvert;
 use sparse_merkle_tree::{default_store::DefaultStore, SparseMerkleTree, H256 as SmtH256};
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
 use std::error::Error as StdError;
 
+use super::precompiles::Precompiles;
+use super::tracer::{CallFrame, Tracer};
 use super::{value, Loader};
+use crate::metrics::METRICS;
 use crate::types::{
-    h256_to_smth256, parse_log, smth256_to_h256, vm_load_data, vm_load_h160, vm_load_h256,
-    vm_load_i32, vm_load_i64, vm_load_u256, vm_load_u32, vm_load_u8, CallKind, CallRecord,
-    Coinbase, ContractAddress, ContractCell, EoaAddress, Program, RunConfig, WitnessData,
-    ALWAYS_SUCCESS_SCRIPT, ONE_CKB, SIGHASH_CELL_DEP,
+    block_hash_available, code_hash, h256_to_smth256, parse_log, sighash_lock, smth256_to_h256,
+    vm_load_data, vm_load_h160, vm_load_h256, vm_load_i32, vm_load_i64, vm_load_u256, vm_load_u32,
+    vm_load_u8, CallKind, CallRecord, Coinbase, ContractAddress, ContractCell, EoaAddress,
+    LimitError, Program, RunConfig, WitnessData, ALWAYS_SUCCESS_SCRIPT, MIN_CELL_CAPACITY, ONE_CKB,
+    SIGHASH_CELL_DEP,
 };
 
+/// The fee of the transactions built by the generator
+pub const TX_FEE: u64 = ONE_CKB;
+
 pub struct Runner {
     pub loader: Loader,
     pub run_config: RunConfig,
@@ -42,21 +50,33 @@ impl Runner {
         Runner { loader, run_config }
     }
 
+    /// The sender may not exist, it's treated as an EoA account with the
+    /// overridden balance (0 by default)
     pub fn static_call(
         &mut self,
         sender: H160,
         destination: ContractAddress,
         input: Bytes,
+        mut overrides: HashMap<H160, StateOverride>,
     ) -> Result<CsalRunContext, Box<dyn StdError>> {
-        let meta = self.loader.load_contract_meta(destination.clone())?;
-        if meta.destructed {
-            return Err(format!("Contract already destructed: {:x}", destination.0).into());
-        }
+        let code_override = overrides
+            .get(&destination.0)
+            .and_then(|state_override| state_override.code.clone());
+        let code = if let Some(code) = code_override {
+            code
+        } else {
+            let meta = self.loader.load_contract_meta(destination.clone())?;
+            if meta.destructed {
+                return Err(format!("Contract already destructed: {:x}", destination.0).into());
+            }
+            meta.code
+        };
+        overrides.entry(sender.clone()).or_default();
         let program = Program::new_call(
             EoaAddress(sender.clone()),
             sender,
             destination.0,
-            meta.code,
+            code,
             input,
             0,
             false,
@@ -65,7 +85,10 @@ impl Runner {
         let tip_block = self.loader.load_block(None)?;
         let mut context =
             CsalRunContext::new(self.loader.clone(), self.run_config.clone(), tip_block);
-        if let Err(err) = context.run(program) {
+        context.overrides = overrides;
+        let result = context.run(program);
+        METRICS.observe_execution("static_call", context.cycles, result.is_ok());
+        if let Err(err) = result {
             log::warn!("Error: {:?}", err);
             return Err(err);
         }
@@ -97,7 +120,9 @@ impl Runner {
         let tip_block = self.loader.load_block(None)?;
         let mut context =
             CsalRunContext::new(self.loader.clone(), self.run_config.clone(), tip_block);
-        if let Err(err) = context.run(program) {
+        let result = context.run(program);
+        METRICS.observe_execution("call", context.cycles, result.is_ok());
+        if let Err(err) = result {
             log::warn!("Error: {:?}", err);
             return Err(err);
         }
@@ -114,14 +139,293 @@ impl Runner {
         let tip_block = self.loader.load_block(None)?;
         let mut context =
             CsalRunContext::new(self.loader.clone(), self.run_config.clone(), tip_block);
-        if let Err(err) = context.run(program) {
+        let result = context.run(program);
+        METRICS.observe_execution("create", context.cycles, result.is_ok());
+        if let Err(err) = result {
+            log::warn!("Error: {:?}", err);
+            return Err(err);
+        }
+        Ok(context)
+    }
+
+    // A create program if destination is None (input is the code)
+    fn build_program(
+        &mut self,
+        sender: H160,
+        destination: Option<ContractAddress>,
+        input: Bytes,
+        value: u64,
+    ) -> Result<Program, Box<dyn StdError>> {
+        if let Some(destination) = destination {
+            let meta = self.loader.load_contract_meta(destination.clone())?;
+            if meta.destructed {
+                return Err(format!("Contract already destructed: {:x}", destination.0).into());
+            }
+            Ok(Program::new_call(
+                EoaAddress(sender.clone()),
+                sender,
+                destination.0,
+                meta.code,
+                input,
+                value,
+                false,
+            ))
+        } else {
+            Ok(Program::new_create(
+                EoaAddress(sender.clone()),
+                sender,
+                input,
+                value,
+            ))
+        }
+    }
+
+    /// Run a create (destination is None) or a call without building the
+    /// transaction, the effects are read from the returned context.
+    pub fn simulate(
+        &mut self,
+        sender: H160,
+        destination: Option<ContractAddress>,
+        input: Bytes,
+        value: u64,
+    ) -> Result<CsalRunContext, Box<dyn StdError>> {
+        let program = self.build_program(sender, destination, input, value)?;
+        let tip_block = self.loader.load_block(None)?;
+        let mut context =
+            CsalRunContext::new(self.loader.clone(), self.run_config.clone(), tip_block);
+        let result = context.run(program);
+        METRICS.observe_execution("simulate", context.cycles, result.is_ok());
+        if let Err(err) = result {
             log::warn!("Error: {:?}", err);
             return Err(err);
         }
         Ok(context)
     }
+
+    /// Run a create (destination is None) or a call with the tracer enabled,
+    /// the call tree is returned even if the execution failed.
+    pub fn trace_call(
+        &mut self,
+        sender: H160,
+        destination: Option<ContractAddress>,
+        input: Bytes,
+        value: u64,
+    ) -> Result<CallFrame, Box<dyn StdError>> {
+        let program = self.build_program(sender, destination, input, value)?;
+        let tip_block = self.loader.load_block(None)?;
+        let mut context =
+            CsalRunContext::new(self.loader.clone(), self.run_config.clone(), tip_block);
+        context.tracer = Some(Tracer::default());
+        let result = context.run(program);
+        if let Some(frame) = context.take_trace() {
+            return Ok(frame);
+        }
+        // Failed before the entrance program started
+        result?;
+        Err(String::from("no call frame recorded").into())
+    }
+
+    /// Transfer value between two EoA accounts, the transaction only spends
+    /// the two EoA cells (the fee is paid by the sender).
+    pub fn transfer(
+        &mut self,
+        sender: H160,
+        destination: H160,
+        value: u64,
+    ) -> Result<Transaction, Box<dyn StdError>> {
+        if sender == destination {
+            return Err(String::from("Can not transfer to the sender itself").into());
+        }
+        if value == 0 {
+            return Err(String::from("Transfer value must be greater than 0").into());
+        }
+        if self
+            .loader
+            .load_contract_meta(ContractAddress(destination.clone()))
+            .is_ok()
+        {
+            return Err(format!(
+                "Destination is a contract (use call instead): {:x}",
+                destination
+            )
+            .into());
+        }
+        let (mut sender_cell, sender_output, sender_data) =
+            self.loader.load_eoa_live_cell(sender)?;
+        let (mut dest_cell, dest_output, dest_data) =
+            self.loader.load_eoa_live_cell(destination)?;
+        sender_cell.sub_balance(value + TX_FEE)?;
+        dest_cell.add_balance(value);
+
+        let cell_deps = vec![
+            SIGHASH_CELL_DEP.clone(),
+            self.run_config.eoa_lock_dep.clone(),
+        ];
+        let inputs = vec![
+            CellInput::new(sender_cell.out_point(), 0),
+            CellInput::new(dest_cell.out_point(), 0),
+        ];
+        let outputs = vec![
+            sender_output
+                .as_builder()
+                .capacity(sender_cell.capacity().pack())
+                .build(),
+            dest_output
+                .as_builder()
+                .capacity(dest_cell.capacity().pack())
+                .build(),
+        ];
+        // The witnesses (lock signature) are filled when signing
+        let tx = TransactionBuilder::default()
+            .cell_deps(cell_deps.pack())
+            .inputs(inputs.pack())
+            .outputs(outputs.pack())
+            .outputs_data(vec![sender_data, dest_data].pack())
+            .build();
+        Ok(tx.data())
+    }
+
+    /// Deposit value from the secp256k1 (sighash) cells of `lock_arg` to an
+    /// EoA account, the change goes back to `lock_arg` (the fee is paid by
+    /// the sighash cells).
+    pub fn deposit(
+        &mut self,
+        lock_arg: H160,
+        destination: H160,
+        value: u64,
+    ) -> Result<Transaction, Box<dyn StdError>> {
+        if value == 0 {
+            return Err(String::from("Deposit value must be greater than 0").into());
+        }
+        let (mut dest_cell, dest_output, dest_data) =
+            self.loader.load_eoa_live_cell(destination)?;
+        dest_cell.add_balance(value);
+
+        let required_capacity = value + TX_FEE;
+        let mut cells = self
+            .loader
+            .collect_cells(EoaAddress(lock_arg.clone()), required_capacity)?;
+        if cells.1 > required_capacity && cells.1 - required_capacity < MIN_CELL_CAPACITY {
+            // The change cell can't hold itself, collect more cells
+            cells = self.loader.collect_cells(
+                EoaAddress(lock_arg.clone()),
+                required_capacity + MIN_CELL_CAPACITY,
+            )?;
+        }
+        let (out_points, total_capacity) = cells;
+
+        let cell_deps = vec![
+            SIGHASH_CELL_DEP.clone(),
+            self.run_config.eoa_lock_dep.clone(),
+        ];
+        let mut inputs = vec![CellInput::new(dest_cell.out_point(), 0)];
+        inputs.extend(
+            out_points
+                .into_iter()
+                .map(|out_point| CellInput::new(out_point, 0)),
+        );
+        let mut outputs = vec![dest_output
+            .as_builder()
+            .capacity(dest_cell.capacity().pack())
+            .build()];
+        let mut outputs_data = vec![dest_data];
+        let change_capacity = total_capacity - required_capacity;
+        if change_capacity > 0 {
+            outputs.push(
+                CellOutput::new_builder()
+                    .capacity(change_capacity.pack())
+                    .lock(sighash_lock(&lock_arg))
+                    .build(),
+            );
+            outputs_data.push(Bytes::default());
+        }
+        // The witnesses (lock signature) are filled when signing
+        let tx = TransactionBuilder::default()
+            .cell_deps(cell_deps.pack())
+            .inputs(inputs.pack())
+            .outputs(outputs.pack())
+            .outputs_data(outputs_data.pack())
+            .build();
+        Ok(tx.data())
+    }
+
+    /// Withdraw value from an EoA account to a secp256k1 (sighash) cell of
+    /// `lock_arg`. When `value` is None, all the capacity is withdrawn and the
+    /// EoA account is closed (the fee is paid by the EoA account).
+    pub fn withdraw(
+        &mut self,
+        sender: H160,
+        lock_arg: H160,
+        value: Option<u64>,
+    ) -> Result<Transaction, Box<dyn StdError>> {
+        let (mut sender_cell, sender_output, sender_data) =
+            self.loader.load_eoa_live_cell(sender)?;
+        let mut outputs = Vec::new();
+        let mut outputs_data = Vec::new();
+        let withdraw_capacity = if let Some(value) = value {
+            if value < MIN_CELL_CAPACITY {
+                return Err(format!(
+                    "Withdraw value must be at least {} CKB",
+                    HumanCapacity(MIN_CELL_CAPACITY)
+                )
+                .into());
+            }
+            sender_cell.sub_balance(value + TX_FEE)?;
+            outputs.push(
+                sender_output
+                    .as_builder()
+                    .capacity(sender_cell.capacity().pack())
+                    .build(),
+            );
+            outputs_data.push(sender_data);
+            value
+        } else {
+            // The occupied capacity of the EoA cell is also withdrawn
+            sender_cell.capacity() - TX_FEE
+        };
+        outputs.push(
+            CellOutput::new_builder()
+                .capacity(withdraw_capacity.pack())
+                .lock(sighash_lock(&lock_arg))
+                .build(),
+        );
+        outputs_data.push(Bytes::default());
+
+        let cell_deps = vec![
+            SIGHASH_CELL_DEP.clone(),
+            self.run_config.eoa_lock_dep.clone(),
+        ];
+        let inputs = vec![CellInput::new(sender_cell.out_point(), 0)];
+        // The witnesses (lock signature) are filled when signing
+        let tx = TransactionBuilder::default()
+            .cell_deps(cell_deps.pack())
+            .inputs(inputs.pack())
+            .outputs(outputs.pack())
+            .outputs_data(outputs_data.pack())
+            .build();
+        Ok(tx.data())
+    }
+}
+
+/// Override the state of an account before execution, like the state override
+/// set of Geth's `eth_call`
+#[derive(Debug, Clone, Default)]
+pub struct StateOverride {
+    /// Replace the contract code (the contract may not exist)
+    pub code: Option<Bytes>,
+    /// Replace the balance of the contract/EoA account
+    pub balance: Option<u64>,
+    /// Replace the values of these storage slots
+    pub storage: HashMap<H256, H256>,
 }
 
+// (input cell, storage tree, balance)
+type ContractState = (
+    Option<ContractInput>,
+    SparseMerkleTree<CkbBlake2bHasher, SmtH256, DefaultStore<SmtH256>>,
+    u64,
+);
+
 pub struct ContractInfo {
     pub address: ContractAddress,
     pub tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, DefaultStore<SmtH256>>,
@@ -132,6 +436,8 @@ pub struct ContractInfo {
     pub balance: u64,
     pub init_balance: u64,
     pub run_result: RunResult,
+    /// key => (old value, new value) of the storage slots written by the programs
+    pub storage_changes: BTreeMap<H256, (H256, H256)>,
     execute_index: usize,
     // (program, logs, return_data, run_proof)
     execute_records: Vec<ExecuteRecord>,
@@ -186,7 +492,12 @@ impl ExecuteRecord {
         }
     }
 
-    pub fn witness_data(&self, first_program: bool, block_opt: Option<&BlockView>) -> WitnessData {
+    pub fn witness_data(
+        &self,
+        first_program: bool,
+        block_opt: Option<&BlockView>,
+        code_deps: &[(H160, Bytes)],
+    ) -> WitnessData {
         // This optmize is for reducing witness size by remove duplicated code field
         let mut program = self.program.clone();
         if !first_program {
@@ -194,19 +505,20 @@ impl ExecuteRecord {
         }
         for call_record in &self.calls {
             log::debug!(
-                "[call]: (destination={:x}, program_index={}, value={}, tranfer_only={}, is_eoa={})",
+                "[call]: (destination={:x}, program_index={}, value={}, tranfer_only={}, is_eoa={}, is_precompile={})",
                 call_record.destination,
                 call_record.program_index,
                 call_record.value,
                 call_record.transfer_only,
                 call_record.is_eoa,
+                call_record.is_precompile,
             );
         }
         log::debug!("[run_proof]: {}", hex::encode(&self.run_proof));
-        let coinbase = if first_program {
-            block_opt.map(Coinbase::new)
+        let (coinbase, code_deps) = if first_program {
+            (block_opt.map(Coinbase::new), code_deps.to_vec())
         } else {
-            None
+            (None, Vec::new())
         };
         WitnessData {
             signature: Bytes::from(vec![0u8; 65]),
@@ -214,6 +526,7 @@ impl ExecuteRecord {
             return_data: self.return_data.clone(),
             selfdestruct: None,
             coinbase,
+            code_deps,
             calls: self.calls.clone(),
             run_proof: self.run_proof.clone(),
         }
@@ -239,6 +552,7 @@ impl ContractInfo {
             execute_records: Vec::new(),
             selfdestruct: None,
             run_result: RunResult::default(),
+            storage_changes: BTreeMap::default(),
             current_calls: Default::default(),
             balance_changed: false,
         }
@@ -310,12 +624,16 @@ impl ContractInfo {
     }
 
     // Serialize all call records to WitnessArgs
-    pub fn witness_data(&self, block_opt: Option<&BlockView>) -> WitnessArgs {
+    pub fn witness_data(
+        &self,
+        block_opt: Option<&BlockView>,
+        code_deps: &[(H160, Bytes)],
+    ) -> WitnessArgs {
         let mut witness_data_vec: Vec<WitnessData> = self
             .execute_records
             .iter()
             .enumerate()
-            .map(|(idx, record)| record.witness_data(idx == 0, block_opt))
+            .map(|(idx, record)| record.witness_data(idx == 0, block_opt, code_deps))
             .collect();
         if !witness_data_vec.is_empty() {
             witness_data_vec[self.execute_records.len() - 1].selfdestruct =
@@ -392,6 +710,8 @@ pub struct CsalRunContext {
     pub tx_origin_output: (CellOutput, Bytes),
     pub other_eoa_cells: BTreeMap<H160, value::EoaLiveCell>,
     pub other_eoa_outputs: BTreeMap<H160, (CellOutput, Bytes)>,
+    // The balances of the EoA accounts when loaded
+    eoa_init_balances: BTreeMap<H160, u64>,
     // First contract input cell (when kind.is_call())
     pub first_contract_input: Option<ContractInput>,
     // The entrance program
@@ -401,6 +721,22 @@ pub struct CsalRunContext {
     contracts: Vec<(ContractAddress, ContractInfo)>,
     state_changed: bool,
     error_message: Option<String>,
+    // Total cycles consumed by all the programs
+    pub cycles: u64,
+    // The cycles of current machine when last syscall (or finish) happened
+    machine_cycles: u64,
+    // Record the call tree when enabled
+    pub tracer: Option<Tracer>,
+    // Applied when an account is loaded (simulation only)
+    pub overrides: HashMap<H160, StateOverride>,
+    // Precompiled contracts run natively
+    pub precompiles: Precompiles,
+    // Depth of current running program (the entrance program is 0)
+    call_depth: u32,
+    // The limit exceeded (nested errors are converted to strings)
+    limit_error: Option<LimitError>,
+    // The code of the contracts read by EXTCODEHASH (address => code)
+    code_deps: BTreeMap<H160, Bytes>,
 }
 
 impl CsalRunContext {
@@ -418,12 +754,21 @@ impl CsalRunContext {
             tx_origin_output: (CellOutput::default(), Bytes::default()),
             other_eoa_cells: Default::default(),
             other_eoa_outputs: Default::default(),
+            eoa_init_balances: Default::default(),
             first_contract_input: None,
             entrance_program: None,
             contract_index: 0,
             contracts: Vec::new(),
             state_changed: false,
             error_message: None,
+            cycles: 0,
+            machine_cycles: 0,
+            tracer: None,
+            overrides: HashMap::default(),
+            precompiles: Precompiles::default(),
+            call_depth: 0,
+            limit_error: None,
+            code_deps: BTreeMap::default(),
         }
     }
 
@@ -438,7 +783,7 @@ impl CsalRunContext {
         CellInput::new(self.tx_origin_cell.out_point(), 0)
     }
 
-    fn state_changed(&self) -> bool {
+    pub fn state_changed(&self) -> bool {
         self.state_changed || self.contracts.iter().any(|(_, info)| info.balance_changed)
     }
 
@@ -449,16 +794,57 @@ impl CsalRunContext {
         if !self.is_static() && !self.state_changed() {
             return Err(String::from("state not changed in create/call").into());
         }
+        // The validator can not verify the call, the transaction would be rejected
+        if let Some(call_record) = self
+            .contracts
+            .iter()
+            .flat_map(|(_, info)| info.execute_records.iter())
+            .flat_map(|record| record.calls.iter())
+            .find(|call_record| {
+                call_record.is_precompile && !self.precompiles.is_verified(&call_record.destination)
+            })
+        {
+            return Err(format!(
+                "precompiled contract {:x} is not supported in transaction",
+                call_record.destination
+            )
+            .into());
+        }
 
-        let tx_fee = ONE_CKB;
+        let tx_fee = TX_FEE;
         // Setup cell_deps
         // TODO: fill load all inputs' headers as dependencies
-        let cell_deps = vec![
+        let mut cell_deps = vec![
             SIGHASH_CELL_DEP.clone(),
             self.run_config.type_dep.clone(),
             self.run_config.lock_dep.clone(),
             self.run_config.eoa_lock_dep.clone(),
         ];
+        // The contracts only read by EXTCODEHASH, the validator verifies the
+        // code in witness by the code hash in the contract cell
+        let code_deps: Vec<(H160, Bytes)> = self
+            .code_deps
+            .iter()
+            .filter(|(address, _)| {
+                self.get_contract_info(&ContractAddress((*address).clone()))
+                    .is_none()
+            })
+            .map(|(address, code)| (address.clone(), code.clone()))
+            .collect();
+        for (address, _) in &code_deps {
+            let change = self.loader.load_latest_contract_change(
+                ContractAddress(address.clone()),
+                None,
+                false,
+                true,
+            )?;
+            cell_deps.push(
+                CellDep::new_builder()
+                    .out_point(change.out_point())
+                    .dep_type(DepType::Code.into())
+                    .build(),
+            );
+        }
 
         // Collect inputs
         let other_inputs: Vec<CellInput> = self
@@ -661,12 +1047,12 @@ impl CsalRunContext {
         let mut witnesses_data = vec![(None, None); self.contracts.len()];
         for (output_index, (_, info)) in self.contracts.iter().enumerate() {
             // entrance contract
-            let block_opt = if output_index == 0 {
-                Some(&self.tip_block)
+            let (block_opt, entrance_code_deps) = if output_index == 0 {
+                (Some(&self.tip_block), &code_deps[..])
             } else {
-                None
+                (None, &[][..])
             };
-            let witness_data = info.witness_data(block_opt);
+            let witness_data = info.witness_data(block_opt, entrance_code_deps);
             if info.is_create() {
                 witnesses_data[output_index].1 = Some(witness_data);
             } else {
@@ -701,53 +1087,149 @@ impl CsalRunContext {
         Ok(tx.data())
     }
 
-    // Add CALLCODE/DELEGATECALL program for callee
-    pub fn add_special_call(&mut self, program: Program) -> Result<(), Box<dyn StdError>> {
+    /// Add the special call (DELEGATECALL/CALLCODE) record to the contract
+    /// providing the code, return the index of the record. It must be added
+    /// before running the program, so the records are ordered by the time they
+    /// are called even when the special calls are nested.
+    pub fn add_special_call(&mut self, program: Program) -> Result<u32, Box<dyn StdError>> {
         let info_address = ContractAddress(program.destination.clone());
-        let (contract_input_opt, tree, balance) = self
-            .get_contract_info(&info_address)
-            .map::<Result<_, String>, _>(|info| {
-                let input_opt: Option<ContractInput> = info.input.clone();
-                let tree = SparseMerkleTree::new(*info.tree.root(), info.tree.store().clone());
-                Ok((input_opt, tree, info.balance))
-            })
-            .unwrap_or_else(|| {
-                let change = self.loader.load_latest_contract_change(
-                    info_address.clone(),
-                    None,
-                    false,
-                    false,
-                )?;
-                let (output, data) = self
-                    .loader
-                    .load_contract_live_cell(change.tx_hash.clone(), change.output_index)?;
-                let input = ContractInput::new(change.out_point(), output, data);
-                Ok((Some(input), change.merkle_tree(), change.balance))
-            })?;
+        let (contract_input_opt, tree, balance) = self.contract_state(&info_address)?;
 
         let empty_run_proof = Bytes::from(RunProofResult::default().serialize_pure().unwrap());
         log::debug!("empty_run_proof: {}", hex::encode(&empty_run_proof));
-        if let Some(contract_index) = self.get_contract_index(&info_address) {
-            self.contract_index = contract_index;
-            let info = &mut self.contracts[contract_index].1;
-            info.add_record(program);
-            info.current_record_mut().run_proof = empty_run_proof;
+        let contract_index = if let Some(contract_index) = self.get_contract_index(&info_address) {
+            contract_index
         } else {
-            self.contract_index = self.contracts.len();
-            let mut info =
-                ContractInfo::new(info_address.clone(), contract_input_opt, balance, tree);
-            info.add_record(program);
-            info.current_record_mut().run_proof = empty_run_proof;
+            let info = ContractInfo::new(info_address.clone(), contract_input_opt, balance, tree);
             self.contracts.push((info_address, info));
+            self.contracts.len() - 1
+        };
+        let info = &mut self.contracts[contract_index].1;
+        // The contract may be running (the record is not the current one)
+        let saved_execute_index = info.execute_index;
+        info.add_record(program);
+        info.current_record_mut().run_proof = empty_run_proof;
+        let program_index = info.get_last_call();
+        if saved_execute_index > 0 {
+            info.execute_index = saved_execute_index;
         }
-        Ok(())
+        self.check_limits()?;
+        Ok(program_index)
     }
 
-    pub fn run(&mut self, mut program: Program) -> Result<(), Box<dyn StdError>> {
+    pub fn run(&mut self, program: Program) -> Result<(), Box<dyn StdError>> {
         if self.contracts.is_empty() {
             self.set_entrance_program(program.clone())?;
         }
+        let result = self.run_with_depth(program);
+        match self.limit_error.take() {
+            // Report the limit error of nested programs as it is
+            Some(err) if result.is_err() && self.call_depth == 0 => Err(Box::new(err)),
+            limit_error => {
+                self.limit_error = limit_error;
+                result
+            }
+        }
+    }
+
+    fn run_with_depth(&mut self, program: Program) -> Result<(), Box<dyn StdError>> {
+        let max_depth = self.run_config.limits.max_depth;
+        if self.call_depth > max_depth {
+            return Err(self.exceed_limit(LimitError::CallDepth {
+                depth: self.call_depth,
+                max: max_depth,
+            }));
+        }
+        self.call_depth += 1;
+        let result = if self.tracer.is_none() {
+            self.run_program(program)
+        } else {
+            self.trace_enter(&program);
+            let saved_cycles = self.cycles;
+            let result = self.run_program(program);
+            let cycles = self.cycles - saved_cycles;
+            if result.is_ok() {
+                self.trace_return();
+            }
+            let error = result.as_ref().err().map(|err| err.to_string());
+            self.trace_exit(error, cycles);
+            result
+        };
+        self.call_depth -= 1;
+        result
+    }
+
+    fn exceed_limit(&mut self, err: LimitError) -> Box<dyn StdError> {
+        log::warn!("{}", err);
+        self.limit_error = Some(err.clone());
+        Box::new(err)
+    }
+
+    // Check the transaction size and cycles limits
+    fn check_limits(&mut self) -> Result<(), Box<dyn StdError>> {
+        let limits = self.run_config.limits.clone();
+        let program_count = self
+            .contracts
+            .iter()
+            .map(|(_, info)| info.execute_records.len())
+            .sum::<usize>();
+        let err = if self.contracts.len() > limits.max_contracts {
+            LimitError::Contracts {
+                count: self.contracts.len(),
+                max: limits.max_contracts,
+            }
+        } else if program_count > limits.max_programs {
+            LimitError::Programs {
+                count: program_count,
+                max: limits.max_programs,
+            }
+        } else if self.cycles > limits.max_cycles {
+            LimitError::Cycles {
+                cycles: self.cycles,
+                max: limits.max_cycles,
+            }
+        } else {
+            return Ok(());
+        };
+        Err(self.exceed_limit(err))
+    }
+
+    fn trace_enter(&mut self, program: &Program) {
+        let destination = self.destination(program, self.contracts.len() as u64);
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.enter(CallFrame::new(program, destination));
+        }
+    }
+
+    // Fill the frame with the result of the program just finished
+    fn trace_return(&mut self) {
+        let info = &self.contracts[self.contract_index].1;
+        let record = info.current_record();
+        if let Some(frame) = self.tracer.as_mut().and_then(|tracer| tracer.current_mut()) {
+            if let Err(err) = frame.set_return(&info.address, &record.return_data, &record.logs) {
+                frame.error = Some(err);
+            }
+        }
+    }
+
+    fn trace_exit(&mut self, error: Option<String>, cycles: u64) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            if let Some(frame) = tracer.current_mut() {
+                frame.cycles = cycles;
+                if error.is_some() {
+                    frame.error = error;
+                }
+            }
+            tracer.exit();
+        }
+    }
+
+    /// The call tree of the entrance program (tracer must be enabled)
+    pub fn take_trace(&mut self) -> Option<CallFrame> {
+        self.tracer.as_mut().and_then(|tracer| tracer.take_root())
+    }
 
+    fn run_program(&mut self, mut program: Program) -> Result<(), Box<dyn StdError>> {
         let mut info_address = match program.kind {
             CallKind::CALL | CallKind::CREATE | CallKind::CREATE2 => {
                 ContractAddress(program.destination.clone())
@@ -761,25 +1243,7 @@ impl CsalRunContext {
         let (contract_input_opt, tree, balance) = if program.is_create() {
             (None, SparseMerkleTree::default(), 0)
         } else {
-            self.get_contract_info(&info_address)
-                .map::<Result<_, String>, _>(|info| {
-                    let input_opt: Option<ContractInput> = info.input.clone();
-                    let tree = SparseMerkleTree::new(*info.tree.root(), info.tree.store().clone());
-                    Ok((input_opt, tree, info.balance))
-                })
-                .unwrap_or_else(|| {
-                    let change = self.loader.load_latest_contract_change(
-                        info_address.clone(),
-                        None,
-                        false,
-                        false,
-                    )?;
-                    let (output, data) = self
-                        .loader
-                        .load_contract_live_cell(change.tx_hash.clone(), change.output_index)?;
-                    let input = ContractInput::new(change.out_point(), output, data);
-                    Ok((Some(input), change.merkle_tree(), change.balance))
-                })?
+            self.contract_state(&info_address)?
         };
         let destination = self.destination(&program, self.contracts.len() as u64);
         let mut new_tree = SparseMerkleTree::new(*tree.root(), tree.store().clone());
@@ -789,6 +1253,10 @@ impl CsalRunContext {
         }
 
         log::debug!("[contract]: {:x}", info_address.0);
+        let saved_calls = self
+            .get_contract_info_mut(&info_address)
+            .map(|info| std::mem::take(&mut info.current_calls))
+            .unwrap_or_default();
         if let Some(contract_index) = self.get_contract_index(&info_address) {
             self.contract_index = contract_index;
             let info = &mut self.contracts[contract_index].1;
@@ -800,6 +1268,7 @@ impl CsalRunContext {
             info.add_record(program.clone());
             self.contracts.push((info_address, info));
         }
+        self.check_limits()?;
 
         self.handle_transfer(&program)?;
 
@@ -812,7 +1281,11 @@ impl CsalRunContext {
         let saved_execute_index = self.current_contract_info().execute_index;
         let config = Config::from(&self.run_config);
         if program.is_create() || !program.input.is_empty() {
-            let _result = match run_with_context(&config, &new_tree, &program_data, self) {
+            let saved_machine_cycles = std::mem::replace(&mut self.machine_cycles, 0);
+            let result = run_with_context(&config, &new_tree, &program_data, self);
+            self.cycles += self.machine_cycles;
+            self.machine_cycles = saved_machine_cycles;
+            let _result = match result {
                 Ok(result) => result,
                 Err(err) => {
                     log::warn!("Error: {:?}", err);
@@ -823,15 +1296,31 @@ impl CsalRunContext {
                     return Err(error_message.into());
                 }
             };
+            self.check_limits()?;
         }
         let current_info = self.current_contract_info_mut();
         current_info.execute_index = saved_execute_index;
 
+        // The calls of the program (special call included)
+        current_info.current_record_mut().calls =
+            std::mem::replace(&mut current_info.current_calls, saved_calls);
         if program.kind.is_special_call() {
             current_info.current_record_mut().run_proof =
                 Bytes::from(RunProofResult::default().serialize_pure().unwrap());
         } else {
             let run_result = std::mem::take(&mut current_info.run_result);
+            if let Some(frame) = self.tracer.as_mut().and_then(|tracer| tracer.current_mut()) {
+                frame.set_storage(&run_result);
+            }
+            let current_info = self.current_contract_info_mut();
+            for (key, value) in &run_result.write_values {
+                let old_value = smth256_to_h256(&new_tree.get(key).map_err(|err| err.to_string())?);
+                current_info
+                    .storage_changes
+                    .entry(smth256_to_h256(key))
+                    .or_insert((old_value, H256::default()))
+                    .1 = smth256_to_h256(value);
+            }
             let proof = run_result.generate_proof(&new_tree)?;
             print_proof(&proof);
             run_result.commit(&mut new_tree).unwrap();
@@ -840,8 +1329,6 @@ impl CsalRunContext {
             // Update run_proof
             current_info.current_record_mut().run_proof =
                 Bytes::from(proof.serialize_pure().unwrap());
-            current_info.current_record_mut().calls =
-                std::mem::take(&mut current_info.current_calls);
             if !run_result.write_values.is_empty() {
                 self.state_changed = true;
             }
@@ -858,6 +1345,7 @@ impl CsalRunContext {
             program.sender
         );
         if program.kind.is_special_call() {
+            // Run in the caller's context, the value is preserved not transferred
             log::debug!("skip special call");
             return Ok(());
         }
@@ -871,7 +1359,6 @@ impl CsalRunContext {
                 .ok_or_else(|| format!("sender {:x} must exists", program.sender))?
                 .sub_balance(program.value)?;
         }
-        // TODO: how to handle special call (CALLCODE/DELEGATECALL)?
         if let Some(dest_info) =
             self.get_contract_info_mut(&ContractAddress(program.destination.clone()))
         {
@@ -894,39 +1381,102 @@ impl CsalRunContext {
                 );
                 cell_mut.add_balance(program.value);
             } else {
-                panic!(
-                    "[ERROR NOT FOUND] add {} wei to address {:x}",
+                return Err(format!(
+                    "add {} wei to address {:x}: account not found",
                     program.value, destination
-                );
+                ));
             }
         }
         Ok(())
     }
 
+    // The state (input cell, storage tree, balance) of the contract, the
+    // overrides are applied when it's loaded from the database
+    fn contract_state(&mut self, address: &ContractAddress) -> Result<ContractState, String> {
+        if let Some(info) = self.get_contract_info(address) {
+            let tree = SparseMerkleTree::new(*info.tree.root(), info.tree.store().clone());
+            return Ok((info.input.clone(), tree, info.balance));
+        }
+        let state_override = self.overrides.get(&address.0).cloned().unwrap_or_default();
+        let (input, mut tree, balance) =
+            match self
+                .loader
+                .load_latest_contract_change(address.clone(), None, false, false)
+            {
+                Ok(change) => {
+                    let (output, data) = self
+                        .loader
+                        .load_contract_live_cell(change.tx_hash.clone(), change.output_index)?;
+                    let input = ContractInput::new(change.out_point(), output, data);
+                    (Some(input), change.merkle_tree(), change.balance)
+                }
+                // The contract only exists in the overrides
+                Err(_) if state_override.code.is_some() => (None, SparseMerkleTree::default(), 0),
+                Err(err) => return Err(err),
+            };
+        for (key, value) in &state_override.storage {
+            tree.update(h256_to_smth256(key), h256_to_smth256(value))
+                .map_err(|err| err.to_string())?;
+        }
+        Ok((input, tree, state_override.balance.unwrap_or(balance)))
+    }
+
+    // Load the EoA cell with the balance overridden, an overridden address which
+    // is neither an EoA nor a contract gets a placeholder cell
+    fn load_eoa_cell(
+        &mut self,
+        address: &H160,
+    ) -> Result<(value::EoaLiveCell, CellOutput, Bytes), String> {
+        let state_override = self.overrides.get(address).cloned();
+        match (
+            self.loader.load_eoa_live_cell(address.clone()),
+            state_override,
+        ) {
+            (
+                Ok((cell, output, data)),
+                Some(StateOverride {
+                    balance: Some(balance),
+                    ..
+                }),
+            ) => {
+                let occupied_capacity = cell.capacity() - cell.balance();
+                let cell = value::EoaLiveCell::new(
+                    cell.tx_hash,
+                    cell.output_index,
+                    occupied_capacity + balance,
+                    balance,
+                );
+                Ok((cell, output, data))
+            }
+            (Ok(loaded), _) => Ok(loaded),
+            (Err(err), Some(state_override)) => {
+                if state_override.code.is_some()
+                    || self
+                        .loader
+                        .load_contract_meta(ContractAddress(address.clone()))
+                        .is_ok()
+                {
+                    return Err(err);
+                }
+                let balance = state_override.balance.unwrap_or(0);
+                let cell = value::EoaLiveCell::new(Default::default(), 0, balance, balance);
+                Ok((cell, CellOutput::default(), Bytes::default()))
+            }
+            (Err(err), None) => Err(err),
+        }
+    }
+
     pub fn set_entrance_program(&mut self, program: Program) -> Result<(), Box<dyn StdError>> {
         if program.kind.is_call() {
-            let latest_change = self.loader.load_latest_contract_change(
-                ContractAddress(program.destination.clone()),
-                None,
-                false,
-                false,
-            )?;
-
-            let out_point = OutPoint::new(latest_change.tx_hash.pack(), latest_change.output_index);
-            let (contract_live_cell, latest_contract_data) = self.loader.load_contract_live_cell(
-                latest_change.tx_hash.clone(),
-                latest_change.output_index,
-            )?;
-            self.first_contract_input = Some(ContractInput::new(
-                out_point,
-                contract_live_cell,
-                latest_contract_data,
-            ));
+            let (input, _tree, _balance) =
+                self.contract_state(&ContractAddress(program.destination.clone()))?;
+            self.first_contract_input = input;
         }
         log::info!("> tx_origin: {:x}", program.sender);
         self.tx_origin = EoaAddress(program.sender.clone());
-        let (eoa_live_cell, output, output_data) =
-            self.loader.load_eoa_live_cell(program.sender.clone())?;
+        let (eoa_live_cell, output, output_data) = self.load_eoa_cell(&program.sender)?;
+        self.eoa_init_balances
+            .insert(program.sender.clone(), eoa_live_cell.balance());
         self.tx_origin_cell = eoa_live_cell;
         self.tx_origin_output = (output, output_data);
         self.entrance_program = Some(program);
@@ -966,6 +1516,10 @@ impl CsalRunContext {
     pub fn entrance_info(&self) -> &ContractInfo {
         &self.contracts[0].1
     }
+    /// All the contracts touched, the entrance contract is the first one
+    pub fn contracts(&self) -> impl Iterator<Item = &(ContractAddress, ContractInfo)> {
+        self.contracts.iter()
+    }
     pub fn created_contracts(&self) -> Vec<ContractAddress> {
         self.contracts
             .iter()
@@ -980,6 +1534,25 @@ impl CsalRunContext {
             .map(|(addr, _)| addr.clone())
             .collect()
     }
+    /// (address, old balance, new balance) of the contract and EoA accounts
+    pub fn balance_changes(&self) -> Vec<(H160, u64, u64)> {
+        let contract_changes = self
+            .contracts
+            .iter()
+            .map(|(addr, info)| (addr.0.clone(), info.init_balance, info.balance));
+        let eoa_changes = self
+            .other_eoa_cells
+            .iter()
+            .chain(std::iter::once((&self.tx_origin.0, &self.tx_origin_cell)))
+            .map(|(addr, cell)| {
+                let init_balance = self.eoa_init_balances.get(addr).cloned().unwrap_or(0);
+                (addr.clone(), init_balance, cell.balance())
+            });
+        contract_changes
+            .chain(eoa_changes)
+            .filter(|(_, old_balance, new_balance)| old_balance != new_balance)
+            .collect()
+    }
     pub fn get_logs(&self) -> Result<Vec<(ContractAddress, Vec<H256>, Bytes)>, String> {
         self.contracts
             .iter()
@@ -998,6 +1571,12 @@ impl CsalRunContext {
             .map(|info| info.code.clone())
             .filter(|code| !code.is_empty())
             .map(Ok)
+            .or_else(|| {
+                self.overrides
+                    .get(&address.0)
+                    .and_then(|state_override| state_override.code.clone())
+                    .map(Ok)
+            })
             .unwrap_or_else(|| {
                 self.loader
                     .load_contract_meta(address.clone())
@@ -1005,6 +1584,35 @@ impl CsalRunContext {
             })
     }
 
+    /// The code hash is zero for EoA accounts and destructed contracts. The
+    /// code of the contracts not in the transaction is recorded, it will be
+    /// put into the witness with the contract cells in cell_deps.
+    pub fn get_code_hash(&mut self, address: &ContractAddress) -> H256 {
+        let info_opt = self.get_contract_info(address);
+        if let Some(code) = info_opt
+            .map(|info| info.code.clone())
+            .filter(|code| !code.is_empty())
+            .or_else(|| {
+                self.overrides
+                    .get(&address.0)
+                    .and_then(|state_override| state_override.code.clone())
+            })
+        {
+            return code_hash(&code);
+        }
+        if info_opt.is_some() {
+            return H256::default();
+        }
+        match self.loader.load_contract_meta(address.clone()) {
+            Ok(meta) if !meta.destructed && !meta.code.is_empty() => {
+                let hash = code_hash(&meta.code);
+                self.code_deps.insert(address.0.clone(), meta.code);
+                hash
+            }
+            _ => H256::default(),
+        }
+    }
+
     pub fn get_contract_index(&self, address: &ContractAddress) -> Option<usize> {
         self.contracts.iter().position(|(addr, _)| addr == address)
     }
@@ -1034,7 +1642,9 @@ impl CsalRunContext {
         }
 
         if !self.other_eoa_cells.contains_key(address) {
-            let (cell, output, output_data) = self.loader.load_eoa_live_cell(address.clone())?;
+            let (cell, output, output_data) = self.load_eoa_cell(address)?;
+            self.eoa_init_balances
+                .insert(address.clone(), cell.balance());
             self.other_eoa_cells.insert(address.clone(), cell);
             self.other_eoa_outputs
                 .insert(address.clone(), (output, output_data));
@@ -1045,6 +1655,7 @@ impl CsalRunContext {
 
 impl<Mac: SupportMachine> RunContext<Mac> for CsalRunContext {
     fn ecall(&mut self, machine: &mut Mac) -> Result<bool, VMError> {
+        self.machine_cycles = machine.cycles();
         let code = machine.registers()[A7].to_u64();
         match code {
             // ckb_debug
@@ -1066,6 +1677,9 @@ impl<Mac: SupportMachine> RunContext<Mac> for CsalRunContext {
 
                 let s = String::from_utf8(buffer).map_err(|_| VMError::ParseError)?;
                 log::debug!("ckb_debug: {}", s);
+                if let Some(frame) = self.tracer.as_mut().and_then(|tracer| tracer.current_mut()) {
+                    frame.debug.push(s);
+                }
                 Ok(true)
             }
 
@@ -1209,21 +1823,23 @@ impl<Mac: SupportMachine> RunContext<Mac> for CsalRunContext {
                 log::debug!("kind: {:?}, flags: {}, depth: {}, destination: {:x}, sender: {:x}, input_data: {}, value: {}",
                             kind, flags, depth, destination, sender, hex::encode(&input_data), value_u64);
 
-                if kind == CallKind::DELEGATECALL && sender != self.tx_origin.0 {
-                    self.error_message = Some(format!(
-                        "Invalid DELEGATECALL: sender={:x}, tx_origin={:x}",
-                        sender, self.tx_origin.0
-                    ));
-                    return Err(VMError::Unexpected);
-                }
+                // The sender of DELEGATECALL is preserved from current frame. For
+                // other kinds the sender is the contract whose storage is in use,
+                // the message sender is the code contract in a special call frame.
+                let sender = if kind == CallKind::DELEGATECALL {
+                    sender
+                } else {
+                    self.current_contract_address().0.clone()
+                };
 
-                let dest_is_eoa = self.get_eoa_cell_mut(&destination).is_ok();
+                let is_precompile = kind.is_call() && self.precompiles.contains(&destination);
+                let dest_is_eoa = !is_precompile && self.get_eoa_cell_mut(&destination).is_ok();
                 let (code, input) = match kind {
                     CallKind::CREATE | CallKind::CREATE2 => {
                         (Bytes::from(input_data), Bytes::default())
                     }
                     CallKind::CALL | CallKind::DELEGATECALL | CallKind::CALLCODE => {
-                        let code = if dest_is_eoa {
+                        let code = if dest_is_eoa || is_precompile {
                             Default::default()
                         } else {
                             self.get_contract_code(&ContractAddress(destination.clone()))
@@ -1254,61 +1870,121 @@ impl<Mac: SupportMachine> RunContext<Mac> for CsalRunContext {
 
                 let mut error_message = None;
                 let destination = self.destination(&program, self.contracts.len() as u64);
-                let (dest_return_data, dest_program_index) =
-                    if program.is_transfer_only() && dest_is_eoa {
-                        log::debug!("transfer to eoa account");
-                        if let Err(err) = self.handle_transfer(&program) {
-                            error_message = Some(err);
-                        }
-                        self.error_message = error_message.take();
-                        if self.error_message.is_some() {
-                            return Err(VMError::Unexpected);
-                        }
-                        (Default::default(), 0)
+                let (dest_return_data, dest_program_index) = if is_precompile {
+                    log::debug!("call precompiled contract");
+                    self.trace_enter(&program);
+                    let cycles = self
+                        .precompiles
+                        .cycles(&program.destination, &program.input)
+                        .expect("precompiled contract");
+                    // Checked before executing, the programs still running are
+                    // not counted in `self.cycles` yet
+                    let max_cycles = self.run_config.limits.max_cycles;
+                    let total_cycles = self
+                        .cycles
+                        .saturating_add(machine.cycles())
+                        .saturating_add(cycles);
+                    let result = if program.value > 0 {
+                        Err(String::from("value transfer not supported"))
+                    } else if total_cycles > max_cycles {
+                        Err(self
+                            .exceed_limit(LimitError::Cycles {
+                                cycles: total_cycles,
+                                max: max_cycles,
+                            })
+                            .to_string())
+                    } else if let Err(err) = machine.add_cycles(cycles) {
+                        Err(err.to_string())
                     } else {
-                        let saved_contract_index = self.contract_index;
-                        if let Err(err) = self.run(program.clone()) {
-                            error_message = Some(format!("run program error: {}", err));
-                        }
-                        self.error_message = error_message.take();
-                        if self.error_message.is_some() {
-                            return Err(VMError::Unexpected);
-                        }
-                        // Must after run the program
-                        if kind.is_special_call() {
-                            if let Err(err) = self.add_special_call(program.clone()) {
-                                error_message = Some(format!("add special call error: {}", err));
+                        self.precompiles
+                            .execute(&program.destination, &program.input)
+                            .expect("precompiled contract")
+                    };
+                    let error_message = match result {
+                        Ok(ref return_data) => {
+                            if let Some(frame) =
+                                self.tracer.as_mut().and_then(|tracer| tracer.current_mut())
+                            {
+                                frame.return_data = JsonBytes::from_bytes(return_data.clone());
                             }
-                            self.error_message = error_message.take();
-                            if self.error_message.is_some() {
+                            None
+                        }
+                        Err(ref err) => Some(format!(
+                            "precompiled contract {:x} failed: {}",
+                            program.destination, err
+                        )),
+                    };
+                    self.trace_exit(error_message.clone(), cycles);
+                    self.error_message = error_message;
+                    if self.error_message.is_some() {
+                        return Err(VMError::Unexpected);
+                    }
+                    (result.unwrap_or_default(), 0)
+                } else if program.is_transfer_only() && dest_is_eoa {
+                    log::debug!("transfer to eoa account");
+                    self.trace_enter(&program);
+                    if let Err(err) = self.handle_transfer(&program) {
+                        error_message = Some(err);
+                    }
+                    self.trace_exit(error_message.clone(), 0);
+                    self.error_message = error_message.take();
+                    if self.error_message.is_some() {
+                        return Err(VMError::Unexpected);
+                    }
+                    (Default::default(), 0)
+                } else {
+                    let saved_contract_index = self.contract_index;
+                    let saved_execute_index = self.current_contract_info().execute_index;
+                    // Must before run the program
+                    let special_program_index = if kind.is_special_call() {
+                        match self.add_special_call(program.clone()) {
+                            Ok(program_index) => Some(program_index),
+                            Err(err) => {
+                                self.error_message =
+                                    Some(format!("add special call error: {}", err));
                                 return Err(VMError::Unexpected);
                             }
-                        };
-                        self.contract_index = saved_contract_index;
-                        let info_address = if kind.is_special_call() {
-                            self.current_contract_address().clone()
-                        } else {
-                            ContractAddress(destination.clone())
-                        };
-                        let dest_return_data = self
-                            .get_contract_info(&info_address)
-                            .expect("get contract info")
-                            .current_return_data()
-                            .clone();
-                        let dest_program_index = self
-                            .get_contract_info(&ContractAddress(destination.clone()))
-                            .expect("get contract info")
-                            .get_last_call();
-                        (dest_return_data, dest_program_index)
+                        }
+                    } else {
+                        None
+                    };
+                    if let Err(err) = self.run(program.clone()) {
+                        error_message = Some(format!("run program error: {}", err));
+                    }
+                    self.error_message = error_message.take();
+                    if self.error_message.is_some() {
+                        return Err(VMError::Unexpected);
+                    }
+                    self.contract_index = saved_contract_index;
+                    let info_address = if kind.is_special_call() {
+                        self.current_contract_address().clone()
+                    } else {
+                        ContractAddress(destination.clone())
                     };
+                    let dest_return_data = self
+                        .get_contract_info(&info_address)
+                        .expect("get contract info")
+                        .current_return_data()
+                        .clone();
+                    // Back to the record of current frame
+                    self.current_contract_info_mut().execute_index = saved_execute_index;
+                    let dest_program_index = special_program_index.unwrap_or_else(|| {
+                        self.get_contract_info(&ContractAddress(destination.clone()))
+                            .expect("get contract info")
+                            .get_last_call()
+                    });
+                    (dest_return_data, dest_program_index)
+                };
 
                 log::debug!("dest_program_index: {}", dest_program_index);
                 let call_record = CallRecord {
                     destination: destination.clone(),
                     program_index: dest_program_index,
-                    value: value_u64,
-                    transfer_only: program.is_transfer_only(),
+                    // No value transferred by special call
+                    value: if kind.is_special_call() { 0 } else { value_u64 },
+                    transfer_only: !is_precompile && program.is_transfer_only(),
                     is_eoa: dest_is_eoa,
+                    is_precompile,
                 };
                 self.current_contract_info_mut()
                     .current_calls
@@ -1402,6 +2078,14 @@ impl<Mac: SupportMachine> RunContext<Mac> for CsalRunContext {
             3081 => {
                 let block_hash_ptr = machine.registers()[A0].to_u64();
                 let number = machine.registers()[A1].to_u64();
+                if !block_hash_available(self.tip_block.number(), number) {
+                    log::debug!("get_block_hash({}), out of the window", number);
+                    machine
+                        .memory_mut()
+                        .store_bytes(block_hash_ptr, H256::default().as_bytes())?;
+                    machine.set_register(A0, Mac::REG::from_u8(0));
+                    return Ok(true);
+                }
                 let mut error_message = None;
                 let block_hash: H256 = match self.loader.load_header(Some(number)) {
                     Ok(header_view) => header_view.hash().unpack(),
@@ -1471,12 +2155,18 @@ impl<Mac: SupportMachine> RunContext<Mac> for CsalRunContext {
                 let balance_ptr = machine.registers()[A1].to_u64();
                 let info_address = ContractAddress(address.clone());
                 let mut error_message = None;
+                let state_override = self.overrides.get(&address).cloned().unwrap_or_default();
                 let balance_u64: u64 = if let Some(info) = self.get_contract_info(&info_address) {
                     // get balance from current related contract account
                     info.balance
+                } else if let Some(balance) = state_override.balance {
+                    balance
                 } else if let Ok(meta) = self.loader.load_contract_meta(info_address.clone()) {
                     // get balance from current unrelated(unchanged) contract account
                     meta.balance
+                } else if state_override.code.is_some() {
+                    // contract only exists in the overrides
+                    0
                 } else {
                     // get balance from EoA account
                     match self.get_eoa_cell_mut(&address) {
@@ -1506,6 +2196,21 @@ impl<Mac: SupportMachine> RunContext<Mac> for CsalRunContext {
                 machine.set_register(A0, Mac::REG::from_u8(0));
                 Ok(true)
             }
+            // evmc_bytes32 get_code_hash
+            3084 => {
+                let address_ptr = machine.registers()[A0].to_u64();
+                let address: H160 = vm_load_h160(machine, address_ptr)?;
+                let code_hash_ptr = machine.registers()[A1].to_u64();
+                let hash = self.get_code_hash(&ContractAddress(address.clone()));
+                log::debug!("get_code_hash: address={:x}, hash={:x}", address, hash);
+                machine
+                    .memory_mut()
+                    .store_bytes(code_hash_ptr, hash.as_bytes())?;
+                machine.set_register(A0, Mac::REG::from_u8(0));
+                Ok(true)
+            }
+            // finish (the cycles are already sampled above)
+            3085 => Ok(true),
             _ => Ok(false),
         }
     }
@@ -1553,3 +2258,583 @@ fn print_proof(proof: &RunProofResult) {
         hex::encode(&proof.write_old_proof[..])
     );
 }
+
+#[cfg(test)]
+mod test {
+    use super::super::db::{cf_handle, open_db, DbOptions};
+    use super::super::{replay_transaction, Indexer, IndexerStatus, Key, KeyType};
+    use super::*;
+    use crate::mock_chain::{eoa_output, index_until, test_run_config, MockChain};
+    use crate::types::ExecutionLimits;
+    use std::sync::Arc;
+
+    // Built by `make -C c`, the tests depend on it are ignored by default and
+    // run by `make test-generator`
+    fn load_generator() -> Bytes {
+        let path = std::env::var("POLYJUICE_GENERATOR")
+            .unwrap_or_else(|_| concat!(env!("CARGO_MANIFEST_DIR"), "/c/build/generator").into());
+        std::fs::read(&path)
+            .map(Bytes::from)
+            .unwrap_or_else(|err| panic!("load generator {}: {}", path, err))
+    }
+
+    // The indexer only requires the entrance program has a non-zero signature
+    fn fake_sign(tx: Transaction) -> Transaction {
+        let sign = |data: BytesOpt| {
+            let mut raw_witness = data.to_opt().expect("witness data").raw_data().to_vec();
+            // 4 bytes is for program length (u32)
+            raw_witness[4..4 + 65].copy_from_slice(&[1u8; 65][..]);
+            BytesOpt::new_builder()
+                .set(Some(Bytes::from(raw_witness).pack()))
+                .build()
+        };
+        let mut witnesses: Vec<_> = tx.witnesses().into_iter().collect();
+        let witness_args = WitnessArgs::from_slice(&witnesses[0].raw_data()).unwrap();
+        let witness_args = if witness_args.output_type().to_opt().is_some() {
+            let data = sign(witness_args.output_type());
+            witness_args.as_builder().output_type(data).build()
+        } else {
+            let data = sign(witness_args.input_type());
+            witness_args.as_builder().input_type(data).build()
+        };
+        witnesses[0] = witness_args.as_bytes().pack();
+        tx.into_view()
+            .as_advanced_builder()
+            .set_witnesses(witnesses)
+            .build()
+            .data()
+    }
+
+    // A chain with an EoA account (the returned address) indexed to genesis
+    fn setup_chain(generator: Bytes) -> (tempfile::TempDir, MockChain, Indexer, H160) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open_db(dir.path(), &DbOptions::default()).unwrap());
+        let run_config = test_run_config(generator);
+        let (sender_output, sender) = eoa_output(
+            &run_config,
+            &H160::from_slice(&[1u8; 20]).unwrap(),
+            &H256::from_slice(&[1u8; 32]).unwrap(),
+            1000 * ONE_CKB,
+        );
+        let genesis_tx = TransactionBuilder::default()
+            .output(sender_output)
+            .output_data(Bytes::default().pack())
+            .build();
+        let chain = MockChain::new(vec![genesis_tx]);
+        let mut indexer = Indexer::with_chain(
+            db,
+            Box::new(chain.clone()),
+            run_config,
+            IndexerStatus::new_shared(),
+        );
+        index_until(&mut indexer, 0);
+        (dir, chain, indexer, sender)
+    }
+
+    #[test]
+    #[ignore = "requires c/build/generator"]
+    fn test_create_contract_round_trip() {
+        let generator = load_generator();
+        let (_dir, chain, mut indexer, sender) = setup_chain(generator);
+        let run_config = indexer.run_config.clone();
+
+        let loader = indexer.loader.clone();
+        let mut runner = Runner::new(loader.clone(), run_config);
+        // SSTORE(0, 1) then return a single STOP as the contract code
+        let code = Bytes::from(hex::decode("6001600055600060005360016000f3").unwrap());
+        let mut context = runner.create(sender.clone(), code, 0).unwrap();
+        let address = context.entrance_contract();
+        let tx = fake_sign(context.build_tx().unwrap());
+        chain.produce_block(vec![tx.clone().into_view()]);
+        index_until(&mut indexer, 1);
+
+        let tx_hash: H256 = tx.calc_tx_hash().unpack();
+        let meta = loader.load_contract_meta(address.clone()).unwrap();
+        assert_eq!(meta.tx_hash, tx_hash);
+        assert!(!meta.destructed);
+        let change = loader
+            .load_latest_contract_change(address, None, false, true)
+            .unwrap();
+        assert!(change.is_create);
+        assert_eq!(change.tx_origin, EoaAddress(sender));
+        assert_eq!(change.tx_hash, tx_hash);
+    }
+
+    #[test]
+    #[ignore = "requires c/build/generator"]
+    fn test_trace_create() {
+        let generator = load_generator();
+        let (_dir, _chain, indexer, sender) = setup_chain(generator);
+        let mut runner = Runner::new(indexer.loader.clone(), indexer.run_config.clone());
+        // SSTORE(0, 1) then return a single STOP as the contract code
+        let code = Bytes::from(hex::decode("6001600055600060005360016000f3").unwrap());
+        let frame = runner
+            .trace_call(sender.clone(), None, code.clone(), 0)
+            .unwrap();
+        assert_eq!(frame.kind, CallKind::CREATE);
+        assert_eq!(frame.sender, sender);
+        assert_eq!(frame.input.as_bytes(), code.as_ref());
+        assert_eq!(frame.return_data.as_bytes(), &[0u8][..]);
+        assert_eq!(frame.error, None);
+        assert!(frame.cycles > 0);
+        assert!(frame.calls.is_empty());
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        assert_eq!(
+            frame.storage_writes,
+            vec![(H256::default(), H256::from_slice(&one).unwrap())]
+        );
+
+        // Not a contract
+        assert!(runner
+            .trace_call(
+                sender,
+                Some(ContractAddress(H160::default())),
+                Bytes::new(),
+                0
+            )
+            .is_err());
+    }
+
+    #[test]
+    #[ignore = "requires c/build/generator"]
+    fn test_simulate_create() {
+        let generator = load_generator();
+        let (_dir, _chain, indexer, sender) = setup_chain(generator);
+        let mut runner = Runner::new(indexer.loader.clone(), indexer.run_config.clone());
+        // SSTORE(0, 1) then return a single STOP as the contract code
+        let code = Bytes::from(hex::decode("6001600055600060005360016000f3").unwrap());
+        let context = runner.simulate(sender.clone(), None, code, 5).unwrap();
+        let address = context.entrance_contract();
+        assert!(context.state_changed());
+        assert!(context.cycles > 0);
+        assert_eq!(context.created_contracts(), vec![address.clone()]);
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let storage_changes = context
+            .contracts()
+            .map(|(addr, info)| (addr.clone(), info.storage_changes.clone()))
+            .collect::<Vec<_>>();
+        let mut expected_changes = BTreeMap::default();
+        expected_changes.insert(
+            H256::default(),
+            (H256::default(), H256::from_slice(&one).unwrap()),
+        );
+        assert_eq!(storage_changes, vec![(address.clone(), expected_changes)]);
+        let balance = context.tx_origin_cell.balance();
+        assert_eq!(
+            context.balance_changes(),
+            vec![(address.0, 0, 5), (sender, balance + 5, balance)]
+        );
+    }
+
+    #[test]
+    #[ignore = "requires c/build/generator"]
+    fn test_static_call_state_overrides() {
+        let generator = load_generator();
+        let (_dir, _chain, indexer, _sender) = setup_chain(generator);
+        let mut runner = Runner::new(indexer.loader.clone(), indexer.run_config.clone());
+        // Neither the sender nor the contract exists
+        let sender = H160::from_slice(&[2u8; 20]).unwrap();
+        let destination = H160::from_slice(&[3u8; 20]).unwrap();
+        // Return SLOAD(0)
+        let code = Bytes::from(hex::decode("60005460005260206000f3").unwrap());
+        let mut value = [0u8; 32];
+        value[31] = 0x2a;
+        let mut storage = HashMap::default();
+        storage.insert(H256::default(), H256::from_slice(&value).unwrap());
+        let mut overrides = HashMap::default();
+        overrides.insert(
+            destination.clone(),
+            StateOverride {
+                code: Some(code),
+                balance: None,
+                storage,
+            },
+        );
+        let context = runner
+            .static_call(
+                sender.clone(),
+                ContractAddress(destination.clone()),
+                Bytes::new(),
+                overrides,
+            )
+            .unwrap();
+        assert_eq!(context.entrance_info().return_data().as_ref(), &value[..]);
+
+        // Not overridden
+        assert!(runner
+            .static_call(
+                sender,
+                ContractAddress(destination),
+                Bytes::new(),
+                HashMap::default()
+            )
+            .is_err());
+    }
+
+    // Deploy the runtime code in a new block, return the contract address
+    fn deploy(
+        chain: &MockChain,
+        indexer: &mut Indexer,
+        sender: &H160,
+        runtime_code: &str,
+    ) -> ContractAddress {
+        let runtime_code = hex::decode(runtime_code).unwrap();
+        // CODECOPY the runtime code (after the 12 bytes init code) then return it
+        let len = runtime_code.len() as u8;
+        let mut code = vec![
+            0x60, len, 0x60, 0x0c, 0x60, 0x00, 0x39, 0x60, len, 0x60, 0x00, 0xf3,
+        ];
+        code.extend(runtime_code);
+        let mut runner = Runner::new(indexer.loader.clone(), indexer.run_config.clone());
+        let mut context = runner.create(sender.clone(), Bytes::from(code), 0).unwrap();
+        let address = context.entrance_contract();
+        let tx = fake_sign(context.build_tx().unwrap());
+        let block = chain.produce_block(vec![tx.into_view()]);
+        index_until(indexer, block.number());
+        address
+    }
+
+    #[test]
+    #[ignore = "requires c/build/generator"]
+    fn test_nested_delegatecall() {
+        let generator = load_generator();
+        let (_dir, chain, mut indexer, sender) = setup_chain(generator);
+        // CALL/DELEGATECALL the address with 1 byte input then STOP
+        let call =
+            |address: &ContractAddress| format!("6000600060016000600073{:x}5af15000", address.0);
+        let delegatecall =
+            |address: &ContractAddress| format!("600060006001600073{:x}5af45000", address.0);
+        // SSTORE(1, 0x22)
+        let lib2 = deploy(&chain, &mut indexer, &sender, "602260015500");
+        // SSTORE(0, 0x11) then DELEGATECALL lib2
+        let lib = deploy(
+            &chain,
+            &mut indexer,
+            &sender,
+            &format!("6011600055{}", delegatecall(&lib2)),
+        );
+        // DELEGATECALL lib
+        let proxy = deploy(&chain, &mut indexer, &sender, &delegatecall(&lib));
+        // CALL proxy, so the DELEGATECALLs are from a nested frame
+        let entrance = deploy(&chain, &mut indexer, &sender, &call(&proxy));
+
+        let loader = indexer.loader.clone();
+        let mut runner = Runner::new(loader.clone(), indexer.run_config.clone());
+        let mut context = runner
+            .call(sender, entrance, Bytes::from(vec![0u8]), 0)
+            .unwrap();
+        let tx = fake_sign(context.build_tx().unwrap());
+        let block = chain.produce_block(vec![tx.into_view()]);
+        index_until(&mut indexer, block.number());
+
+        let value = |byte: u8| {
+            let mut value = [0u8; 32];
+            value[31] = byte;
+            H256::from_slice(&value).unwrap()
+        };
+        let mut expected_storage = HashMap::default();
+        expected_storage.insert(value(0), value(0x11));
+        expected_storage.insert(value(1), value(0x22));
+        // All the storage changes are in the proxy contract
+        let change = loader
+            .load_latest_contract_change(proxy, None, false, false)
+            .unwrap();
+        assert_eq!(change.new_storage, expected_storage);
+        for address in vec![lib, lib2] {
+            let change = loader
+                .load_latest_contract_change(address, None, false, false)
+                .unwrap();
+            assert!(change.new_storage.is_empty());
+        }
+    }
+
+    #[test]
+    #[ignore = "requires c/build/generator"]
+    fn test_execution_limits() {
+        let generator = load_generator();
+        let (_dir, chain, mut indexer, sender) = setup_chain(generator);
+        // CALL itself with 1 byte input forever
+        let address = deploy(
+            &chain,
+            &mut indexer,
+            &sender,
+            "60006000600160006000305af15000",
+        );
+        let run = |limits: ExecutionLimits| {
+            let mut run_config = indexer.run_config.clone();
+            run_config.limits = limits;
+            let mut runner = Runner::new(indexer.loader.clone(), run_config);
+            let err = runner
+                .call(sender.clone(), address.clone(), Bytes::from(vec![0u8]), 0)
+                .map(|_| ())
+                .unwrap_err();
+            err.downcast_ref::<LimitError>().cloned()
+        };
+
+        let limits = ExecutionLimits {
+            max_depth: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            run(limits),
+            Some(LimitError::CallDepth { depth: 4, max: 3 })
+        );
+        let limits = ExecutionLimits {
+            max_programs: 2,
+            ..Default::default()
+        };
+        assert_eq!(run(limits), Some(LimitError::Programs { count: 3, max: 2 }));
+    }
+
+    #[test]
+    #[ignore = "requires c/build/generator"]
+    fn test_replay_transaction() {
+        let generator = load_generator();
+        let (_dir, chain, mut indexer, sender) = setup_chain(generator);
+        let run_config = indexer.run_config.clone();
+        let mut loader = indexer.loader.clone();
+        let mut runner = Runner::new(loader.clone(), run_config.clone());
+        let code = Bytes::from(hex::decode("6001600055600060005360016000f3").unwrap());
+        let mut context = runner.create(sender.clone(), code, 0).unwrap();
+        let address = context.entrance_contract();
+        let tx = fake_sign(context.build_tx().unwrap());
+        let tx_hash: H256 = tx.calc_tx_hash().unpack();
+        // Not committed yet
+        assert!(replay_transaction(&mut loader, &run_config, &tx_hash).is_err());
+
+        chain.produce_block(vec![tx.into_view()]);
+        index_until(&mut indexer, 1);
+        let frame = replay_transaction(&mut loader, &run_config, &tx_hash).unwrap();
+        assert_eq!(frame.kind, CallKind::CREATE);
+        assert_eq!(frame.sender, sender);
+        assert_eq!(frame.destination, address.0);
+        assert_eq!(frame.return_data.as_bytes(), &[0u8][..]);
+        assert_eq!(frame.error, None);
+        assert!(frame.cycles > 0);
+        assert_eq!(frame.storage_writes.len(), 1);
+    }
+
+    #[test]
+    fn test_deposit_and_withdraw() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open_db(dir.path(), &DbOptions::default()).unwrap());
+        let run_config = test_run_config(Bytes::default());
+        let lock_arg = H160::from_slice(&[1u8; 20]).unwrap();
+        let (account_output, account) = eoa_output(
+            &run_config,
+            &lock_arg,
+            &H256::from_slice(&[1u8; 32]).unwrap(),
+            1000 * ONE_CKB,
+        );
+        let genesis_tx = TransactionBuilder::default()
+            .output(account_output)
+            .output_data(Bytes::default().pack())
+            .output(
+                CellOutput::new_builder()
+                    .capacity((500 * ONE_CKB).pack())
+                    .lock(sighash_lock(&lock_arg))
+                    .build(),
+            )
+            .output_data(Bytes::default().pack())
+            .build();
+        let chain = MockChain::new(vec![genesis_tx]);
+        let mut indexer = Indexer::with_chain(
+            db,
+            Box::new(chain.clone()),
+            run_config,
+            IndexerStatus::new_shared(),
+        );
+        index_until(&mut indexer, 0);
+        let mut runner = Runner::new(indexer.loader.clone(), indexer.run_config.clone());
+        let load_balance = |runner: &mut Runner| {
+            runner
+                .loader
+                .load_eoa_live_cell(account.clone())
+                .map(|(cell, _, _)| cell.balance())
+        };
+        let init_balance = load_balance(&mut runner).unwrap();
+
+        // The change goes back to the sighash lock
+        let tx = runner
+            .deposit(lock_arg.clone(), account.clone(), 200 * ONE_CKB)
+            .unwrap();
+        assert_eq!(tx.raw().inputs().len(), 2);
+        let change: u64 = tx.raw().outputs().get(1).unwrap().capacity().unpack();
+        assert_eq!(change, 299 * ONE_CKB);
+        let block = chain.produce_block(vec![tx.into_view()]);
+        index_until(&mut indexer, block.number());
+        assert_eq!(
+            load_balance(&mut runner).unwrap(),
+            init_balance + 200 * ONE_CKB
+        );
+
+        // The withdrawn cell must be able to hold itself
+        assert!(runner
+            .withdraw(account.clone(), lock_arg.clone(), Some(ONE_CKB))
+            .is_err());
+        let tx = runner
+            .withdraw(account.clone(), lock_arg.clone(), Some(100 * ONE_CKB))
+            .unwrap();
+        let block = chain.produce_block(vec![tx.into_view()]);
+        index_until(&mut indexer, block.number());
+        assert_eq!(
+            load_balance(&mut runner).unwrap(),
+            init_balance + 99 * ONE_CKB
+        );
+
+        // Withdraw all closes the account
+        let tx = runner
+            .withdraw(account.clone(), lock_arg.clone(), None)
+            .unwrap();
+        assert_eq!(tx.raw().outputs().len(), 1);
+        let capacity: u64 = tx.raw().outputs().get(0).unwrap().capacity().unpack();
+        assert_eq!(capacity, 1098 * ONE_CKB);
+        let block = chain.produce_block(vec![tx.into_view()]);
+        index_until(&mut indexer, block.number());
+        assert!(load_balance(&mut runner).is_err());
+        assert!(runner.deposit(lock_arg, account, 200 * ONE_CKB).is_err());
+    }
+
+    // The limits are checked before the program runs, no generator required
+    #[test]
+    fn test_limits_before_execution() {
+        let (_dir, _chain, indexer, sender) = setup_chain(Bytes::default());
+        let code = Bytes::from(hex::decode("6001600055600060005360016000f3").unwrap());
+        let run = |limits: ExecutionLimits| {
+            let mut run_config = indexer.run_config.clone();
+            run_config.limits = limits;
+            let mut runner = Runner::new(indexer.loader.clone(), run_config);
+            let err = runner
+                .create(sender.clone(), code.clone(), 0)
+                .map(|_| ())
+                .unwrap_err();
+            err.downcast_ref::<LimitError>().cloned()
+        };
+        let limits = ExecutionLimits {
+            max_contracts: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            run(limits),
+            Some(LimitError::Contracts { count: 1, max: 0 })
+        );
+        let limits = ExecutionLimits {
+            max_programs: 0,
+            ..Default::default()
+        };
+        assert_eq!(run(limits), Some(LimitError::Programs { count: 1, max: 0 }));
+
+        // Not a contract
+        let mut runner = Runner::new(indexer.loader.clone(), indexer.run_config.clone());
+        let destination = ContractAddress(H160::from_slice(&[3u8; 20]).unwrap());
+        assert!(runner
+            .call(sender.clone(), destination.clone(), Bytes::new(), 0)
+            .is_err());
+        assert!(runner
+            .trace_call(sender, Some(destination), Bytes::new(), 0)
+            .is_err());
+    }
+
+    #[test]
+    fn test_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open_db(dir.path(), &DbOptions::default()).unwrap());
+        let run_config = test_run_config(Bytes::default());
+        let eoa = |byte: u8, capacity: u64| {
+            eoa_output(
+                &run_config,
+                &H160::from_slice(&[byte; 20]).unwrap(),
+                &H256::from_slice(&[byte; 32]).unwrap(),
+                capacity,
+            )
+        };
+        let (alice_output, alice) = eoa(1, 1000 * ONE_CKB);
+        let (bob_output, bob) = eoa(2, 100 * ONE_CKB);
+        let genesis_tx = TransactionBuilder::default()
+            .output(alice_output)
+            .output_data(Bytes::default().pack())
+            .output(bob_output)
+            .output_data(Bytes::default().pack())
+            .build();
+        let chain = MockChain::new(vec![genesis_tx]);
+        let mut indexer = Indexer::with_chain(
+            db,
+            Box::new(chain.clone()),
+            run_config,
+            IndexerStatus::new_shared(),
+        );
+        index_until(&mut indexer, 0);
+        let mut runner = Runner::new(indexer.loader.clone(), indexer.run_config.clone());
+        let (alice_cell, _, _) = runner.loader.load_eoa_live_cell(alice.clone()).unwrap();
+        let (bob_cell, _, _) = runner.loader.load_eoa_live_cell(bob.clone()).unwrap();
+
+        assert!(runner.transfer(alice.clone(), alice.clone(), 1).is_err());
+        assert!(runner.transfer(alice.clone(), bob.clone(), 0).is_err());
+        // The fee is paid by the sender
+        assert!(runner
+            .transfer(alice.clone(), bob.clone(), alice_cell.balance())
+            .is_err());
+
+        let tx = runner
+            .transfer(alice.clone(), bob.clone(), 200 * ONE_CKB)
+            .unwrap();
+        assert_eq!(tx.raw().inputs().len(), 2);
+        let capacity =
+            |index: usize| -> u64 { tx.raw().outputs().get(index).unwrap().capacity().unpack() };
+        assert_eq!(capacity(0), alice_cell.capacity() - 200 * ONE_CKB - TX_FEE);
+        assert_eq!(capacity(1), bob_cell.capacity() + 200 * ONE_CKB);
+        let block = chain.produce_block(vec![tx.into_view()]);
+        index_until(&mut indexer, block.number());
+        let (new_bob_cell, _, _) = runner.loader.load_eoa_live_cell(bob).unwrap();
+        assert_eq!(new_bob_cell.balance(), bob_cell.balance() + 200 * ONE_CKB);
+    }
+
+    #[test]
+    fn test_code_hash_of_untouched_contract() {
+        let (_dir, _chain, mut indexer, sender) = setup_chain(Bytes::default());
+        let address = ContractAddress(H160::from_slice(&[3u8; 20]).unwrap());
+        let code = Bytes::from(vec![0x60, 0x00]);
+        let meta = value::ContractMeta {
+            code: code.clone(),
+            tx_hash: H256::default(),
+            output_index: 0,
+            balance: 0,
+            destructed: false,
+        };
+        let db = &indexer.loader.db;
+        db.put_cf(
+            cf_handle(db, KeyType::ContractMeta),
+            &Bytes::from(&Key::ContractMeta(address.clone())),
+            &bincode::serialize(&meta).unwrap(),
+        )
+        .unwrap();
+
+        let tip_block = indexer.loader.load_block(None).unwrap();
+        let mut context = CsalRunContext::new(
+            indexer.loader.clone(),
+            indexer.run_config.clone(),
+            tip_block,
+        );
+        // The contract exists but is not in the transaction
+        assert_eq!(context.get_contract_code(&address).unwrap(), code);
+        assert_eq!(context.get_code_hash(&address), code_hash(&code));
+        assert_eq!(context.code_deps.get(&address.0), Some(&code));
+        assert_eq!(
+            context.get_code_hash(&ContractAddress(sender)),
+            H256::default()
+        );
+        let mut overrides = HashMap::default();
+        overrides.insert(
+            address.0.clone(),
+            StateOverride {
+                code: Some(code.clone()),
+                balance: None,
+                storage: HashMap::default(),
+            },
+        );
+        context.overrides = overrides;
+        assert_eq!(context.get_code_hash(&address), code_hash(&code));
+    }
+}
@@ -0,0 +1,410 @@
+//! Snapshot file format (all integers are little endian):
+//!
+//!   magic (8 bytes) | format version (u32) | schema version (u32)
+//!   | last block number (u64) | last block hash (32 bytes)
+//!   | records: (key length (u32), key, value length (u32), value)*
+//!   | end mark: key length = 0 (u32)
+//!   | checksum: blake2b of all the bytes above (32 bytes)
+//!
+//! The column family of a record is decided by the first byte of the key
+//! (`KeyType`), so the snapshot is not bound to the database layout.
+
+use bincode::deserialize;
+use ckb_hash::{new_blake2b, Blake2b};
+use ckb_types::{bytes::Bytes, H256};
+use rocksdb::{WriteBatch, DB};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::db::{cf_handle, is_empty, COLUMN_KEY_TYPES};
+use super::migration::{migrate, SCHEMA_VERSION};
+use super::{value, Key, KeyType};
+use crate::client::{ChainSource, HttpRpcClient};
+
+const MAGIC: &[u8; 8] = b"PJSNAPSH";
+const FORMAT_VERSION: u32 = 1;
+/// How many records are written in one write batch when importing
+const IMPORT_BATCH_SIZE: usize = 10000;
+/// Upper bounds of the record sizes, a corrupted length must not make us
+/// allocate the whole memory before the checksum is verified
+const MAX_KEY_SIZE: usize = 1024;
+const MAX_VALUE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+    pub schema_version: u32,
+    /// The last indexed block
+    pub number: u64,
+    pub hash: H256,
+    /// Total records (keys)
+    pub records: u64,
+}
+
+/// Dump the database into `path`. All keys are read from one RocksDB
+/// snapshot so they are consistent with the `Key::Last` block. The event
+/// cursors belong to the local event sinks and are not exported.
+pub fn export_snapshot<P: AsRef<Path>>(db: &DB, path: P) -> Result<SnapshotInfo, String> {
+    let snapshot = db.snapshot();
+    let schema_version: value::Version = snapshot
+        .get_cf(cf_handle(db, KeyType::Version), &Bytes::from(&Key::Version))
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| String::from("Database schema version not found"))
+        .and_then(|value_bytes| deserialize(&value_bytes).map_err(|err| err.to_string()))?;
+    let schema_version = schema_version.0;
+    let last: value::Last = snapshot
+        .get_cf(cf_handle(db, KeyType::Last), &Bytes::from(&Key::Last))
+        .map_err(|err| err.to_string())?
+        .ok_or_else(|| String::from("Nothing indexed yet"))
+        .and_then(|value_bytes| deserialize(&value_bytes).map_err(|err| err.to_string()))?;
+
+    let file = File::create(path).map_err(|err| err.to_string())?;
+    let mut writer = HashWriter::new(BufWriter::new(file));
+    let mut records: u64 = 0;
+    (|| -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&schema_version.to_le_bytes())?;
+        writer.write_all(&last.number.to_le_bytes())?;
+        writer.write_all(last.hash.as_bytes())?;
+        for key_type in [KeyType::Version].iter().chain(COLUMN_KEY_TYPES.iter()) {
+            let mut iter = snapshot.raw_iterator_cf(cf_handle(db, *key_type));
+            iter.seek_to_first();
+            while iter.valid() {
+                let key = iter.key().unwrap();
+                let value = iter.value().unwrap();
+                if key.first() == Some(&(KeyType::EventCursor as u8)) {
+                    iter.next();
+                    continue;
+                }
+                writer.write_all(&(key.len() as u32).to_le_bytes())?;
+                writer.write_all(key)?;
+                writer.write_all(&(value.len() as u32).to_le_bytes())?;
+                writer.write_all(value)?;
+                records += 1;
+                iter.next();
+            }
+        }
+        writer.write_all(&0u32.to_le_bytes())?;
+        let (mut inner, checksum) = writer.finish();
+        inner.write_all(&checksum)?;
+        inner.flush()
+    })()
+    .map_err(|err| err.to_string())?;
+
+    Ok(SnapshotInfo {
+        schema_version,
+        number: last.number,
+        hash: last.hash,
+        records,
+    })
+}
+
+/// Restore the snapshot into an empty database. The checksum is verified
+/// before writing anything, and the last block must be on the chain of the
+/// CKB node. The database is left empty if the import fails.
+pub fn import_snapshot<P: AsRef<Path>>(
+    db: &DB,
+    path: P,
+    ckb_uri: &str,
+) -> Result<SnapshotInfo, String> {
+    if !is_empty(db) {
+        return Err(String::from("The database is not empty"));
+    }
+    let info = read_snapshot(path.as_ref(), |_, _| Ok(()))?;
+    if info.schema_version > SCHEMA_VERSION {
+        return Err(format!(
+            "Snapshot schema version {} is newer than supported version {}, please upgrade polyjuice",
+            info.schema_version, SCHEMA_VERSION
+        ));
+    }
+    let header = HttpRpcClient::new(ckb_uri.to_string())
+        .get_header_by_number(info.number)?
+        .ok_or_else(|| format!("CKB node has not synced to block {}", info.number))?;
+    if header.hash != info.hash {
+        return Err(format!(
+            "Snapshot block {} hash {:#x} mismatch with CKB chain: {:#x}",
+            info.number, info.hash, header.hash
+        ));
+    }
+
+    write_snapshot(db, path.as_ref())
+}
+
+// Write all records of the snapshot into the database and upgrade it, clear
+// the database on error so the import can be retried.
+fn write_snapshot(db: &DB, path: &Path) -> Result<SnapshotInfo, String> {
+    let result = (|| -> Result<SnapshotInfo, String> {
+        let mut batch = WriteBatch::default();
+        let mut batch_size = 0;
+        let info = read_snapshot(path, |key, value| {
+            let key_type = key
+                .first()
+                .ok_or_else(|| String::from("empty key"))
+                .and_then(|byte| KeyType::try_from(*byte))?;
+            batch.put_cf(cf_handle(db, key_type), key, value);
+            batch_size += 1;
+            if batch_size >= IMPORT_BATCH_SIZE {
+                db.write(std::mem::take(&mut batch))
+                    .map_err(|err| err.to_string())?;
+                batch_size = 0;
+            }
+            Ok(())
+        })?;
+        db.write(batch).map_err(|err| err.to_string())?;
+        // Upgrade snapshot from older polyjuice
+        migrate(db)?;
+        Ok(info)
+    })();
+    if result.is_err() {
+        clear_db(db)?;
+    }
+    result
+}
+
+fn clear_db(db: &DB) -> Result<(), String> {
+    for key_type in [KeyType::Version].iter().chain(COLUMN_KEY_TYPES.iter()) {
+        let cf = cf_handle(db, *key_type);
+        let mut batch = WriteBatch::default();
+        let mut iter = db.raw_iterator_cf(cf);
+        iter.seek_to_first();
+        while iter.valid() {
+            batch.delete_cf(cf, iter.key().unwrap());
+            iter.next();
+        }
+        db.write(batch).map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+/// Read all records of the snapshot and verify the checksum
+fn read_snapshot<F>(path: &Path, mut f: F) -> Result<SnapshotInfo, String>
+where
+    F: FnMut(&[u8], &[u8]) -> Result<(), String>,
+{
+    let file = File::open(path).map_err(|err| err.to_string())?;
+    let mut reader = HashReader::new(BufReader::new(file));
+    let io_err = |err: io::Error| format!("Read snapshot error: {}", err);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(io_err)?;
+    if &magic != MAGIC {
+        return Err(String::from("Not a polyjuice snapshot file"));
+    }
+    let format_version = read_u32(&mut reader).map_err(io_err)?;
+    if format_version != FORMAT_VERSION {
+        return Err(format!(
+            "Unsupported snapshot format version: {}",
+            format_version
+        ));
+    }
+    let schema_version = read_u32(&mut reader).map_err(io_err)?;
+    let mut number_bytes = [0u8; 8];
+    reader.read_exact(&mut number_bytes).map_err(io_err)?;
+    let mut hash_bytes = [0u8; 32];
+    reader.read_exact(&mut hash_bytes).map_err(io_err)?;
+
+    let mut records: u64 = 0;
+    loop {
+        let key_len = read_u32(&mut reader).map_err(io_err)? as usize;
+        if key_len == 0 {
+            break;
+        }
+        if key_len > MAX_KEY_SIZE {
+            return Err(format!("Snapshot key too large: {} bytes", key_len));
+        }
+        let key = read_bytes(&mut reader, key_len).map_err(io_err)?;
+        let value_len = read_u32(&mut reader).map_err(io_err)? as usize;
+        if value_len > MAX_VALUE_SIZE {
+            return Err(format!("Snapshot value too large: {} bytes", value_len));
+        }
+        let value = read_bytes(&mut reader, value_len).map_err(io_err)?;
+        f(&key, &value)?;
+        records += 1;
+    }
+    let (mut inner, expected_checksum) = reader.finish();
+    let mut checksum = [0u8; 32];
+    inner.read_exact(&mut checksum).map_err(io_err)?;
+    if checksum != expected_checksum {
+        return Err(String::from("Snapshot checksum mismatch"));
+    }
+    if inner.read(&mut [0u8; 1]).map_err(io_err)? != 0 {
+        return Err(String::from("Unexpected data after snapshot checksum"));
+    }
+    Ok(SnapshotInfo {
+        schema_version,
+        number: u64::from_le_bytes(number_bytes),
+        hash: H256::from(hash_bytes),
+        records,
+    })
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_bytes<R: Read>(reader: &mut R, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+struct HashWriter<W> {
+    inner: W,
+    hasher: Blake2b,
+}
+
+impl<W: Write> HashWriter<W> {
+    fn new(inner: W) -> HashWriter<W> {
+        HashWriter {
+            inner,
+            hasher: new_blake2b(),
+        }
+    }
+    fn finish(self) -> (W, [u8; 32]) {
+        let mut hash = [0u8; 32];
+        self.hasher.finalize(&mut hash);
+        (self.inner, hash)
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.hasher.update(&buf[..size]);
+        Ok(size)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct HashReader<R> {
+    inner: R,
+    hasher: Blake2b,
+}
+
+impl<R: Read> HashReader<R> {
+    fn new(inner: R) -> HashReader<R> {
+        HashReader {
+            inner,
+            hasher: new_blake2b(),
+        }
+    }
+    fn finish(self) -> (R, [u8; 32]) {
+        let mut hash = [0u8; 32];
+        self.hasher.finalize(&mut hash);
+        (self.inner, hash)
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        self.hasher.update(&buf[..size]);
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::db::{open_db, DbOptions};
+    use super::*;
+    use bincode::serialize;
+    use ckb_types::h256;
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path().join("db"), &DbOptions::default()).unwrap();
+        migrate(&db).unwrap();
+        let last = value::Last {
+            number: 3,
+            hash: h256!("0x3"),
+        };
+        db.put_cf(
+            cf_handle(&db, KeyType::Last),
+            &Bytes::from(&Key::Last),
+            &serialize(&last).unwrap(),
+        )
+        .unwrap();
+        db.put_cf(
+            cf_handle(&db, KeyType::BlockMap),
+            &Bytes::from(&Key::BlockMap(3)),
+            &[3u8; 32][..],
+        )
+        .unwrap();
+        db.put_cf(
+            cf_handle(&db, KeyType::EventCursor),
+            &Bytes::from(&Key::EventCursor("sink".to_string())),
+            &[1u8][..],
+        )
+        .unwrap();
+
+        let path = dir.path().join("snapshot");
+        let info = export_snapshot(&db, &path).unwrap();
+        assert_eq!(info.number, 3);
+        assert_eq!(info.hash, last.hash);
+        // Version + Last + BlockMap, the event cursor is excluded
+        assert_eq!(info.records, 3);
+
+        let mut keys = Vec::new();
+        let read_info = read_snapshot(&path, |key, _| {
+            keys.push(key.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(read_info.records, 3);
+        assert_eq!(read_info.hash, last.hash);
+        assert_eq!(read_info.schema_version, SCHEMA_VERSION);
+        assert!(keys.contains(&Bytes::from(&Key::BlockMap(3)).to_vec()));
+    }
+
+    #[test]
+    fn test_snapshot_corrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open_db(dir.path().join("db"), &DbOptions::default()).unwrap();
+        migrate(&db).unwrap();
+        let last = value::Last {
+            number: 1,
+            hash: h256!("0x1"),
+        };
+        db.put_cf(
+            cf_handle(&db, KeyType::Last),
+            &Bytes::from(&Key::Last),
+            &serialize(&last).unwrap(),
+        )
+        .unwrap();
+        let path = dir.path().join("snapshot");
+        export_snapshot(&db, &path).unwrap();
+
+        let mut data = std::fs::read(&path).unwrap();
+        let idx = data.len() - 40;
+        data[idx] ^= 0xFF;
+        std::fs::write(&path, &data).unwrap();
+        assert!(read_snapshot(&path, |_, _| Ok(())).is_err());
+
+        // The records before the checksum are written, then cleared
+        let new_db = open_db(dir.path().join("new_db"), &DbOptions::default()).unwrap();
+        assert!(write_snapshot(&new_db, &path).is_err());
+        assert!(is_empty(&new_db));
+    }
+
+    #[test]
+    fn test_snapshot_record_too_large() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+        let mut data = Vec::new();
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(&SCHEMA_VERSION.to_le_bytes());
+        data.extend_from_slice(&[0u8; 8 + 32]);
+        data.extend_from_slice(&u32::max_value().to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+        let err = read_snapshot(&path, |_, _| Ok(())).unwrap_err();
+        assert!(err.contains("too large"));
+    }
+}
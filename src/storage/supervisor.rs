@@ -0,0 +1,209 @@
+use serde::Serialize;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::indexer::{sleep_unless_stopped, Indexer, IndexerError};
+
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+pub type SharedIndexerStatus = Arc<RwLock<IndexerStatus>>;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexerState {
+    Starting,
+    Running,
+    /// Waiting to restart after a transient error
+    Restarting,
+    /// Stopped by the stop signal
+    Stopped,
+    /// Stopped by a fatal error (or panic)
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexerStatus {
+    pub state: IndexerState,
+    /// How many times the indexer restarted after transient errors
+    pub restarts: u32,
+    /// The last transient error
+    pub last_error: Option<String>,
+    /// The error which stopped the indexer
+    pub fatal_error: Option<String>,
+    /// Unix timestamp (seconds) of last state change
+    pub updated_at: u64,
+    pub last_rollback: Option<RollbackInfo>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct RollbackInfo {
+    /// The last block number after rollback
+    pub number: u64,
+    /// How many blocks rolled back in a row
+    pub depth: u64,
+    /// Unix timestamp (seconds)
+    pub time: u64,
+}
+
+impl Default for IndexerStatus {
+    fn default() -> IndexerStatus {
+        IndexerStatus {
+            state: IndexerState::Starting,
+            restarts: 0,
+            last_error: None,
+            fatal_error: None,
+            updated_at: unix_timestamp(),
+            last_rollback: None,
+        }
+    }
+}
+
+impl IndexerStatus {
+    pub fn new_shared() -> SharedIndexerStatus {
+        Arc::new(RwLock::new(IndexerStatus::default()))
+    }
+}
+
+/// Run the indexer, restart it with backoff on transient RPC errors and
+/// record fatal errors (including panics) into the shared status.
+pub struct IndexerSupervisor {
+    indexer: Indexer,
+    stop: Arc<AtomicBool>,
+    status: SharedIndexerStatus,
+}
+
+impl IndexerSupervisor {
+    pub fn new(
+        indexer: Indexer,
+        stop: Arc<AtomicBool>,
+        status: SharedIndexerStatus,
+    ) -> IndexerSupervisor {
+        IndexerSupervisor {
+            indexer,
+            stop,
+            status,
+        }
+    }
+
+    /// Block until the indexer is stopped (Ok) or failed (Err).
+    pub fn run(mut self) -> Result<(), String> {
+        let mut delay = MIN_RESTART_DELAY;
+        loop {
+            self.update(|status| status.state = IndexerState::Running);
+            let started_at = Instant::now();
+            let indexer = &mut self.indexer;
+            let stop = &self.stop;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| indexer.index(stop)));
+            let err = match result {
+                Ok(Ok(())) => {
+                    self.update(|status| status.state = IndexerState::Stopped);
+                    return Ok(());
+                }
+                Ok(Err(IndexerError::Rpc(err))) => err,
+                Ok(Err(IndexerError::Fatal(err))) => return self.fail(err),
+                Err(panic_info) => {
+                    return self.fail(format!("panicked: {}", panic_message(&*panic_info)))
+                }
+            };
+            // The indexer worked well for a while, not a continuous failure
+            if started_at.elapsed() > MAX_RESTART_DELAY {
+                delay = MIN_RESTART_DELAY;
+            }
+            log::warn!("Indexer RPC error: {}, restart in {:?}", err, delay);
+            self.update(|status| {
+                status.state = IndexerState::Restarting;
+                status.restarts += 1;
+                status.last_error = Some(err);
+            });
+            sleep_unless_stopped(&self.stop, delay);
+            delay = std::cmp::min(delay * 2, MAX_RESTART_DELAY);
+            if self.stop.load(Ordering::SeqCst) {
+                self.update(|status| status.state = IndexerState::Stopped);
+                return Ok(());
+            }
+        }
+    }
+
+    fn fail(&self, err: String) -> Result<(), String> {
+        log::error!("Indexer failed: {}", err);
+        self.update(|status| {
+            status.state = IndexerState::Failed;
+            status.fatal_error = Some(err.clone());
+        });
+        Err(err)
+    }
+
+    fn update<F: FnOnce(&mut IndexerStatus)>(&self, f: F) {
+        let mut status = self.status.write().expect("write indexer status");
+        f(&mut status);
+        status.updated_at = unix_timestamp();
+    }
+}
+
+fn panic_message(panic_info: &(dyn Any + Send)) -> String {
+    if let Some(s) = panic_info.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic_info.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::db::{open_db, DbOptions};
+    use super::*;
+    use crate::mock_chain::{index_until, test_run_config, MockChain};
+    use ckb_types::bytes::Bytes;
+    use std::thread;
+
+    #[test]
+    fn test_restart_on_unreachable_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open_db(dir.path(), &DbOptions::default()).unwrap());
+        let chain = MockChain::new(Vec::new());
+        let status = IndexerStatus::new_shared();
+        let mut indexer = Indexer::with_chain(
+            db,
+            Box::new(chain.clone()),
+            test_run_config(Bytes::default()),
+            Arc::clone(&status),
+        );
+        index_until(&mut indexer, 0);
+
+        chain.set_unreachable(true);
+        let stop = Arc::new(AtomicBool::new(false));
+        let supervisor = IndexerSupervisor::new(indexer, Arc::clone(&stop), Arc::clone(&status));
+        let handle = thread::spawn(move || supervisor.run());
+        let started_at = Instant::now();
+        while status.read().unwrap().restarts == 0 {
+            assert!(started_at.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        {
+            let status = status.read().unwrap();
+            assert!(status
+                .last_error
+                .as_ref()
+                .unwrap()
+                .contains("mock chain is down"));
+            assert!(status.fatal_error.is_none());
+        }
+
+        stop.store(true, Ordering::SeqCst);
+        assert_eq!(handle.join().unwrap(), Ok(()));
+        assert_eq!(status.read().unwrap().state, IndexerState::Stopped);
+    }
+}
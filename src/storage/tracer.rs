@@ -0,0 +1,169 @@
+use ckb_jsonrpc_types::JsonBytes;
+use ckb_simple_account_layer::RunResult;
+use ckb_types::{bytes::Bytes, H160, H256};
+use serde::Serialize;
+use sparse_merkle_tree::H256 as SmtH256;
+
+use crate::types::{parse_log, smth256_to_h256, CallKind, ContractAddress, Program};
+
+/// A log emitted in a call frame
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct TraceLog {
+    pub address: ContractAddress,
+    pub topics: Vec<H256>,
+    pub data: JsonBytes,
+}
+
+/// One program execution (or EoA transfer) in the call tree
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    pub sender: H160,
+    pub destination: H160,
+    pub value: u64,
+    /// The code for create, the input data for call
+    pub input: JsonBytes,
+    pub return_data: JsonBytes,
+    pub logs: Vec<TraceLog>,
+    /// Non-zero storage values read from the contract's storage tree
+    pub storage_reads: Vec<(H256, H256)>,
+    pub storage_writes: Vec<(H256, H256)>,
+    /// Cycles consumed by the program and all its sub calls
+    pub cycles: u64,
+    /// The error message if the execution failed
+    pub error: Option<String>,
+    /// Output of `ckb_debug` in the program
+    pub debug: Vec<String>,
+    pub calls: Vec<CallFrame>,
+}
+
+impl CallFrame {
+    pub fn new(program: &Program, destination: H160) -> CallFrame {
+        let input = if program.is_create() {
+            program.code.clone()
+        } else {
+            program.input.clone()
+        };
+        CallFrame {
+            kind: program.kind,
+            sender: program.sender.clone(),
+            destination,
+            value: program.value,
+            input: JsonBytes::from_bytes(input),
+            return_data: JsonBytes::default(),
+            logs: Vec::new(),
+            storage_reads: Vec::new(),
+            storage_writes: Vec::new(),
+            cycles: 0,
+            error: None,
+            debug: Vec::new(),
+            calls: Vec::new(),
+        }
+    }
+
+    pub fn set_return(
+        &mut self,
+        address: &ContractAddress,
+        return_data: &Bytes,
+        logs: &[Bytes],
+    ) -> Result<(), String> {
+        self.return_data = JsonBytes::from_bytes(return_data.clone());
+        for raw in logs {
+            let (topics, data) = parse_log(raw)?;
+            self.add_log(address, topics, data);
+        }
+        Ok(())
+    }
+
+    pub fn add_log(&mut self, address: &ContractAddress, topics: Vec<H256>, data: Bytes) {
+        self.logs.push(TraceLog {
+            address: address.clone(),
+            topics,
+            data: JsonBytes::from_bytes(data),
+        });
+    }
+
+    pub fn set_storage(&mut self, run_result: &RunResult) {
+        self.storage_reads = sorted_pairs(run_result.read_values.iter());
+        self.storage_writes = sorted_pairs(run_result.write_values.iter());
+    }
+}
+
+fn sorted_pairs<'a, I>(values: I) -> Vec<(H256, H256)>
+where
+    I: Iterator<Item = (&'a SmtH256, &'a SmtH256)>,
+{
+    let mut pairs = values
+        .map(|(key, value)| (smth256_to_h256(key), smth256_to_h256(value)))
+        .collect::<Vec<_>>();
+    pairs.sort();
+    pairs
+}
+
+/// Build the call tree while the programs are running
+#[derive(Debug, Default)]
+pub struct Tracer {
+    // The frames entered but not exited yet
+    stack: Vec<CallFrame>,
+    root: Option<CallFrame>,
+}
+
+impl Tracer {
+    pub fn enter(&mut self, frame: CallFrame) {
+        self.stack.push(frame);
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut CallFrame> {
+        self.stack.last_mut()
+    }
+
+    pub fn exit(&mut self) {
+        if let Some(frame) = self.stack.pop() {
+            if let Some(parent) = self.stack.last_mut() {
+                parent.calls.push(frame);
+            } else {
+                self.root = Some(frame);
+            }
+        }
+    }
+
+    /// The entrance frame, available after the entrance program exited
+    pub fn take_root(&mut self) -> Option<CallFrame> {
+        self.root.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ckb_types::h160;
+
+    #[test]
+    fn test_tracer_call_tree() {
+        let mut tracer = Tracer::default();
+        let program = Program::new_create(
+            Default::default(),
+            h160!("0x1"),
+            Bytes::from(vec![0x60, 0x00]),
+            0,
+        );
+        tracer.enter(CallFrame::new(&program, h160!("0x2")));
+        let mut sub_program = program.clone();
+        sub_program.kind = CallKind::CALL;
+        sub_program.sender = h160!("0x2");
+        sub_program.input = Bytes::from(vec![0x01]);
+        tracer.enter(CallFrame::new(&sub_program, h160!("0x3")));
+        tracer.current_mut().unwrap().error = Some(String::from("revert"));
+        tracer.exit();
+        assert!(tracer.take_root().is_none());
+        tracer.exit();
+
+        let root = tracer.take_root().unwrap();
+        assert_eq!(root.kind, CallKind::CREATE);
+        assert_eq!(root.input.as_bytes(), &[0x60, 0x00][..]);
+        assert_eq!(root.calls.len(), 1);
+        assert_eq!(root.calls[0].destination, h160!("0x3"));
+        assert_eq!(root.calls[0].input.as_bytes(), &[0x01][..]);
+        assert_eq!(root.calls[0].error, Some(String::from("revert")));
+    }
+}
@@ -0,0 +1,653 @@
+use bincode::deserialize;
+use ckb_hash::blake2b_256;
+use ckb_types::{packed, H160, H256};
+use rocksdb::DB;
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::db::cf_handle;
+use super::indexer::{rollback_block, sleep_unless_stopped};
+use super::{db_get, value, Key, KeyType, Loader};
+use crate::client::{ChainSource, HttpRpcClient};
+use crate::metrics::METRICS;
+use crate::types::{smth256_to_h256, ContractAddress, ContractChange};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DiscrepancyKind {
+    /// The latest contract cell is not live on chain
+    ContractCellDead,
+    /// Storage root in cell data != root of indexed storage
+    StorageRootMismatch,
+    /// Code hash in cell data != blake2b(indexed code)
+    CodeHashMismatch,
+    ContractCapacityMismatch,
+    /// The EoA cell is not live on chain
+    EoaCellDead,
+    EoaCapacityMismatch,
+}
+
+#[derive(Debug, Clone)]
+pub struct Discrepancy {
+    pub kind: DiscrepancyKind,
+    /// Contract address or EoA address
+    pub address: H160,
+    /// The block number of the indexed record
+    pub number: u64,
+    pub out_point: packed::OutPoint,
+    pub detail: String,
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?}: address={:x}, number={}, out_point={}, {}",
+            self.kind, self.address, self.number, self.out_point, self.detail
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// The last indexed block when the verification started
+    pub number: u64,
+    pub contracts: u64,
+    pub eoa_accounts: u64,
+    /// Records can not be verified since the indexer is not synced with CKB
+    pub skipped: u64,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+/// Compare the indexed contract cells and EoA cells with live cells on chain
+pub struct Verifier {
+    db: Arc<DB>,
+    loader: Loader,
+    client: Box<dyn ChainSource>,
+}
+
+impl Verifier {
+    pub fn new(db: Arc<DB>, ckb_uri: &str) -> Result<Verifier, String> {
+        Ok(Verifier::with_chain(
+            db,
+            Box::new(HttpRpcClient::new(ckb_uri.to_string())),
+        ))
+    }
+
+    pub fn with_chain(db: Arc<DB>, client: Box<dyn ChainSource>) -> Verifier {
+        Verifier {
+            loader: Loader::with_chain(Arc::clone(&db), client.clone()),
+            client,
+            db,
+        }
+    }
+
+    /// Walk all live contracts and EoA cells. It's safe to run while the
+    /// indexer is running, a dead cell is reported only when the indexer is
+    /// synced with CKB tip (otherwise it's counted as skipped).
+    pub fn verify(&mut self) -> Result<VerifyReport, String> {
+        let last: value::Last =
+            db_get(&self.db, &Key::Last)?.ok_or_else(|| String::from("Nothing indexed yet"))?;
+        let mut report = VerifyReport {
+            number: last.number,
+            ..Default::default()
+        };
+
+        for (address, code_hash) in self.load_contracts()? {
+            report.contracts += 1;
+            self.verify_contract(address, code_hash, &mut report)?;
+        }
+        for (address, cell) in self.load_eoa_cells()? {
+            report.eoa_accounts += 1;
+            self.verify_eoa_cell(address, cell, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    /// Rollback the index to the block before the earliest discrepancy, the
+    /// indexer will re-index from there. Must NOT run with the indexer.
+    /// Return the last indexed block number after repair.
+    pub fn repair(&mut self, report: &VerifyReport) -> Result<Option<u64>, String> {
+        let target = match report.discrepancies.iter().map(|d| d.number).min() {
+            Some(number) => number,
+            None => return Ok(Some(report.number)),
+        };
+        while let Some(last) = db_get::<value::Last>(&self.db, &Key::Last)? {
+            if last.number < target {
+                return Ok(Some(last.number));
+            }
+            log::info!(
+                "Rollback block, number={}, hash={:#x}",
+                last.number,
+                last.hash
+            );
+            rollback_block(&self.db, last.number)?;
+        }
+        Ok(None)
+    }
+
+    /// Verify every `interval` until `stop` is set, only report discrepancies
+    pub fn run_periodically(mut self, interval: Duration, stop: Arc<AtomicBool>) {
+        loop {
+            sleep_unless_stopped(&stop, interval);
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            match self.verify() {
+                Ok(report) => {
+                    for discrepancy in &report.discrepancies {
+                        log::error!("Index discrepancy: {}", discrepancy);
+                    }
+                    log::info!(
+                        "Verified index at block {}: contracts={}, eoa_accounts={}, skipped={}, discrepancies={}",
+                        report.number,
+                        report.contracts,
+                        report.eoa_accounts,
+                        report.skipped,
+                        report.discrepancies.len()
+                    );
+                    METRICS
+                        .verify_discrepancies
+                        .set(report.discrepancies.len() as u64);
+                }
+                Err(err) => log::warn!("Verify index error: {}", err),
+            }
+        }
+    }
+
+    // (address, blake2b(code)) of all not destructed contracts
+    fn load_contracts(&self) -> Result<Vec<(ContractAddress, H256)>, String> {
+        let mut contracts = Vec::new();
+        let mut iter = self
+            .db
+            .raw_iterator_cf(cf_handle(&self.db, KeyType::ContractMeta));
+        iter.seek_to_first();
+        while iter.valid() {
+            let address = match Key::try_from(iter.key().unwrap())? {
+                Key::ContractMeta(address) => address,
+                key => return Err(format!("Invalid ContractMeta key: {:?}", key)),
+            };
+            let meta: value::ContractMeta =
+                deserialize(iter.value().unwrap()).map_err(|err| err.to_string())?;
+            if !meta.destructed {
+                contracts.push((address, H256::from(blake2b_256(&meta.code))));
+            }
+            iter.next();
+        }
+        Ok(contracts)
+    }
+
+    fn load_eoa_cells(&self) -> Result<Vec<(H160, value::EoaLiveCell)>, String> {
+        let mut cells = Vec::new();
+        let mut iter = self
+            .db
+            .raw_iterator_cf(cf_handle(&self.db, KeyType::EoaLiveCell));
+        iter.seek_to_first();
+        while iter.valid() {
+            let address = match Key::try_from(iter.key().unwrap())? {
+                Key::EoaLiveCell(address) => address,
+                key => return Err(format!("Invalid EoaLiveCell key: {:?}", key)),
+            };
+            let cell: value::EoaLiveCell =
+                deserialize(iter.value().unwrap()).map_err(|err| err.to_string())?;
+            cells.push((address, cell));
+            iter.next();
+        }
+        Ok(cells)
+    }
+
+    fn verify_contract(
+        &mut self,
+        address: ContractAddress,
+        code_hash: H256,
+        report: &mut VerifyReport,
+    ) -> Result<(), String> {
+        let change =
+            self.loader
+                .load_latest_contract_change(address.clone(), None, false, false)?;
+        let out_point = change.out_point();
+        let cell_with_status = self.client.get_live_cell(out_point.clone().into(), true)?;
+        let cell = match cell_with_status.cell {
+            Some(cell) => cell,
+            None => {
+                let latest =
+                    self.loader
+                        .load_latest_contract_change(address.clone(), None, false, false)?;
+                if latest.out_point() != out_point || !self.is_synced()? {
+                    report.skipped += 1;
+                } else {
+                    report.discrepancies.push(Discrepancy {
+                        kind: DiscrepancyKind::ContractCellDead,
+                        address: address.0,
+                        number: change.number,
+                        out_point,
+                        detail: format!("status={}", cell_with_status.status),
+                    });
+                }
+                return Ok(());
+            }
+        };
+        let data = cell
+            .data
+            .map(|data| data.content.into_bytes())
+            .unwrap_or_default();
+        let storage_root = smth256_to_h256(change.merkle_tree().root());
+        // The storage of a change is the base of the next change, so an
+        // earlier corrupted change makes all the following changes mismatch
+        let storage_diverged = data.len() >= 64 && &data[0..32] != storage_root.as_bytes();
+        let storage_mismatch_number = if storage_diverged {
+            Some(self.first_divergent_change(&address, &change)?)
+        } else {
+            None
+        };
+        let mut add = |kind, number, detail: String| {
+            report.discrepancies.push(Discrepancy {
+                kind,
+                address: address.0.clone(),
+                number,
+                out_point: out_point.clone(),
+                detail,
+            })
+        };
+
+        let capacity = cell.output.capacity.value();
+        if capacity != change.capacity {
+            add(
+                DiscrepancyKind::ContractCapacityMismatch,
+                change.number,
+                format!("chain={}, indexed={}", capacity, change.capacity),
+            );
+        }
+        if data.len() < 64 {
+            add(
+                DiscrepancyKind::StorageRootMismatch,
+                change.number,
+                format!("invalid cell data length: {}", data.len()),
+            );
+            return Ok(());
+        }
+        if let Some(number) = storage_mismatch_number {
+            add(
+                DiscrepancyKind::StorageRootMismatch,
+                number,
+                format!(
+                    "chain=0x{}, indexed={:#x}",
+                    hex::encode(&data[0..32]),
+                    storage_root
+                ),
+            );
+        }
+        if &data[32..64] != code_hash.as_bytes() {
+            add(
+                DiscrepancyKind::CodeHashMismatch,
+                change.number,
+                format!(
+                    "chain=0x{}, indexed={:#x}",
+                    hex::encode(&data[32..64]),
+                    code_hash
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    // Walk back through the changes of the contract, return the block number
+    // of the earliest change in a row (ending with `change`) whose storage
+    // root differs from its cell on chain.
+    fn first_divergent_change(
+        &mut self,
+        address: &ContractAddress,
+        change: &ContractChange,
+    ) -> Result<u64, String> {
+        let mut number = change.number;
+        while let Some(previous) =
+            self.loader
+                .load_contract_change_before(address.clone(), number, false)?
+        {
+            let storage_root = smth256_to_h256(previous.merkle_tree().root());
+            if self.chain_storage_root(&previous)?.as_ref() == Some(&storage_root) {
+                break;
+            }
+            number = previous.number;
+        }
+        Ok(number)
+    }
+
+    // The storage root in the cell data of the (maybe dead) contract cell
+    fn chain_storage_root(&mut self, change: &ContractChange) -> Result<Option<H256>, String> {
+        let tx = self
+            .client
+            .get_transaction(change.tx_hash.clone())?
+            .ok_or_else(|| format!("transaction {:#x} not found", change.tx_hash))?;
+        Ok(tx
+            .transaction
+            .inner
+            .outputs_data
+            .get(change.output_index as usize)
+            .map(|data| data.as_bytes())
+            .filter(|data| data.len() >= 32)
+            .map(|data| H256::from_slice(&data[0..32]).expect("checked length")))
+    }
+
+    fn verify_eoa_cell(
+        &mut self,
+        address: H160,
+        cell: value::EoaLiveCell,
+        report: &mut VerifyReport,
+    ) -> Result<(), String> {
+        let out_point = cell.out_point();
+        let cell_with_status = self.client.get_live_cell(out_point.clone().into(), false)?;
+        let (kind, detail) = match cell_with_status.cell {
+            Some(live_cell) => {
+                let capacity = live_cell.output.capacity.value();
+                if capacity == cell.capacity() {
+                    return Ok(());
+                }
+                (
+                    DiscrepancyKind::EoaCapacityMismatch,
+                    format!("chain={}, indexed={}", capacity, cell.capacity()),
+                )
+            }
+            None => {
+                let latest =
+                    db_get::<value::EoaLiveCell>(&self.db, &Key::EoaLiveCell(address.clone()))?;
+                if latest.as_ref() != Some(&cell) || !self.is_synced()? {
+                    report.skipped += 1;
+                    return Ok(());
+                }
+                (
+                    DiscrepancyKind::EoaCellDead,
+                    format!("status={}", cell_with_status.status),
+                )
+            }
+        };
+        let number = db_get::<value::LiveCellMap>(&self.db, &Key::LiveCellMap(out_point.clone()))?
+            .ok_or_else(|| format!("LiveCellMap not found: {}", out_point))?
+            .number;
+        report.discrepancies.push(Discrepancy {
+            kind,
+            address,
+            number,
+            out_point,
+            detail,
+        });
+        Ok(())
+    }
+
+    // The indexer has indexed the CKB tip block
+    fn is_synced(&mut self) -> Result<bool, String> {
+        let tip = self.client.get_tip_header()?;
+        Ok(db_get::<value::Last>(&self.db, &Key::Last)?
+            .map(|last| last.number == tip.inner.number.value() && last.hash == tip.hash)
+            .unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::db::{open_db, DbOptions};
+    use super::super::supervisor::IndexerStatus;
+    use super::super::Indexer;
+    use super::*;
+    use crate::mock_chain::{eoa_output, index_until, test_run_config, MockChain};
+    use crate::types::{h256_to_smth256, EoaAddress, ONE_CKB};
+    use bincode::serialize;
+    use ckb_simple_account_layer::CkbBlake2bHasher;
+    use ckb_types::{bytes::Bytes, core, h160, h256, prelude::*};
+    use sparse_merkle_tree::{default_store::DefaultStore, SparseMerkleTree, H256 as SmtH256};
+
+    fn transfer(
+        inputs: Vec<packed::OutPoint>,
+        outputs: Vec<(packed::CellOutput, Bytes)>,
+    ) -> core::TransactionView {
+        core::TransactionBuilder::default()
+            .inputs(
+                inputs
+                    .into_iter()
+                    .map(|out_point| packed::CellInput::new(out_point, 0)),
+            )
+            .outputs_data(outputs.iter().map(|(_, data)| data.pack()))
+            .outputs(outputs.into_iter().map(|(output, _)| output))
+            .build()
+    }
+
+    fn db_put<T: serde::Serialize>(db: &DB, key: &Key, value: &T) {
+        db.put_cf(
+            cf_handle(db, key.key_type()),
+            &Bytes::from(key),
+            &serialize(value).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_verify_and_repair() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open_db(dir.path(), &DbOptions::default()).unwrap());
+        let run_config = test_run_config(Bytes::default());
+        let eoa = |lock_arg: &H160, type_id: &H256, capacity: u64| {
+            let (output, address) = eoa_output(&run_config, lock_arg, type_id, capacity * ONE_CKB);
+            ((output, Bytes::default()), address)
+        };
+        let (alice_output, alice) = eoa(&h160!("0x1"), &h256!("0x1"), 1000);
+        let genesis_tx = transfer(Vec::new(), vec![alice_output]);
+        let chain = MockChain::new(vec![genesis_tx.clone()]);
+        // Block 1: Alice transfers to Bob
+        let (bob_output, bob) = eoa(&h160!("0x2"), &h256!("0x2"), 499);
+        let tx = transfer(
+            vec![packed::OutPoint::new(genesis_tx.hash(), 0)],
+            vec![eoa(&h160!("0x1"), &h256!("0x1"), 500).0, bob_output],
+        );
+        chain.produce_block(vec![tx]);
+        // Block 2: the cell of a contract
+        let code = Bytes::from(vec![0x60, 0x00]);
+        let mut data = vec![0u8; 32];
+        data.extend_from_slice(&blake2b_256(&code));
+        let contract_output = packed::CellOutput::new_builder()
+            .capacity((200 * ONE_CKB).pack())
+            .build();
+        let contract_tx = transfer(Vec::new(), vec![(contract_output, Bytes::from(data))]);
+        chain.produce_block(vec![contract_tx.clone()]);
+        chain.produce_block(Vec::new());
+        let mut indexer = Indexer::with_chain(
+            Arc::clone(&db),
+            Box::new(chain.clone()),
+            run_config,
+            IndexerStatus::new_shared(),
+        );
+        index_until(&mut indexer, 3);
+
+        // Index the contract by hand, contracts can't be created without the generator
+        let address = ContractAddress(h160!("0xc0"));
+        let meta = value::ContractMeta {
+            code,
+            tx_hash: contract_tx.hash().unpack(),
+            output_index: 0,
+            balance: 0,
+            destructed: false,
+        };
+        db_put(&db, &Key::ContractMeta(address.clone()), &meta);
+        let change_key = Key::ContractChange {
+            address: address.clone(),
+            number: Some(2),
+            tx_index: Some(0),
+            output_index: Some(0),
+        };
+        let mut change = value::ContractChange {
+            tx_hash: contract_tx.hash().unpack(),
+            tx_origin: EoaAddress(alice.clone()),
+            new_storage: Vec::new(),
+            capacity: 200 * ONE_CKB,
+            balance: 0,
+            is_create: true,
+        };
+        db_put(&db, &change_key, &change);
+
+        let mut verifier = Verifier::with_chain(Arc::clone(&db), Box::new(chain.clone()));
+        let report = verifier.verify().unwrap();
+        assert_eq!(report.number, 3);
+        assert_eq!(report.contracts, 1);
+        assert_eq!(report.eoa_accounts, 2);
+        assert_eq!(report.skipped, 0);
+        assert!(report.discrepancies.is_empty());
+
+        // Corrupt the contract change of block 2
+        change.capacity = 100 * ONE_CKB;
+        db_put(&db, &change_key, &change);
+        let report = verifier.verify().unwrap();
+        assert_eq!(report.discrepancies.len(), 1);
+        let discrepancy = &report.discrepancies[0];
+        assert_eq!(discrepancy.kind, DiscrepancyKind::ContractCapacityMismatch);
+        assert_eq!(discrepancy.address, address.0);
+        assert_eq!(discrepancy.number, 2);
+
+        // Corrupt Bob's EoA cell of block 1
+        let bob_key = Key::EoaLiveCell(bob.clone());
+        let bob_cell: value::EoaLiveCell = db_get(&db, &bob_key).unwrap().unwrap();
+        let corrupted = value::EoaLiveCell::new(
+            bob_cell.tx_hash.clone(),
+            bob_cell.output_index,
+            bob_cell.capacity() + 1,
+            bob_cell.balance(),
+        );
+        db_put(&db, &bob_key, &corrupted);
+        let report = verifier.verify().unwrap();
+        assert_eq!(report.discrepancies.len(), 2);
+        let discrepancy = report
+            .discrepancies
+            .iter()
+            .find(|discrepancy| discrepancy.kind == DiscrepancyKind::EoaCapacityMismatch)
+            .unwrap();
+        assert_eq!(discrepancy.address, bob);
+        assert_eq!(discrepancy.number, 1);
+
+        // Rollback to the block before the earliest discrepancy
+        assert_eq!(verifier.repair(&report).unwrap(), Some(0));
+        let last: value::Last = db_get(&db, &Key::Last).unwrap().unwrap();
+        assert_eq!(last.number, 0);
+        assert!(db_get::<value::BlockMap>(&db, &Key::BlockMap(1))
+            .unwrap()
+            .is_none());
+        assert!(db_get::<value::EoaLiveCell>(&db, &bob_key)
+            .unwrap()
+            .is_none());
+        assert!(db_get::<value::EoaLiveCell>(&db, &Key::EoaLiveCell(alice))
+            .unwrap()
+            .is_some());
+    }
+
+    fn storage_root(storage: &[(H256, H256)]) -> H256 {
+        let mut tree: SparseMerkleTree<CkbBlake2bHasher, SmtH256, DefaultStore<SmtH256>> =
+            SparseMerkleTree::default();
+        for (key, value) in storage {
+            tree.update(h256_to_smth256(key), h256_to_smth256(value))
+                .unwrap();
+        }
+        smth256_to_h256(tree.root())
+    }
+
+    #[test]
+    fn test_repair_storage_root_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open_db(dir.path(), &DbOptions::default()).unwrap());
+        let code = Bytes::from(vec![0x60, 0x00]);
+        let storages = vec![
+            vec![],
+            vec![(h256!("0x1"), h256!("0x11"))],
+            vec![(h256!("0x1"), h256!("0x11")), (h256!("0x2"), h256!("0x22"))],
+        ];
+        // Block 1, 2, 3: the contract cell is updated with the storages
+        let chain = MockChain::new(Vec::new());
+        let mut contract_txs: Vec<core::TransactionView> = Vec::new();
+        for storage in &storages {
+            let mut data = storage_root(storage).as_bytes().to_vec();
+            data.extend_from_slice(&blake2b_256(&code));
+            let output = packed::CellOutput::new_builder()
+                .capacity((200 * ONE_CKB).pack())
+                .build();
+            let inputs = contract_txs
+                .last()
+                .map(|tx| vec![packed::OutPoint::new(tx.hash(), 0)])
+                .unwrap_or_default();
+            let tx = transfer(inputs, vec![(output, Bytes::from(data))]);
+            chain.produce_block(vec![tx.clone()]);
+            contract_txs.push(tx);
+        }
+        let mut indexer = Indexer::with_chain(
+            Arc::clone(&db),
+            Box::new(chain.clone()),
+            test_run_config(Bytes::default()),
+            IndexerStatus::new_shared(),
+        );
+        index_until(&mut indexer, 3);
+
+        // Index the contract by hand, contracts can't be created without the generator
+        let address = ContractAddress(h160!("0xc0"));
+        let meta = value::ContractMeta {
+            code,
+            tx_hash: contract_txs[0].hash().unpack(),
+            output_index: 0,
+            balance: 0,
+            destructed: false,
+        };
+        db_put(&db, &Key::ContractMeta(address.clone()), &meta);
+        let put_change = |number: u64, new_storage: Vec<(H256, H256)>| {
+            let change_key = Key::ContractChange {
+                address: address.clone(),
+                number: Some(number),
+                tx_index: Some(0),
+                output_index: Some(0),
+            };
+            let change = value::ContractChange {
+                tx_hash: contract_txs[number as usize - 1].hash().unpack(),
+                tx_origin: EoaAddress(H160::default()),
+                new_storage,
+                capacity: 200 * ONE_CKB,
+                balance: 0,
+                is_create: number == 1,
+            };
+            db_put(&db, &change_key, &change);
+        };
+        for (index, storage) in storages.iter().enumerate() {
+            put_change(index as u64 + 1, storage.clone());
+        }
+        let mut verifier = Verifier::with_chain(Arc::clone(&db), Box::new(chain.clone()));
+        assert!(verifier.verify().unwrap().discrepancies.is_empty());
+
+        // Only the latest change is corrupted
+        put_change(3, vec![(h256!("0x1"), h256!("0x11"))]);
+        let report = verifier.verify().unwrap();
+        assert_eq!(report.discrepancies.len(), 1);
+        assert_eq!(
+            report.discrepancies[0].kind,
+            DiscrepancyKind::StorageRootMismatch
+        );
+        assert_eq!(report.discrepancies[0].number, 3);
+
+        // The corrupted change of block 2 is carried to block 3
+        put_change(2, vec![(h256!("0x1"), h256!("0x12"))]);
+        put_change(
+            3,
+            vec![(h256!("0x1"), h256!("0x12")), (h256!("0x2"), h256!("0x22"))],
+        );
+        let report = verifier.verify().unwrap();
+        assert_eq!(report.discrepancies.len(), 1);
+        assert_eq!(report.discrepancies[0].number, 2);
+        assert_eq!(verifier.repair(&report).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_repair_without_discrepancy() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(open_db(dir.path(), &DbOptions::default()).unwrap());
+        let chain = MockChain::new(Vec::new());
+        let mut verifier = Verifier::with_chain(db, Box::new(chain));
+        let report = VerifyReport {
+            number: 5,
+            ..Default::default()
+        };
+        assert_eq!(verifier.repair(&report).unwrap(), Some(5));
+    }
+}
@@ -11,13 +11,16 @@ use ckb_vm::{Error as VMError, Memory, Register, SupportMachine};
 use numext_fixed_uint::prelude::UintConvert;
 use serde::{Deserialize, Serialize};
 use sparse_merkle_tree::{default_store::DefaultStore, SparseMerkleTree, H256 as SmtH256};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
+use std::fmt;
+use tiny_keccak::{Hasher, Keccak};
 
 use crate::storage::{value, Key};
 
 pub const ONE_CKB: u64 = 100_000_000;
-// pub const MIN_CELL_CAPACITY: u64 = 61 * ONE_CKB;
+/// The occupied capacity of a secp256k1 (sighash) cell without type script and data
+pub const MIN_CELL_CAPACITY: u64 = 61 * ONE_CKB;
 
 pub const SIGHASH_TYPE_HASH: H256 =
     h256!("0x9bd7e06f3ecf4be0f2fcd2188b23f1b9fcc88e5d4b65a8637b17723bbda3cce8");
@@ -26,6 +29,16 @@ pub const ALWAYS_SUCCESS_CODE_HASH: H256 =
 
 pub const CELLBASE_MATURITY: EpochNumberWithFraction =
     EpochNumberWithFraction::new_unchecked(4, 0, 1);
+/// BLOCKHASH only returns the hashes of the most recent blocks
+pub const BLOCK_HASH_WINDOW: u64 = 256;
+/// A transaction must fit in a block (MAX_BLOCK_BYTES of CKB consensus)
+pub const MAX_TX_SIZE: u64 = 597_000;
+/// The cycles of a transaction can not exceed MAX_BLOCK_CYCLES of CKB consensus
+pub const MAX_TX_CYCLES: u64 = 3_500_000_000;
+/// The call depth limit of EVM
+pub const EVM_MAX_DEPTH: u32 = 1024;
+/// Every call frame keeps a ckb-vm machine (with its memory) alive in the host
+pub const HOST_MAX_DEPTH: u32 = 128;
 
 lazy_static::lazy_static! {
     pub static ref SECP256K1: secp256k1::Secp256k1<secp256k1::All> = secp256k1::Secp256k1::new();
@@ -72,8 +85,97 @@ pub struct RunConfig {
     // Lock script for EoA account
     pub eoa_lock_dep: packed::CellDep,
     pub eoa_lock_script: packed::Script,
+    pub limits: ExecutionLimits,
 }
 
+/// The limits of executing a transaction in the generator, the transaction
+/// exceeds them can not be committed by CKB.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExecutionLimits {
+    /// Max depth of nested calls (the entrance program is 0)
+    pub max_depth: u32,
+    /// Max programs (witnesses) in a transaction
+    pub max_programs: usize,
+    /// Max contracts (input/output cells) in a transaction
+    pub max_contracts: usize,
+    /// Max cycles consumed by all the programs
+    pub max_cycles: u64,
+}
+
+impl Default for ExecutionLimits {
+    fn default() -> ExecutionLimits {
+        // Every program has a witness, every contract has an input and an output
+        let min_program_size = WitnessData::new(Program::default()).serialize().len() as u64;
+        let contract_output = packed::CellOutput::new_builder()
+            .lock(ALWAYS_SUCCESS_SCRIPT.clone())
+            .type_(
+                Some(
+                    packed::Script::new_builder()
+                        .args(Bytes::from(vec![0u8; 20]).pack())
+                        .build(),
+                )
+                .pack(),
+            )
+            .build();
+        let min_contract_size = (packed::CellInput::default().as_slice().len()
+            + contract_output.as_slice().len()
+            + ContractCell::new(H256::default(), H256::default())
+                .serialize()
+                .len()) as u64
+            + min_program_size;
+        let max_programs = (MAX_TX_SIZE / min_program_size) as usize;
+        ExecutionLimits {
+            max_depth: EVM_MAX_DEPTH.min(HOST_MAX_DEPTH).min(max_programs as u32),
+            max_programs,
+            max_contracts: (MAX_TX_SIZE / min_contract_size) as usize,
+            max_cycles: MAX_TX_CYCLES,
+        }
+    }
+}
+
+/// The execution exceeded one of the `ExecutionLimits`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LimitError {
+    CallDepth { depth: u32, max: u32 },
+    Programs { count: usize, max: usize },
+    Contracts { count: usize, max: usize },
+    Cycles { cycles: u64, max: u64 },
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitError::CallDepth { depth, max } => {
+                write!(f, "Exceeded max call depth: {} > {}", depth, max)
+            }
+            LimitError::Programs { count, max } => {
+                write!(
+                    f,
+                    "Exceeded max programs in transaction: {} > {}",
+                    count, max
+                )
+            }
+            LimitError::Contracts { count, max } => {
+                write!(
+                    f,
+                    "Exceeded max contracts in transaction: {} > {}",
+                    count, max
+                )
+            }
+            LimitError::Cycles { cycles, max } => {
+                write!(
+                    f,
+                    "Exceeded max cycles of transaction: {} > {}",
+                    cycles, max
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
 /// A contract account's cell data
 pub struct ContractCell {
     /// The merkle root of key-value storage
@@ -112,10 +214,19 @@ pub struct WitnessData {
     pub calls: Vec<CallRecord>,
     /// The data required to read and verify coinbase, only in entrance program
     pub coinbase: Option<Coinbase>,
+    /// The code of the contracts not in the transaction but read by
+    /// EXTCODEHASH, only in entrance program. The contract cells are in
+    /// cell_deps, so the validator can verify the code.
+    pub code_deps: Vec<(H160, Bytes)>,
     /// Provide storage diff and diff proofs.
     pub run_proof: Bytes,
 }
 
+/// The flags byte of a call record. It was the `is_eoa` byte (0 or 1) before
+/// the precompiled contracts, so the layout of the existing witnesses is kept.
+pub const CALL_FLAG_EOA: u8 = 0b01;
+pub const CALL_FLAG_PRECOMPILE: u8 = 0b10;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct CallRecord {
     pub destination: H160,
@@ -124,6 +235,8 @@ pub struct CallRecord {
     pub transfer_only: bool,
     // destination is EoA account
     pub is_eoa: bool,
+    // destination is a precompiled contract (run natively)
+    pub is_precompile: bool,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -181,6 +294,7 @@ pub struct Program {
 }
 
 /// The contract metadata
+#[derive(Clone)]
 pub struct ContractMeta {
     pub address: ContractAddress,
     pub code: Bytes,
@@ -194,7 +308,7 @@ pub struct ContractMeta {
 }
 
 /// Represent a change record of a contract call
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct ContractChange {
     pub tx_origin: EoaAddress,
     pub address: ContractAddress,
@@ -501,7 +615,7 @@ impl TryFrom<u8> for CallKind {
 impl WitnessData {
     pub fn load_from(data: &[u8]) -> Result<Option<(usize, WitnessData)>, String> {
         let mut offset = 0;
-        let (signature, program, return_data, selfdestruct, calls, coinbase) = {
+        let (signature, program, return_data, selfdestruct, calls, coinbase, code_deps) = {
             let program_data = load_var_slice(data, &mut offset)?;
             if program_data.is_empty() {
                 // The end of all programs (just like '\0' of C string)
@@ -530,19 +644,22 @@ impl WitnessData {
                 let program_index = load_u32(program_data, &mut inner_offset)?;
                 let value = load_u64(program_data, &mut inner_offset)?;
                 let transfer_only = load_u8(program_data, &mut inner_offset)?;
-                let is_eoa = load_u8(program_data, &mut inner_offset)?;
+                let flags = load_u8(program_data, &mut inner_offset)?;
                 if transfer_only != 1 && transfer_only != 0 {
                     return Err(format!("Invalid transfer_only value: {}", transfer_only));
                 }
-                if is_eoa != 1 && is_eoa != 0 {
-                    return Err(format!("Invalid is_eoa value: {}", is_eoa));
+                if flags & !(CALL_FLAG_EOA | CALL_FLAG_PRECOMPILE) != 0
+                    || flags == CALL_FLAG_EOA | CALL_FLAG_PRECOMPILE
+                {
+                    return Err(format!("Invalid call flags: {}", flags));
                 }
                 calls.push(CallRecord {
                     destination,
                     program_index,
                     value,
                     transfer_only: transfer_only == 1,
-                    is_eoa: is_eoa == 1,
+                    is_eoa: flags & CALL_FLAG_EOA != 0,
+                    is_precompile: flags & CALL_FLAG_PRECOMPILE != 0,
                 });
             }
             let coinbase_bytes = load_var_slice(program_data, &mut inner_offset)?;
@@ -551,6 +668,16 @@ impl WitnessData {
             } else {
                 None
             };
+            // Omitted when empty
+            let mut code_deps = Vec::new();
+            if inner_offset < program_data.len() {
+                let code_deps_len = load_u32(program_data, &mut inner_offset)?;
+                for _ in 0..code_deps_len {
+                    let address = load_h160(program_data, &mut inner_offset)?;
+                    let code = load_var_slice(program_data, &mut inner_offset)?;
+                    code_deps.push((address, Bytes::from(code.to_vec())));
+                }
+            }
             (
                 Bytes::from(signature[..].to_vec()),
                 program,
@@ -558,6 +685,7 @@ impl WitnessData {
                 selfdestruct,
                 calls,
                 coinbase,
+                code_deps,
             )
         };
 
@@ -581,6 +709,7 @@ impl WitnessData {
             selfdestruct,
             calls,
             coinbase,
+            code_deps,
             run_proof,
         };
         Ok(Some((end, witness_data)))
@@ -593,6 +722,7 @@ impl WitnessData {
             return_data: Bytes::default(),
             selfdestruct: None,
             coinbase: None,
+            code_deps: Vec::new(),
             run_proof: Bytes::default(),
             calls: Vec::new(),
         }
@@ -637,9 +767,15 @@ impl WitnessData {
             buf.put(&call_record.program_index.to_le_bytes()[..]);
             buf.put(&call_record.value.to_le_bytes()[..]);
             let transfer_only: u8 = if call_record.transfer_only { 1 } else { 0 };
-            let is_eoa = if call_record.is_eoa { 1 } else { 0 };
+            let mut flags = 0;
+            if call_record.is_eoa {
+                flags |= CALL_FLAG_EOA;
+            }
+            if call_record.is_precompile {
+                flags |= CALL_FLAG_PRECOMPILE;
+            }
             buf.put(&[transfer_only][..]);
-            buf.put(&[is_eoa][..]);
+            buf.put(&[flags][..]);
         }
         let coinbase_bytes = self
             .coinbase
@@ -648,6 +784,15 @@ impl WitnessData {
             .unwrap_or_default();
         buf.put(&(coinbase_bytes.len() as u32).to_le_bytes()[..]);
         buf.put(coinbase_bytes.as_ref());
+        // code_deps: Vec<(H160, Bytes)>, keep the layout unchanged when empty
+        if !self.code_deps.is_empty() {
+            buf.put(&(self.code_deps.len() as u32).to_le_bytes()[..]);
+            for (address, code) in &self.code_deps {
+                buf.put(address.as_bytes());
+                buf.put(&(code.len() as u32).to_le_bytes()[..]);
+                buf.put(code.as_ref());
+            }
+        }
         buf.freeze()
     }
 }
@@ -679,6 +824,31 @@ impl ContractChange {
         tree
     }
 
+    /// The storage keys changed since `previous` change (None: empty storage),
+    /// sorted by key: (key, old_value, new_value), None value means absent.
+    pub fn storage_diff(
+        &self,
+        previous: Option<&ContractChange>,
+    ) -> Vec<(H256, Option<H256>, Option<H256>)> {
+        let empty = HashMap::default();
+        let old_storage = previous.map(|change| &change.new_storage).unwrap_or(&empty);
+        let mut diff = BTreeMap::default();
+        for (key, value) in &self.new_storage {
+            let old_value = old_storage.get(key);
+            if old_value != Some(value) {
+                diff.insert(key.clone(), (old_value.cloned(), Some(value.clone())));
+            }
+        }
+        for (key, old_value) in old_storage {
+            if !self.new_storage.contains_key(key) {
+                diff.insert(key.clone(), (Some(old_value.clone()), None));
+            }
+        }
+        diff.into_iter()
+            .map(|(key, (old_value, new_value))| (key, old_value, new_value))
+            .collect()
+    }
+
     pub fn out_point(&self) -> packed::OutPoint {
         packed::OutPoint::new_builder()
             .tx_hash(self.tx_hash.pack())
@@ -731,6 +901,24 @@ pub fn h256_to_smth256(hash: &H256) -> SmtH256 {
     SmtH256::from(buf)
 }
 
+/// The EXTCODEHASH of the contract code (keccak256), zero for empty accounts
+pub fn code_hash(code: &[u8]) -> H256 {
+    if code.is_empty() {
+        return H256::default();
+    }
+    let mut hasher = Keccak::v256();
+    hasher.update(code);
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash);
+    H256::from(hash)
+}
+
+/// If BLOCKHASH of the block number is available in current block, the
+/// current block itself and the future blocks are not available.
+pub fn block_hash_available(current_number: u64, number: u64) -> bool {
+    number < current_number && current_number - number <= BLOCK_HASH_WINDOW
+}
+
 pub fn cell_balance(output: &packed::CellOutput, data_capacity: u64) -> u64 {
     let capacity: u64 = output.capacity().unpack();
     let occupied_capacity: u64 = output
@@ -740,6 +928,15 @@ pub fn cell_balance(output: &packed::CellOutput, data_capacity: u64) -> u64 {
     capacity - occupied_capacity
 }
 
+/// The default secp256k1 (sighash) lock script
+pub fn sighash_lock(lock_arg: &H160) -> packed::Script {
+    packed::Script::new_builder()
+        .code_hash(SIGHASH_TYPE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::from(lock_arg.as_bytes().to_vec()).pack())
+        .build()
+}
+
 pub fn contract_account_balance(output: &packed::CellOutput) -> u64 {
     cell_balance(output, (32 + 32) * ONE_CKB)
 }
@@ -952,7 +1149,38 @@ pub fn parse_log(raw: &[u8]) -> Result<(Vec<H256>, Bytes), String> {
 mod test {
     use super::*;
     use ckb_simple_account_layer::RunProofResult;
-    use ckb_types::h160;
+    use ckb_types::{h160, h256};
+
+    #[test]
+    fn test_default_execution_limits() {
+        let limits = ExecutionLimits::default();
+        assert_eq!(limits.max_depth, HOST_MAX_DEPTH);
+        assert_eq!(limits.max_cycles, MAX_TX_CYCLES);
+        assert!(limits.max_contracts > 0);
+        assert!(limits.max_contracts < limits.max_programs);
+        let witness_size = WitnessData::new(Program::default()).serialize().len();
+        assert!(limits.max_programs * witness_size <= MAX_TX_SIZE as usize);
+    }
+
+    #[test]
+    fn test_code_hash() {
+        assert_eq!(code_hash(&[]), H256::default());
+        assert_eq!(
+            code_hash(&[0x00]),
+            h256!("0xbc36789e7a1e281436464229828f817d6612f7b477d66591ff96a9e064bcc98a")
+        );
+    }
+
+    #[test]
+    fn test_block_hash_window() {
+        assert!(!block_hash_available(0, 0));
+        assert!(block_hash_available(1, 0));
+        assert!(!block_hash_available(300, 300));
+        assert!(!block_hash_available(300, 301));
+        assert!(block_hash_available(300, 299));
+        assert!(block_hash_available(300, 44));
+        assert!(!block_hash_available(300, 43));
+    }
 
     #[test]
     fn test_serde_program() {
@@ -989,10 +1217,36 @@ mod test {
             return_data: Bytes::from("return data"),
             selfdestruct: None,
             calls: vec![
-                (ContractAddress(h160!("0x33")), 0),
-                (ContractAddress(h160!("0x44")), 3),
+                CallRecord {
+                    destination: h160!("0x33"),
+                    program_index: 0,
+                    value: 0,
+                    transfer_only: false,
+                    is_eoa: false,
+                    is_precompile: false,
+                },
+                CallRecord {
+                    destination: h160!("0x44"),
+                    program_index: 3,
+                    value: 5,
+                    transfer_only: true,
+                    is_eoa: true,
+                    is_precompile: false,
+                },
+                CallRecord {
+                    destination: h160!("0x2"),
+                    program_index: 0,
+                    value: 0,
+                    transfer_only: false,
+                    is_eoa: false,
+                    is_precompile: true,
+                },
             ],
             coinbase: None,
+            code_deps: vec![
+                (h160!("0x55"), Bytes::from("6000")),
+                (h160!("0x66"), Bytes::default()),
+            ],
             run_proof: Bytes::from(run_proof_data),
         };
         let program_data = witness_data1.program_data();
@@ -1000,4 +1254,95 @@ mod test {
         let witness_data2 = WitnessData::load_from(binary.as_ref()).unwrap().unwrap().1;
         assert_eq!(witness_data1, witness_data2);
     }
+
+    #[test]
+    fn test_call_record_flags() {
+        let run_proof_data = RunProofResult::default().serialize_pure().unwrap();
+        let destination = H160::from_slice(&[0x77; 20]).unwrap();
+        let witness_data = |is_eoa: bool, is_precompile: bool| WitnessData {
+            signature: Bytes::from([1u8; 65].to_vec()),
+            program: Program::new_create(
+                Default::default(),
+                Default::default(),
+                Bytes::from("abcdef"),
+                0,
+            ),
+            return_data: Bytes::default(),
+            selfdestruct: None,
+            calls: vec![CallRecord {
+                destination: destination.clone(),
+                program_index: 1,
+                value: 0,
+                transfer_only: is_eoa,
+                is_eoa,
+                is_precompile,
+            }],
+            coinbase: None,
+            code_deps: Vec::new(),
+            run_proof: Bytes::from(run_proof_data.clone()),
+        };
+        // (destination, program_index, value, transfer_only, flags)
+        let flags_offset = |data: &[u8]| {
+            let position = data
+                .windows(20)
+                .position(|window| window == destination.as_bytes())
+                .unwrap();
+            position + 20 + 4 + 8 + 1
+        };
+
+        // Same layout as the witnesses before precompiled contracts
+        for (is_eoa, is_precompile, flags) in
+            vec![(false, false, 0u8), (true, false, 1), (false, true, 2)]
+        {
+            let data = witness_data(is_eoa, is_precompile).program_data();
+            let offset = flags_offset(&data);
+            assert_eq!(data[offset], flags);
+            // The coinbase (empty) follows the flags
+            assert_eq!(&data[offset + 1..offset + 5], &[0u8; 4][..]);
+        }
+
+        let mut data = witness_data(false, true).program_data().to_vec();
+        for flags in vec![3u8, 4] {
+            let offset = flags_offset(&data);
+            data[offset] = flags;
+            let mut binary = Vec::new();
+            binary.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            binary.extend_from_slice(&data);
+            binary.extend_from_slice(&run_proof_data);
+            assert!(WitnessData::load_from(&binary).is_err());
+        }
+    }
+
+    #[test]
+    fn test_storage_diff() {
+        let mut change1 = ContractChange::default();
+        change1.new_storage.insert(h256!("0x1"), h256!("0x11"));
+        change1.new_storage.insert(h256!("0x2"), h256!("0x22"));
+        let mut change2 = ContractChange::default();
+        change2.new_storage.insert(h256!("0x1"), h256!("0x11"));
+        change2.new_storage.insert(h256!("0x2"), h256!("0x23"));
+        change2.new_storage.insert(h256!("0x3"), h256!("0x33"));
+
+        assert_eq!(
+            change1.storage_diff(None),
+            vec![
+                (h256!("0x1"), None, Some(h256!("0x11"))),
+                (h256!("0x2"), None, Some(h256!("0x22"))),
+            ]
+        );
+        assert_eq!(
+            change2.storage_diff(Some(&change1)),
+            vec![
+                (h256!("0x2"), Some(h256!("0x22")), Some(h256!("0x23"))),
+                (h256!("0x3"), None, Some(h256!("0x33"))),
+            ]
+        );
+        assert_eq!(
+            change1.storage_diff(Some(&change2)),
+            vec![
+                (h256!("0x2"), Some(h256!("0x23")), Some(h256!("0x22"))),
+                (h256!("0x3"), Some(h256!("0x33")), None),
+            ]
+        );
+    }
 }